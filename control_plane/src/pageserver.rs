@@ -347,6 +347,56 @@ pub fn parse_config(mut settings: HashMap<&str, &str>) -> anyhow::Result<models:
                 .map(serde_json::from_str)
                 .transpose()
                 .context("Failed to parse 'compaction_algorithm' json")?,
+            compaction_max_versions_per_key: settings
+                .remove("compaction_max_versions_per_key")
+                .map(|x| x.parse::<usize>())
+                .transpose()
+                .context("Failed to parse 'compaction_max_versions_per_key' as an integer")?,
+            compaction_io_throttle_bytes_per_sec: settings
+                .remove("compaction_io_throttle_bytes_per_sec")
+                .map(|x| x.parse::<u64>())
+                .transpose()
+                .context("Failed to parse 'compaction_io_throttle_bytes_per_sec' as an integer")?,
+            compaction_min_hole_coverage_size: settings
+                .remove("compaction_min_hole_coverage_size")
+                .map(|x| x.parse::<usize>())
+                .transpose()
+                .context("Failed to parse 'compaction_min_hole_coverage_size' as an integer")?,
+            compaction_oversize_warn_multiplier: settings
+                .remove("compaction_oversize_warn_multiplier")
+                .map(|x| x.parse::<u64>())
+                .transpose()
+                .context("Failed to parse 'compaction_oversize_warn_multiplier' as an integer")?,
+            compaction_align_to_partitions: settings
+                .remove("compaction_align_to_partitions")
+                .map(|x| x.parse::<bool>())
+                .transpose()
+                .context("Failed to parse 'compaction_align_to_partitions' as bool")?,
+            compaction_upload_concurrency: settings
+                .remove("compaction_upload_concurrency")
+                .map(|x| x.parse::<usize>())
+                .transpose()
+                .context("Failed to parse 'compaction_upload_concurrency' as an integer")?,
+            gc_compaction_verify_every_nth_key: settings
+                .remove("gc_compaction_verify_every_nth_key")
+                .map(|x| x.parse::<u64>())
+                .transpose()
+                .context("Failed to parse 'gc_compaction_verify_every_nth_key' as an integer")?,
+            compaction_layer_lock_timeout: settings
+                .remove("compaction_layer_lock_timeout")
+                .map(|x| x.to_string()),
+            compaction_time_budget: settings
+                .remove("compaction_time_budget")
+                .map(|x| x.to_string()),
+            l0_compaction_delta_size_limit: settings
+                .remove("l0_compaction_delta_size_limit")
+                .map(|x| x.parse::<u64>())
+                .transpose()
+                .context("Failed to parse 'l0_compaction_delta_size_limit' as an integer")?,
+            compact_level0_phase1_value_access: settings
+                .remove("compact_level0_phase1_value_access")
+                .map(models::CompactL0Phase1ValueAccess::from_json_str)
+                .transpose()?,
             gc_horizon: settings
                 .remove("gc_horizon")
                 .map(|x| x.parse::<u64>())
@@ -443,6 +493,62 @@ pub async fn tenant_config(
                     .map(serde_json::from_str)
                     .transpose()
                     .context("Failed to parse 'compaction_algorithm' json")?,
+                compaction_max_versions_per_key: settings
+                    .remove("compaction_max_versions_per_key")
+                    .map(|x| x.parse::<usize>())
+                    .transpose()
+                    .context("Failed to parse 'compaction_max_versions_per_key' as an integer")?,
+                compaction_io_throttle_bytes_per_sec: settings
+                    .remove("compaction_io_throttle_bytes_per_sec")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context(
+                        "Failed to parse 'compaction_io_throttle_bytes_per_sec' as an integer",
+                    )?,
+                compaction_min_hole_coverage_size: settings
+                    .remove("compaction_min_hole_coverage_size")
+                    .map(|x| x.parse::<usize>())
+                    .transpose()
+                    .context("Failed to parse 'compaction_min_hole_coverage_size' as an integer")?,
+                compaction_oversize_warn_multiplier: settings
+                    .remove("compaction_oversize_warn_multiplier")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context(
+                        "Failed to parse 'compaction_oversize_warn_multiplier' as an integer",
+                    )?,
+                compaction_align_to_partitions: settings
+                    .remove("compaction_align_to_partitions")
+                    .map(|x| x.parse::<bool>())
+                    .transpose()
+                    .context("Failed to parse 'compaction_align_to_partitions' as bool")?,
+                compaction_upload_concurrency: settings
+                    .remove("compaction_upload_concurrency")
+                    .map(|x| x.parse::<usize>())
+                    .transpose()
+                    .context("Failed to parse 'compaction_upload_concurrency' as an integer")?,
+                gc_compaction_verify_every_nth_key: settings
+                    .remove("gc_compaction_verify_every_nth_key")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context(
+                        "Failed to parse 'gc_compaction_verify_every_nth_key' as an integer",
+                    )?,
+                compaction_layer_lock_timeout: settings
+                    .remove("compaction_layer_lock_timeout")
+                    .map(|x| x.to_string()),
+                compaction_time_budget: settings
+                    .remove("compaction_time_budget")
+                    .map(|x| x.to_string()),
+                l0_compaction_delta_size_limit: settings
+                    .remove("l0_compaction_delta_size_limit")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context("Failed to parse 'l0_compaction_delta_size_limit' as an integer")?,
+                compact_level0_phase1_value_access: settings
+                    .remove("compact_level0_phase1_value_access")
+                    .map(models::CompactL0Phase1ValueAccess::from_json_str)
+                    .transpose()?,
                 gc_horizon: settings
                     .remove("gc_horizon")
                     .map(|x| x.parse::<u64>())
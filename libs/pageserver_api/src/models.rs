@@ -14,6 +14,7 @@
 };
 
 use byteorder::{BigEndian, ReadBytesExt};
+use itertools::Itertools;
 use postgres_ffi::BLCKSZ;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
@@ -285,6 +286,17 @@ pub struct TenantConfig {
     pub compaction_threshold: Option<usize>,
     // defer parsing compaction_algorithm, like eviction_policy
     pub compaction_algorithm: Option<CompactionAlgorithmSettings>,
+    pub compaction_max_versions_per_key: Option<usize>,
+    pub compaction_io_throttle_bytes_per_sec: Option<u64>,
+    pub compaction_min_hole_coverage_size: Option<usize>,
+    pub compaction_oversize_warn_multiplier: Option<u64>,
+    pub compaction_align_to_partitions: Option<bool>,
+    pub compaction_upload_concurrency: Option<usize>,
+    pub gc_compaction_verify_every_nth_key: Option<u64>,
+    pub compaction_layer_lock_timeout: Option<String>,
+    pub compaction_time_budget: Option<String>,
+    pub l0_compaction_delta_size_limit: Option<u64>,
+    pub compact_level0_phase1_value_access: Option<CompactL0Phase1ValueAccess>,
     pub gc_horizon: Option<u64>,
     pub gc_period: Option<String>,
     pub image_creation_threshold: Option<usize>,
@@ -475,6 +487,121 @@ pub struct CompactionAlgorithmSettings {
     pub kind: CompactionAlgorithm,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum CompactL0Phase1ValueAccess {
+    /// The old way.
+    PageCachedBlobIo,
+    /// The new way.
+    StreamingKmerge {
+        /// If set, we run both the old way and the new way, validate that
+        /// they are identical (=> [`CompactL0BypassPageCacheValidation`]),
+        /// and if the validation fails,
+        /// - in tests: fail them with a panic or
+        /// - in prod, log a rate-limited warning and use the old way's results.
+        ///
+        /// If not set, we only run the new way and trust its results.
+        validate: Option<CompactL0BypassPageCacheValidation>,
+    },
+}
+
+/// See [`CompactL0Phase1ValueAccess::StreamingKmerge`].
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompactL0BypassPageCacheValidation {
+    /// Validate that the series of (key, lsn) pairs are the same.
+    KeyLsn,
+    /// Validate that the entire output of old and new way is identical.
+    KeyLsnValue,
+}
+
+impl Default for CompactL0Phase1ValueAccess {
+    fn default() -> Self {
+        CompactL0Phase1ValueAccess::StreamingKmerge {
+            // TODO(https://github.com/neondatabase/neon/issues/8184): change to None once confident
+            validate: Some(CompactL0BypassPageCacheValidation::KeyLsnValue),
+        }
+    }
+}
+
+/// A valid `mode` of [`CompactL0Phase1ValueAccess`], as returned by
+/// [`CompactL0Phase1ValueAccess::describe`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CompactL0Phase1ValueAccessMode {
+    /// The value of the `mode` field.
+    pub mode: String,
+    /// The valid values of the optional `validate` field, if the mode has one.
+    pub validate: Option<Vec<String>>,
+}
+
+impl std::fmt::Display for CompactL0Phase1ValueAccessMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.mode)?;
+        if let Some(validate) = &self.validate {
+            let validate = validate.iter().map(|v| format!("{v:?}")).join(" | ");
+            write!(f, " (optional validate: {validate})")?;
+        }
+        Ok(())
+    }
+}
+
+impl CompactL0Phase1ValueAccess {
+    /// Lists the valid modes and their sub-options, spelled as in the config.
+    pub fn describe() -> Vec<CompactL0Phase1ValueAccessMode> {
+        // Take the names from the serialized values, so that they can't diverge from what we accept.
+        fn name_of<T: Serialize>(value: &T) -> String {
+            let name = match serde_json::to_value(value).expect("serializable") {
+                serde_json::Value::Object(mut fields) => fields.remove("mode"),
+                value => Some(value),
+            };
+            match name {
+                Some(serde_json::Value::String(name)) => name,
+                name => unreachable!("unexpected serialization {name:?}"),
+            }
+        }
+
+        [
+            Self::PageCachedBlobIo,
+            Self::StreamingKmerge { validate: None },
+        ]
+        .into_iter()
+        .map(|mode| {
+            let validate = match &mode {
+                Self::PageCachedBlobIo => None,
+                Self::StreamingKmerge { .. } => Some(
+                    [
+                        CompactL0BypassPageCacheValidation::KeyLsn,
+                        CompactL0BypassPageCacheValidation::KeyLsnValue,
+                    ]
+                    .iter()
+                    .map(name_of)
+                    .collect(),
+                ),
+            };
+            CompactL0Phase1ValueAccessMode {
+                mode: name_of(&mode),
+                validate,
+            }
+        })
+        .collect()
+    }
+
+    /// The valid modes, formatted for error messages.
+    pub fn describe_alternatives() -> String {
+        Self::describe().iter().join(", ")
+    }
+
+    /// Parses an operator-supplied JSON value, listing the valid modes if it is invalid.
+    pub fn from_json_str(s: &str) -> anyhow::Result<Self> {
+        serde_json::from_str(s).map_err(|e| {
+            anyhow::anyhow!(
+                "invalid compact_level0_phase1_value_access {s:?}: {e}, valid modes are: {}",
+                Self::describe_alternatives()
+            )
+        })
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct EvictionPolicyLayerAccessThreshold {
     #[serde(with = "humantime_serde")]
@@ -756,8 +883,34 @@ pub struct LayerAccessStats {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "kind")]
 pub enum InMemoryLayerInfo {
-    Open { lsn_start: Lsn },
-    Frozen { lsn_start: Lsn, lsn_end: Lsn },
+    Open {
+        lsn_start: Lsn,
+        /// How long the layer has been open, in milliseconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        open_duration_ms: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stats: Option<InMemoryLayerStats>,
+    },
+    Frozen {
+        lsn_start: Lsn,
+        lsn_end: Lsn,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stats: Option<InMemoryLayerStats>,
+    },
+}
+
+/// Summary of the contents of an in-memory layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InMemoryLayerStats {
+    /// Number of distinct keys in the index
+    pub num_keys: usize,
+    /// Number of (key, lsn) entries in the index, including tombstones
+    pub num_versions: usize,
+    /// Length of the backing ephemeral file
+    pub file_bytes: u64,
+    /// Lowest and highest LSN of any entry, `None` if the layer is empty
+    pub oldest_lsn: Option<Lsn>,
+    pub newest_lsn: Option<Lsn>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1537,6 +1690,61 @@ fn test_tenantinfo_serde() {
         assert!(format!("{:?}", &original_broken.state).contains("backtrace info"));
     }
 
+    #[test]
+    fn test_inmemory_layer_info_serde() {
+        let open = InMemoryLayerInfo::Open {
+            lsn_start: Lsn(0x10),
+            open_duration_ms: Some(42),
+            stats: None,
+        };
+        let expected = json!({
+            "kind": "Open",
+            "lsn_start": "0/10",
+            "open_duration_ms": 42,
+        });
+        assert_eq!(serde_json::to_value(&open).unwrap(), expected);
+
+        // Older pageservers don't report the open duration
+        let parsed = serde_json::from_value::<InMemoryLayerInfo>(json!({
+            "kind": "Open",
+            "lsn_start": "0/10",
+        }))
+        .unwrap();
+        assert!(matches!(
+            parsed,
+            InMemoryLayerInfo::Open {
+                lsn_start: Lsn(0x10),
+                open_duration_ms: None,
+                stats: None,
+            }
+        ));
+
+        let frozen = InMemoryLayerInfo::Frozen {
+            lsn_start: Lsn(0x10),
+            lsn_end: Lsn(0x20),
+            stats: Some(InMemoryLayerStats {
+                num_keys: 2,
+                num_versions: 3,
+                file_bytes: 8192,
+                oldest_lsn: Some(Lsn(0x10)),
+                newest_lsn: Some(Lsn(0x18)),
+            }),
+        };
+        let expected = json!({
+            "kind": "Frozen",
+            "lsn_start": "0/10",
+            "lsn_end": "0/20",
+            "stats": {
+                "num_keys": 2,
+                "num_versions": 3,
+                "file_bytes": 8192,
+                "oldest_lsn": "0/10",
+                "newest_lsn": "0/18",
+            },
+        });
+        assert_eq!(serde_json::to_value(&frozen).unwrap(), expected);
+    }
+
     #[test]
     fn test_reject_unknown_field() {
         let id = TenantId::generate();
@@ -5,7 +5,10 @@
 //! See also `settings.md` for better description on every parameter.
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
-use pageserver_api::{models::ImageCompressionAlgorithm, shard::TenantShardId};
+use pageserver_api::{
+    models::{CompactL0Phase1ValueAccess, ImageCompressionAlgorithm},
+    shard::TenantShardId,
+};
 use remote_storage::{RemotePath, RemoteStorageConfig};
 use serde::de::IntoDeserializer;
 use serde::{self, Deserialize};
@@ -31,12 +34,14 @@
 
 use crate::l0_flush::L0FlushConfig;
 use crate::tenant::config::TenantConfOpt;
-use crate::tenant::timeline::compaction::CompactL0Phase1ValueAccess;
 use crate::tenant::vectored_blob_io::MaxVectoredReadBytes;
 use crate::tenant::{TENANTS_SEGMENT_NAME, TIMELINES_SEGMENT_NAME};
 use crate::{disk_usage_eviction_task::DiskUsageEvictionTaskConfig, virtual_file::io_engine};
 use crate::{tenant::config::TenantConf, virtual_file};
-use crate::{TENANT_HEATMAP_BASENAME, TENANT_LOCATION_CONFIG_NAME, TIMELINE_DELETE_MARK_SUFFIX};
+use crate::{
+    GC_COMPACTION_MANIFEST_NAME, TENANT_HEATMAP_BASENAME, TENANT_LOCATION_CONFIG_NAME,
+    TIMELINE_DELETE_MARK_SUFFIX,
+};
 
 use self::defaults::DEFAULT_CONCURRENT_TENANT_WARMUP;
 
@@ -91,10 +96,14 @@ pub mod defaults {
 
     pub const DEFAULT_IMAGE_COMPRESSION: &str = "zstd(1)";
 
+    pub const DEFAULT_IMAGE_LAYER_DEDUP: bool = false;
+
     pub const DEFAULT_VALIDATE_VECTORED_GET: bool = false;
 
     pub const DEFAULT_EPHEMERAL_BYTES_PER_MEMORY_KB: usize = 0;
 
+    pub const DEFAULT_EPHEMERAL_VALUE_CHECKSUMS: bool = false;
+
     ///
     /// Default built-in configuration file.
     ///
@@ -274,6 +283,10 @@ pub struct PageServerConf {
 
     pub image_compression: ImageCompressionAlgorithm,
 
+    /// Store identical images in an image layer only once, and reference the stored copy from
+    /// the other keys. Pageservers without support for such references can't read these layers.
+    pub image_layer_dedup: bool,
+
     /// How many bytes of ephemeral layer content will we allow per kilobyte of RAM.  When this
     /// is exceeded, we start proactively closing ephemeral layers to limit the total amount
     /// of ephemeral data.
@@ -281,6 +294,14 @@ pub struct PageServerConf {
     /// Setting this to zero disables limits on total ephemeral layer size.
     pub ephemeral_bytes_per_memory_kb: usize,
 
+    /// Sync ephemeral files to disk whenever this many bytes have been written to them since
+    /// the last sync. `None` disables the periodic sync.
+    pub ephemeral_file_sync_interval_bytes: Option<u64>,
+
+    /// Follow each value written to the ephemeral files of in-memory layers with a CRC32C
+    /// checksum, which is verified whenever the value is read back.
+    pub ephemeral_value_checksums: bool,
+
     pub l0_flush: L0FlushConfig,
 
     /// This flag is temporary and will be removed after gradual rollout.
@@ -386,8 +407,14 @@ struct PageServerConfigBuilder {
 
     image_compression: BuilderValue<ImageCompressionAlgorithm>,
 
+    image_layer_dedup: BuilderValue<bool>,
+
     ephemeral_bytes_per_memory_kb: BuilderValue<usize>,
 
+    ephemeral_file_sync_interval_bytes: BuilderValue<Option<u64>>,
+
+    ephemeral_value_checksums: BuilderValue<bool>,
+
     l0_flush: BuilderValue<L0FlushConfig>,
 
     compact_level0_phase1_value_access: BuilderValue<CompactL0Phase1ValueAccess>,
@@ -477,7 +504,10 @@ fn default_values() -> Self {
                 NonZeroUsize::new(DEFAULT_MAX_VECTORED_READ_BYTES).unwrap(),
             )),
             image_compression: Set(DEFAULT_IMAGE_COMPRESSION.parse().unwrap()),
+            image_layer_dedup: Set(DEFAULT_IMAGE_LAYER_DEDUP),
             ephemeral_bytes_per_memory_kb: Set(DEFAULT_EPHEMERAL_BYTES_PER_MEMORY_KB),
+            ephemeral_file_sync_interval_bytes: Set(None),
+            ephemeral_value_checksums: Set(DEFAULT_EPHEMERAL_VALUE_CHECKSUMS),
             l0_flush: Set(L0FlushConfig::default()),
             compact_level0_phase1_value_access: Set(CompactL0Phase1ValueAccess::default()),
             virtual_file_direct_io: Set(virtual_file::DirectIoMode::default()),
@@ -644,10 +674,22 @@ pub fn get_image_compression(&mut self, value: ImageCompressionAlgorithm) {
         self.image_compression = BuilderValue::Set(value);
     }
 
+    pub fn get_image_layer_dedup(&mut self, value: bool) {
+        self.image_layer_dedup = BuilderValue::Set(value);
+    }
+
     pub fn get_ephemeral_bytes_per_memory_kb(&mut self, value: usize) {
         self.ephemeral_bytes_per_memory_kb = BuilderValue::Set(value);
     }
 
+    pub fn ephemeral_file_sync_interval_bytes(&mut self, value: Option<u64>) {
+        self.ephemeral_file_sync_interval_bytes = BuilderValue::Set(value);
+    }
+
+    pub fn ephemeral_value_checksums(&mut self, value: bool) {
+        self.ephemeral_value_checksums = BuilderValue::Set(value);
+    }
+
     pub fn l0_flush(&mut self, value: L0FlushConfig) {
         self.l0_flush = BuilderValue::Set(value);
     }
@@ -712,7 +754,10 @@ macro_rules! conf {
                 ingest_batch_size,
                 max_vectored_read_bytes,
                 image_compression,
+                image_layer_dedup,
                 ephemeral_bytes_per_memory_kb,
+                ephemeral_file_sync_interval_bytes,
+                ephemeral_value_checksums,
                 l0_flush,
                 compact_level0_phase1_value_access,
                 virtual_file_direct_io,
@@ -824,6 +869,15 @@ pub fn timeline_path(
             .join(timeline_id.to_string())
     }
 
+    pub(crate) fn gc_compaction_manifest_path(
+        &self,
+        tenant_shard_id: &TenantShardId,
+        timeline_id: &TimelineId,
+    ) -> Utf8PathBuf {
+        self.timeline_path(tenant_shard_id, timeline_id)
+            .join(GC_COMPACTION_MANIFEST_NAME)
+    }
+
     pub(crate) fn timeline_delete_mark_file_path(
         &self,
         tenant_shard_id: TenantShardId,
@@ -973,14 +1027,23 @@ pub fn parse_and_validate(
                 "image_compression" => {
                     builder.get_image_compression(parse_toml_from_str("image_compression", item)?)
                 }
+                "image_layer_dedup" => {
+                    builder.get_image_layer_dedup(parse_toml_bool(key, item)?)
+                }
                 "ephemeral_bytes_per_memory_kb" => {
                     builder.get_ephemeral_bytes_per_memory_kb(parse_toml_u64("ephemeral_bytes_per_memory_kb", item)? as usize)
                 }
+                "ephemeral_file_sync_interval_bytes" => {
+                    builder.ephemeral_file_sync_interval_bytes(Some(parse_toml_u64(key, item)?))
+                }
+                "ephemeral_value_checksums" => {
+                    builder.ephemeral_value_checksums(parse_toml_bool(key, item)?)
+                }
                 "l0_flush" => {
                     builder.l0_flush(utils::toml_edit_ext::deserialize_item(item).context("l0_flush")?)
                 }
                 "compact_level0_phase1_value_access" => {
-                    builder.compact_level0_phase1_value_access(utils::toml_edit_ext::deserialize_item(item).context("compact_level0_phase1_value_access")?)
+                    builder.compact_level0_phase1_value_access(utils::toml_edit_ext::deserialize_item(item).with_context(|| format!("compact_level0_phase1_value_access, valid modes are: {}", CompactL0Phase1ValueAccess::describe_alternatives()))?)
                 }
                 "virtual_file_direct_io" => {
                     builder.virtual_file_direct_io(utils::toml_edit_ext::deserialize_item(item).context("virtual_file_direct_io")?)
@@ -1064,7 +1127,10 @@ pub fn dummy_conf(repo_dir: Utf8PathBuf) -> Self {
                     .expect("Invalid default constant"),
             ),
             image_compression: defaults::DEFAULT_IMAGE_COMPRESSION.parse().unwrap(),
+            image_layer_dedup: defaults::DEFAULT_IMAGE_LAYER_DEDUP,
             ephemeral_bytes_per_memory_kb: defaults::DEFAULT_EPHEMERAL_BYTES_PER_MEMORY_KB,
+            ephemeral_file_sync_interval_bytes: None,
+            ephemeral_value_checksums: defaults::DEFAULT_EPHEMERAL_VALUE_CHECKSUMS,
             l0_flush: L0FlushConfig::default(),
             compact_level0_phase1_value_access: CompactL0Phase1ValueAccess::default(),
             virtual_file_direct_io: virtual_file::DirectIoMode::default(),
@@ -1204,7 +1270,7 @@ mod tests {
     use std::{fs, num::NonZeroU32};
 
     use camino_tempfile::{tempdir, Utf8TempDir};
-    use pageserver_api::models::EvictionPolicy;
+    use pageserver_api::models::{CompactL0Phase1ValueAccessMode, EvictionPolicy};
     use remote_storage::{RemoteStorageKind, S3Config};
     use utils::serde_percent::Percent;
 
@@ -1304,7 +1370,10 @@ fn parse_defaults() -> anyhow::Result<()> {
                         .expect("Invalid default constant")
                 ),
                 image_compression: defaults::DEFAULT_IMAGE_COMPRESSION.parse().unwrap(),
+                image_layer_dedup: defaults::DEFAULT_IMAGE_LAYER_DEDUP,
                 ephemeral_bytes_per_memory_kb: defaults::DEFAULT_EPHEMERAL_BYTES_PER_MEMORY_KB,
+                ephemeral_file_sync_interval_bytes: None,
+                ephemeral_value_checksums: defaults::DEFAULT_EPHEMERAL_VALUE_CHECKSUMS,
                 l0_flush: L0FlushConfig::default(),
                 compact_level0_phase1_value_access: CompactL0Phase1ValueAccess::default(),
                 virtual_file_direct_io: virtual_file::DirectIoMode::default(),
@@ -1377,7 +1446,10 @@ fn parse_basic_config() -> anyhow::Result<()> {
                         .expect("Invalid default constant")
                 ),
                 image_compression: defaults::DEFAULT_IMAGE_COMPRESSION.parse().unwrap(),
+                image_layer_dedup: defaults::DEFAULT_IMAGE_LAYER_DEDUP,
                 ephemeral_bytes_per_memory_kb: defaults::DEFAULT_EPHEMERAL_BYTES_PER_MEMORY_KB,
+                ephemeral_file_sync_interval_bytes: None,
+                ephemeral_value_checksums: defaults::DEFAULT_EPHEMERAL_VALUE_CHECKSUMS,
                 l0_flush: L0FlushConfig::default(),
                 compact_level0_phase1_value_access: CompactL0Phase1ValueAccess::default(),
                 virtual_file_direct_io: virtual_file::DirectIoMode::default(),
@@ -1523,6 +1595,42 @@ fn parse_incorrect_tenant_config() -> anyhow::Result<()> {
         Ok(())
     }
 
+    #[test]
+    fn describe_compact_level0_phase1_value_access() {
+        let modes = CompactL0Phase1ValueAccess::describe();
+        assert_eq!(
+            modes,
+            vec![
+                CompactL0Phase1ValueAccessMode {
+                    mode: "page-cached-blob-io".to_string(),
+                    validate: None,
+                },
+                CompactL0Phase1ValueAccessMode {
+                    mode: "streaming-kmerge".to_string(),
+                    validate: Some(vec!["key-lsn".to_string(), "key-lsn-value".to_string()]),
+                },
+            ]
+        );
+
+        // Every described mode and sub-option parses.
+        for mode in modes {
+            for validate in mode.validate.unwrap_or_default() {
+                let s = format!(r#"{{"mode": "{}", "validate": "{validate}"}}"#, mode.mode);
+                CompactL0Phase1ValueAccess::from_json_str(&s).unwrap();
+            }
+            let s = format!(r#"{{"mode": "{}"}}"#, mode.mode);
+            CompactL0Phase1ValueAccess::from_json_str(&s).unwrap();
+        }
+
+        let err = CompactL0Phase1ValueAccess::from_json_str(r#"{"mode": "streaming"}"#)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(r#""page-cached-blob-io", "streaming-kmerge" (optional validate: "key-lsn" | "key-lsn-value")"#),
+            "{err}"
+        );
+    }
+
     #[test]
     fn parse_override_tenant_config() -> anyhow::Result<()> {
         let config_string = r#"tenant_config={ min_resident_size_override =  400 }"#.to_string();
@@ -76,6 +76,7 @@
 use crate::tenant::storage_layer::LayerAccessStatsReset;
 use crate::tenant::storage_layer::LayerName;
 use crate::tenant::timeline::CompactFlags;
+use crate::tenant::timeline::CompactOptions;
 use crate::tenant::timeline::CompactionError;
 use crate::tenant::timeline::Timeline;
 use crate::tenant::GetTimelineError;
@@ -183,6 +184,7 @@ fn from(pre: PageReconstructError) -> ApiError {
             PageReconstructError::Cancelled => ApiError::Cancelled,
             PageReconstructError::AncestorLsnTimeout(e) => ApiError::Timeout(format!("{e}").into()),
             PageReconstructError::WalRedo(pre) => ApiError::InternalServerError(pre),
+            PageReconstructError::Corruption(e) => ApiError::InternalServerError(e),
         }
     }
 }
@@ -1229,6 +1231,60 @@ async fn evict_timeline_layer_handler(
     }
 }
 
+async fn pin_timeline_layer_handler(
+    request: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_shard_id: TenantShardId = parse_request_param(&request, "tenant_shard_id")?;
+    check_permission(&request, Some(tenant_shard_id.tenant_id))?;
+    let timeline_id: TimelineId = parse_request_param(&request, "timeline_id")?;
+    let layer_file_name = get_request_param(&request, "layer_file_name")?;
+    let state = get_state(&request);
+
+    let layer_name = LayerName::from_str(layer_file_name)
+        .map_err(|s| ApiError::BadRequest(anyhow::anyhow!(s)))?;
+
+    let timeline =
+        active_timeline_of_active_tenant(&state.tenant_manager, tenant_shard_id, timeline_id)
+            .await?;
+    let pinned = timeline
+        .pin_layer(&layer_name)
+        .await
+        .map_err(ApiError::InternalServerError)?;
+
+    match pinned {
+        Some(true) => json_response(StatusCode::OK, ()),
+        Some(false) => json_response(StatusCode::NOT_MODIFIED, ()),
+        None => json_response(
+            StatusCode::BAD_REQUEST,
+            format!("Layer {tenant_shard_id}/{timeline_id}/{layer_file_name} not found"),
+        ),
+    }
+}
+
+async fn unpin_timeline_layer_handler(
+    request: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_shard_id: TenantShardId = parse_request_param(&request, "tenant_shard_id")?;
+    check_permission(&request, Some(tenant_shard_id.tenant_id))?;
+    let timeline_id: TimelineId = parse_request_param(&request, "timeline_id")?;
+    let layer_file_name = get_request_param(&request, "layer_file_name")?;
+    let state = get_state(&request);
+
+    let layer_name = LayerName::from_str(layer_file_name)
+        .map_err(|s| ApiError::BadRequest(anyhow::anyhow!(s)))?;
+
+    let timeline =
+        active_timeline_of_active_tenant(&state.tenant_manager, tenant_shard_id, timeline_id)
+            .await?;
+    if timeline.unpin_layer(&layer_name) {
+        json_response(StatusCode::OK, ())
+    } else {
+        json_response(StatusCode::NOT_MODIFIED, ())
+    }
+}
+
 async fn timeline_gc_blocking_handler(
     request: Request<Body>,
     _cancel: CancellationToken,
@@ -1708,22 +1764,48 @@ async fn timeline_compact_handler(
     if Some(true) == parse_query_param::<_, bool>(&request, "enhanced_gc_bottom_most_compaction")? {
         flags |= CompactFlags::EnhancedGcBottomMostCompaction;
     }
+    let compact_range = match (
+        parse_query_param::<_, crate::repository::Key>(&request, "compact_key_range_start")?,
+        parse_query_param::<_, crate::repository::Key>(&request, "compact_key_range_end")?,
+    ) {
+        (Some(start), Some(end)) => Some(start..end),
+        (None, None) => None,
+        _ => {
+            return Err(ApiError::BadRequest(anyhow!(
+                "compact_key_range_start and compact_key_range_end must be specified together"
+            )))
+        }
+    };
+    let time_budget = parse_query_param(&request, "time_budget_ms")?.map(Duration::from_millis);
+    let options = CompactOptions {
+        flags,
+        compact_range,
+        time_budget,
+    };
     let wait_until_uploaded =
         parse_query_param::<_, bool>(&request, "wait_until_uploaded")?.unwrap_or(false);
 
     async {
         let ctx = RequestContext::new(TaskKind::MgmtRequest, DownloadBehavior::Download);
         let timeline = active_timeline_of_active_tenant(&state.tenant_manager, tenant_shard_id, timeline_id).await?;
-        timeline
-            .compact(&cancel, flags, &ctx)
+        if let Some(compact_range) = &options.compact_range {
+            timeline
+                .check_compact_range(compact_range)
+                .map_err(ApiError::BadRequest)?;
+        }
+        let outcome = timeline
+            .compact_with_options(&cancel, options, &ctx)
             .await
-            .map_err(|e| ApiError::InternalServerError(e.into()))?;
+            .map_err(|e| match e {
+                e @ CompactionError::LockTimeout(_) => ApiError::Timeout(e.to_string().into()),
+                e => ApiError::InternalServerError(e.into()),
+            })?;
         if wait_until_uploaded {
             timeline.remote_client.wait_completion().await
             // XXX map to correct ApiError for the cases where it's due to shutdown
             .context("wait completion").map_err(ApiError::InternalServerError)?;
         }
-        json_response(StatusCode::OK, ())
+        json_response(StatusCode::OK, outcome)
     }
     .instrument(info_span!("manual_compaction", tenant_id = %tenant_shard_id.tenant_id, shard_id = %tenant_shard_id.shard_slug(), %timeline_id))
     .await
@@ -1774,6 +1856,7 @@ async fn timeline_checkpoint_handler(
                 .map_err(|e|
                     match e {
                         CompactionError::ShuttingDown => ApiError::ShuttingDown,
+                        e @ CompactionError::LockTimeout(_) => ApiError::Timeout(e.to_string().into()),
                         CompactionError::Other(e) => ApiError::InternalServerError(e)
                     }
                 )?;
@@ -1793,6 +1876,57 @@ async fn timeline_checkpoint_handler(
     .await
 }
 
+// Recompute the visibility hints of the timeline's layers, without running a compaction.
+async fn timeline_update_layer_visibility_handler(
+    request: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_shard_id: TenantShardId = parse_request_param(&request, "tenant_shard_id")?;
+    let timeline_id: TimelineId = parse_request_param(&request, "timeline_id")?;
+    check_permission(&request, Some(tenant_shard_id.tenant_id))?;
+
+    let state = get_state(&request);
+
+    async {
+        let timeline = active_timeline_of_active_tenant(&state.tenant_manager, tenant_shard_id, timeline_id).await?;
+        let summary = timeline
+            .recompute_layer_visibility()
+            .await
+            .map_err(|_| ApiError::ShuttingDown)?;
+        json_response(StatusCode::OK, summary)
+    }
+    .instrument(info_span!("update_layer_visibility", tenant_id = %tenant_shard_id.tenant_id, shard_id = %tenant_shard_id.shard_slug(), %timeline_id))
+    .await
+}
+
+// Estimate the outstanding compaction work of the timeline, without compacting.
+async fn timeline_compaction_estimate_handler(
+    request: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_shard_id: TenantShardId = parse_request_param(&request, "tenant_shard_id")?;
+    let timeline_id: TimelineId = parse_request_param(&request, "timeline_id")?;
+    check_permission(&request, Some(tenant_shard_id.tenant_id))?;
+
+    let state = get_state(&request);
+
+    async {
+        let ctx = RequestContext::new(TaskKind::MgmtRequest, DownloadBehavior::Download);
+        let timeline = active_timeline_of_active_tenant(&state.tenant_manager, tenant_shard_id, timeline_id).await?;
+        let estimate = timeline
+            .estimate_compaction_work(&ctx)
+            .await
+            .map_err(|e| match e {
+                CompactionError::ShuttingDown => ApiError::ShuttingDown,
+                e @ CompactionError::LockTimeout(_) => ApiError::Timeout(e.to_string().into()),
+                CompactionError::Other(e) => ApiError::InternalServerError(e),
+            })?;
+        json_response(StatusCode::OK, estimate)
+    }
+    .instrument(info_span!("compaction_estimate", tenant_id = %tenant_shard_id.tenant_id, shard_id = %tenant_shard_id.shard_slug(), %timeline_id))
+    .await
+}
+
 async fn timeline_download_remote_layers_handler_post(
     mut request: Request<Body>,
     _cancel: CancellationToken,
@@ -1979,6 +2113,42 @@ fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
     .await
 }
 
+async fn timeline_shard_key_counts_handler(
+    request: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_shard_id: TenantShardId = parse_request_param(&request, "tenant_shard_id")?;
+    let timeline_id: TimelineId = parse_request_param(&request, "timeline_id")?;
+    check_permission(&request, Some(tenant_shard_id.tenant_id))?;
+    let state = get_state(&request);
+
+    struct Key(crate::repository::Key);
+
+    impl std::str::FromStr for Key {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+            crate::repository::Key::from_hex(s).map(Key)
+        }
+    }
+
+    let start: Key = parse_query_param(&request, "start")?
+        .ok_or_else(|| ApiError::BadRequest(anyhow!("missing 'start' query parameter")))?;
+    let end: Key = parse_query_param(&request, "end")?
+        .ok_or_else(|| ApiError::BadRequest(anyhow!("missing 'end' query parameter")))?;
+
+    async {
+        let timeline = active_timeline_of_active_tenant(&state.tenant_manager, tenant_shard_id, timeline_id).await?;
+        let counts = timeline
+            .shard_key_counts(start.0..end.0)
+            .map_err(ApiError::BadRequest)?;
+
+        json_response(StatusCode::OK, counts)
+    }
+    .instrument(info_span!("timeline_shard_key_counts", tenant_id = %tenant_shard_id.tenant_id, shard_id = %tenant_shard_id.shard_slug(), %timeline_id))
+    .await
+}
+
 async fn timeline_collect_keyspace(
     request: Request<Body>,
     _cancel: CancellationToken,
@@ -2943,6 +3113,14 @@ pub fn make_router(
             "/v1/tenant/:tenant_shard_id/timeline/:timeline_id/checkpoint",
             |r| testing_api_handler("run timeline checkpoint", r, timeline_checkpoint_handler),
         )
+        .put(
+            "/v1/tenant/:tenant_shard_id/timeline/:timeline_id/update_layer_visibility",
+            |r| api_handler(r, timeline_update_layer_visibility_handler),
+        )
+        .get(
+            "/v1/tenant/:tenant_shard_id/timeline/:timeline_id/compaction_estimate",
+            |r| api_handler(r, timeline_compaction_estimate_handler),
+        )
         .post(
             "/v1/tenant/:tenant_shard_id/timeline/:timeline_id/download_remote_layers",
             |r| api_handler(r, timeline_download_remote_layers_handler_post),
@@ -2970,6 +3148,14 @@ pub fn make_router(
             "/v1/tenant/:tenant_shard_id/timeline/:timeline_id/layer/:layer_file_name",
             |r| api_handler(r, evict_timeline_layer_handler),
         )
+        .put(
+            "/v1/tenant/:tenant_shard_id/timeline/:timeline_id/layer/:layer_file_name/pin",
+            |r| api_handler(r, pin_timeline_layer_handler),
+        )
+        .delete(
+            "/v1/tenant/:tenant_shard_id/timeline/:timeline_id/layer/:layer_file_name/pin",
+            |r| api_handler(r, unpin_timeline_layer_handler),
+        )
         .post(
             "/v1/tenant/:tenant_shard_id/timeline/:timeline_id/block_gc",
             |r| api_handler(r, timeline_gc_blocking_handler),
@@ -3011,6 +3197,10 @@ pub fn make_router(
             "/v1/tenant/:tenant_shard_id/timeline/:timeline_id/keyspace",
             |r| api_handler(r, timeline_collect_keyspace),
         )
+        .get(
+            "/v1/tenant/:tenant_shard_id/timeline/:timeline_id/shard_key_counts",
+            |r| api_handler(r, timeline_shard_key_counts_handler),
+        )
         .put("/v1/io_engine", |r| api_handler(r, put_io_engine_handler))
         .put(
             "/v1/tenant/:tenant_shard_id/timeline/:timeline_id/force_aux_policy_switch",
@@ -1,4 +1,6 @@
-use std::{num::NonZeroUsize, sync::Arc};
+use std::{num::NonZeroUsize, sync::Arc, time::Instant};
+
+use crate::metrics::{L0_FLUSH_WAITERS, L0_FLUSH_WAIT_TIME};
 
 #[derive(Debug, PartialEq, Eq, Clone, serde::Deserialize)]
 #[serde(tag = "mode", rename_all = "kebab-case", deny_unknown_fields)]
@@ -37,3 +39,24 @@ pub fn inner(&self) -> &Arc<Inner> {
         &self.0
     }
 }
+
+impl Inner {
+    /// Waits for a permit to write a layer to disk, recording the time spent waiting and the
+    /// number of waiting flushes in the metrics.
+    pub async fn acquire_permit(
+        &self,
+    ) -> Result<tokio::sync::SemaphorePermit<'_>, tokio::sync::AcquireError> {
+        match self {
+            Inner::Direct { semaphore } => {
+                let started_at = Instant::now();
+                L0_FLUSH_WAITERS.inc();
+                scopeguard::defer! {
+                    L0_FLUSH_WAITERS.dec();
+                }
+                let permit = semaphore.acquire().await;
+                L0_FLUSH_WAIT_TIME.observe(started_at.elapsed().as_secs_f64());
+                permit
+            }
+        }
+    }
+}
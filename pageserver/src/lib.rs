@@ -258,6 +258,10 @@ struct Results {
 /// tenant path while in secondary mode.
 pub(crate) const TENANT_HEATMAP_BASENAME: &str = "heatmap-v1.json";
 
+/// Progress of an interrupted gc-compaction, which is resumed after a restart.
+/// Full path: `tenants/<tenant_id>/timelines/<timeline_id>/gc-compaction-manifest-v1.json`.
+pub(crate) const GC_COMPACTION_MANIFEST_NAME: &str = "gc-compaction-manifest-v1.json";
+
 /// A suffix used for various temporary files. Any temporary files found in the
 /// data directory at pageserver startup can be automatically removed.
 pub(crate) const TEMP_FILE_SUFFIX: &str = "___temp";
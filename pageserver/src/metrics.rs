@@ -455,6 +455,23 @@ pub(crate) fn page_cache_errors_inc(error_kind: PageCacheErrorKind) {
     .expect("failed to define a metric")
 });
 
+pub(crate) static L0_FLUSH_WAIT_TIME: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "pageserver_l0_flush_wait_seconds",
+        "Time spent by layer flushes waiting for a concurrency permit",
+        CRITICAL_OP_BUCKETS.into(),
+    )
+    .expect("failed to define a metric")
+});
+
+pub(crate) static L0_FLUSH_WAITERS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "pageserver_l0_flush_waiters",
+        "Number of layer flushes waiting for a concurrency permit"
+    )
+    .expect("failed to define a metric")
+});
+
 static LAST_RECORD_LSN: Lazy<IntGaugeVec> = Lazy::new(|| {
     register_int_gauge_vec!(
         "pageserver_last_record_lsn",
@@ -498,6 +515,33 @@ pub(crate) enum MetricLayerKind {
     .expect("failed to define a metric")
 });
 
+static COMPACTION_DISCARDED_LAYERS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pageserver_compaction_discarded_layers_total",
+        "Number of layers produced by gc-compaction that were discarded because the same layer already exists in the current generation",
+        &["tenant_id", "shard_id", "timeline_id", "kind"]
+    )
+    .expect("failed to define a metric")
+});
+
+static COMPACTION_L0_TRUNCATED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pageserver_compaction_l0_truncated_total",
+        "Number of L0 compactions that left out L0 delta layers because they reached the delta size limit",
+        &["tenant_id", "shard_id", "timeline_id"]
+    )
+    .expect("failed to define a metric")
+});
+
+static COMPACTION_L0_REMAINING: Lazy<UIntGaugeVec> = Lazy::new(|| {
+    register_uint_gauge_vec!(
+        "pageserver_compaction_l0_remaining",
+        "Number of L0 delta layers that the last L0 compaction left out because of the delta size limit",
+        &["tenant_id", "shard_id", "timeline_id"]
+    )
+    .expect("failed to define a metric")
+});
+
 static TIMELINE_ARCHIVE_SIZE: Lazy<UIntGaugeVec> = Lazy::new(|| {
     register_uint_gauge_vec!(
         "pageserver_archive_size",
@@ -883,6 +927,37 @@ fn drop(&mut self) {
     .expect("Failed to register metric")
 });
 
+pub(crate) static EPHEMERAL_FILES: Lazy<UIntGauge> = Lazy::new(|| {
+    register_uint_gauge!(
+        "pageserver_ephemeral_files",
+        "Number of open ephemeral files, for all timelines"
+    )
+    .expect("Failed to register metric")
+});
+
+/// Upper bounds of the buckets of [`EPHEMERAL_FILES_BY_SIZE`]: 8 KiB to 2 GiB.
+pub(crate) const EPHEMERAL_FILE_SIZE_BUCKETS: [u64; 10] = [
+    8 << 10,
+    32 << 10,
+    128 << 10,
+    512 << 10,
+    2 << 20,
+    8 << 20,
+    32 << 20,
+    128 << 20,
+    512 << 20,
+    2 << 30,
+];
+
+pub(crate) static EPHEMERAL_FILES_BY_SIZE: Lazy<UIntGaugeVec> = Lazy::new(|| {
+    register_uint_gauge_vec!(
+        "pageserver_ephemeral_files_by_size",
+        "Number of open ephemeral files no larger than `le` bytes. Approximate, periodically updated.",
+        &["le"]
+    )
+    .expect("failed to define a metric")
+});
+
 /// Metrics related to the lifecycle of a [`crate::tenant::Tenant`] object: things
 /// like how long it took to load.
 ///
@@ -2276,6 +2351,12 @@ pub(crate) struct TimelineMetrics {
     pub(crate) layer_count_image: UIntGauge,
     pub(crate) layer_size_delta: UIntGauge,
     pub(crate) layer_count_delta: UIntGauge,
+    pub(crate) compaction_discarded_layers_image: IntCounter,
+    pub(crate) compaction_discarded_layers_delta: IntCounter,
+    /// Incremented whenever the L0 compaction picker stops at the delta size limit.
+    pub(crate) compaction_l0_truncated: IntCounter,
+    /// The L0 delta layers left out by the last L0 compaction, zero if it picked all of them.
+    pub(crate) compaction_l0_remaining: UIntGauge,
     pub standby_horizon_gauge: IntGauge,
     pub resident_physical_size_gauge: UIntGauge,
     pub visible_physical_size_gauge: UIntGauge,
@@ -2395,6 +2476,31 @@ pub fn new(
             ])
             .unwrap();
 
+        let compaction_discarded_layers_image = COMPACTION_DISCARDED_LAYERS
+            .get_metric_with_label_values(&[
+                &tenant_id,
+                &shard_id,
+                &timeline_id,
+                MetricLayerKind::Image.into(),
+            ])
+            .unwrap();
+
+        let compaction_discarded_layers_delta = COMPACTION_DISCARDED_LAYERS
+            .get_metric_with_label_values(&[
+                &tenant_id,
+                &shard_id,
+                &timeline_id,
+                MetricLayerKind::Delta.into(),
+            ])
+            .unwrap();
+
+        let compaction_l0_truncated = COMPACTION_L0_TRUNCATED
+            .get_metric_with_label_values(&[&tenant_id, &shard_id, &timeline_id])
+            .unwrap();
+        let compaction_l0_remaining = COMPACTION_L0_REMAINING
+            .get_metric_with_label_values(&[&tenant_id, &shard_id, &timeline_id])
+            .unwrap();
+
         let standby_horizon_gauge = STANDBY_HORIZON
             .get_metric_with_label_values(&[&tenant_id, &shard_id, &timeline_id])
             .unwrap();
@@ -2456,6 +2562,10 @@ pub fn new(
             layer_count_image,
             layer_size_delta,
             layer_count_delta,
+            compaction_discarded_layers_image,
+            compaction_discarded_layers_delta,
+            compaction_l0_truncated,
+            compaction_l0_remaining,
             standby_horizon_gauge,
             resident_physical_size_gauge,
             visible_physical_size_gauge,
@@ -2543,6 +2653,20 @@ pub(crate) fn shutdown(&self) {
             timeline_id,
             MetricLayerKind::Delta.into(),
         ]);
+        let _ = COMPACTION_DISCARDED_LAYERS.remove_label_values(&[
+            tenant_id,
+            shard_id,
+            timeline_id,
+            MetricLayerKind::Image.into(),
+        ]);
+        let _ = COMPACTION_DISCARDED_LAYERS.remove_label_values(&[
+            tenant_id,
+            shard_id,
+            timeline_id,
+            MetricLayerKind::Delta.into(),
+        ]);
+        let _ = COMPACTION_L0_TRUNCATED.remove_label_values(&[tenant_id, shard_id, timeline_id]);
+        let _ = COMPACTION_L0_REMAINING.remove_label_values(&[tenant_id, shard_id, timeline_id]);
 
         let _ = EVICTIONS.remove_label_values(&[tenant_id, shard_id, timeline_id]);
         let _ = AUX_FILE_SIZE.remove_label_values(&[tenant_id, shard_id, timeline_id]);
@@ -3236,12 +3360,14 @@ pub fn preinitialize_metrics() {
 
     // gauges
     WALRECEIVER_ACTIVE_MANAGERS.get();
+    L0_FLUSH_WAITERS.get();
 
     // histograms
     [
         &READ_NUM_LAYERS_VISITED,
         &VEC_READ_NUM_LAYERS_VISITED,
         &WAIT_LSN_TIME,
+        &L0_FLUSH_WAIT_TIME,
         &WAL_REDO_TIME,
         &WAL_REDO_RECORDS_HISTOGRAM,
         &WAL_REDO_BYTES_HISTOGRAM,
@@ -1716,7 +1716,8 @@ pub async fn put_file(
                     }
                     Err(
                         e @ (PageReconstructError::Cancelled
-                        | PageReconstructError::AncestorLsnTimeout(_)),
+                        | PageReconstructError::AncestorLsnTimeout(_)
+                        | PageReconstructError::Corruption(_)),
                     ) => {
                         // Important that we do not interpret a shutdown error as "not found" and thereby
                         // reset the map.
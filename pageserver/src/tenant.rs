@@ -65,6 +65,7 @@
 use self::mgr::GetTenantError;
 use self::remote_timeline_client::upload::upload_index_part;
 use self::remote_timeline_client::RemoteTimelineClient;
+use self::timeline::compaction::io_throttle::CompactionIoThrottle;
 use self::timeline::uninit::TimelineCreateGuard;
 use self::timeline::uninit::TimelineExclusionError;
 use self::timeline::uninit::UninitializedTimeline;
@@ -302,6 +303,10 @@ pub struct Tenant {
     pub(crate) timeline_get_throttle:
         Arc<throttle::Throttle<&'static crate::metrics::tenant_throttling::TimelineGet>>,
 
+    /// Throttle applied to the layer downloads and writes of compaction.
+    /// All [`Tenant::timelines`] of a given [`Tenant`] instance share the same instance.
+    pub(crate) compaction_io_throttle: Arc<CompactionIoThrottle>,
+
     /// An ongoing timeline detach concurrency limiter.
     ///
     /// As a tenant will likely be restarted as part of timeline detach ancestor it makes no sense
@@ -1093,6 +1098,7 @@ async fn attach(
                 TimelineResources {
                     remote_client,
                     timeline_get_throttle: self.timeline_get_throttle.clone(),
+                    compaction_io_throttle: self.compaction_io_throttle.clone(),
                     l0_flush_global_state: self.l0_flush_global_state.clone(),
                 },
                 ctx,
@@ -1791,7 +1797,9 @@ async fn compaction_iteration(
                 .instrument(info_span!("compact_timeline", %timeline_id))
                 .await
                 .inspect_err(|e| match e {
-                    timeline::CompactionError::ShuttingDown => (),
+                    // A lock timeout only means that compaction should back off for a while.
+                    timeline::CompactionError::ShuttingDown
+                    | timeline::CompactionError::LockTimeout(_) => (),
                     timeline::CompactionError::Other(e) => {
                         self.compaction_circuit_breaker
                             .lock()
@@ -1835,6 +1843,8 @@ async fn ingest_housekeeping(&self) {
         for timeline in &timelines {
             timeline.maybe_freeze_ephemeral_layer().await;
         }
+
+        ephemeral_file::maybe_publish_metrics();
     }
 
     pub fn current_state(&self) -> TenantState {
@@ -2576,9 +2586,22 @@ fn get_timeline_get_throttle_config(
             .unwrap_or(psconf.default_tenant_conf.timeline_get_throttle.clone())
     }
 
+    fn get_compaction_io_throttle_bytes_per_sec(
+        psconf: &'static PageServerConf,
+        overrides: &TenantConfOpt,
+    ) -> u64 {
+        overrides.compaction_io_throttle_bytes_per_sec.unwrap_or(
+            psconf
+                .default_tenant_conf
+                .compaction_io_throttle_bytes_per_sec,
+        )
+    }
+
     pub(crate) fn tenant_conf_updated(&self, new_conf: &TenantConfOpt) {
         let conf = Self::get_timeline_get_throttle_config(self.conf, new_conf);
-        self.timeline_get_throttle.reconfigure(conf)
+        self.timeline_get_throttle.reconfigure(conf);
+        let bytes_per_sec = Self::get_compaction_io_throttle_bytes_per_sec(self.conf, new_conf);
+        self.compaction_io_throttle.reconfigure(bytes_per_sec);
     }
 
     /// Helper function to create a new Timeline struct.
@@ -2739,6 +2762,9 @@ fn new(
                 Tenant::get_timeline_get_throttle_config(conf, &attached_conf.tenant_conf),
                 &crate::metrics::tenant_throttling::TIMELINE_GET,
             )),
+            compaction_io_throttle: Arc::new(CompactionIoThrottle::new(
+                Tenant::get_compaction_io_throttle_bytes_per_sec(conf, &attached_conf.tenant_conf),
+            )),
             tenant_conf: Arc::new(ArcSwap::from_pointee(attached_conf)),
             ongoing_timeline_detach: std::sync::Mutex::default(),
             gc_block: Default::default(),
@@ -3499,6 +3525,7 @@ fn build_timeline_resources(&self, timeline_id: TimelineId) -> TimelineResources
         TimelineResources {
             remote_client,
             timeline_get_throttle: self.timeline_get_throttle.clone(),
+            compaction_io_throttle: self.compaction_io_throttle.clone(),
             l0_flush_global_state: self.l0_flush_global_state.clone(),
         }
     }
@@ -3888,6 +3915,25 @@ fn from(tenant_conf: TenantConf) -> Self {
                 compaction_period: Some(tenant_conf.compaction_period),
                 compaction_threshold: Some(tenant_conf.compaction_threshold),
                 compaction_algorithm: Some(tenant_conf.compaction_algorithm),
+                compaction_max_versions_per_key: Some(tenant_conf.compaction_max_versions_per_key),
+                compaction_io_throttle_bytes_per_sec: Some(
+                    tenant_conf.compaction_io_throttle_bytes_per_sec,
+                ),
+                compaction_min_hole_coverage_size: Some(
+                    tenant_conf.compaction_min_hole_coverage_size,
+                ),
+                compaction_oversize_warn_multiplier: Some(
+                    tenant_conf.compaction_oversize_warn_multiplier,
+                ),
+                compaction_align_to_partitions: Some(tenant_conf.compaction_align_to_partitions),
+                compaction_upload_concurrency: Some(tenant_conf.compaction_upload_concurrency),
+                gc_compaction_verify_every_nth_key: Some(
+                    tenant_conf.gc_compaction_verify_every_nth_key,
+                ),
+                compaction_layer_lock_timeout: tenant_conf.compaction_layer_lock_timeout,
+                compaction_time_budget: tenant_conf.compaction_time_budget,
+                l0_compaction_delta_size_limit: tenant_conf.l0_compaction_delta_size_limit,
+                compact_level0_phase1_value_access: tenant_conf.compact_level0_phase1_value_access,
                 gc_horizon: Some(tenant_conf.gc_horizon),
                 gc_period: Some(tenant_conf.gc_period),
                 image_creation_threshold: Some(tenant_conf.image_creation_threshold),
@@ -4123,7 +4169,7 @@ mod tests {
     use crate::pgdatadir_mapping::AuxFilesDirectory;
     use crate::repository::{Key, Value};
     use crate::tenant::harness::*;
-    use crate::tenant::timeline::CompactFlags;
+    use crate::tenant::timeline::{CompactFlags, CompactOptions};
     use crate::walrecord::NeonWalRecord;
     use crate::DEFAULT_PG_VERSION;
     use bytes::{Bytes, BytesMut};
@@ -4131,12 +4177,17 @@ mod tests {
     use itertools::Itertools;
     use pageserver_api::key::{AUX_FILES_KEY, AUX_KEY_PREFIX, NON_INHERITED_RANGE};
     use pageserver_api::keyspace::KeySpace;
-    use pageserver_api::models::{CompactionAlgorithm, CompactionAlgorithmSettings};
+    use pageserver_api::models::{
+        CompactL0Phase1ValueAccess, CompactionAlgorithm, CompactionAlgorithmSettings,
+    };
     use rand::{thread_rng, Rng};
-    use storage_layer::PersistentLayerKey;
+    use storage_layer::{LayerName, PersistentLayerDesc, PersistentLayerKey};
     use tests::storage_layer::ValuesReconstructState;
     use tests::timeline::{GetVectoredError, ShutdownMode};
-    use timeline::compaction::{KeyHistoryRetention, KeyLogAtLsn};
+    use timeline::compaction::manifest::{layer_name_of_key, GcCompactionManifest};
+    use timeline::compaction::{
+        CompactionObserver, KeyHistoryRetention, KeyLogAtLsn, KeyRetention,
+    };
     use timeline::{DeltaLayerTestDesc, GcInfo};
     use utils::bin_ser::BeSer;
     use utils::id::TenantId;
@@ -5934,6 +5985,236 @@ async fn test_metadata_compaction_trigger() -> anyhow::Result<()> {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_l0_compaction_delta_size_limit() -> anyhow::Result<()> {
+        let tenant_conf = TenantConf {
+            // Every L0 layer is above the limit, so the picker stops after the second one.
+            l0_compaction_delta_size_limit: Some(1),
+            ..TenantConf::default()
+        };
+        let harness = TenantHarness::create_custom(
+            "test_l0_compaction_delta_size_limit",
+            tenant_conf,
+            TenantId::generate(),
+            ShardIdentity::unsharded(),
+            Generation::new(0xdeadbeef),
+        )
+        .await?;
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+
+        let cancel = CancellationToken::new();
+
+        let mut base_key = Key::from_hex("000000000033333333444444445500000000").unwrap();
+        base_key.field1 = AUX_KEY_PREFIX;
+        let test_key = base_key;
+        let mut lsn = Lsn(0x10);
+
+        for _ in 0..20 {
+            lsn = Lsn(lsn.0 + 0x10);
+            let mut writer = tline.writer().await;
+            writer
+                .put(
+                    test_key,
+                    lsn,
+                    &Value::Image(test_img(&format!("{} at {}", 0, lsn))),
+                    &ctx,
+                )
+                .await?;
+            writer.finish_write(lsn);
+            drop(writer);
+            tline.freeze_and_flush().await?; // force create a delta layer
+        }
+
+        let before_num_l0_delta_files =
+            tline.layers.read().await.layer_map()?.level0_deltas().len();
+
+        let has_pending_tasks = tline.compact(&cancel, EnumSet::empty(), &ctx).await?;
+        assert!(
+            has_pending_tasks,
+            "the L0 layers should not be fully compacted"
+        );
+
+        let after_num_l0_delta_files = tline.layers.read().await.layer_map()?.level0_deltas().len();
+        assert_eq!(after_num_l0_delta_files, before_num_l0_delta_files - 2);
+
+        // The truncation is visible in the metrics, with the L0 layers that were left out.
+        assert_eq!(tline.metrics.compaction_l0_truncated.get(), 1);
+        assert_eq!(
+            tline.metrics.compaction_l0_remaining.get(),
+            after_num_l0_delta_files as u64
+        );
+
+        assert_eq!(
+            tline.get(test_key, lsn, &ctx).await?,
+            test_img(&format!("{} at {}", 0, lsn))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compaction_time_budget() -> anyhow::Result<()> {
+        let tenant_conf = TenantConf {
+            compaction_time_budget: Some(Duration::ZERO),
+            ..TenantConf::default()
+        };
+        let harness = TenantHarness::create_custom(
+            "test_compaction_time_budget",
+            tenant_conf,
+            TenantId::generate(),
+            ShardIdentity::unsharded(),
+            Generation::new(0xdeadbeef),
+        )
+        .await?;
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+
+        let cancel = CancellationToken::new();
+
+        let mut base_key = Key::from_hex("000000000033333333444444445500000000").unwrap();
+        base_key.field1 = AUX_KEY_PREFIX;
+        let test_key = base_key;
+        let mut lsn = Lsn(0x10);
+
+        for _ in 0..20 {
+            lsn = Lsn(lsn.0 + 0x10);
+            let mut writer = tline.writer().await;
+            writer
+                .put(
+                    test_key,
+                    lsn,
+                    &Value::Image(test_img(&format!("{} at {}", 0, lsn))),
+                    &ctx,
+                )
+                .await?;
+            writer.finish_write(lsn);
+            drop(writer);
+            tline.freeze_and_flush().await?; // force create a delta layer
+        }
+
+        let before_num_l0_delta_files =
+            tline.layers.read().await.layer_map()?.level0_deltas().len();
+
+        // The budget is used up before the first phase that writes layers.
+        let has_pending_tasks = tline
+            .compact_with_options(
+                &cancel,
+                CompactOptions {
+                    time_budget: Some(Duration::ZERO),
+                    ..Default::default()
+                },
+                &ctx,
+            )
+            .await?
+            .has_pending_tasks;
+        assert!(has_pending_tasks, "the compaction should be postponed");
+        assert_eq!(
+            tline.layers.read().await.layer_map()?.level0_deltas().len(),
+            before_num_l0_delta_files
+        );
+
+        // Background compactions use the budget of the tenant config.
+        let has_pending_tasks = tline.compact(&cancel, EnumSet::empty(), &ctx).await?;
+        assert!(has_pending_tasks, "the compaction should be postponed");
+        assert_eq!(
+            tline.layers.read().await.layer_map()?.level0_deltas().len(),
+            before_num_l0_delta_files
+        );
+
+        // Without a budget, the L0 layers are compacted.
+        tline
+            .compact_with_options(&cancel, CompactOptions::default(), &ctx)
+            .await?;
+        assert!(
+            tline.layers.read().await.layer_map()?.level0_deltas().len()
+                < before_num_l0_delta_files
+        );
+
+        assert_eq!(
+            tline.get(test_key, lsn, &ctx).await?,
+            test_img(&format!("{} at {}", 0, lsn))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_level0_phase1_value_access_override() -> anyhow::Result<()> {
+        let trusting = CompactL0Phase1ValueAccess::StreamingKmerge { validate: None };
+        let tenant_conf = TenantConf {
+            compact_level0_phase1_value_access: Some(trusting.clone()),
+            ..TenantConf::default()
+        };
+        let harness = TenantHarness::create_custom(
+            "test_compact_level0_phase1_value_access_override",
+            tenant_conf,
+            TenantId::generate(),
+            ShardIdentity::unsharded(),
+            Generation::new(0xdeadbeef),
+        )
+        .await?;
+        // Without the override, the tenant validates the streaming k-merge against the page
+        // cached path, like the pageserver does by default.
+        assert_eq!(
+            harness.conf.compact_level0_phase1_value_access,
+            CompactL0Phase1ValueAccess::default()
+        );
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+
+        // Without validation, L0 compaction only builds the merge iterator, and not the
+        // all_keys_iter that reads every value through the page cache.
+        assert_eq!(tline.get_compact_level0_phase1_value_access(), trusting);
+
+        let test_key = Key::from_hex("010000000033333333444444445500000000").unwrap();
+        let mut lsn = Lsn(0x10);
+        for _ in 0..10 {
+            lsn = Lsn(lsn.0 + 0x10);
+            let mut writer = tline.writer().await;
+            writer
+                .put(
+                    test_key,
+                    lsn,
+                    &Value::Image(test_img(&format!("foo at {}", lsn))),
+                    &ctx,
+                )
+                .await?;
+            writer.finish_write(lsn);
+            drop(writer);
+            tline.freeze_and_flush().await?;
+        }
+
+        let cancel = CancellationToken::new();
+        tline.compact(&cancel, EnumSet::empty(), &ctx).await?;
+        assert!(tline
+            .layers
+            .read()
+            .await
+            .layer_map()?
+            .level0_deltas()
+            .is_empty());
+        assert_eq!(
+            tline.get(test_key, lsn, &ctx).await?,
+            test_img(&format!("foo at {}", lsn))
+        );
+
+        // Resetting the override falls back to the pageserver-wide setting.
+        tenant.set_new_tenant_config(TenantConfOpt::default());
+        assert_eq!(
+            tline.get_compact_level0_phase1_value_access(),
+            CompactL0Phase1ValueAccess::default()
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_branch_copies_dirty_aux_file_flag() {
         let harness = TenantHarness::create("test_branch_copies_dirty_aux_file_flag")
@@ -6824,6 +7105,64 @@ async fn test_metadata_tombstone_empty_image_creation() {
         assert_eq!(images.len(), 0); // the image layer should not contain tombstones, or it is not created
     }
 
+    #[tokio::test]
+    async fn test_bottom_most_compaction_without_layers() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("test_bottom_most_compaction_without_layers").await?;
+        let (tenant, ctx) = harness.load().await;
+        let cancel = CancellationToken::new();
+
+        // A freshly created timeline has no layers at all.
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+        {
+            let mut guard = tline.gc_info.write().unwrap();
+            guard.cutoffs.time = Lsn(0x10);
+            guard.cutoffs.space = Lsn(0x10);
+        }
+        tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await?;
+        assert!(tline.inspect_historic_layers().await?.is_empty());
+
+        // All the layers of this timeline are above the GC cutoff.
+        let key = Key::from_hex("620000000033333333444444445500000000").unwrap();
+        let tline = tenant
+            .create_test_timeline_with_layers(
+                NEW_TIMELINE_ID,
+                Lsn(0x10),
+                DEFAULT_PG_VERSION,
+                &ctx,
+                vec![DeltaLayerTestDesc::new_with_inferred_key_range(
+                    Lsn(0x20)..Lsn(0x30),
+                    vec![(
+                        key,
+                        Lsn(0x20),
+                        Value::Image(Bytes::from_static(b"value@0x20")),
+                    )],
+                )],
+                vec![],
+                Lsn(0x30),
+            )
+            .await?;
+        {
+            let mut guard = tline.gc_info.write().unwrap();
+            guard.cutoffs.time = Lsn(0x10);
+            guard.cutoffs.space = Lsn(0x10);
+        }
+        let layers = tline.inspect_historic_layers().await?;
+        tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await?;
+        assert_eq!(tline.inspect_historic_layers().await?, layers);
+        assert_eq!(
+            tline.get(key, Lsn(0x30), &ctx).await?,
+            Bytes::from_static(b"value@0x20")
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_simple_bottom_most_compaction_images() -> anyhow::Result<()> {
         let harness = TenantHarness::create("test_simple_bottom_most_compaction_images").await?;
@@ -6947,7 +7286,7 @@ fn get_key(id: u32) -> Key {
 
         let cancel = CancellationToken::new();
         tline
-            .compact_with_gc(&cancel, EnumSet::new(), &ctx)
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
             .await
             .unwrap();
 
@@ -7011,13 +7350,9 @@ fn get_key(id: u32) -> Key {
         assert_eq!(
             all_layers,
             vec![
-                // Image layer at GC horizon
+                // Image layer at GC horizon, covering the keys of the picked layers
                 PersistentLayerKey {
-                    key_range: {
-                        let mut key = Key::MAX;
-                        key.field6 -= 1;
-                        Key::MIN..key
-                    },
+                    key_range: get_key(0)..get_key(10),
                     lsn_range: Lsn(0x30)..Lsn(0x31),
                     is_delta: false
                 },
@@ -7044,7 +7379,7 @@ fn get_key(id: u32) -> Key {
             guard.cutoffs.space = Lsn(0x40);
         }
         tline
-            .compact_with_gc(&cancel, EnumSet::new(), &ctx)
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
             .await
             .unwrap();
 
@@ -7333,6 +7668,7 @@ fn get_key(id: u32) -> Key {
                 },
                 leases: Default::default(),
                 within_ancestor_pitr: false,
+                covered_by_children: Default::default(),
             };
         }
 
@@ -7381,7 +7717,7 @@ fn get_key(id: u32) -> Key {
 
         let cancel = CancellationToken::new();
         tline
-            .compact_with_gc(&cancel, EnumSet::new(), &ctx)
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
             .await
             .unwrap();
 
@@ -7410,7 +7746,7 @@ fn get_key(id: u32) -> Key {
             guard.cutoffs.space = Lsn(0x40);
         }
         tline
-            .compact_with_gc(&cancel, EnumSet::new(), &ctx)
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
             .await
             .unwrap();
 
@@ -7482,6 +7818,8 @@ async fn test_generate_key_retention() -> anyhow::Result<()> {
                 Lsn(0x60),
                 &[Lsn(0x20), Lsn(0x40), Lsn(0x50)],
                 3,
+                usize::MAX,
+                None,
                 None,
             )
             .await
@@ -7607,7 +7945,16 @@ async fn test_generate_key_retention() -> anyhow::Result<()> {
             ),
         ];
         let res = tline
-            .generate_key_retention(key, &history, Lsn(0x60), &[Lsn(0x40), Lsn(0x50)], 3, None)
+            .generate_key_retention(
+                key,
+                &history,
+                Lsn(0x60),
+                &[Lsn(0x40), Lsn(0x50)],
+                3,
+                usize::MAX,
+                None,
+                None,
+            )
             .await
             .unwrap();
         let expected_res = KeyHistoryRetention {
@@ -7685,7 +8032,9 @@ async fn test_generate_key_retention() -> anyhow::Result<()> {
                 Lsn(0x60),
                 &[],
                 3,
+                usize::MAX,
                 Some((key, Lsn(0x10), Bytes::copy_from_slice(b"0x10"))),
+                None,
             )
             .await
             .unwrap();
@@ -7733,7 +8082,9 @@ async fn test_generate_key_retention() -> anyhow::Result<()> {
                 Lsn(0x60),
                 &[Lsn(0x30)],
                 3,
+                usize::MAX,
                 Some((key, Lsn(0x10), Bytes::copy_from_slice(b"0x10"))),
+                None,
             )
             .await
             .unwrap();
@@ -7765,22 +8116,324 @@ async fn test_generate_key_retention() -> anyhow::Result<()> {
     }
 
     #[tokio::test]
-    async fn test_simple_bottom_most_compaction_with_retain_lsns() -> anyhow::Result<()> {
+    async fn test_generate_key_retention_max_versions_per_key() -> anyhow::Result<()> {
+        const NUM_VERSIONS: u64 = 100;
+        const MAX_VERSIONS_PER_KEY: usize = 8;
+
         let harness =
-            TenantHarness::create("test_simple_bottom_most_compaction_with_retain_lsns").await?;
+            TenantHarness::create("test_generate_key_retention_max_versions_per_key").await?;
         let (tenant, ctx) = harness.load().await;
-
-        fn get_key(id: u32) -> Key {
-            // using aux key here b/c they are guaranteed to be inside `collect_keyspace`.
-            let mut key = Key::from_hex("620000000033333333444444445500000000").unwrap();
-            key.field6 = id;
-            key
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+        tline.force_advance_lsn(Lsn(0x1000));
+        let key = Key::from_hex("010000000033333333444444445500000000").unwrap();
+        let mut history = vec![(
+            key,
+            Lsn(0x10),
+            Value::Image(Bytes::copy_from_slice(b"0x10")),
+        )];
+        for i in 1..=NUM_VERSIONS {
+            history.push((
+                key,
+                Lsn(0x10 + i),
+                Value::WalRecord(NeonWalRecord::wal_append(&format!(";{i}"))),
+            ));
         }
-
-        let img_layer = (0..10)
-            .map(|id| (get_key(id), Bytes::from(format!("value {id}@0x10"))))
-            .collect_vec();
-
+        // A hot key whose versions all go into the same batch, which keeps its deltas.
+        let res = tline
+            .generate_key_retention(
+                key,
+                &history,
+                Lsn(0x1000),
+                &[Lsn(0x10)],
+                usize::MAX,
+                MAX_VERSIONS_PER_KEY,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.below_horizon.len(), 2);
+        assert_eq!(
+            res.below_horizon[0].1 .0,
+            vec![(Lsn(0x10), Value::Image(Bytes::copy_from_slice(b"0x10")))]
+        );
+        let logs = &res.below_horizon[1].1 .0;
+        assert_eq!(logs.len(), NUM_VERSIONS as usize);
+
+        // Every MAX_VERSIONS_PER_KEY-th version is materialized, so that at most that many records
+        // are replayed on top of an image.
+        let mut expected_img = String::from("0x10");
+        let mut records_since_image = 0;
+        let mut num_images = 0;
+        for (idx, (lsn, val)) in logs.iter().enumerate() {
+            let i = idx as u64 + 1;
+            assert_eq!(*lsn, Lsn(0x10 + i));
+            expected_img.push_str(&format!(";{i}"));
+            records_since_image += 1;
+            if records_since_image == MAX_VERSIONS_PER_KEY {
+                assert_eq!(val, &Value::Image(Bytes::from(expected_img.clone())));
+                records_since_image = 0;
+                num_images += 1;
+            } else {
+                assert_eq!(
+                    val,
+                    &Value::WalRecord(NeonWalRecord::wal_append(&format!(";{i}")))
+                );
+            }
+        }
+        assert_eq!(num_images, NUM_VERSIONS as usize / MAX_VERSIONS_PER_KEY);
+        assert!(res.above_horizon.0.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generate_key_retention_single_image() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("test_generate_key_retention_single_image").await?;
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+        tline.force_advance_lsn(Lsn(0x70));
+        let branch = tenant
+            .branch_timeline_test(&tline, NEW_TIMELINE_ID, Some(Lsn(0x30)), &ctx)
+            .await?;
+        let key = Key::from_hex("010000000033333333444444445500000000").unwrap();
+        let horizon = Lsn(0x40);
+
+        for tline in [&tline, &branch] {
+            for lsn in [Lsn(0x20), horizon, Lsn(0x50)] {
+                for delta_threshold_cnt in [1, 3] {
+                    let history = vec![(key, lsn, Value::Image(Bytes::from(format!("{lsn}"))))];
+                    let fast = tline
+                        .generate_key_retention(
+                            key,
+                            &history,
+                            horizon,
+                            &[],
+                            delta_threshold_cnt,
+                            usize::MAX,
+                            None,
+                            None,
+                        )
+                        .await?;
+                    let replayed = tline
+                        .generate_key_retention_by_replay(
+                            key,
+                            &history,
+                            horizon,
+                            &[],
+                            delta_threshold_cnt,
+                            usize::MAX,
+                            None,
+                            KeyRetention::default(),
+                        )
+                        .await?;
+                    assert_eq!(
+                        fast, replayed,
+                        "timeline={} lsn={lsn} delta_threshold_cnt={delta_threshold_cnt}",
+                        tline.timeline_id
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generate_key_retention_override() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("test_generate_key_retention_override").await?;
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+        tline.force_advance_lsn(Lsn(0x70));
+        let history_of = |key: Key| {
+            let mut history = vec![(key, Lsn(0x10), Value::Image(Bytes::from_static(b"0x10")))];
+            for lsn in [Lsn(0x20), Lsn(0x30), Lsn(0x40), Lsn(0x50)] {
+                let rec = NeonWalRecord::wal_append(&format!(";0x{:x}", lsn.0));
+                history.push((key, lsn, Value::WalRecord(rec)));
+            }
+            history
+        };
+        let image = |lsn: Lsn, img: &'static [u8]| (lsn, Value::Image(Bytes::from_static(img)));
+        let delta = |lsn: Lsn| {
+            let rec = NeonWalRecord::wal_append(&format!(";0x{:x}", lsn.0));
+            (lsn, Value::WalRecord(rec))
+        };
+
+        // Force images for the keys with a field1 of 0x01.
+        let force_images = |key: Key| KeyRetention {
+            force_image: key.field1 == 0x01,
+            retain_history_above: None,
+        };
+        let forced_key = Key::from_hex("010000000033333333444444445500000000").unwrap();
+        let other_key = Key::from_hex("020000000033333333444444445500000000").unwrap();
+        let horizon = Lsn(0x50);
+        let retain_lsns = [Lsn(0x20), Lsn(0x40)];
+
+        let res = tline
+            .generate_key_retention(
+                forced_key,
+                &history_of(forced_key),
+                horizon,
+                &retain_lsns,
+                3,
+                usize::MAX,
+                None,
+                Some(&force_images),
+            )
+            .await?;
+        let expected_res = KeyHistoryRetention {
+            below_horizon: vec![
+                (Lsn(0x20), KeyLogAtLsn(vec![image(Lsn(0x20), b"0x10;0x20")])),
+                (
+                    Lsn(0x40),
+                    KeyLogAtLsn(vec![image(Lsn(0x40), b"0x10;0x20;0x30;0x40")]),
+                ),
+                (
+                    Lsn(0x50),
+                    KeyLogAtLsn(vec![image(Lsn(0x50), b"0x10;0x20;0x30;0x40;0x50")]),
+                ),
+            ],
+            above_horizon: KeyLogAtLsn(vec![]),
+        };
+        assert_eq!(res, expected_res);
+
+        // Other keys keep the deltas below the delta threshold, as without an override.
+        let res = tline
+            .generate_key_retention(
+                other_key,
+                &history_of(other_key),
+                horizon,
+                &retain_lsns,
+                3,
+                usize::MAX,
+                None,
+                Some(&force_images),
+            )
+            .await?;
+        let default_res = tline
+            .generate_key_retention(
+                other_key,
+                &history_of(other_key),
+                horizon,
+                &retain_lsns,
+                3,
+                usize::MAX,
+                None,
+                None,
+            )
+            .await?;
+        let expected_res = KeyHistoryRetention {
+            below_horizon: vec![
+                (Lsn(0x20), KeyLogAtLsn(vec![image(Lsn(0x20), b"0x10;0x20")])),
+                (
+                    Lsn(0x40),
+                    KeyLogAtLsn(vec![delta(Lsn(0x30)), delta(Lsn(0x40))]),
+                ),
+                (
+                    Lsn(0x50),
+                    KeyLogAtLsn(vec![image(Lsn(0x50), b"0x10;0x20;0x30;0x40;0x50")]),
+                ),
+            ],
+            above_horizon: KeyLogAtLsn(vec![]),
+        };
+        assert_eq!(res, expected_res);
+        assert_eq!(default_res, expected_res);
+
+        // Retaining the history above 0x30 keeps all records of the batches reaching above it.
+        let retain_history = |_: Key| KeyRetention {
+            force_image: true,
+            retain_history_above: Some(Lsn(0x30)),
+        };
+        let res = tline
+            .generate_key_retention(
+                forced_key,
+                &history_of(forced_key),
+                horizon,
+                &retain_lsns,
+                3,
+                usize::MAX,
+                None,
+                Some(&retain_history),
+            )
+            .await?;
+        let expected_res = KeyHistoryRetention {
+            below_horizon: vec![
+                (Lsn(0x20), KeyLogAtLsn(vec![image(Lsn(0x20), b"0x10;0x20")])),
+                (
+                    Lsn(0x40),
+                    KeyLogAtLsn(vec![delta(Lsn(0x30)), delta(Lsn(0x40))]),
+                ),
+                (Lsn(0x50), KeyLogAtLsn(vec![delta(Lsn(0x50))])),
+            ],
+            above_horizon: KeyLogAtLsn(vec![]),
+        };
+        assert_eq!(res, expected_res);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generate_key_retention_reconstruct_error() -> anyhow::Result<()> {
+        let harness =
+            TenantHarness::create("test_generate_key_retention_reconstruct_error").await?;
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+        tline.force_advance_lsn(Lsn(0x70));
+        let key = Key::from_hex("010000000033333333444444445500000000").unwrap();
+        // The record can only be applied to a visibility map page, so WAL redo fails.
+        let history = vec![
+            (
+                key,
+                Lsn(0x10),
+                Value::Image(Bytes::copy_from_slice(b"0x10")),
+            ),
+            (
+                key,
+                Lsn(0x20),
+                Value::WalRecord(NeonWalRecord::ClearVisibilityMapFlags {
+                    new_heap_blkno: Some(0),
+                    old_heap_blkno: None,
+                    flags: 0,
+                }),
+            ),
+        ];
+        let err = tline
+            .generate_key_retention(key, &history, Lsn(0x60), &[], 3, usize::MAX, None, None)
+            .await
+            .unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains(&key.to_string()), "{msg}");
+        assert!(msg.contains(&Lsn(0x60).to_string()), "{msg}");
+        assert!(msg.contains(&format!("d@{}", Lsn(0x20))), "{msg}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_simple_bottom_most_compaction_with_retain_lsns() -> anyhow::Result<()> {
+        let harness =
+            TenantHarness::create("test_simple_bottom_most_compaction_with_retain_lsns").await?;
+        let (tenant, ctx) = harness.load().await;
+
+        fn get_key(id: u32) -> Key {
+            // using aux key here b/c they are guaranteed to be inside `collect_keyspace`.
+            let mut key = Key::from_hex("620000000033333333444444445500000000").unwrap();
+            key.field6 = id;
+            key
+        }
+
+        let img_layer = (0..10)
+            .map(|id| (get_key(id), Bytes::from(format!("value {id}@0x10"))))
+            .collect_vec();
+
         let delta1 = vec![
             (
                 get_key(1),
@@ -7862,6 +8515,7 @@ fn get_key(id: u32) -> Key {
                 },
                 leases: Default::default(),
                 within_ancestor_pitr: false,
+                covered_by_children: Default::default(),
             };
         }
 
@@ -7957,11 +8611,11 @@ fn get_key(id: u32) -> Key {
         verify_result().await;
 
         let cancel = CancellationToken::new();
-        let mut dryrun_flags = EnumSet::new();
-        dryrun_flags.insert(CompactFlags::DryRun);
+        let mut dryrun_options = CompactOptions::default();
+        dryrun_options.flags.insert(CompactFlags::DryRun);
 
         tline
-            .compact_with_gc(&cancel, dryrun_flags, &ctx)
+            .compact_with_gc(&cancel, dryrun_options, None, &ctx)
             .await
             .unwrap();
         // We expect layer map to be the same b/c the dry run flag, but we don't know whether there will be other background jobs
@@ -7969,14 +8623,14 @@ fn get_key(id: u32) -> Key {
         verify_result().await;
 
         tline
-            .compact_with_gc(&cancel, EnumSet::new(), &ctx)
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
             .await
             .unwrap();
         verify_result().await;
 
         // compact again
         tline
-            .compact_with_gc(&cancel, EnumSet::new(), &ctx)
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
             .await
             .unwrap();
         verify_result().await;
@@ -7989,14 +8643,14 @@ fn get_key(id: u32) -> Key {
             guard.cutoffs.space = Lsn(0x38);
         }
         tline
-            .compact_with_gc(&cancel, EnumSet::new(), &ctx)
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
             .await
             .unwrap();
         verify_result().await; // no wals between 0x30 and 0x38, so we should obtain the same result
 
         // not increasing the GC horizon and compact again
         tline
-            .compact_with_gc(&cancel, EnumSet::new(), &ctx)
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
             .await
             .unwrap();
         verify_result().await;
@@ -8114,6 +8768,7 @@ fn get_key(id: u32) -> Key {
                 },
                 leases: Default::default(),
                 within_ancestor_pitr: false,
+                covered_by_children: Default::default(),
             };
         }
 
@@ -8128,6 +8783,7 @@ fn get_key(id: u32) -> Key {
                 },
                 leases: Default::default(),
                 within_ancestor_pitr: false,
+                covered_by_children: Default::default(),
             };
         }
 
@@ -8180,12 +8836,1324 @@ fn get_key(id: u32) -> Key {
 
         let cancel = CancellationToken::new();
         branch_tline
-            .compact_with_gc(&cancel, EnumSet::new(), &ctx)
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await
+            .unwrap();
+
+        verify_result().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bottom_most_compaction_on_branch_skips_ancestor_images() -> anyhow::Result<()> {
+        let harness =
+            TenantHarness::create("test_bottom_most_compaction_on_branch_skips_ancestor_images")
+                .await?;
+        let (tenant, ctx) = harness.load().await;
+
+        fn get_key(id: u32) -> Key {
+            let mut key = Key::from_hex("000000000033333333444444445500000000").unwrap();
+            key.field6 = id;
+            key
+        }
+
+        let img_layer = (0..10)
+            .map(|id| (get_key(id), Bytes::from(format!("value {id}@0x10"))))
+            .collect_vec();
+
+        // Keys 0..5 are overwritten by an image on the branch, so they don't need the ancestor image to be
+        // reconstructed. Keys 5..10 only have deltas on the branch.
+        let delta = (0..10)
+            .map(|id| {
+                let val = if id < 5 {
+                    Value::Image(Bytes::from(format!("value {id}@0x20")))
+                } else {
+                    Value::WalRecord(NeonWalRecord::wal_append("@0x20"))
+                };
+                (get_key(id), Lsn(0x20), val)
+            })
+            .collect_vec();
+
+        let parent_tline = tenant
+            .create_test_timeline_with_layers(
+                TIMELINE_ID,
+                Lsn(0x10),
+                DEFAULT_PG_VERSION,
+                &ctx,
+                vec![],                       // delta layers
+                vec![(Lsn(0x18), img_layer)], // image layers
+                Lsn(0x18),
+            )
+            .await?;
+
+        parent_tline.add_extra_test_dense_keyspace(KeySpace::single(get_key(0)..get_key(10)));
+
+        let branch_tline = tenant
+            .branch_timeline_test_with_layers(
+                &parent_tline,
+                NEW_TIMELINE_ID,
+                Some(Lsn(0x18)),
+                &ctx,
+                vec![DeltaLayerTestDesc::new_with_inferred_key_range(
+                    Lsn(0x20)..Lsn(0x48),
+                    delta,
+                )], // delta layers
+                vec![], // image layers
+                Lsn(0x50),
+            )
+            .await?;
+
+        branch_tline.add_extra_test_dense_keyspace(KeySpace::single(get_key(0)..get_key(10)));
+
+        {
+            // Update GC info
+            let mut guard = branch_tline.gc_info.write().unwrap();
+            *guard = GcInfo {
+                retain_lsns: vec![],
+                cutoffs: GcCutoffs {
+                    time: Lsn(0x50),
+                    space: Lsn(0x50),
+                },
+                leases: Default::default(),
+                within_ancestor_pitr: false,
+                covered_by_children: Default::default(),
+            };
+        }
+
+        let expected_result = (0..10)
+            .map(|id| {
+                if id < 5 {
+                    Bytes::from(format!("value {id}@0x20"))
+                } else {
+                    Bytes::from(format!("value {id}@0x10@0x20"))
+                }
+            })
+            .collect_vec();
+
+        let verify_result = || async {
+            for (idx, expected) in expected_result.iter().enumerate() {
+                assert_eq!(
+                    branch_tline
+                        .get(get_key(idx as u32), Lsn(0x50), &ctx)
+                        .await
+                        .unwrap(),
+                    expected
+                );
+            }
+        };
+
+        verify_result().await;
+
+        let cancel = CancellationToken::new();
+        let stat = branch_tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
             .await
             .unwrap();
 
+        // All keys fit into a single batch, and only the keys without a base image on the branch are read
+        // from the ancestor.
+        assert_eq!(stat.num_ancestor_image_reads(), 1);
+        assert_eq!(stat.num_ancestor_images_fetched(), 5);
+
         verify_result().await;
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_bottom_most_compaction_discarded_layers_metrics() -> anyhow::Result<()> {
+        let harness =
+            TenantHarness::create("test_bottom_most_compaction_discarded_layers_metrics").await?;
+        let (tenant, ctx) = harness.load().await;
+
+        fn get_key(id: u32) -> Key {
+            let mut key = Key::from_hex("000000000033333333444444445500000000").unwrap();
+            key.field6 = id;
+            key
+        }
+
+        let img_layer = (0..10)
+            .map(|id| (get_key(id), Bytes::from(format!("value {id}@0x10"))))
+            .collect_vec();
+
+        let delta = (0..10)
+            .map(|id| {
+                (
+                    get_key(id),
+                    Lsn(0x40),
+                    Value::WalRecord(NeonWalRecord::wal_append("@0x40")),
+                )
+            })
+            .collect_vec();
+
+        let tline = tenant
+            .create_test_timeline_with_layers(
+                TIMELINE_ID,
+                Lsn(0x10),
+                DEFAULT_PG_VERSION,
+                &ctx,
+                vec![DeltaLayerTestDesc::new_with_inferred_key_range(
+                    Lsn(0x10)..Lsn(0x48),
+                    delta,
+                )], // delta layers
+                vec![(Lsn(0x10), img_layer)], // image layers
+                Lsn(0x50),
+            )
+            .await?;
+        {
+            // Update GC info
+            let mut guard = tline.gc_info.write().unwrap();
+            *guard = GcInfo {
+                retain_lsns: vec![],
+                cutoffs: GcCutoffs {
+                    time: Lsn(0x30),
+                    space: Lsn(0x30),
+                },
+                leases: Default::default(),
+                within_ancestor_pitr: false,
+                covered_by_children: Default::default(),
+            };
+        }
+
+        let cancel = CancellationToken::new();
+        tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await
+            .unwrap();
+        assert_eq!(tline.metrics.compaction_discarded_layers_image.get(), 0);
+
+        // Compacting again with the same GC horizon reproduces the same image layer in the same
+        // generation, which gets discarded.
+        let stat = tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await
+            .unwrap();
+        assert_eq!(tline.metrics.compaction_discarded_layers_image.get(), 1);
+        assert_eq!(
+            tline.metrics.compaction_discarded_layers_delta.get(),
+            serde_json::to_value(&stat)?["num_delta_layer_discarded"]
+                .as_u64()
+                .unwrap()
+        );
+
+        for idx in 0..10 {
+            assert_eq!(
+                tline.get(get_key(idx), Lsn(0x50), &ctx).await?,
+                Bytes::from(format!("value {idx}@0x10@0x40"))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bottom_most_compaction_coalesces_thin_deltas() -> anyhow::Result<()> {
+        let harness =
+            TenantHarness::create("test_bottom_most_compaction_coalesces_thin_deltas").await?;
+        let (tenant, ctx) = harness.load().await;
+
+        fn get_key(id: u32) -> Key {
+            let mut key = Key::from_hex("000000000033333333444444445500000000").unwrap();
+            key.field6 = id;
+            key
+        }
+
+        let img_layer = (0..20)
+            .map(|id| (get_key(id), Bytes::from(format!("value {id}@0x10"))))
+            .collect_vec();
+
+        // One thin delta layer per key, except for key 10, which is only covered by a delta layer
+        // above the GC horizon that won't be picked for the compaction.
+        let mut delta_layers = (0..20)
+            .filter(|id| *id != 10)
+            .map(|id| {
+                DeltaLayerTestDesc::new_with_inferred_key_range(
+                    Lsn(0x10)..Lsn(0x48),
+                    vec![(
+                        get_key(id),
+                        Lsn(0x40),
+                        Value::WalRecord(NeonWalRecord::wal_append("@0x40")),
+                    )],
+                )
+            })
+            .collect_vec();
+        delta_layers.push(DeltaLayerTestDesc::new_with_inferred_key_range(
+            Lsn(0x38)..Lsn(0x40),
+            vec![(
+                get_key(10),
+                Lsn(0x38),
+                Value::WalRecord(NeonWalRecord::wal_append("@0x38")),
+            )],
+        ));
+
+        let tline = tenant
+            .create_test_timeline_with_layers(
+                TIMELINE_ID,
+                Lsn(0x10),
+                DEFAULT_PG_VERSION,
+                &ctx,
+                delta_layers,
+                vec![(Lsn(0x10), img_layer)], // image layers
+                Lsn(0x50),
+            )
+            .await?;
+        {
+            // Update GC info
+            let mut guard = tline.gc_info.write().unwrap();
+            *guard = GcInfo {
+                retain_lsns: vec![],
+                cutoffs: GcCutoffs {
+                    time: Lsn(0x30),
+                    space: Lsn(0x30),
+                },
+                leases: Default::default(),
+                within_ancestor_pitr: false,
+                covered_by_children: Default::default(),
+            };
+        }
+
+        let cancel = CancellationToken::new();
+        tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await
+            .unwrap();
+
+        for idx in 0..20 {
+            let expected = if idx == 10 {
+                format!("value {idx}@0x10@0x38")
+            } else {
+                format!("value {idx}@0x10@0x40")
+            };
+            assert_eq!(
+                tline.get(get_key(idx), Lsn(0x50), &ctx).await?,
+                Bytes::from(expected)
+            );
+        }
+
+        // The thin deltas are merged into as few layers as possible: they are only split around
+        // the layer that was not picked for the compaction.
+        let mut delta_layers = tline
+            .inspect_historic_layers()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|layer| layer.is_delta)
+            .collect_vec();
+        delta_layers.sort_by_key(|layer| (layer.key_range.start, layer.lsn_range.start));
+        assert_eq!(
+            delta_layers,
+            vec![
+                PersistentLayerKey {
+                    key_range: get_key(0)..get_key(10),
+                    lsn_range: Lsn(0x30)..Lsn(0x41),
+                    is_delta: true
+                },
+                PersistentLayerKey {
+                    key_range: get_key(10)..get_key(11),
+                    lsn_range: Lsn(0x38)..Lsn(0x40),
+                    is_delta: true
+                },
+                PersistentLayerKey {
+                    key_range: get_key(11)..get_key(20),
+                    lsn_range: Lsn(0x30)..Lsn(0x41),
+                    is_delta: true
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bottom_most_compaction_resume_after_crash() -> anyhow::Result<()> {
+        const NUM_KEYS: u32 = 256;
+
+        fn get_key(id: u32) -> Key {
+            let mut key = Key::from_hex("000000000033333333444444445500000000").unwrap();
+            key.field6 = id;
+            key
+        }
+
+        /// An image that does not compress well, so that the compaction writes enough data to
+        /// record its progress a few times.
+        fn get_image(id: u32) -> Bytes {
+            let mut image = format!("value {id}@0x10 ");
+            let mut state = id as u64 + 1;
+            for _ in 0..64 {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                image.push_str(&format!("{state:016x}"));
+            }
+            Bytes::from(image)
+        }
+
+        async fn create_timeline(
+            test_name: &'static str,
+        ) -> anyhow::Result<(TenantHarness, Arc<Tenant>, Arc<Timeline>, RequestContext)> {
+            let tenant_conf = TenantConf {
+                gc_period: Duration::ZERO,
+                compaction_period: Duration::ZERO,
+                compaction_target_size: 16 * 1024,
+                ..TenantConf::default()
+            };
+            let harness = TenantHarness::create_custom(
+                test_name,
+                tenant_conf,
+                TenantId::generate(),
+                ShardIdentity::unsharded(),
+                Generation::new(0xdeadbeef),
+            )
+            .await?;
+            let (tenant, ctx) = harness.load().await;
+
+            let img_layer = (0..NUM_KEYS)
+                .map(|id| (get_key(id), get_image(id)))
+                .collect_vec();
+            let delta = (0..NUM_KEYS)
+                .map(|id| {
+                    (
+                        get_key(id),
+                        Lsn(0x40),
+                        Value::WalRecord(NeonWalRecord::wal_append("@0x40")),
+                    )
+                })
+                .collect_vec();
+            let tline = tenant
+                .create_test_timeline_with_layers(
+                    TIMELINE_ID,
+                    Lsn(0x10),
+                    DEFAULT_PG_VERSION,
+                    &ctx,
+                    vec![DeltaLayerTestDesc::new_with_inferred_key_range(
+                        Lsn(0x10)..Lsn(0x48),
+                        delta,
+                    )], // delta layers
+                    vec![(Lsn(0x10), img_layer)], // image layers
+                    Lsn(0x50),
+                )
+                .await?;
+            {
+                // Update GC info
+                let mut guard = tline.gc_info.write().unwrap();
+                *guard = GcInfo {
+                    retain_lsns: vec![],
+                    cutoffs: GcCutoffs {
+                        time: Lsn(0x30),
+                        space: Lsn(0x30),
+                    },
+                    leases: Default::default(),
+                    within_ancestor_pitr: false,
+                    covered_by_children: Default::default(),
+                };
+            }
+            Ok((harness, tenant, tline, ctx))
+        }
+
+        async fn sorted_layers(tline: &Arc<Timeline>) -> Vec<PersistentLayerKey> {
+            let mut layers = tline.inspect_historic_layers().await.unwrap();
+            layers.sort_by_key(|layer| {
+                (
+                    layer.is_delta,
+                    layer.key_range.start,
+                    layer.key_range.end,
+                    layer.lsn_range.start,
+                )
+            });
+            layers
+        }
+
+        let cancel = CancellationToken::new();
+
+        // Compact another timeline with the same layers to get the expected result.
+        let (_harness, _tenant, tline, ctx) =
+            create_timeline("test_bottom_most_compaction_resume_after_crash_reference").await?;
+        tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await
+            .unwrap();
+        let expected_layers = sorted_layers(&tline).await;
+        // Recording the progress splits the image layer.
+        assert!(expected_layers.iter().filter(|l| !l.is_delta).count() > 1);
+
+        let (harness, _tenant, tline, ctx) =
+            create_timeline("test_bottom_most_compaction_resume_after_crash").await?;
+        tline
+            .gc_compaction_fail_after_checkpoint
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await
+            .unwrap_err();
+        let manifest_path = harness
+            .conf
+            .gc_compaction_manifest_path(&tline.tenant_shard_id, &tline.timeline_id);
+        let manifest = GcCompactionManifest::read(&manifest_path)?.expect("progress is recorded");
+        assert!(!manifest.produced_layers.is_empty());
+
+        tline
+            .gc_compaction_fail_after_checkpoint
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        let stat = tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await
+            .unwrap();
+        // Only the keys from the recorded progress on are processed again.
+        assert_eq!(
+            serde_json::to_value(&stat)?["num_unique_keys_visited"]
+                .as_u64()
+                .unwrap(),
+            (0..NUM_KEYS)
+                .filter(|id| get_key(*id) >= manifest.resume_key)
+                .count() as u64
+        );
+        assert!(!manifest_path.exists());
+        assert_eq!(sorted_layers(&tline).await, expected_layers);
+
+        for id in 0..NUM_KEYS {
+            let mut expected = get_image(id).to_vec();
+            expected.extend_from_slice(b"@0x40");
+            assert_eq!(
+                tline.get(get_key(id), Lsn(0x50), &ctx).await?,
+                Bytes::from(expected)
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bottom_most_compaction_verification() -> anyhow::Result<()> {
+        const NUM_KEYS: u32 = 64;
+
+        fn get_key(id: u32) -> Key {
+            let mut key = Key::from_hex("000000000033333333444444445500000000").unwrap();
+            key.field6 = id;
+            key
+        }
+
+        let tenant_conf = TenantConf {
+            gc_period: Duration::ZERO,
+            compaction_period: Duration::ZERO,
+            // Verifies a sample of the keys, which still catches a bug affecting all of them.
+            gc_compaction_verify_every_nth_key: 10,
+            ..TenantConf::default()
+        };
+        let harness = TenantHarness::create_custom(
+            "test_bottom_most_compaction_verification",
+            tenant_conf,
+            TenantId::generate(),
+            ShardIdentity::unsharded(),
+            Generation::new(0xdeadbeef),
+        )
+        .await?;
+        let (tenant, ctx) = harness.load().await;
+
+        let img_layer = (0..NUM_KEYS)
+            .map(|id| (get_key(id), Bytes::from(format!("value {id}@0x10"))))
+            .collect_vec();
+        let delta1 = (0..NUM_KEYS)
+            .map(|id| {
+                (
+                    get_key(id),
+                    Lsn(0x20),
+                    Value::WalRecord(NeonWalRecord::wal_append("@0x20")),
+                )
+            })
+            .collect_vec();
+        let delta2 = (0..NUM_KEYS)
+            .map(|id| {
+                (
+                    get_key(id),
+                    Lsn(0x40),
+                    Value::WalRecord(NeonWalRecord::wal_append("@0x40")),
+                )
+            })
+            .collect_vec();
+        let tline = tenant
+            .create_test_timeline_with_layers(
+                TIMELINE_ID,
+                Lsn(0x10),
+                DEFAULT_PG_VERSION,
+                &ctx,
+                vec![
+                    DeltaLayerTestDesc::new_with_inferred_key_range(Lsn(0x10)..Lsn(0x28), delta1),
+                    DeltaLayerTestDesc::new_with_inferred_key_range(Lsn(0x38)..Lsn(0x48), delta2),
+                ], // delta layers
+                vec![(Lsn(0x10), img_layer)], // image layers
+                Lsn(0x50),
+            )
+            .await?;
+        {
+            // Update GC info
+            let mut guard = tline.gc_info.write().unwrap();
+            *guard = GcInfo {
+                retain_lsns: vec![(Lsn(0x20), tline.timeline_id)],
+                cutoffs: GcCutoffs {
+                    time: Lsn(0x30),
+                    space: Lsn(0x30),
+                },
+                leases: Default::default(),
+                within_ancestor_pitr: false,
+                covered_by_children: Default::default(),
+            };
+        }
+
+        let cancel = CancellationToken::new();
+        let layers_before = tline.inspect_historic_layers().await?;
+
+        // A compaction that retains wrong images fails, and leaves the layers alone.
+        tline
+            .gc_compaction_corrupt_images
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        let err = tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("gc-compaction verification failed"),
+            "{err:#}"
+        );
+        assert_eq!(tline.inspect_historic_layers().await?, layers_before);
+
+        tline
+            .gc_compaction_corrupt_images
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await?;
+        assert_ne!(tline.inspect_historic_layers().await?, layers_before);
+        for id in 0..NUM_KEYS {
+            assert_eq!(
+                tline.get(get_key(id), Lsn(0x20), &ctx).await?,
+                Bytes::from(format!("value {id}@0x10@0x20"))
+            );
+            assert_eq!(
+                tline.get(get_key(id), Lsn(0x50), &ctx).await?,
+                Bytes::from(format!("value {id}@0x10@0x20@0x40"))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bottom_most_compaction_dry_run_layer_map_diff() -> anyhow::Result<()> {
+        const NUM_KEYS: u32 = 64;
+
+        fn get_key(id: u32) -> Key {
+            let mut key = Key::from_hex("000000000033333333444444445500000000").unwrap();
+            key.field6 = id;
+            key
+        }
+
+        /// An image that does not compress well, so that the compaction produces several layers.
+        fn get_image(id: u32) -> Bytes {
+            let mut image = format!("value {id}@0x10 ");
+            let mut state = id as u64 + 1;
+            for _ in 0..64 {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                image.push_str(&format!("{state:016x}"));
+            }
+            Bytes::from(image)
+        }
+
+        let tenant_conf = TenantConf {
+            gc_period: Duration::ZERO,
+            compaction_period: Duration::ZERO,
+            compaction_target_size: 16 * 1024,
+            ..TenantConf::default()
+        };
+        let harness = TenantHarness::create_custom(
+            "test_bottom_most_compaction_dry_run_layer_map_diff",
+            tenant_conf,
+            TenantId::generate(),
+            ShardIdentity::unsharded(),
+            Generation::new(0xdeadbeef),
+        )
+        .await?;
+        let (tenant, ctx) = harness.load().await;
+
+        let img_layer = (0..NUM_KEYS)
+            .map(|id| (get_key(id), get_image(id)))
+            .collect_vec();
+        let delta1 = (0..NUM_KEYS)
+            .map(|id| {
+                (
+                    get_key(id),
+                    Lsn(0x20),
+                    Value::WalRecord(NeonWalRecord::wal_append("@0x20")),
+                )
+            })
+            .collect_vec();
+        let delta2 = (0..NUM_KEYS)
+            .map(|id| {
+                (
+                    get_key(id),
+                    Lsn(0x40),
+                    Value::WalRecord(NeonWalRecord::wal_append("@0x40")),
+                )
+            })
+            .collect_vec();
+        let tline = tenant
+            .create_test_timeline_with_layers(
+                TIMELINE_ID,
+                Lsn(0x10),
+                DEFAULT_PG_VERSION,
+                &ctx,
+                vec![
+                    DeltaLayerTestDesc::new_with_inferred_key_range(Lsn(0x10)..Lsn(0x28), delta1),
+                    DeltaLayerTestDesc::new_with_inferred_key_range(Lsn(0x38)..Lsn(0x48), delta2),
+                ], // delta layers
+                vec![(Lsn(0x10), img_layer)], // image layers
+                Lsn(0x50),
+            )
+            .await?;
+        {
+            // Update GC info
+            let mut guard = tline.gc_info.write().unwrap();
+            *guard = GcInfo {
+                retain_lsns: vec![],
+                cutoffs: GcCutoffs {
+                    time: Lsn(0x30),
+                    space: Lsn(0x30),
+                },
+                leases: Default::default(),
+                within_ancestor_pitr: false,
+                covered_by_children: Default::default(),
+            };
+        }
+
+        async fn layer_names(tline: &Arc<Timeline>) -> HashSet<LayerName> {
+            tline
+                .inspect_historic_layers()
+                .await
+                .unwrap()
+                .iter()
+                .map(layer_name_of_key)
+                .collect()
+        }
+
+        let cancel = CancellationToken::new();
+        let mut dryrun_options = CompactOptions::default();
+        dryrun_options.flags.insert(CompactFlags::DryRun);
+        let stat = tline
+            .compact_with_gc(&cancel, dryrun_options, None, &ctx)
+            .await
+            .unwrap();
+        let diff = stat.layer_map_diff().expect("dry run reports a diff");
+        let removed: HashSet<LayerName> = diff.removed.iter().cloned().collect();
+        let added: HashSet<LayerName> = diff.added.iter().cloned().collect();
+        // More than one image layer is produced.
+        assert!(added.iter().filter(|l| !l.is_delta()).count() > 1);
+
+        let layers_before = layer_names(&tline).await;
+        tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await
+            .unwrap();
+        let layers_after = layer_names(&tline).await;
+        assert_eq!(
+            removed,
+            layers_before.difference(&layers_after).cloned().collect()
+        );
+        assert_eq!(
+            added,
+            layers_after.difference(&layers_before).cloned().collect()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bottom_most_compaction_observer() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("test_bottom_most_compaction_observer").await?;
+        let (tenant, ctx) = harness.load().await;
+
+        fn get_key(id: u32) -> Key {
+            let mut key = Key::from_hex("000000000033333333444444445500000000").unwrap();
+            key.field6 = id;
+            key
+        }
+
+        let img_layer = (0..10)
+            .map(|id| (get_key(id), Bytes::from(format!("value {id}@0x10"))))
+            .collect_vec();
+        let delta1 = (0..10)
+            .map(|id| {
+                (
+                    get_key(id),
+                    Lsn(0x20),
+                    Value::WalRecord(NeonWalRecord::wal_append("@0x20")),
+                )
+            })
+            .collect_vec();
+        let delta2 = (0..10)
+            .map(|id| {
+                (
+                    get_key(id),
+                    Lsn(0x40),
+                    Value::WalRecord(NeonWalRecord::wal_append("@0x40")),
+                )
+            })
+            .collect_vec();
+        let tline = tenant
+            .create_test_timeline_with_layers(
+                TIMELINE_ID,
+                Lsn(0x10),
+                DEFAULT_PG_VERSION,
+                &ctx,
+                vec![
+                    DeltaLayerTestDesc::new_with_inferred_key_range(Lsn(0x10)..Lsn(0x28), delta1),
+                    DeltaLayerTestDesc::new_with_inferred_key_range(Lsn(0x38)..Lsn(0x48), delta2),
+                ], // delta layers
+                vec![(Lsn(0x10), img_layer)], // image layers
+                Lsn(0x50),
+            )
+            .await?;
+        {
+            // Update GC info
+            let mut guard = tline.gc_info.write().unwrap();
+            *guard = GcInfo {
+                retain_lsns: vec![],
+                cutoffs: GcCutoffs {
+                    time: Lsn(0x30),
+                    space: Lsn(0x30),
+                },
+                leases: Default::default(),
+                within_ancestor_pitr: false,
+                covered_by_children: Default::default(),
+            };
+        }
+
+        #[derive(Default)]
+        struct CollectingObserver(std::sync::Mutex<Vec<PersistentLayerKey>>);
+
+        impl CompactionObserver for CollectingObserver {
+            fn on_layer_produced(&self, layer: &PersistentLayerDesc) {
+                self.0.lock().unwrap().push(layer.key());
+            }
+        }
+
+        let layers_before: HashSet<PersistentLayerKey> =
+            tline.inspect_historic_layers().await?.into_iter().collect();
+        let observer = CollectingObserver::default();
+        let cancel = CancellationToken::new();
+        tline
+            .compact_with_gc(&cancel, CompactOptions::default(), Some(&observer), &ctx)
+            .await
+            .unwrap();
+        let layers_after: HashSet<PersistentLayerKey> =
+            tline.inspect_historic_layers().await?.into_iter().collect();
+
+        let observed = observer.0.into_inner().unwrap();
+        assert!(!observed.is_empty());
+        assert_eq!(
+            observed.into_iter().collect::<HashSet<_>>(),
+            layers_after.difference(&layers_before).cloned().collect()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bottom_most_compaction_key_range() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("test_bottom_most_compaction_key_range").await?;
+        let (tenant, ctx) = harness.load().await;
+
+        fn get_key(rel: u32, id: u32) -> Key {
+            let mut key = Key::from_hex("000000000033333333444444445500000000").unwrap();
+            key.field4 = rel;
+            key.field6 = id;
+            key
+        }
+
+        let img_layer = |rel| {
+            (0..10)
+                .map(|id| (get_key(rel, id), Bytes::from(format!("value {id}@0x10"))))
+                .collect_vec()
+        };
+        let delta = |rel| {
+            (0..10)
+                .map(|id| {
+                    (
+                        get_key(rel, id),
+                        Lsn(0x20),
+                        Value::WalRecord(NeonWalRecord::wal_append("@0x20")),
+                    )
+                })
+                .collect_vec()
+        };
+        let tline = tenant
+            .create_test_timeline_with_layers(
+                TIMELINE_ID,
+                Lsn(0x10),
+                DEFAULT_PG_VERSION,
+                &ctx,
+                vec![
+                    DeltaLayerTestDesc::new_with_inferred_key_range(
+                        Lsn(0x10)..Lsn(0x28),
+                        delta(0x55),
+                    ),
+                    DeltaLayerTestDesc::new_with_inferred_key_range(
+                        Lsn(0x10)..Lsn(0x28),
+                        delta(0x66),
+                    ),
+                ], // delta layers
+                vec![(Lsn(0x10), img_layer(0x55)), (Lsn(0x10), img_layer(0x66))], // image layers
+                Lsn(0x50),
+            )
+            .await?;
+        {
+            // Update GC info
+            let mut guard = tline.gc_info.write().unwrap();
+            *guard = GcInfo {
+                retain_lsns: vec![],
+                cutoffs: GcCutoffs {
+                    time: Lsn(0x30),
+                    space: Lsn(0x30),
+                },
+                leases: Default::default(),
+                within_ancestor_pitr: false,
+                covered_by_children: Default::default(),
+            };
+        }
+
+        let compact_range = get_key(0x55, 0)..get_key(0x55, 10);
+        let layers_before: HashSet<PersistentLayerKey> =
+            tline.inspect_historic_layers().await?.into_iter().collect();
+        let cancel = CancellationToken::new();
+        tline
+            .compact_with_gc(
+                &cancel,
+                CompactOptions {
+                    compact_range: Some(compact_range.clone()),
+                    ..Default::default()
+                },
+                None,
+                &ctx,
+            )
+            .await
+            .unwrap();
+        let layers_after: HashSet<PersistentLayerKey> =
+            tline.inspect_historic_layers().await?.into_iter().collect();
+
+        // The layers of the other relation are untouched, and the new ones don't overlap with it.
+        for layer in &layers_before {
+            let overlaps = layer.key_range.start < compact_range.end
+                && compact_range.start < layer.key_range.end;
+            assert_eq!(!overlaps, layers_after.contains(layer), "{layer}");
+        }
+        for layer in layers_after.difference(&layers_before) {
+            assert!(
+                compact_range.start <= layer.key_range.start
+                    && layer.key_range.end <= compact_range.end,
+                "{layer}"
+            );
+        }
+
+        for rel in [0x55, 0x66] {
+            for id in 0..10 {
+                assert_eq!(
+                    tline.get(get_key(rel, id), Lsn(0x50), &ctx).await?,
+                    Bytes::from(format!("value {id}@0x10@0x20"))
+                );
+            }
+        }
+
+        // A range without keys is rejected.
+        let empty_range = get_key(0x55, 10)..get_key(0x55, 10);
+        assert!(tline
+            .compact_with_gc(
+                &cancel,
+                CompactOptions {
+                    compact_range: Some(empty_range),
+                    ..Default::default()
+                },
+                None,
+                &ctx,
+            )
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compaction_layer_lock_timeout() -> anyhow::Result<()> {
+        let tenant_conf = TenantConf {
+            compaction_layer_lock_timeout: Some(Duration::from_millis(100)),
+            ..TenantConf::default()
+        };
+        let harness = TenantHarness::create_custom(
+            "test_compaction_layer_lock_timeout",
+            tenant_conf,
+            TenantId::generate(),
+            ShardIdentity::unsharded(),
+            Generation::new(0xdeadbeef),
+        )
+        .await?;
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+        {
+            let mut guard = tline.gc_info.write().unwrap();
+            guard.cutoffs.time = Lsn(0x10);
+            guard.cutoffs.space = Lsn(0x10);
+        }
+        let cancel = CancellationToken::new();
+
+        {
+            // Compaction gives up instead of queueing behind a writer that holds the lock.
+            let _guard = tline.layers.write().await;
+            let res = tline.read_layers_for_compaction().await;
+            assert!(matches!(
+                res,
+                Err(timeline::CompactionError::LockTimeout(_))
+            ));
+            let err = tline
+                .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+                .await
+                .expect_err("compaction should time out");
+            assert!(
+                matches!(
+                    err.downcast_ref::<timeline::CompactionError>(),
+                    Some(timeline::CompactionError::LockTimeout(_))
+                ),
+                "{err:?}"
+            );
+            let res = tline
+                .compact_with_options(
+                    &cancel,
+                    CompactOptions {
+                        flags: {
+                            let mut flags = EnumSet::new();
+                            flags.insert(CompactFlags::EnhancedGcBottomMostCompaction);
+                            flags
+                        },
+                        ..Default::default()
+                    },
+                    &ctx,
+                )
+                .await;
+            assert!(
+                matches!(res, Err(timeline::CompactionError::LockTimeout(_))),
+                "{res:?}"
+            );
+        }
+
+        tline.read_layers_for_compaction().await?;
+        tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_estimate_compaction_work() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("test_estimate_compaction_work").await?;
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+
+        let test_key = Key::from_hex("010000000033333333444444445500000000").unwrap();
+        let mut lsn = Lsn(0x10);
+        for _ in 0..20 {
+            lsn = Lsn(lsn.0 + 0x10);
+            let mut writer = tline.writer().await;
+            writer
+                .put(
+                    test_key,
+                    lsn,
+                    &Value::Image(test_img(&format!("{lsn}"))),
+                    &ctx,
+                )
+                .await?;
+            writer.finish_write(lsn);
+            drop(writer);
+            tline.freeze_and_flush().await?;
+        }
+
+        let (num_l0, l0_bytes) = {
+            let guard = tline.layers.read().await;
+            let level0_deltas = guard.layer_map()?.level0_deltas().clone();
+            let bytes = level0_deltas.iter().map(|l| l.file_size).sum::<u64>();
+            (level0_deltas.len(), bytes)
+        };
+        let layers_before = tline.inspect_historic_layers().await?;
+
+        let estimate = tline.estimate_compaction_work(&ctx).await?;
+        assert_eq!(estimate.l0_deltas, num_l0);
+        assert_eq!(estimate.l0_deltas_bytes, l0_bytes);
+        assert!(estimate.l0_deltas_selected > 0);
+        assert!(estimate.l0_deltas_selected <= num_l0);
+
+        // The estimate doesn't change the layer map.
+        assert_eq!(tline.inspect_historic_layers().await?, layers_before);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_shard_ancestors_rewrite() -> anyhow::Result<()> {
+        use pageserver_api::shard::{ShardCount, ShardIndex, ShardNumber};
+
+        let shard_identity =
+            ShardIdentity::new(ShardNumber(0), ShardCount::new(2), ShardStripeSize(0x8000))?;
+        let harness = TenantHarness::create_custom(
+            "test_compact_shard_ancestors_rewrite",
+            TenantConf::default(),
+            TenantId::generate(),
+            shard_identity,
+            Generation::new(0xdeadbeef),
+        )
+        .await?;
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+        tline.force_advance_lsn(Lsn(0x40));
+        let cancel = CancellationToken::new();
+
+        // An image layer of the unsharded tenant, spanning several stripes of both shards.
+        let base_key = Key::from_hex("000000067f00000001000000ae0000000000").unwrap();
+        let keys = (0..64)
+            .map(|i| {
+                let mut key = base_key;
+                key.field6 = i * 0x800;
+                key
+            })
+            .collect_vec();
+        let images = keys
+            .iter()
+            .map(|key| (*key, test_img(&format!("{key}"))))
+            .collect_vec();
+        tline
+            .force_create_ancestor_image_layer(Lsn(0x20), images, ShardIndex::unsharded(), &ctx)
+            .await?;
+        let ancestor_size = {
+            let guard = tline.layers.read().await;
+            let layer_map = guard.layer_map()?;
+            let desc = layer_map
+                .iter_historic_layers()
+                .find(|desc| !desc.is_delta() && desc.image_layer_lsn() == Lsn(0x20))
+                .unwrap();
+            desc.file_size
+        };
+        tline
+            .latest_gc_cutoff_lsn
+            .lock_for_write()
+            .store_and_unlock(Lsn(0x30))
+            .wait()
+            .await;
+
+        // The layer is of the current generation, so it is only rewritten when forced to.
+        let summary = tline
+            .compact_legacy(&cancel, CompactOptions::default(), None, &ctx)
+            .await?
+            .shard_ancestors
+            .unwrap();
+        assert_eq!(summary.layers_rewritten, 0);
+
+        tline
+            .force_shard_ancestor_rewrite
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        let summary = tline
+            .compact_legacy(&cancel, CompactOptions::default(), None, &ctx)
+            .await?
+            .shard_ancestors
+            .unwrap();
+        assert_eq!(summary.layers_rewritten, 1);
+        assert_eq!(summary.layers_dropped, 0);
+        assert_eq!(summary.bytes_read, ancestor_size);
+        assert!(summary.bytes_written < ancestor_size);
+
+        // The rewritten layer replaced the ancestral one, and only has the keys of this shard.
+        {
+            let guard = tline.layers.read().await;
+            let layer_map = guard.layer_map()?;
+            let images = layer_map
+                .iter_historic_layers()
+                .filter(|desc| !desc.is_delta() && desc.image_layer_lsn() == Lsn(0x20))
+                .collect_vec();
+            assert_eq!(images.len(), 1);
+            let layer = guard.get_from_desc(&images[0]);
+            assert_eq!(layer.metadata().shard, tline.get_shard_index());
+            assert_eq!(layer.metadata().file_size, summary.bytes_written);
+        }
+        for key in keys {
+            if !shard_identity.is_key_disposable(&key) {
+                assert_eq!(
+                    tline.get(key, Lsn(0x40), &ctx).await?,
+                    test_img(&format!("{key}"))
+                );
+            }
+        }
+
+        // Nothing is left to rewrite.
+        let summary = tline
+            .compact_legacy(&cancel, CompactOptions::default(), None, &ctx)
+            .await?
+            .shard_ancestors
+            .unwrap();
+        assert_eq!(summary.layers_rewritten, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_shard_ancestors_pinned_layer() -> anyhow::Result<()> {
+        use pageserver_api::shard::{ShardCount, ShardIndex, ShardNumber};
+
+        let shard_identity =
+            ShardIdentity::new(ShardNumber(0), ShardCount::new(2), ShardStripeSize(0x8000))?;
+        let harness = TenantHarness::create_custom(
+            "test_compact_shard_ancestors_pinned_layer",
+            TenantConf::default(),
+            TenantId::generate(),
+            shard_identity,
+            Generation::new(0xdeadbeef),
+        )
+        .await?;
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+        tline.force_advance_lsn(Lsn(0x40));
+        let cancel = CancellationToken::new();
+
+        let base_key = Key::from_hex("000000067f00000001000000ae0000000000").unwrap();
+        let images = (0..64)
+            .map(|i| {
+                let mut key = base_key;
+                key.field6 = i * 0x800;
+                (key, test_img(&format!("{key}")))
+            })
+            .collect_vec();
+        tline
+            .force_create_ancestor_image_layer(Lsn(0x20), images, ShardIndex::unsharded(), &ctx)
+            .await?;
+        tline
+            .latest_gc_cutoff_lsn
+            .lock_for_write()
+            .store_and_unlock(Lsn(0x30))
+            .wait()
+            .await;
+        tline
+            .force_shard_ancestor_rewrite
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+
+        let ancestor_layer = {
+            let guard = tline.layers.read().await;
+            guard
+                .layer_map()?
+                .iter_historic_layers()
+                .find(|desc| !desc.is_delta() && desc.image_layer_lsn() == Lsn(0x20))
+                .unwrap()
+                .layer_name()
+        };
+        assert_eq!(tline.pin_layer(&ancestor_layer).await?, Some(true));
+        assert_eq!(tline.pin_layer(&ancestor_layer).await?, Some(false));
+
+        // The pinned layer would be rewritten otherwise.
+        let layers_before = tline.inspect_historic_layers().await?;
+        let summary = tline
+            .compact_legacy(&cancel, CompactOptions::default(), None, &ctx)
+            .await?
+            .shard_ancestors
+            .unwrap();
+        assert_eq!(summary.layers_rewritten, 0);
+        assert_eq!(summary.layers_dropped, 0);
+        assert_eq!(tline.inspect_historic_layers().await?, layers_before);
+
+        assert!(tline.unpin_layer(&ancestor_layer));
+        assert!(!tline.unpin_layer(&ancestor_layer));
+        let summary = tline
+            .compact_legacy(&cancel, CompactOptions::default(), None, &ctx)
+            .await?
+            .shard_ancestors
+            .unwrap();
+        assert_eq!(summary.layers_rewritten, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tiered_compaction_sparse_keyspace() -> anyhow::Result<()> {
+        let mut harness = TenantHarness::create("test_tiered_compaction_sparse_keyspace").await?;
+        harness.tenant_conf.compaction_algorithm = CompactionAlgorithmSettings {
+            kind: CompactionAlgorithm::Tiered,
+        };
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+
+        const NUM_KEYS: u32 = 10;
+        let base_key = Key::from_hex("620000000033333333444444445500000000").unwrap();
+        assert_eq!(base_key.field1, AUX_KEY_PREFIX);
+        let keyspace = KeySpace::single(base_key..base_key.add(NUM_KEYS));
+
+        // Every flush produces an L0 delta layer with updates of the sparse keys only.
+        let mut lsn = Lsn(0x10);
+        for iter in 0..20 {
+            for blknum in 0..NUM_KEYS {
+                lsn = Lsn(lsn.0 + 0x10);
+                let mut writer = tline.writer().await;
+                writer
+                    .put(
+                        base_key.add(blknum),
+                        lsn,
+                        &Value::Image(test_img(&format!("{blknum} at {iter}"))),
+                        &ctx,
+                    )
+                    .await?;
+                writer.finish_write(lsn);
+                drop(writer);
+            }
+            tline.freeze_and_flush().await?;
+        }
+        let end_lsn = {
+            let guard = tline.layers.read().await;
+            let layer_map = guard.layer_map()?;
+            layer_map
+                .level0_deltas()
+                .iter()
+                .map(|l| l.lsn_range.end)
+                .max()
+                .unwrap()
+        };
+
+        tline
+            .compact(&CancellationToken::new(), EnumSet::empty(), &ctx)
+            .await?;
+
+        {
+            let guard = tline.layers.read().await;
+            let layer_map = guard.layer_map()?;
+            assert!(layer_map.iter_historic_layers().any(|l| !l.is_delta()
+                && l.lsn_range.start == end_lsn
+                && l.key_range.contains(&base_key)));
+        }
+
+        // The sparse keys can now be read from the image alone.
+        let mut reconstruct_state = ValuesReconstructState::default();
+        let values = tline
+            .get_vectored_impl(keyspace, end_lsn, &mut reconstruct_state, &ctx)
+            .await?;
+        assert_eq!(reconstruct_state.get_delta_layers_visited(), 0);
+        assert_eq!(values.len(), NUM_KEYS as usize);
+        for (key, value) in values {
+            let blknum = key.field6 - base_key.field6;
+            assert_eq!(value?, test_img(&format!("{blknum} at 19")));
+        }
+
+        Ok(())
+    }
 }
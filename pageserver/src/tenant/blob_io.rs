@@ -7,9 +7,10 @@
 //! is written as a four-byte integer, in big-endian, with the high
 //! bit set. This way, we can detect whether it's 1- or 4-byte header
 //! by peeking at the first byte. For blobs larger than 128 bits,
-//! we also specify three reserved bits, only one of the three bit
-//! patterns is currently in use (0b011) and signifies compression
-//! with zstd.
+//! we also specify three reserved bits, only two of the three bit
+//! patterns are currently in use: 0b001 signifies compression with
+//! zstd, and 0b010 a reference to an earlier blob in the same file,
+//! whose offset is stored as the 8-byte big-endian payload.
 //!
 //! len <  128: 0XXXXXXX
 //! len >= 128: 1CCCXXXX XXXXXXXX XXXXXXXX XXXXXXXX
@@ -54,6 +55,33 @@ pub async fn read_blob_into_buf(
         dstbuf: &mut Vec<u8>,
         ctx: &RequestContext,
     ) -> Result<(), std::io::Error> {
+        let Some(target) = self.read_blob_or_ref_into_buf(offset, dstbuf, ctx).await? else {
+            return Ok(());
+        };
+        // References always point to an earlier blob which is not a reference itself.
+        if target >= offset
+            || self
+                .read_blob_or_ref_into_buf(target, dstbuf, ctx)
+                .await?
+                .is_some()
+        {
+            let error = std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid blob reference from {offset} to {target}"),
+            );
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Reads the blob at `offset` into `dstbuf`, unless it is a reference to another blob. In
+    /// that case, returns the offset of the referenced blob instead.
+    async fn read_blob_or_ref_into_buf(
+        &self,
+        offset: u64,
+        dstbuf: &mut Vec<u8>,
+        ctx: &RequestContext,
+    ) -> Result<Option<u64>, std::io::Error> {
         let mut blknum = (offset / PAGE_SZ as u64) as u32;
         let mut off = (offset % PAGE_SZ as u64) as usize;
 
@@ -92,7 +120,11 @@ pub async fn read_blob_into_buf(
 
         let mut tmp_buf = Vec::new();
         let buf_to_write;
-        let compression = if compression_bits <= BYTE_UNCOMPRESSED || !self.read_compressed {
+        let is_ref = self.read_compressed && compression_bits == BYTE_REF;
+        let compression = if is_ref {
+            buf_to_write = &mut tmp_buf;
+            None
+        } else if compression_bits <= BYTE_UNCOMPRESSED || !self.read_compressed {
             if compression_bits > BYTE_UNCOMPRESSED {
                 warn!("reading key above future limit ({len} bytes)");
             }
@@ -129,6 +161,18 @@ pub async fn read_blob_into_buf(
             off += this_blk_len;
         }
 
+        if is_ref {
+            let target = <[u8; BLOB_REF_LEN]>::try_from(&tmp_buf[..])
+                .map(u64::from_be_bytes)
+                .map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("invalid blob reference length {len}"),
+                    )
+                })?;
+            return Ok(Some(target));
+        }
+
         if let Some(dstbuf) = compression {
             if compression_bits == BYTE_ZSTD {
                 let mut decoder = async_compression::tokio::write::ZstdDecoder::new(dstbuf);
@@ -139,7 +183,7 @@ pub async fn read_blob_into_buf(
             }
         }
 
-        Ok(())
+        Ok(None)
     }
 }
 
@@ -152,6 +196,10 @@ pub async fn read_blob_into_buf(
 
 pub(super) const BYTE_UNCOMPRESSED: u8 = 0x80;
 pub(super) const BYTE_ZSTD: u8 = BYTE_UNCOMPRESSED | 0x10;
+pub(super) const BYTE_REF: u8 = BYTE_UNCOMPRESSED | 0x20;
+
+/// The payload length of a reference blob: the offset of the referenced blob.
+pub(super) const BLOB_REF_LEN: usize = 8;
 
 /// A wrapper of `VirtualFile` that allows users to write blobs.
 ///
@@ -384,6 +432,28 @@ pub(crate) async fn write_blob_maybe_compressed<Buf: IoBuf + Send>(
         };
         (srcbuf, res.map(|_| (offset, compression_info)))
     }
+
+    /// Write a reference to the blob at `target`, which must have been written
+    /// before by this writer. Reading the reference returns the referenced blob.
+    /// Returns the offset that the reference was written to.
+    pub(crate) async fn write_blob_ref(
+        &mut self,
+        target: u64,
+        ctx: &RequestContext,
+    ) -> Result<u64, Error> {
+        assert!(target < self.offset, "references must point backwards");
+        let offset = self.offset;
+
+        let mut io_buf = self.io_buf.take().expect("we always put it back below");
+        io_buf.clear();
+        let mut len_buf = (BLOB_REF_LEN as u32).to_be_bytes();
+        len_buf[0] |= BYTE_REF;
+        io_buf.extend_from_slice(&len_buf[..]);
+        io_buf.extend_from_slice(&target.to_be_bytes());
+        let (io_buf_slice, res) = self.write_all(io_buf.slice_len(), ctx).await;
+        self.io_buf = Some(io_buf_slice.into_raw_slice().into_inner());
+        res.map(|_| offset)
+    }
 }
 
 impl BlobWriter<true> {
@@ -293,6 +293,12 @@ pub fn new() -> Self {
         BlockBuf { blocks: Vec::new() }
     }
 
+    pub fn with_capacity(blocks: usize) -> Self {
+        BlockBuf {
+            blocks: Vec::with_capacity(blocks),
+        }
+    }
+
     pub fn size(&self) -> u64 {
         (self.blocks.len() * PAGE_SZ) as u64
     }
@@ -10,6 +10,7 @@
 //!
 use anyhow::bail;
 use pageserver_api::models::AuxFilePolicy;
+use pageserver_api::models::CompactL0Phase1ValueAccess;
 use pageserver_api::models::CompactionAlgorithm;
 use pageserver_api::models::CompactionAlgorithmSettings;
 use pageserver_api::models::EvictionPolicy;
@@ -41,6 +42,13 @@ pub mod defaults {
 
     pub const DEFAULT_COMPACTION_PERIOD: &str = "20 s";
     pub const DEFAULT_COMPACTION_THRESHOLD: usize = 10;
+    pub const DEFAULT_COMPACTION_MAX_VERSIONS_PER_KEY: usize = usize::MAX;
+    pub const DEFAULT_COMPACTION_IO_THROTTLE_BYTES_PER_SEC: u64 = 0;
+    pub const DEFAULT_COMPACTION_MIN_HOLE_COVERAGE_SIZE: usize = 3;
+    pub const DEFAULT_COMPACTION_OVERSIZE_WARN_MULTIPLIER: u64 = 2;
+    pub const DEFAULT_COMPACTION_ALIGN_TO_PARTITIONS: bool = false;
+    pub const DEFAULT_COMPACTION_UPLOAD_CONCURRENCY: usize = 0;
+    pub const DEFAULT_GC_COMPACTION_VERIFY_EVERY_NTH_KEY: u64 = 0;
     pub const DEFAULT_COMPACTION_ALGORITHM: super::CompactionAlgorithm =
         super::CompactionAlgorithm::Legacy;
 
@@ -307,6 +315,40 @@ pub struct TenantConf {
     // Level0 delta layer threshold for compaction.
     pub compaction_threshold: usize,
     pub compaction_algorithm: CompactionAlgorithmSettings,
+    // Maximum number of versions of a single key that gc-compaction keeps in a row before
+    // materializing an image, bounding the history replayed for hot keys. Unlimited by default.
+    pub compaction_max_versions_per_key: usize,
+    // Maximum number of bytes per second that compaction downloads and writes, shared by all
+    // timelines of the tenant. Zero disables the throttle.
+    pub compaction_io_throttle_bytes_per_sec: u64,
+    // Minimum number of image layers that must cover a key range without any keys in the
+    // compacted L0 layers for L0 compaction to treat it as a hole and not let L1 layers span it.
+    pub compaction_min_hole_coverage_size: usize,
+    // L0 compaction warns about produced delta layers larger than this multiple of the target
+    // size, plus two pages of overhead.
+    pub compaction_oversize_warn_multiplier: u64,
+    // Split the L1 delta layers produced by L0 compaction at the boundaries of the key space
+    // partitions, so that they line up with the image layers.
+    pub compaction_align_to_partitions: bool,
+    // The maximum number of layers uploaded concurrently by a compaction. Zero means no limit.
+    pub compaction_upload_concurrency: usize,
+    // gc-compaction checks that every N-th key it processes reads the same before and after the
+    // compaction, and fails the compaction otherwise. Zero disables the check.
+    pub gc_compaction_verify_every_nth_key: u64,
+    // How long compaction waits for the layer map lock before giving up and backing off. Unset
+    // means it waits as long as it takes.
+    #[serde(with = "humantime_serde")]
+    pub compaction_layer_lock_timeout: Option<Duration>,
+    // How long a background compaction pass may run before it stops starting new phases and
+    // leaves the rest for the next pass. Unset means a pass runs all phases.
+    #[serde(with = "humantime_serde")]
+    pub compaction_time_budget: Option<Duration>,
+    // Overrides the total size of the L0 delta layers compacted in one pass, which is otherwise
+    // derived from the compaction threshold and the checkpoint distance.
+    pub l0_compaction_delta_size_limit: Option<u64>,
+    // Overrides the pageserver-wide `compact_level0_phase1_value_access`, e.g. to skip the
+    // validation of the streaming k-merge for this tenant.
+    pub compact_level0_phase1_value_access: Option<CompactL0Phase1ValueAccess>,
     // Determines how much history is retained, to allow
     // branching and read replicas at an older point in time.
     // The unit is #of bytes of WAL.
@@ -403,6 +445,52 @@ pub struct TenantConfOpt {
     #[serde(default)]
     pub compaction_algorithm: Option<CompactionAlgorithmSettings>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub compaction_max_versions_per_key: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub compaction_io_throttle_bytes_per_sec: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub compaction_min_hole_coverage_size: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub compaction_oversize_warn_multiplier: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub compaction_align_to_partitions: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub compaction_upload_concurrency: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub gc_compaction_verify_every_nth_key: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(with = "humantime_serde")]
+    #[serde(default)]
+    pub compaction_layer_lock_timeout: Option<Duration>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(with = "humantime_serde")]
+    #[serde(default)]
+    pub compaction_time_budget: Option<Duration>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub l0_compaction_delta_size_limit: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub compact_level0_phase1_value_access: Option<CompactL0Phase1ValueAccess>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub gc_horizon: Option<u64>,
@@ -501,6 +589,40 @@ pub fn merge(&self, global_conf: TenantConf) -> TenantConf {
                 .as_ref()
                 .unwrap_or(&global_conf.compaction_algorithm)
                 .clone(),
+            compaction_max_versions_per_key: self
+                .compaction_max_versions_per_key
+                .unwrap_or(global_conf.compaction_max_versions_per_key),
+            compaction_io_throttle_bytes_per_sec: self
+                .compaction_io_throttle_bytes_per_sec
+                .unwrap_or(global_conf.compaction_io_throttle_bytes_per_sec),
+            compaction_min_hole_coverage_size: self
+                .compaction_min_hole_coverage_size
+                .unwrap_or(global_conf.compaction_min_hole_coverage_size),
+            compaction_oversize_warn_multiplier: self
+                .compaction_oversize_warn_multiplier
+                .unwrap_or(global_conf.compaction_oversize_warn_multiplier),
+            compaction_align_to_partitions: self
+                .compaction_align_to_partitions
+                .unwrap_or(global_conf.compaction_align_to_partitions),
+            compaction_upload_concurrency: self
+                .compaction_upload_concurrency
+                .unwrap_or(global_conf.compaction_upload_concurrency),
+            gc_compaction_verify_every_nth_key: self
+                .gc_compaction_verify_every_nth_key
+                .unwrap_or(global_conf.gc_compaction_verify_every_nth_key),
+            compaction_layer_lock_timeout: self
+                .compaction_layer_lock_timeout
+                .or(global_conf.compaction_layer_lock_timeout),
+            compaction_time_budget: self
+                .compaction_time_budget
+                .or(global_conf.compaction_time_budget),
+            l0_compaction_delta_size_limit: self
+                .l0_compaction_delta_size_limit
+                .or(global_conf.l0_compaction_delta_size_limit),
+            compact_level0_phase1_value_access: self
+                .compact_level0_phase1_value_access
+                .clone()
+                .or(global_conf.compact_level0_phase1_value_access),
             gc_horizon: self.gc_horizon.unwrap_or(global_conf.gc_horizon),
             gc_period: self.gc_period.unwrap_or(global_conf.gc_period),
             image_creation_threshold: self
@@ -559,6 +681,17 @@ fn default() -> Self {
             compaction_algorithm: CompactionAlgorithmSettings {
                 kind: DEFAULT_COMPACTION_ALGORITHM,
             },
+            compaction_max_versions_per_key: DEFAULT_COMPACTION_MAX_VERSIONS_PER_KEY,
+            compaction_io_throttle_bytes_per_sec: DEFAULT_COMPACTION_IO_THROTTLE_BYTES_PER_SEC,
+            compaction_min_hole_coverage_size: DEFAULT_COMPACTION_MIN_HOLE_COVERAGE_SIZE,
+            compaction_oversize_warn_multiplier: DEFAULT_COMPACTION_OVERSIZE_WARN_MULTIPLIER,
+            compaction_align_to_partitions: DEFAULT_COMPACTION_ALIGN_TO_PARTITIONS,
+            compaction_upload_concurrency: DEFAULT_COMPACTION_UPLOAD_CONCURRENCY,
+            gc_compaction_verify_every_nth_key: DEFAULT_GC_COMPACTION_VERIFY_EVERY_NTH_KEY,
+            compaction_layer_lock_timeout: None,
+            compaction_time_budget: None,
+            l0_compaction_delta_size_limit: None,
+            compact_level0_phase1_value_access: None,
             gc_horizon: DEFAULT_GC_HORIZON,
             gc_period: humantime::parse_duration(DEFAULT_GC_PERIOD)
                 .expect("cannot parse default gc period"),
@@ -643,6 +776,17 @@ fn humantime(d: Duration) -> String {
             compaction_target_size: value.compaction_target_size,
             compaction_period: value.compaction_period.map(humantime),
             compaction_threshold: value.compaction_threshold,
+            compaction_max_versions_per_key: value.compaction_max_versions_per_key,
+            compaction_io_throttle_bytes_per_sec: value.compaction_io_throttle_bytes_per_sec,
+            compaction_min_hole_coverage_size: value.compaction_min_hole_coverage_size,
+            compaction_oversize_warn_multiplier: value.compaction_oversize_warn_multiplier,
+            compaction_align_to_partitions: value.compaction_align_to_partitions,
+            compaction_upload_concurrency: value.compaction_upload_concurrency,
+            gc_compaction_verify_every_nth_key: value.gc_compaction_verify_every_nth_key,
+            compaction_layer_lock_timeout: value.compaction_layer_lock_timeout.map(humantime),
+            compaction_time_budget: value.compaction_time_budget.map(humantime),
+            l0_compaction_delta_size_limit: value.l0_compaction_delta_size_limit,
+            compact_level0_phase1_value_access: value.compact_level0_phase1_value_access,
             gc_horizon: value.gc_horizon,
             gc_period: value.gc_period.map(humantime),
             image_creation_threshold: value.image_creation_threshold,
@@ -3,26 +3,134 @@
 
 use crate::config::PageServerConf;
 use crate::context::RequestContext;
-use crate::page_cache;
+use crate::metrics::{EPHEMERAL_FILES, EPHEMERAL_FILES_BY_SIZE, EPHEMERAL_FILE_SIZE_BUCKETS};
+use crate::page_cache::{self, PAGE_SZ};
 use crate::tenant::block_io::{BlockCursor, BlockLease, BlockReader};
 use crate::virtual_file::{self, VirtualFile};
+use async_stream::try_stream;
 use camino::Utf8PathBuf;
+use futures::Stream;
+use once_cell::sync::Lazy;
 use pageserver_api::shard::TenantShardId;
 
+use std::collections::HashMap;
 use std::io;
-use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use utils::id::TimelineId;
+use utils::rate_limit::RateLimit;
 
 pub struct EphemeralFile {
     _tenant_shard_id: TenantShardId,
     _timeline_id: TimelineId,
 
     rw: page_caching::RW,
+
+    /// Registration in [`OPEN_EPHEMERAL_FILES`], removed when the file is dropped.
+    open: OpenEphemeralFile,
+
+    /// If set, [`Self::write_raw`] syncs the file whenever this many bytes have been
+    /// flushed to it since the last sync.
+    sync_interval_bytes: Option<u64>,
+    /// Offset up to which the file contents are known to be durable.
+    synced_up_to: u64,
 }
 
 mod page_caching;
 mod zero_padded_read_write;
 
+/// The ephemeral files that are currently open, and their sizes.
+pub(crate) struct OpenEphemeralFiles {
+    /// Size of each open file, by the disambiguator in its filename.
+    files: Mutex<HashMap<u64, Arc<AtomicU64>>>,
+    /// Whether to update the global metrics. Only [`OPEN_EPHEMERAL_FILES`] does.
+    publish_metrics: bool,
+}
+
+pub(crate) static OPEN_EPHEMERAL_FILES: Lazy<OpenEphemeralFiles> =
+    Lazy::new(|| OpenEphemeralFiles::new(true));
+
+impl OpenEphemeralFiles {
+    fn new(publish_metrics: bool) -> Self {
+        Self {
+            files: Mutex::new(HashMap::new()),
+            publish_metrics,
+        }
+    }
+
+    fn register(&'static self, id: u64) -> OpenEphemeralFile {
+        let len = Arc::new(AtomicU64::new(0));
+        self.files.lock().unwrap().insert(id, Arc::clone(&len));
+        if self.publish_metrics {
+            EPHEMERAL_FILES.inc();
+        }
+        OpenEphemeralFile {
+            registry: self,
+            id,
+            len,
+        }
+    }
+
+    /// A snapshot of the sizes of the open ephemeral files, in no particular order.
+    pub(crate) fn sizes(&self) -> Vec<u64> {
+        self.files
+            .lock()
+            .unwrap()
+            .values()
+            .map(|len| len.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// The number of open files no larger than each of [`EPHEMERAL_FILE_SIZE_BUCKETS`], and the
+    /// total number of open files.
+    fn size_distribution(&self) -> ([usize; EPHEMERAL_FILE_SIZE_BUCKETS.len()], usize) {
+        let sizes = self.sizes();
+        let buckets = EPHEMERAL_FILE_SIZE_BUCKETS
+            .map(|upper_bound| sizes.iter().filter(|size| **size <= upper_bound).count());
+        (buckets, sizes.len())
+    }
+}
+
+/// Publish the size distribution of the open ephemeral files. Called periodically for every
+/// tenant, but only takes effect once per interval.
+pub(crate) fn maybe_publish_metrics() {
+    static PUBLISHED: Lazy<Mutex<RateLimit>> =
+        Lazy::new(|| Mutex::new(RateLimit::new(Duration::from_secs(10))));
+    PUBLISHED.lock().unwrap().call(|| {
+        let (buckets, count) = OPEN_EPHEMERAL_FILES.size_distribution();
+        for (upper_bound, files) in EPHEMERAL_FILE_SIZE_BUCKETS.iter().zip(buckets) {
+            EPHEMERAL_FILES_BY_SIZE
+                .with_label_values(&[&upper_bound.to_string()])
+                .set(files as u64);
+        }
+        EPHEMERAL_FILES_BY_SIZE
+            .with_label_values(&["+Inf"])
+            .set(count as u64);
+    });
+}
+
+struct OpenEphemeralFile {
+    registry: &'static OpenEphemeralFiles,
+    id: u64,
+    len: Arc<AtomicU64>,
+}
+
+impl OpenEphemeralFile {
+    fn set_len(&self, len: u64) {
+        self.len.store(len, Ordering::Relaxed);
+    }
+}
+
+impl Drop for OpenEphemeralFile {
+    fn drop(&mut self) {
+        self.registry.files.lock().unwrap().remove(&self.id);
+        if self.registry.publish_metrics {
+            EPHEMERAL_FILES.dec();
+        }
+    }
+}
+
 impl EphemeralFile {
     pub async fn create(
         conf: &PageServerConf,
@@ -30,6 +138,25 @@ pub async fn create(
         timeline_id: TimelineId,
         gate_guard: utils::sync::gate::GateGuard,
         ctx: &RequestContext,
+    ) -> Result<EphemeralFile, io::Error> {
+        Self::create_in(
+            &OPEN_EPHEMERAL_FILES,
+            conf,
+            tenant_shard_id,
+            timeline_id,
+            gate_guard,
+            ctx,
+        )
+        .await
+    }
+
+    async fn create_in(
+        open_files: &'static OpenEphemeralFiles,
+        conf: &PageServerConf,
+        tenant_shard_id: TenantShardId,
+        timeline_id: TimelineId,
+        gate_guard: utils::sync::gate::GateGuard,
+        ctx: &RequestContext,
     ) -> Result<EphemeralFile, io::Error> {
         static NEXT_FILENAME: AtomicU64 = AtomicU64::new(1);
         let filename_disambiguator =
@@ -55,9 +182,26 @@ pub async fn create(
             _tenant_shard_id: tenant_shard_id,
             _timeline_id: timeline_id,
             rw: page_caching::RW::new(file, gate_guard),
+            open: open_files.register(filename_disambiguator),
+            sync_interval_bytes: conf.ephemeral_file_sync_interval_bytes,
+            synced_up_to: 0,
         })
     }
 
+    /// Make the file contents up to `pos` durable, as far as they have been flushed from
+    /// the in-memory tail buffer to the file.
+    ///
+    /// Returns the offset up to which the contents are durable, which may be below `pos`
+    /// if the tail hasn't been flushed yet.
+    pub(crate) async fn sync_up_to(&mut self, pos: u64) -> Result<u64, io::Error> {
+        let flushed = self.rw.bytes_flushed();
+        if self.synced_up_to < pos.min(flushed) {
+            self.rw.sync_data().await?;
+            self.synced_up_to = flushed;
+        }
+        Ok(self.synced_up_to.min(pos))
+    }
+
     pub(crate) fn len(&self) -> u64 {
         self.rw.bytes_written()
     }
@@ -67,10 +211,36 @@ pub(crate) fn page_cache_file_id(&self) -> page_cache::FileId {
     }
 
     /// See [`self::page_caching::RW::load_to_vec`].
+    #[cfg(test)]
     pub(crate) async fn load_to_vec(&self, ctx: &RequestContext) -> Result<Vec<u8>, io::Error> {
         self.rw.load_to_vec(ctx).await
     }
 
+    /// Read the file contents in order, in windows of `window_size` bytes rounded up to
+    /// [`PAGE_SZ`], without loading the whole file into memory at once.
+    ///
+    /// Each item is the offset of the window and its contents. The last window is
+    /// zero-padded to [`PAGE_SZ`]. A window can be read through
+    /// `BlockCursor::new(BlockReaderRef::Slice(&window))`, using block numbers relative to the
+    /// window's offset.
+    pub(crate) fn stream_windows<'a>(
+        &'a self,
+        window_size: usize,
+        ctx: &'a RequestContext,
+    ) -> impl Stream<Item = Result<(u64, Vec<u8>), io::Error>> + 'a {
+        let window_size = u64::try_from(window_size.max(1).next_multiple_of(PAGE_SZ)).unwrap();
+        let end = self.len().next_multiple_of(PAGE_SZ as u64);
+        try_stream! {
+            let mut offset = 0;
+            while offset < end {
+                let window_end = (offset + window_size).min(end);
+                let window = self.rw.load_range_to_vec(offset..window_end, ctx).await?;
+                yield (offset, window);
+                offset = window_end;
+            }
+        }
+    }
+
     pub(crate) async fn read_blk(
         &self,
         blknum: u32,
@@ -79,6 +249,45 @@ pub(crate) async fn read_blk(
         self.rw.read_blk(blknum, ctx).await
     }
 
+    /// Test helper: read the blob at `offset` like [`BlockCursor::read_blob`] does, but directly
+    /// from the file and the in-memory tail buffer, bypassing the [`crate::page_cache`]. Tests use
+    /// it to check that the reads through the page cache return what was written.
+    #[cfg(test)]
+    pub(crate) async fn read_blob_bypassing_page_cache(
+        &self,
+        offset: u64,
+        ctx: &RequestContext,
+    ) -> Result<Vec<u8>, io::Error> {
+        let first_len_byte = self.read_bypassing_page_cache(offset, 1, ctx).await?[0];
+        let (header_len, len) = if first_len_byte < 0x80 {
+            (1, first_len_byte as usize)
+        } else {
+            let mut len_buf: [u8; 4] = self
+                .read_bypassing_page_cache(offset, 4, ctx)
+                .await?
+                .try_into()
+                .unwrap();
+            len_buf[0] &= 0x7f;
+            (4, u32::from_be_bytes(len_buf) as usize)
+        };
+        self.read_bypassing_page_cache(offset + header_len, len, ctx)
+            .await
+    }
+
+    #[cfg(test)]
+    async fn read_bypassing_page_cache(
+        &self,
+        offset: u64,
+        len: usize,
+        ctx: &RequestContext,
+    ) -> Result<Vec<u8>, io::Error> {
+        let start = offset - offset % PAGE_SZ as u64;
+        let end = (offset + len as u64).next_multiple_of(PAGE_SZ as u64);
+        let window = self.rw.load_range_to_vec(start..end, ctx).await?;
+        let off = usize::try_from(offset - start).unwrap();
+        Ok(window[off..off + len].to_vec())
+    }
+
     #[cfg(test)]
     // This is a test helper: outside of tests, we are always written to via a pre-serialized batch.
     pub(crate) async fn write_blob(
@@ -100,6 +309,7 @@ pub(crate) async fn write_blob(
 
         // Write the payload
         self.rw.write_all_borrowed(srcbuf, ctx).await?;
+        self.open.set_len(self.rw.bytes_written());
 
         Ok(pos)
     }
@@ -115,18 +325,30 @@ pub(crate) async fn write_raw(
 
         // Write the payload
         self.rw.write_all_borrowed(srcbuf, ctx).await?;
+        self.open.set_len(self.rw.bytes_written());
+
+        if let Some(sync_interval_bytes) = self.sync_interval_bytes {
+            if self.rw.bytes_flushed() - self.synced_up_to >= sync_interval_bytes {
+                self.sync_up_to(self.rw.bytes_written()).await?;
+            }
+        }
 
         Ok(pos)
     }
 }
 
 /// Does the given filename look like an ephemeral file?
+///
+/// The suffix must be a disambiguator exactly as [`EphemeralFile::create`] formats it:
+/// a decimal u64 without sign or leading zeros.
 pub fn is_ephemeral_file(filename: &str) -> bool {
-    if let Some(rest) = filename.strip_prefix("ephemeral-") {
-        rest.parse::<u32>().is_ok()
-    } else {
-        false
+    let Some(rest) = filename.strip_prefix("ephemeral-") else {
+        return false;
+    };
+    if !rest.bytes().all(|b| b.is_ascii_digit()) || (rest.len() > 1 && rest.starts_with('0')) {
+        return false;
     }
+    rest.parse::<u64>().is_ok()
 }
 
 impl BlockReader for EphemeralFile {
@@ -141,6 +363,7 @@ mod tests {
     use crate::context::DownloadBehavior;
     use crate::task_mgr::TaskKind;
     use crate::tenant::block_io::BlockReaderRef;
+    use futures::TryStreamExt;
     use rand::{thread_rng, RngCore};
     use std::fs;
     use std::str::FromStr;
@@ -235,6 +458,200 @@ async fn test_ephemeral_blobs() -> Result<(), io::Error> {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_ephemeral_read_paths_agree() -> Result<(), io::Error> {
+        let (conf, tenant_id, timeline_id, ctx) = harness("ephemeral_read_paths_agree")?;
+
+        let gate = utils::sync::gate::Gate::default();
+
+        let mut file =
+            EphemeralFile::create(conf, tenant_id, timeline_id, gate.enter().unwrap(), &ctx)
+                .await?;
+
+        // Small blobs with 1-byte length headers, larger ones with 4-byte headers, and some that
+        // span several pages. Enough of them that most are flushed out of the in-memory tail.
+        let mut blobs = Vec::new();
+        for i in 0..2000 {
+            let data = match i % 3 {
+                0 => format!("blob{}", i).into_bytes(),
+                1 => format!("blob{}", i).as_bytes().repeat(50),
+                _ => {
+                    let mut data = vec![0; 10000 + i];
+                    thread_rng().fill_bytes(&mut data);
+                    data
+                }
+            };
+            let pos = file.write_blob(&data, &ctx).await?;
+
+            // A blob is readable at the returned offset right after writing it
+            let direct = file.read_blob_bypassing_page_cache(pos, &ctx).await?;
+            assert_eq!(direct, data, "blob {i} right after writing it");
+
+            blobs.push((pos, data));
+        }
+        assert!(file.rw.bytes_flushed() > 0);
+
+        let cursor = file.block_cursor();
+        for (i, (pos, expected)) in blobs.iter().enumerate() {
+            let cached = cursor.read_blob(*pos, &ctx).await?;
+            let direct = file.read_blob_bypassing_page_cache(*pos, &ctx).await?;
+            assert_eq!(cached, direct, "blob {i} at {pos}");
+            assert_eq!(&direct, expected, "blob {i} at {pos}");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_sync() -> Result<(), io::Error> {
+        let (conf, tenant_id, timeline_id, ctx) = harness("ephemeral_sync")?;
+        let conf: &'static PageServerConf = Box::leak(Box::new(PageServerConf {
+            ephemeral_file_sync_interval_bytes: Some(64 * 1024),
+            ..conf.clone()
+        }));
+
+        let gate = utils::sync::gate::Gate::default();
+
+        let mut file =
+            EphemeralFile::create(conf, tenant_id, timeline_id, gate.enter().unwrap(), &ctx)
+                .await?;
+
+        let mut blobs = Vec::new();
+        for i in 0..1000 {
+            let data = format!("blob{}", i).as_bytes().repeat(100);
+            let pos = file.write_blob(&data, &ctx).await?;
+            blobs.push((pos, data));
+        }
+        // Going through write_raw syncs periodically
+        let data = b"raw".repeat(100);
+        let pos = file.write_raw(&data, &ctx).await?;
+        assert!(file.synced_up_to > 0);
+
+        let len = file.len();
+        let synced = file.sync_up_to(len).await?;
+        assert!(synced <= len);
+        assert_eq!(synced, file.rw.bytes_flushed().min(len));
+        assert_eq!(file.len(), len);
+        assert_eq!(file.sync_up_to(len).await?, synced);
+
+        let cursor = file.block_cursor();
+        for (pos, expected) in blobs {
+            let actual = cursor.read_blob(pos, &ctx).await?;
+            assert_eq!(actual, expected);
+        }
+        let mut raw = Vec::new();
+        for blknum in 0..(len.div_ceil(PAGE_SZ as u64) as u32) {
+            raw.extend_from_slice(file.read_blk(blknum, &ctx).await?.as_slice());
+        }
+        assert_eq!(&raw[pos as usize..len as usize], &data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_stream_windows() -> Result<(), io::Error> {
+        let (conf, tenant_id, timeline_id, ctx) = harness("ephemeral_stream_windows")?;
+
+        let gate = utils::sync::gate::Gate::default();
+
+        let mut file =
+            EphemeralFile::create(conf, tenant_id, timeline_id, gate.enter().unwrap(), &ctx)
+                .await?;
+
+        // A few megabytes, ending in a partial block held in the in-memory tail
+        let mut data = vec![0; 3 * 1024 * 1024 + 1234];
+        thread_rng().fill_bytes(&mut data);
+        for chunk in data.chunks(10000) {
+            file.write_raw(chunk, &ctx).await?;
+        }
+
+        let expected = file.load_to_vec(&ctx).await?;
+        for window_size in [PAGE_SZ, 1024 * 1024, 100_000, 16 * 1024 * 1024] {
+            let windows: Vec<(u64, Vec<u8>)> =
+                file.stream_windows(window_size, &ctx).try_collect().await?;
+            let mut streamed = Vec::new();
+            for (offset, window) in windows {
+                assert_eq!(offset, streamed.len() as u64);
+                assert_eq!(window.len() % PAGE_SZ, 0);
+                streamed.extend_from_slice(&window);
+            }
+            assert_eq!(streamed, expected, "window size {window_size}");
+        }
+        assert_eq!(&expected[..data.len()], &data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_ephemeral_files() -> Result<(), io::Error> {
+        let (conf, tenant_id, timeline_id, ctx) = harness("open_ephemeral_files")?;
+        // A registry of our own, the global one counts the files of the other tests too
+        let open_files: &'static OpenEphemeralFiles =
+            Box::leak(Box::new(OpenEphemeralFiles::new(false)));
+
+        let gate = utils::sync::gate::Gate::default();
+
+        let mut files = Vec::new();
+        for i in 0..5 {
+            let mut file = EphemeralFile::create_in(
+                open_files,
+                conf,
+                tenant_id,
+                timeline_id,
+                gate.enter().unwrap(),
+                &ctx,
+            )
+            .await?;
+            file.write_raw(&vec![0; 1000 * i], &ctx).await?;
+            files.push(file);
+            assert_eq!(open_files.sizes().len(), i + 1);
+        }
+
+        let mut sizes = open_files.sizes();
+        sizes.sort();
+        assert_eq!(sizes, vec![0, 1000, 2000, 3000, 4000]);
+        let (buckets, count) = open_files.size_distribution();
+        assert_eq!(count, 5);
+        assert_eq!(buckets[0], 5);
+        assert!(buckets.iter().all(|files| *files == 5));
+
+        // A file spanning several pages moves up the buckets
+        files[0].write_raw(&vec![0; 10_000], &ctx).await?;
+        let (buckets, count) = open_files.size_distribution();
+        assert_eq!(count, 5);
+        assert_eq!(buckets[0], 4);
+        assert!(buckets[1..].iter().all(|files| *files == 5));
+
+        files.truncate(2);
+        assert_eq!(open_files.sizes().len(), 2);
+        let mut sizes = open_files.sizes();
+        sizes.sort();
+        assert_eq!(sizes, vec![1000, 10_000]);
+
+        drop(files);
+        assert_eq!(open_files.sizes().len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_ephemeral_file() {
+        assert!(is_ephemeral_file("ephemeral-1"));
+        assert!(is_ephemeral_file("ephemeral-1234"));
+        // Beyond u32::MAX
+        assert!(is_ephemeral_file("ephemeral-4294967296"));
+        assert!(is_ephemeral_file(&format!("ephemeral-{}", u64::MAX)));
+
+        assert!(!is_ephemeral_file("ephemeral-"));
+        assert!(!is_ephemeral_file("ephemeral-01"));
+        assert!(!is_ephemeral_file("ephemeral-+1"));
+        assert!(!is_ephemeral_file("ephemeral--1"));
+        assert!(!is_ephemeral_file("ephemeral-1.tmp"));
+        assert!(!is_ephemeral_file("ephemeral-1 "));
+        assert!(!is_ephemeral_file("ephemeral-18446744073709551616"));
+        assert!(!is_ephemeral_file("xephemeral-1"));
+    }
+
     #[tokio::test]
     async fn ephemeral_file_holds_gate_open() {
         const FOREVER: std::time::Duration = std::time::Duration::from_secs(5);
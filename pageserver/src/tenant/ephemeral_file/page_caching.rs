@@ -51,10 +51,22 @@ pub(crate) fn bytes_written(&self) -> u64 {
         self.rw.bytes_written()
     }
 
+    /// Number of bytes that have been written out to the underlying [`VirtualFile`].
+    /// The remainder is still in the in-memory tail buffer.
+    pub(crate) fn bytes_flushed(&self) -> u64 {
+        self.rw.as_writer().bytes_written()
+    }
+
+    /// `fdatasync` the bytes flushed to the underlying [`VirtualFile`] so far.
+    pub(crate) async fn sync_data(&self) -> Result<(), io::Error> {
+        self.rw.as_writer().as_inner().sync_data().await
+    }
+
     /// Load all blocks that can be read via [`Self::read_blk`] into a contiguous memory buffer.
     ///
     /// This includes the blocks that aren't yet flushed to disk by the internal buffered writer.
     /// The last block is zero-padded to [`PAGE_SZ`], so, the returned buffer is always a multiple of [`PAGE_SZ`].
+    #[cfg(test)]
     pub(super) async fn load_to_vec(&self, ctx: &RequestContext) -> Result<Vec<u8>, io::Error> {
         // round up to the next PAGE_SZ multiple, required by blob_io
         let size = {
@@ -65,26 +77,45 @@ pub(super) async fn load_to_vec(&self, ctx: &RequestContext) -> Result<Vec<u8>,
                 s.checked_add(PAGE_SZ - (s % PAGE_SZ)).unwrap()
             }
         };
-        let vec = Vec::with_capacity(size);
+        let vec = self
+            .load_range_to_vec(0..u64::try_from(size).unwrap(), ctx)
+            .await?;
+        assert_eq!(vec.len(), size);
+        assert_eq!(vec.len() % PAGE_SZ, 0);
+        Ok(vec)
+    }
 
-        // read from disk what we've already flushed
+    /// Load a [`PAGE_SZ`]-aligned range of the blocks that can be read via [`Self::read_blk`]
+    /// into a contiguous memory buffer. The last block is zero-padded to [`PAGE_SZ`].
+    pub(super) async fn load_range_to_vec(
+        &self,
+        range: std::ops::Range<u64>,
+        ctx: &RequestContext,
+    ) -> Result<Vec<u8>, io::Error> {
+        assert_eq!(range.start % PAGE_SZ as u64, 0);
         let file_size_tracking_writer = self.rw.as_writer();
-        let flushed_range = 0..usize::try_from(file_size_tracking_writer.bytes_written()).unwrap();
-        let mut vec = file_size_tracking_writer
-            .as_inner()
-            .read_exact_at(
-                vec.slice(0..(flushed_range.end - flushed_range.start)),
-                u64::try_from(flushed_range.start).unwrap(),
-                ctx,
-            )
-            .await?
-            .into_inner();
+        let flushed = file_size_tracking_writer.bytes_written();
+        let buffered = self.rw.get_tail_zero_padded();
+        assert!(range.end <= flushed + u64::try_from(buffered.len()).unwrap());
+
+        let mut vec = Vec::with_capacity(usize::try_from(range.end - range.start).unwrap());
+
+        // read from disk what we've already flushed
+        if range.start < flushed {
+            let disk_len = usize::try_from(range.end.min(flushed) - range.start).unwrap();
+            vec = file_size_tracking_writer
+                .as_inner()
+                .read_exact_at(vec.slice(0..disk_len), range.start, ctx)
+                .await?
+                .into_inner();
+        }
 
         // copy from in-memory buffer what we haven't flushed yet but would return when accessed via read_blk
-        let buffered = self.rw.get_tail_zero_padded();
-        vec.extend_from_slice(buffered);
-        assert_eq!(vec.len(), size);
-        assert_eq!(vec.len() % PAGE_SZ, 0);
+        if range.end > flushed {
+            let tail_start = usize::try_from(range.start.saturating_sub(flushed)).unwrap();
+            let tail_end = usize::try_from(range.end - flushed).unwrap();
+            vec.extend_from_slice(&buffered[tail_start..tail_end]);
+        }
         Ok(vec)
     }
 
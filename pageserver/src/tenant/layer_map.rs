@@ -880,7 +880,27 @@ pub async fn dump(&self, verbose: bool, ctx: &RequestContext) -> Result<()> {
     /// looking up and updating the Layer objects for these layer descriptors.
     pub fn get_visibility(
         &self,
-        mut read_points: Vec<Lsn>,
+        read_points: Vec<Lsn>,
+    ) -> (
+        Vec<(Arc<PersistentLayerDesc>, LayerVisibilityHint)>,
+        KeySpace,
+    ) {
+        self.get_visibility_with_coverage(
+            read_points
+                .into_iter()
+                .map(|lsn| (lsn, KeySpace::default()))
+                .collect(),
+        )
+    }
+
+    /// Like [`Self::get_visibility`], but each read point also comes with the keyspace that is
+    /// never read below it: for a branch point, this is the keyspace that the child timeline
+    /// covers with its own image layers, as returned by its own call to this function.
+    ///
+    /// The returned keyspace is the one covered below the lowest read point.
+    pub fn get_visibility_with_coverage(
+        &self,
+        mut read_points: Vec<(Lsn, KeySpace)>,
     ) -> (
         Vec<(Arc<PersistentLayerDesc>, LayerVisibilityHint)>,
         KeySpace,
@@ -914,8 +934,14 @@ fn cover(&mut self, insert: Range<Key>) -> bool {
                 self.inner.ranges_insert(range_incl)
             }
 
-            fn reset(&mut self) {
-                self.inner = Default::default();
+            /// Only keep the keys that are also covered by the given keyspace.
+            fn intersect(&mut self, keyspace: &KeySpace) {
+                let other: RangeSetBlaze<i128> = keyspace
+                    .ranges
+                    .iter()
+                    .map(|range| range.start.to_i128()..=range.end.to_i128() - 1)
+                    .collect();
+                self.inner = &self.inner & &other;
             }
 
             fn to_keyspace(&self) -> KeySpace {
@@ -933,13 +959,13 @@ fn to_keyspace(&self) -> KeySpace {
         }
 
         // The 'shadow' will be updated as we sweep through the layers: an image layer subtracts from the shadow,
-        // and a ReadPoint
-        read_points.sort_by_key(|rp| rp.0);
+        // and a ReadPoint only keeps the keys that are not read from there.
+        read_points.sort_by_key(|(lsn, _)| lsn.0);
         let mut shadow = KeyShadow::new();
 
         // We will interleave all our read points and layers into a sorted collection
         enum Item {
-            ReadPoint { lsn: Lsn },
+            ReadPoint { lsn: Lsn, covered: KeySpace },
             Layer(Arc<PersistentLayerDesc>),
         }
 
@@ -948,7 +974,7 @@ enum Item {
         items.extend(
             read_points
                 .into_iter()
-                .map(|rp| Item::ReadPoint { lsn: rp }),
+                .map(|(lsn, covered)| Item::ReadPoint { lsn, covered }),
         );
 
         // Ordering: we want to iterate like this:
@@ -964,7 +990,7 @@ enum Item {
                         (layer.image_layer_lsn(), 1)
                     }
                 }
-                Item::ReadPoint { lsn } => (*lsn, 2),
+                Item::ReadPoint { lsn, .. } => (*lsn, 2),
             })
         });
 
@@ -974,7 +1000,7 @@ enum Item {
 
         for item in items {
             let (reached_lsn, is_readpoint) = match &item {
-                Item::ReadPoint { lsn } => (lsn, true),
+                Item::ReadPoint { lsn, .. } => (lsn, true),
                 Item::Layer(layer) => (&layer.lsn_range.start, false),
             };
             maybe_covered_deltas.retain(|d| {
@@ -994,10 +1020,10 @@ enum Item {
             });
 
             match item {
-                Item::ReadPoint { lsn: _lsn } => {
-                    // TODO: propagate the child timeline's shadow from their own run of this function, so that we don't have
-                    // to assume that the whole key range is visible at the branch point.
-                    shadow.reset();
+                Item::ReadPoint { covered, .. } => {
+                    // Reads at this point may reach any key, except for those that the child timeline
+                    // covers with its own image layers.
+                    shadow.intersect(&covered);
                 }
                 Item::Layer(layer) => {
                     let visibility = if layer.is_delta() {
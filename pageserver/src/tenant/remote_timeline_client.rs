@@ -2160,6 +2160,16 @@ pub(crate) fn latest_uploaded_index_part(&self) -> &IndexPart {
             }
         }
     }
+
+    #[cfg(test)]
+    pub(crate) fn num_inprogress_layer_uploads(&self) -> usize {
+        match &*self.inner {
+            UploadQueue::Initialized(x) => x.num_inprogress_layer_uploads,
+            UploadQueue::Uninitialized | UploadQueue::Stopped(_) => {
+                unreachable!("checked before constructing")
+            }
+        }
+    }
 }
 
 pub fn remote_tenant_path(tenant_shard_id: &TenantShardId) -> RemotePath {
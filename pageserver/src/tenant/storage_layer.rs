@@ -244,6 +244,46 @@ pub(crate) fn update_key(
         }
     }
 
+    /// Mark a key as done because a tombstone was found for it.
+    ///
+    /// Keys which have not collected any values yet are omitted from the results of
+    /// the read, i.e. they read back as absent.
+    pub(crate) fn on_key_deleted(&mut self, key: &Key) {
+        match self.keys.get_mut(key) {
+            Some(Ok(state)) => {
+                if state.situation == ValueReconstructSituation::Continue {
+                    state.situation = ValueReconstructSituation::Complete;
+                    self.keys_done.add_key(*key);
+                }
+            }
+            Some(Err(_)) => {}
+            None => self.keys_done.add_key(*key),
+        }
+    }
+
+    /// Returns true if nothing more needs to be collected for this key.
+    pub(crate) fn is_key_done(&self, key: &Key) -> bool {
+        match self.keys.get(key) {
+            Some(Ok(state)) => state.situation == ValueReconstructSituation::Complete,
+            Some(Err(_)) => true,
+            None => false,
+        }
+    }
+
+    /// Returns the number of keys in `keyspace` which still need more values to be reconstructed.
+    ///
+    /// Like [`Self::on_lsn_advanced`], this iterates over the keys for which we've already
+    /// collected some reconstruct data. Keys deleted before any value was collected for them
+    /// are still counted as outstanding, so this may overestimate, but never underestimates.
+    pub(crate) fn num_outstanding_keys(&self, keyspace: &KeySpace) -> u64 {
+        let done = self
+            .keys
+            .keys()
+            .filter(|key| keyspace.contains(key) && self.is_key_done(key))
+            .count();
+        keyspace.total_raw_size().saturating_sub(done) as u64
+    }
+
     /// Returns the Lsn at which this key is cached if one exists.
     /// The read path should go no further than this Lsn for the given key.
     pub(crate) fn get_cached_lsn(&self, key: &Key) -> Option<Lsn> {
@@ -405,6 +445,15 @@ fn eq(&self, other: &Self) -> bool {
 
 impl Eq for ReadDesc {}
 
+/// Whether [`ReadableLayer::get_values_reconstruct_data`] could read the layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LayerReadOutcome {
+    Read,
+    /// The in-memory layer was written to disk, and its ephemeral file reclaimed, since the read
+    /// was planned. Nothing was read: the layer map has the layers to read instead.
+    Reclaimed,
+}
+
 impl ReadableLayer {
     pub(crate) fn id(&self) -> LayerId {
         match self {
@@ -419,12 +468,13 @@ pub(crate) async fn get_values_reconstruct_data(
         lsn_range: Range<Lsn>,
         reconstruct_state: &mut ValuesReconstructState,
         ctx: &RequestContext,
-    ) -> Result<(), GetVectoredError> {
+    ) -> Result<LayerReadOutcome, GetVectoredError> {
         match self {
             ReadableLayer::PersistentLayer(layer) => {
                 layer
                     .get_values_reconstruct_data(keyspace, lsn_range, reconstruct_state, ctx)
-                    .await
+                    .await?;
+                Ok(LayerReadOutcome::Read)
             }
             ReadableLayer::InMemoryLayer(layer) => {
                 layer
@@ -34,7 +34,7 @@
 use crate::tenant::blob_io::BlobWriter;
 use crate::tenant::block_io::{BlockBuf, BlockCursor, BlockLease, BlockReader, FileBlockReader};
 use crate::tenant::disk_btree::{
-    DiskBtreeBuilder, DiskBtreeIterator, DiskBtreeReader, VisitDirection,
+    self, DiskBtreeBuilder, DiskBtreeIterator, DiskBtreeReader, VisitDirection,
 };
 use crate::tenant::timeline::GetVectoredError;
 use crate::tenant::vectored_blob_io::{
@@ -405,6 +405,7 @@ async fn new(
         tenant_shard_id: TenantShardId,
         key_start: Key,
         lsn_range: Range<Lsn>,
+        expected_entries: Option<usize>,
         ctx: &RequestContext,
     ) -> anyhow::Result<Self> {
         // Create the file initially with a temporary filename. We don't know
@@ -421,8 +422,12 @@ async fn new(
         file.seek(SeekFrom::Start(PAGE_SZ as u64)).await?;
         let blob_writer = BlobWriter::new(file, PAGE_SZ as u64);
 
-        // Initialize the b-tree index builder
-        let block_buf = BlockBuf::new();
+        // Initialize the b-tree index builder. If we know how many entries are coming, make
+        // room for the index blocks upfront.
+        let block_buf = match expected_entries {
+            Some(entries) => BlockBuf::with_capacity(Self::estimated_index_blocks(entries)),
+            None => BlockBuf::new(),
+        };
         let tree_builder = DiskBtreeBuilder::new(block_buf);
 
         Ok(Self {
@@ -437,6 +442,12 @@ async fn new(
         })
     }
 
+    /// A rough estimate of the number of index blocks for `entries` key-lsns: the leaf blocks,
+    /// ignoring the key prefix compression, plus one block for the upper levels.
+    fn estimated_index_blocks(entries: usize) -> usize {
+        (entries * (DELTA_KEY_SIZE + disk_btree::VALUE_SZ)).div_ceil(PAGE_SZ) + 1
+    }
+
     ///
     /// Append a key-value pair to the file.
     ///
@@ -627,12 +638,16 @@ impl DeltaLayerWriter {
     ///
     /// Start building a new delta layer.
     ///
+    /// `expected_entries` is the number of key-lsns that will be written, if known. It is only
+    /// used to size the in-memory index buffer upfront.
+    ///
     pub async fn new(
         conf: &'static PageServerConf,
         timeline_id: TimelineId,
         tenant_shard_id: TenantShardId,
         key_start: Key,
         lsn_range: Range<Lsn>,
+        expected_entries: Option<usize>,
         ctx: &RequestContext,
     ) -> anyhow::Result<Self> {
         Ok(Self {
@@ -643,6 +658,7 @@ pub async fn new(
                     tenant_shard_id,
                     key_start,
                     lsn_range,
+                    expected_entries,
                     ctx,
                 )
                 .await?,
@@ -1870,6 +1886,57 @@ fn pick_random_keyspace(rng: &mut StdRng, key_range: &Range<Key>) -> KeySpace {
         keyspace
     }
 
+    #[tokio::test]
+    async fn test_delta_layer_expected_entries_do_not_change_contents() -> anyhow::Result<()> {
+        let harness =
+            TenantHarness::create("test_delta_layer_expected_entries_do_not_change_contents")
+                .await?;
+        let (tenant, ctx) = harness.load().await;
+        let _timeline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x08), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+
+        let base_key = Key::from_hex("000000067F00008000000000000000000000").unwrap();
+        let mut entries = Vec::new();
+        for blknum in 0..1000 {
+            let key = base_key.add(blknum);
+            for lsn in [0x10, 0x20, 0x30] {
+                let value = Value::Image(Bytes::from(format!("{blknum} at {lsn:#x}")));
+                entries.push((key, Lsn(lsn), value));
+            }
+        }
+
+        let mut contents = Vec::new();
+        for expected_entries in [None, Some(0), Some(entries.len()), Some(10 * entries.len())] {
+            let mut writer = DeltaLayerWriter::new(
+                harness.conf,
+                TIMELINE_ID,
+                harness.tenant_shard_id,
+                base_key,
+                Lsn(0x10)..Lsn(0x40),
+                expected_entries,
+                &ctx,
+            )
+            .await?;
+            for (key, lsn, value) in &entries {
+                writer.put_value(*key, *lsn, value.clone(), &ctx).await?;
+            }
+            let (_, path) = writer.finish(base_key.add(1000), &ctx).await?;
+            contents.push((expected_entries, std::fs::read(&path)?));
+            std::fs::remove_file(&path)?;
+        }
+
+        let (_, expected) = &contents[0];
+        for (expected_entries, actual) in &contents[1..] {
+            assert!(
+                actual == expected,
+                "layer written with {expected_entries:?} expected entries differs"
+            );
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_delta_layer_vectored_read_end_to_end() -> anyhow::Result<()> {
         let harness = TenantHarness::create("test_delta_layer_oversized_vectored_read").await?;
@@ -1895,6 +1962,7 @@ async fn test_delta_layer_vectored_read_end_to_end() -> anyhow::Result<()> {
             harness.tenant_shard_id,
             entries_meta.key_range.start,
             entries_meta.lsn_range.clone(),
+            None,
             &ctx,
         )
         .await?;
@@ -2086,6 +2154,7 @@ async fn copy_delta_prefix_smoke() {
                 tenant.tenant_shard_id,
                 Key::MIN,
                 Lsn(0x11)..truncate_at,
+                None,
                 ctx,
             )
             .await
@@ -2219,6 +2288,7 @@ pub(crate) async fn produce_delta_layer(
             tenant.tenant_shard_id,
             *key_start,
             (*lsn_min)..lsn_end,
+            None,
             ctx,
         )
         .await?;
@@ -50,8 +50,10 @@
 use pageserver_api::shard::{ShardIdentity, TenantShardId};
 use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io::SeekFrom;
 use std::ops::Range;
 use std::os::unix::prelude::FileExt;
@@ -675,6 +677,52 @@ pub(crate) fn iter<'a>(&'a self, ctx: &'a RequestContext) -> ImageLayerIterator<
     }
 }
 
+/// Images smaller than this are always written out: a reference to them would not save much.
+const MIN_DEDUP_IMAGE_SIZE: usize = 64;
+
+/// How many bytes of images an [`ImageDedup`] keeps around for comparison. Images written after
+/// that are not deduplicated against.
+const MAX_DEDUP_INDEX_BYTES: usize = 32 * 1024 * 1024;
+
+/// Remembers the images written to an image layer, so that identical images are stored once
+/// and referenced from the other keys.
+#[derive(Default)]
+struct ImageDedup {
+    /// The images written so far with their offsets, by hash. Images with colliding hashes
+    /// share an entry and are told apart by comparing their contents.
+    written: HashMap<u64, Vec<(u64, Bytes)>>,
+    written_bytes: usize,
+}
+
+impl ImageDedup {
+    fn hash(img: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        img.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns the offset of an identical image written before.
+    fn find(&self, hash: u64, img: &[u8]) -> Option<u64> {
+        self.written
+            .get(&hash)?
+            .iter()
+            .find(|(_, written)| written[..] == *img)
+            .map(|(off, _)| *off)
+    }
+
+    fn insert(&mut self, hash: u64, off: u64, img: &[u8]) {
+        if self.written_bytes + img.len() > MAX_DEDUP_INDEX_BYTES {
+            return;
+        }
+        self.written_bytes += img.len();
+        // Copy the image, so that we don't keep the caller's (possibly much larger) buffer alive.
+        self.written
+            .entry(hash)
+            .or_default()
+            .push((off, Bytes::copy_from_slice(img)));
+    }
+}
+
 /// A builder object for constructing a new image layer.
 ///
 /// Usage:
@@ -708,6 +756,12 @@ struct ImageLayerWriterInner {
     // Number of keys in the layer.
     num_keys: usize,
 
+    // Number of keys whose image references an identical one written before
+    num_deduplicated_keys: usize,
+
+    /// Set if `image_layer_dedup` is enabled.
+    dedup: Option<ImageDedup>,
+
     blob_writer: BlobWriter<false>,
     tree: DiskBtreeBuilder<BlockBuf, KEY_SIZE>,
 
@@ -774,6 +828,8 @@ async fn new(
             uncompressed_bytes_eligible: 0,
             uncompressed_bytes_chosen: 0,
             num_keys: 0,
+            num_deduplicated_keys: 0,
+            dedup: conf.image_layer_dedup.then(ImageDedup::default),
             last_written_key: Key::MIN,
         };
 
@@ -796,12 +852,29 @@ async fn put_image(
         let uncompressed_len = img.len() as u64;
         self.uncompressed_bytes += uncompressed_len;
         self.num_keys += 1;
-        let (_img, res) = self
+
+        let dedup_hash = match &self.dedup {
+            Some(dedup) if img.len() >= MIN_DEDUP_IMAGE_SIZE => {
+                let hash = ImageDedup::hash(&img);
+                if let Some(target) = dedup.find(hash, &img) {
+                    let off = self.blob_writer.write_blob_ref(target, ctx).await?;
+                    self.num_deduplicated_keys += 1;
+                    return self.append_index_entry(key, off);
+                }
+                Some(hash)
+            }
+            _ => None,
+        };
+
+        let (img, res) = self
             .blob_writer
             .write_blob_maybe_compressed(img.slice_len(), ctx, compression)
             .await;
         // TODO: re-use the buffer for `img` further upstack
         let (off, compression_info) = res?;
+        if let (Some(dedup), Some(hash)) = (&mut self.dedup, dedup_hash) {
+            dedup.insert(hash, off, &img);
+        }
         if compression_info.compressed_size.is_some() {
             // The image has been considered for compression at least
             self.uncompressed_bytes_eligible += uncompressed_len;
@@ -811,6 +884,10 @@ async fn put_image(
             self.uncompressed_bytes_chosen += uncompressed_len;
         }
 
+        self.append_index_entry(key, off)
+    }
+
+    fn append_index_entry(&mut self, key: Key, off: u64) -> anyhow::Result<()> {
         let mut keybuf: [u8; KEY_SIZE] = [0u8; KEY_SIZE];
         key.write_to_byte_slice(&mut keybuf);
         self.tree.append(&keybuf, off)?;
@@ -843,6 +920,13 @@ async fn finish(
         crate::metrics::COMPRESSION_IMAGE_INPUT_BYTES_CHOSEN.inc_by(self.uncompressed_bytes_chosen);
         crate::metrics::COMPRESSION_IMAGE_OUTPUT_BYTES.inc_by(compressed_size);
 
+        if self.num_deduplicated_keys > 0 {
+            debug!(
+                "deduplicated the images of {} out of {} keys",
+                self.num_deduplicated_keys, self.num_keys
+            );
+        }
+
         let mut file = self.blob_writer.into_inner();
 
         // Write out the index
@@ -997,9 +1081,8 @@ pub(crate) async fn finish(
         self.inner.take().unwrap().finish(timeline, ctx, None).await
     }
 
-    #[cfg(test)]
     /// Finish writing the image layer with an end key, used in [`super::split_writer::SplitImageLayerWriter`]. The end key determines the end of the image layer's covered range and is exclusive.
-    pub(super) async fn finish_with_end_key(
+    pub(crate) async fn finish_with_end_key(
         mut self,
         timeline: &Arc<Timeline>,
         end_key: Key,
@@ -1105,6 +1188,7 @@ mod test {
         key::Key,
         shard::{ShardCount, ShardIdentity, ShardNumber, ShardStripeSize},
     };
+    use rand::Rng;
     use utils::{
         generation::Generation,
         id::{TenantId, TimelineId},
@@ -1112,7 +1196,9 @@ mod test {
     };
 
     use crate::{
+        config::PageServerConf,
         context::RequestContext,
+        page_cache::PAGE_SZ,
         repository::Value,
         tenant::{
             config::TenantConf,
@@ -1404,4 +1490,75 @@ fn get_key(id: u32) -> Key {
             }
         }
     }
+
+    #[tokio::test]
+    async fn image_layer_dedup() {
+        let harness = TenantHarness::create("image_layer_dedup").await.unwrap();
+        let (tenant, ctx) = harness.load().await;
+
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await
+            .unwrap();
+
+        fn get_key(id: u32) -> Key {
+            let mut key = Key::from_hex("000000000033333333444444445500000000").unwrap();
+            key.field6 = id;
+            key
+        }
+        // A few distinct pages, each of them written for many keys.
+        let mut rng = rand::thread_rng();
+        let pages = (0..4)
+            .map(|_| Bytes::from((0..PAGE_SZ).map(|_| rng.gen::<u8>()).collect_vec()))
+            .collect_vec();
+        const N: usize = 256;
+        let test_imgs = (0..N)
+            .map(|idx| (get_key(idx as u32), pages[idx % pages.len()].clone()))
+            .collect_vec();
+        let key_range = get_key(0)..get_key(N as u32);
+
+        let mut layer_sizes = Vec::new();
+        for (dedup, lsn) in [(false, Lsn(0x10)), (true, Lsn(0x20))] {
+            let conf: &'static PageServerConf = Box::leak(Box::new(PageServerConf {
+                image_layer_dedup: dedup,
+                ..tenant.conf.clone()
+            }));
+            let mut writer = ImageLayerWriter::new(
+                conf,
+                tline.timeline_id,
+                tenant.tenant_shard_id,
+                &key_range,
+                lsn,
+                &ctx,
+            )
+            .await
+            .unwrap();
+            for (key, img) in test_imgs.iter() {
+                writer.put_image(*key, img.clone(), &ctx).await.unwrap();
+            }
+            let resident_layer = writer.finish(&tline, &ctx).await.unwrap();
+            layer_sizes.push(resident_layer.metadata().file_size);
+
+            let img_layer = resident_layer.get_as_image(&ctx).await.unwrap();
+            let loaded = img_layer.load_key_values(&ctx).await.unwrap();
+            assert_eq!(loaded.len(), N);
+            for ((k1, _, v1), (k2, i2)) in loaded.iter().zip(test_imgs.iter()) {
+                assert_eq!(k1, k2);
+                assert!(matches!(v1, Value::Image(i1) if i1 == i2));
+            }
+            // Small reads don't contain the referenced images, large ones do.
+            for (max_read_size, batch_size) in [(1, 1), (1024 * 1024, 1024)] {
+                let mut iter = img_layer.iter(&ctx);
+                iter.planner = StreamingVectoredReadPlanner::new(max_read_size, batch_size);
+                assert_img_iter_equal(&mut iter, &test_imgs, lsn).await;
+            }
+        }
+
+        let (naive_size, dedup_size) = (layer_sizes[0], layer_sizes[1]);
+        assert!(
+            dedup_size < naive_size / 10,
+            "deduplicated layer of {dedup_size} bytes should be much smaller than {naive_size} bytes"
+        );
+        assert!(dedup_size > (pages.len() * PAGE_SZ) as u64);
+    }
 }
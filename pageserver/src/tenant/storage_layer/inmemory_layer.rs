@@ -6,25 +6,26 @@
 //!
 use crate::config::PageServerConf;
 use crate::context::{PageContentKind, RequestContext, RequestContextBuilder};
-use crate::page_cache::PAGE_SZ;
 use crate::repository::{Key, Value};
-use crate::tenant::block_io::{BlockCursor, BlockReader, BlockReaderRef};
+use crate::tenant::block_io::{BlockCursor, BlockReader};
 use crate::tenant::ephemeral_file::EphemeralFile;
 use crate::tenant::timeline::GetVectoredError;
 use crate::tenant::PageReconstructError;
 use crate::virtual_file::owned_buffers_io::io_buf_ext::IoBufExt;
 use crate::{l0_flush, page_cache};
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use camino::Utf8PathBuf;
+use futures::TryStreamExt;
+use itertools::Either;
 use pageserver_api::key::CompactKey;
 use pageserver_api::keyspace::KeySpace;
-use pageserver_api::models::InMemoryLayerInfo;
+use pageserver_api::models::{InMemoryLayerInfo, InMemoryLayerStats};
 use pageserver_api::shard::TenantShardId;
 use std::collections::BTreeMap;
 use std::sync::{Arc, OnceLock};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tracing::*;
-use utils::{bin_ser::BeSer, id::TimelineId, lsn::Lsn, vec_map::VecMap};
+use utils::{bin_ser::BeSer, id::TimelineId, lsn::Lsn, rate_limit::RateLimit, vec_map::VecMap};
 // avoid binding to Write (conflicts with std::io::Write)
 // while being able to use std::fmt::Write's methods
 use crate::metrics::TIMELINE_EPHEMERAL_BYTES;
@@ -36,12 +37,74 @@
 use tokio::sync::RwLock;
 
 use super::{
-    DeltaLayerWriter, PersistentLayerDesc, ValueReconstructSituation, ValuesReconstructState,
+    DeltaLayerWriter, LayerReadOutcome, PersistentLayerDesc, ValueReconstructSituation,
+    ValuesReconstructState,
 };
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub(crate) struct InMemoryLayerFileId(page_cache::FileId);
 
+/// Sentinel stored in [`InMemoryLayerInner::index`] in place of an ephemeral file offset to
+/// mark that the key was deleted at that LSN. No blob can ever start at this offset.
+const TOMBSTONE_OFFSET: u64 = u64::MAX;
+
+/// Deleted key ranges of up to this many keys get a tombstone for each of their keys, so that the
+/// deletion also hides the versions of keys which are only stored in older layers. Wider ranges
+/// are only partially covered: they only get tombstones for the keys which have versions in this
+/// layer.
+const MAX_ENUMERATED_TOMBSTONE_KEYS: usize = 1024;
+
+/// The most versions [`InMemoryLayer::dump`] prints in verbose mode, so that dumping a large
+/// layer doesn't flood the output.
+const DUMP_MAX_VERSIONS: usize = 10_000;
+
+/// With [`PageServerConf::ephemeral_value_checksums`], each serialized value in the ephemeral
+/// file is followed by a big-endian CRC32C of the value, included in the blob length.
+const VALUE_CHECKSUM_LEN: usize = 4;
+
+/// The most blob bytes [`InMemoryLayer::write_to_disk`] holds in memory at once. Versions are
+/// written in key order but stored in the ephemeral file in the order they arrived, so a larger
+/// layer is written in several passes over the file, each collecting the blobs of the next
+/// versions to write.
+const WRITE_TO_DISK_MAX_CHUNK_BYTES: u64 = 64 * 1024 * 1024;
+
+/// The size of the windows in which [`InMemoryLayer::write_to_disk`] streams the ephemeral file.
+const WRITE_TO_DISK_WINDOW_SIZE: usize = 1024 * 1024;
+
+/// The most [`SerializedBatch::from_values`] allocates up front for the serialized batch. Batches
+/// are soft-limited to [`crate::pgdatadir_mapping::DatadirModification::MAX_PENDING_BYTES`], so
+/// only exceptionally large ones grow their buffer beyond this.
+pub(crate) const DEFAULT_MAX_BATCH_PREALLOCATION: usize = 16 * 1024 * 1024;
+
+/// Whether an open [`InMemoryLayer`] should be rolled, see [`InMemoryLayer::should_roll`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RollDecision {
+    RollDueToSize,
+    RollDueToAge,
+    Keep,
+}
+
+/// The limits after which an open [`InMemoryLayer`] is rolled.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RollThresholds {
+    /// The size of the layer's ephemeral file.
+    pub(crate) max_size: u64,
+    /// The time since the layer was opened.
+    pub(crate) max_age: Duration,
+}
+
+impl RollThresholds {
+    pub(crate) fn decide(&self, size: u64, opened_at: Instant, now: Instant) -> RollDecision {
+        if size >= self.max_size {
+            RollDecision::RollDueToSize
+        } else if now.saturating_duration_since(opened_at) >= self.max_age {
+            RollDecision::RollDueToAge
+        } else {
+            RollDecision::Keep
+        }
+    }
+}
+
 pub struct InMemoryLayer {
     conf: &'static PageServerConf,
     tenant_shard_id: TenantShardId,
@@ -61,6 +124,14 @@ pub struct InMemoryLayer {
 
     opened_at: Instant,
 
+    /// Fingerprints of the keys in `inner.index`. Updated while holding the write lock
+    /// of `inner`, so that [`Self::may_contain`] can be checked without taking the lock.
+    key_filter: KeyFilter,
+
+    /// Whether the values in the ephemeral file are followed by a checksum, see
+    /// [`PageServerConf::ephemeral_value_checksums`].
+    value_checksums: bool,
+
     /// The above fields never change, except for `end_lsn`, which is only set once.
     /// All other changing parts are in `inner`, and protected by a mutex.
     inner: RwLock<InMemoryLayerInner>,
@@ -79,15 +150,73 @@ fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 pub struct InMemoryLayerInner {
     /// All versions of all pages in the layer are kept here. Indexed
     /// by block number and LSN. The value is an offset into the
-    /// ephemeral file where the page version is stored.
+    /// ephemeral file where the page version is stored, or [`TOMBSTONE_OFFSET`]
+    /// if the key was deleted at that LSN.
     index: BTreeMap<CompactKey, VecMap<Lsn, u64>>,
 
     /// The values are stored in a serialized format in this file.
-    /// Each serialized Value is preceded by a 'u32' length field.
+    /// Each serialized Value is preceded by a 'u32' length field, and followed by its checksum
+    /// if the layer has [`InMemoryLayer::value_checksums`].
     /// PerSeg::page_versions map stores offsets into this file.
-    file: EphemeralFile,
+    ///
+    /// `None` once the file was released by [`InMemoryLayer::reclaim_ephemeral_file`].
+    file: Option<EphemeralFile>,
 
     resource_units: GlobalResourceUnits,
+
+    duplicate_lsns: DuplicateLsnWarning,
+}
+
+/// Counts the values written again at a (key, LSN) that the layer already has, and warns about
+/// them at most once per interval: replaying WAL that was already ingested can produce a lot of
+/// them.
+struct DuplicateLsnWarning {
+    count: u64,
+    rate_limit: RateLimit,
+}
+
+impl DuplicateLsnWarning {
+    const INTERVAL: Duration = Duration::from_secs(10);
+
+    fn new(interval: Duration) -> Self {
+        Self {
+            count: 0,
+            rate_limit: RateLimit::new(interval),
+        }
+    }
+
+    /// Returns whether a warning was logged.
+    fn record(&mut self, key: CompactKey, lsn: Lsn) -> bool {
+        self.count += 1;
+        let count = self.count;
+        let mut logged = false;
+        self.rate_limit.call(|| {
+            // We already had an entry for this LSN. That's odd..
+            warn!(
+                "Key {key} at {lsn} already exists ({count} duplicate values in this layer so far)"
+            );
+            logged = true;
+        });
+        logged
+    }
+}
+
+impl InMemoryLayerInner {
+    fn file(&self) -> Result<&EphemeralFile> {
+        self.file
+            .as_ref()
+            .context("the ephemeral file of the in-memory layer was reclaimed")
+    }
+
+    fn file_mut(&mut self) -> &mut EphemeralFile {
+        self.file
+            .as_mut()
+            .expect("only frozen layers have their ephemeral file reclaimed")
+    }
+
+    fn file_len(&self) -> u64 {
+        self.file.as_ref().map_or(0, |file| file.len())
+    }
 }
 
 impl std::fmt::Debug for InMemoryLayerInner {
@@ -96,6 +225,78 @@ fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     }
 }
 
+/// Order in which [`InMemoryLayer::get_values_reconstruct_data_directed`] visits keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KeyScanDirection {
+    Ascending,
+    // No read path scans keys backwards yet.
+    #[allow(dead_code)]
+    Descending,
+}
+
+/// A bloom filter over the keys written to an [`InMemoryLayer`].
+///
+/// It is sized once when the layer is created and never shrinks or grows: once a layer
+/// holds many keys the filter saturates and every probe returns `true`, which is still
+/// correct. False negatives are never possible.
+struct KeyFilter {
+    bits: Box<[AtomicU64]>,
+}
+
+impl KeyFilter {
+    /// 64Ki bits, i.e. 8KiB per layer.
+    const NUM_WORDS: usize = 1024;
+    const NUM_HASHES: u64 = 3;
+
+    /// Key ranges wider than this are not probed key by key: [`InMemoryLayer::may_contain`]
+    /// conservatively answers `true` for them.
+    const MAX_PROBED_RANGE: i128 = 64;
+
+    fn new() -> Self {
+        Self {
+            bits: (0..Self::NUM_WORDS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Bit positions for a key, using double hashing over a single 64 bit mix.
+    fn positions(key: i128) -> impl Iterator<Item = usize> {
+        let mut h = (key as u64) ^ ((key >> 64) as u64).rotate_left(29);
+        // splitmix64 finalizer
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+        h ^= h >> 31;
+
+        let num_bits = (Self::NUM_WORDS * 64) as u64;
+        let (h1, h2) = (h & 0xFFFF_FFFF, (h >> 32) | 1);
+        (0..Self::NUM_HASHES)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    fn insert(&self, key: i128) {
+        for pos in Self::positions(key) {
+            self.bits[pos / 64].fetch_or(1 << (pos % 64), AtomicOrdering::Release);
+        }
+    }
+
+    fn may_contain_key(&self, key: i128) -> bool {
+        Self::positions(key)
+            .all(|pos| self.bits[pos / 64].load(AtomicOrdering::Acquire) & (1 << (pos % 64)) != 0)
+    }
+
+    fn may_contain_range(&self, range: &Range<Key>) -> bool {
+        // `Key::to_i128` only keeps the low bits of field1 and field2, so the keys of a range
+        // spanning different values of those don't map to the integers between its ends.
+        if (range.start.field1, range.start.field2) != (range.end.field1, range.end.field2) {
+            return true;
+        }
+        let (start, end) = (range.start.to_i128(), range.end.to_i128());
+        if end <= start || end - start > Self::MAX_PROBED_RANGE {
+            return true;
+        }
+        (start..end).any(|key| self.may_contain_key(key))
+    }
+}
+
 /// State shared by all in-memory (ephemeral) layers.  Updated infrequently during background ticks in Timeline,
 /// to minimize contention.
 ///
@@ -111,11 +312,34 @@ pub(crate) struct GlobalResources {
     dirty_layers: AtomicUsize,
 }
 
+impl GlobalResources {
+    /// The layer size limit to apply when there are `global_dirty_bytes` in total, if above
+    /// `max_dirty_bytes`.
+    fn layer_size_limit(&self, global_dirty_bytes: u64) -> Option<u64> {
+        let max_dirty_bytes = self.max_dirty_bytes.load(AtomicOrdering::Relaxed);
+        if max_dirty_bytes > 0 && global_dirty_bytes > max_dirty_bytes {
+            // The counters are updated independently with relaxed ordering, so we may observe
+            // zero dirty layers, e.g. if the last layer's Drop decremented the count after we
+            // read the dirty bytes. Treat that as a single layer instead of dividing by zero.
+            let dirty_layers = self.dirty_layers.load(AtomicOrdering::Relaxed).max(1);
+
+            // Set the layer file limit to the average layer size: this implies that all above-average
+            // sized layers will be elegible for freezing.  They will be frozen in the order they
+            // next enter publish_size.
+            Some(global_dirty_bytes / dirty_layers as u64)
+        } else {
+            None
+        }
+    }
+}
+
 // Per-timeline RAII struct for its contribution to [`GlobalResources`]
 struct GlobalResourceUnits {
     // How many dirty bytes have I added to the global dirty_bytes: this guard object is responsible
     // for decrementing the global counter by this many bytes when dropped.
     dirty_bytes: u64,
+    // Always [`GLOBAL_RESOURCES`], except in tests
+    resources: &'static GlobalResources,
 }
 
 impl GlobalResourceUnits {
@@ -125,10 +349,15 @@ impl GlobalResourceUnits {
     const MAX_SIZE_DRIFT: u64 = 10 * 1024 * 1024;
 
     fn new() -> Self {
-        GLOBAL_RESOURCES
-            .dirty_layers
-            .fetch_add(1, AtomicOrdering::Relaxed);
-        Self { dirty_bytes: 0 }
+        Self::new_in(&GLOBAL_RESOURCES)
+    }
+
+    fn new_in(resources: &'static GlobalResources) -> Self {
+        resources.dirty_layers.fetch_add(1, AtomicOrdering::Relaxed);
+        Self {
+            dirty_bytes: 0,
+            resources,
+        }
     }
 
     /// Do not call this frequently: all timelines will write to these same global atomics,
@@ -138,17 +367,19 @@ fn new() -> Self {
     /// the total number of dirty bytes below the configured maximum.
     fn publish_size(&mut self, size: u64) -> Option<u64> {
         let new_global_dirty_bytes = match size.cmp(&self.dirty_bytes) {
-            Ordering::Equal => GLOBAL_RESOURCES.dirty_bytes.load(AtomicOrdering::Relaxed),
+            Ordering::Equal => self.resources.dirty_bytes.load(AtomicOrdering::Relaxed),
             Ordering::Greater => {
                 let delta = size - self.dirty_bytes;
-                let old = GLOBAL_RESOURCES
+                let old = self
+                    .resources
                     .dirty_bytes
                     .fetch_add(delta, AtomicOrdering::Relaxed);
                 old + delta
             }
             Ordering::Less => {
                 let delta = self.dirty_bytes - size;
-                let old = GLOBAL_RESOURCES
+                let old = self
+                    .resources
                     .dirty_bytes
                     .fetch_sub(delta, AtomicOrdering::Relaxed);
                 old - delta
@@ -163,25 +394,12 @@ fn publish_size(&mut self, size: u64) -> Option<u64> {
 
         self.dirty_bytes = size;
 
-        let max_dirty_bytes = GLOBAL_RESOURCES
-            .max_dirty_bytes
-            .load(AtomicOrdering::Relaxed);
-        if max_dirty_bytes > 0 && new_global_dirty_bytes > max_dirty_bytes {
-            // Set the layer file limit to the average layer size: this implies that all above-average
-            // sized layers will be elegible for freezing.  They will be frozen in the order they
-            // next enter publish_size.
-            Some(
-                new_global_dirty_bytes
-                    / GLOBAL_RESOURCES.dirty_layers.load(AtomicOrdering::Relaxed) as u64,
-            )
-        } else {
-            None
-        }
+        self.resources.layer_size_limit(new_global_dirty_bytes)
     }
 
     // Call publish_size if the input size differs from last published size by more than
-    // the drift limit
-    fn maybe_publish_size(&mut self, size: u64) {
+    // the drift limit. Returns the layer size limit from publish_size, if it was called.
+    fn maybe_publish_size(&mut self, size: u64) -> Option<u64> {
         let publish = match size.cmp(&self.dirty_bytes) {
             Ordering::Equal => false,
             Ordering::Greater => size - self.dirty_bytes > Self::MAX_SIZE_DRIFT,
@@ -189,14 +407,16 @@ fn maybe_publish_size(&mut self, size: u64) {
         };
 
         if publish {
-            self.publish_size(size);
+            self.publish_size(size)
+        } else {
+            None
         }
     }
 }
 
 impl Drop for GlobalResourceUnits {
     fn drop(&mut self) {
-        GLOBAL_RESOURCES
+        self.resources
             .dirty_layers
             .fetch_sub(1, AtomicOrdering::Relaxed);
 
@@ -224,14 +444,53 @@ pub(crate) fn info(&self) -> InMemoryLayerInfo {
         let lsn_start = self.start_lsn;
 
         if let Some(&lsn_end) = self.end_lsn.get() {
-            InMemoryLayerInfo::Frozen { lsn_start, lsn_end }
+            InMemoryLayerInfo::Frozen {
+                lsn_start,
+                lsn_end,
+                stats: None,
+            }
         } else {
-            InMemoryLayerInfo::Open { lsn_start }
+            InMemoryLayerInfo::Open {
+                lsn_start,
+                open_duration_ms: Some(self.opened_at.elapsed().as_millis() as u64),
+                stats: None,
+            }
         }
     }
 
+    /// Like [`Self::info`], including the [`Self::stats`], which need the layer lock.
+    pub(crate) async fn info_with_stats(&self) -> InMemoryLayerInfo {
+        let mut info = self.info();
+        let (InMemoryLayerInfo::Open { stats, .. } | InMemoryLayerInfo::Frozen { stats, .. }) =
+            &mut info;
+        *stats = Some(self.stats().await);
+        info
+    }
+
+    pub(crate) async fn stats(&self) -> InMemoryLayerStats {
+        let inner = self.inner.read().await;
+
+        let mut stats = InMemoryLayerStats {
+            num_keys: inner.index.len(),
+            num_versions: 0,
+            file_bytes: inner.file_len(),
+            oldest_lsn: None,
+            newest_lsn: None,
+        };
+        for vec_map in inner.index.values() {
+            let versions = vec_map.as_slice();
+            stats.num_versions += versions.len();
+            if let (Some((first, _)), Some((last, _))) = (versions.first(), versions.last()) {
+                stats.oldest_lsn = Some(stats.oldest_lsn.map_or(*first, |lsn| lsn.min(*first)));
+                stats.newest_lsn = Some(stats.newest_lsn.map_or(*last, |lsn| lsn.max(*last)));
+            }
+        }
+
+        stats
+    }
+
     pub(crate) fn try_len(&self) -> Option<u64> {
-        self.inner.try_read().map(|i| i.file.len()).ok()
+        self.inner.try_read().map(|i| i.file_len()).ok()
     }
 
     pub(crate) fn assert_writable(&self) {
@@ -248,18 +507,138 @@ pub(crate) fn get_lsn_range(&self) -> Range<Lsn> {
 
     /// debugging function to print out the contents of the layer
     ///
-    /// this is likely completly unused
-    pub async fn dump(&self, _verbose: bool, _ctx: &RequestContext) -> Result<()> {
+    /// In verbose mode, prints every version of every key along with a summary of its value,
+    /// up to [`DUMP_MAX_VERSIONS`] versions.
+    pub async fn dump(&self, verbose: bool, ctx: &RequestContext) -> Result<()> {
+        let mut out = String::new();
+        self.dump_to(&mut out, verbose, DUMP_MAX_VERSIONS, ctx)
+            .await?;
+        print!("{out}");
+        Ok(())
+    }
+
+    async fn dump_to(
+        &self,
+        out: &mut String,
+        verbose: bool,
+        max_versions: usize,
+        ctx: &RequestContext,
+    ) -> Result<()> {
         let end_str = self.end_lsn_or_max();
 
-        println!(
+        writeln!(
+            out,
             "----- in-memory layer for tli {} LSNs {}-{} ----",
             self.timeline_id, self.start_lsn, end_str,
-        );
+        )?;
+
+        if !verbose {
+            return Ok(());
+        }
+
+        async fn describe_value(
+            reader: &BlockCursor<'_>,
+            pos: u64,
+            value_checksums: bool,
+            ctx: &RequestContext,
+        ) -> Result<String> {
+            let buf = reader.read_blob(pos, ctx).await?;
+            let value_buf = verify_value_checksum(&buf, value_checksums)?;
+            let desc = match Value::des(value_buf)? {
+                Value::Image(img) => format!("img {} bytes", img.len()),
+                Value::WalRecord(rec) => format!(
+                    "rec {} bytes will_init: {} {}",
+                    value_buf.len(),
+                    rec.will_init(),
+                    crate::walrecord::describe_wal_record(&rec)?
+                ),
+            };
+            Ok(desc)
+        }
+
+        let inner = self.inner.read().await;
+        let reader = inner.file()?.block_cursor();
+        let versions = inner
+            .index
+            .iter()
+            .flat_map(|(key, vec_map)| vec_map.as_slice().iter().map(move |v| (key, v)));
+        let mut prev_key = None;
+        let mut num_versions = 0;
+        for (key, (lsn, pos)) in versions {
+            if num_versions == max_versions {
+                writeln!(out, "  ... stopped after {max_versions} versions")?;
+                break;
+            }
+            num_versions += 1;
+
+            if prev_key != Some(key) {
+                writeln!(out, "  key {}:", Key::from_compact(*key))?;
+                prev_key = Some(key);
+            }
+
+            let desc = if *pos == TOMBSTONE_OFFSET {
+                "tombstone".to_string()
+            } else {
+                match describe_value(&reader, *pos, self.value_checksums, ctx).await {
+                    Ok(desc) => desc,
+                    Err(err) => format!("ERROR: {err:#}"),
+                }
+            };
+            writeln!(out, "    at {lsn} offset {pos}: {desc}")?;
+        }
 
         Ok(())
     }
 
+    /// Returns an iterator over the values of this frozen layer in (key, lsn) order. Tombstones
+    /// are skipped.
+    ///
+    /// The iterator holds the layer lock, so the ephemeral file cannot be reclaimed while it is
+    /// in use.
+    ///
+    /// Only available in tests: the read path and the flush read the ephemeral file directly.
+    #[cfg(test)]
+    pub(crate) async fn iter<'a>(
+        &'a self,
+        ctx: &'a RequestContext,
+    ) -> Result<InMemoryLayerIterator<'a>> {
+        ensure!(
+            self.end_lsn.get().is_some(),
+            "cannot iterate over the writable in-memory layer {self}"
+        );
+        let inner = self.inner.read().await;
+        // Fail early if the file was reclaimed.
+        inner.file()?;
+        let mut positions: Vec<(Key, Lsn, u64)> = inner
+            .index
+            .iter()
+            .flat_map(|(key, vec_map)| {
+                vec_map
+                    .as_slice()
+                    .iter()
+                    .filter(|(_, pos)| *pos != TOMBSTONE_OFFSET)
+                    .map(move |(lsn, pos)| (Key::from_compact(*key), *lsn, *pos))
+            })
+            .collect();
+        positions.reverse();
+        Ok(InMemoryLayerIterator {
+            inner,
+            ctx,
+            positions,
+            value_checksums: self.value_checksums,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Cheap check whether this layer may hold any key of the keyspace, without taking
+    /// the layer lock. A `false` answer is exact; `true` may be a false positive.
+    pub(crate) fn may_contain(&self, keyspace: &KeySpace) -> bool {
+        keyspace
+            .ranges
+            .iter()
+            .any(|range| self.key_filter.may_contain_range(range))
+    }
+
     // Look up the keys in the provided keyspace and update
     // the reconstruct state with whatever is found.
     //
@@ -270,28 +649,83 @@ pub(crate) async fn get_values_reconstruct_data(
         end_lsn: Lsn,
         reconstruct_state: &mut ValuesReconstructState,
         ctx: &RequestContext,
-    ) -> Result<(), GetVectoredError> {
+    ) -> Result<LayerReadOutcome, GetVectoredError> {
+        self.get_values_reconstruct_data_directed(
+            keyspace,
+            self.start_lsn..end_lsn,
+            KeyScanDirection::Ascending,
+            reconstruct_state,
+            ctx,
+        )
+        .await
+    }
+
+    /// Like [`Self::get_values_reconstruct_data`], but visits the keys in the given order and
+    /// only returns the versions within `lsn_range`. Versions of each key are always visited
+    /// newest first.
+    pub(crate) async fn get_values_reconstruct_data_directed(
+        &self,
+        keyspace: KeySpace,
+        lsn_range: Range<Lsn>,
+        direction: KeyScanDirection,
+        reconstruct_state: &mut ValuesReconstructState,
+        ctx: &RequestContext,
+    ) -> Result<LayerReadOutcome, GetVectoredError> {
+        // Nothing to do if the layer cannot hold any of the keys, or if newer layers
+        // already provided everything needed to reconstruct them.
+        let start_lsn = std::cmp::max(lsn_range.start, self.start_lsn);
+        let end_lsn = lsn_range.end;
+        let mut num_outstanding_keys = reconstruct_state.num_outstanding_keys(&keyspace);
+        if num_outstanding_keys == 0 || !self.may_contain(&keyspace) {
+            reconstruct_state.on_lsn_advanced(&keyspace, start_lsn);
+            return Ok(LayerReadOutcome::Read);
+        }
+
         let ctx = RequestContextBuilder::extend(ctx)
             .page_content_kind(PageContentKind::InMemoryLayer)
             .build();
 
         let inner = self.inner.read().await;
-        let reader = inner.file.block_cursor();
+        // The layer was written to disk while the read was planned: the reader has to look up
+        // the new layers instead, from the same LSN.
+        let Some(file) = inner.file.as_ref() else {
+            return Ok(LayerReadOutcome::Reclaimed);
+        };
+        let reader = file.block_cursor();
 
-        for range in keyspace.ranges.iter() {
-            for (key, vec_map) in inner
+        let ranges = match direction {
+            KeyScanDirection::Ascending => Either::Left(keyspace.ranges.iter()),
+            KeyScanDirection::Descending => Either::Right(keyspace.ranges.iter().rev()),
+        };
+        for range in ranges {
+            if num_outstanding_keys == 0 {
+                break;
+            }
+            let entries = inner
                 .index
-                .range(range.start.to_compact()..range.end.to_compact())
-            {
+                .range(range.start.to_compact()..range.end.to_compact());
+            let entries = match direction {
+                KeyScanDirection::Ascending => Either::Left(entries),
+                KeyScanDirection::Descending => Either::Right(entries.rev()),
+            };
+            for (key, vec_map) in entries {
                 let key = Key::from_compact(*key);
+                let was_done = reconstruct_state.is_key_done(&key);
+                // A cached version only raises the lower bound, it never widens the window.
                 let lsn_range = match reconstruct_state.get_cached_lsn(&key) {
-                    Some(cached_lsn) => (cached_lsn + 1)..end_lsn,
-                    None => self.start_lsn..end_lsn,
+                    Some(cached_lsn) => std::cmp::max(cached_lsn + 1, start_lsn)..end_lsn,
+                    None => start_lsn..end_lsn,
                 };
 
                 let slice = vec_map.slice_range(lsn_range);
 
                 for (entry_lsn, pos) in slice.iter().rev() {
+                    if *pos == TOMBSTONE_OFFSET {
+                        // The key was deleted at this LSN: nothing older is visible.
+                        reconstruct_state.on_key_deleted(&key);
+                        break;
+                    }
+
                     // TODO: this uses the page cache => https://github.com/neondatabase/neon/issues/8183
                     let buf = reader.read_blob(*pos, &ctx).await;
                     if let Err(e) = buf {
@@ -299,7 +733,18 @@ pub(crate) async fn get_values_reconstruct_data(
                         break;
                     }
 
-                    let value = Value::des(&buf.unwrap());
+                    let buf = buf.unwrap();
+                    let value_buf = match verify_value_checksum(&buf, self.value_checksums) {
+                        Ok(value_buf) => value_buf,
+                        Err(e) => {
+                            let e = e.context(format!("value at {entry_lsn} offset {pos}"));
+                            reconstruct_state
+                                .on_key_error(key, PageReconstructError::Corruption(e));
+                            break;
+                        }
+                    };
+
+                    let value = Value::des(value_buf);
                     if let Err(e) = value {
                         reconstruct_state.on_key_error(key, PageReconstructError::from(anyhow!(e)));
                         break;
@@ -311,12 +756,19 @@ pub(crate) async fn get_values_reconstruct_data(
                         break;
                     }
                 }
+
+                if !was_done && reconstruct_state.is_key_done(&key) {
+                    num_outstanding_keys -= 1;
+                    if num_outstanding_keys == 0 {
+                        break;
+                    }
+                }
             }
         }
 
-        reconstruct_state.on_lsn_advanced(&keyspace, self.start_lsn);
+        reconstruct_state.on_lsn_advanced(&keyspace, start_lsn);
 
-        Ok(())
+        Ok(LayerReadOutcome::Read)
     }
 }
 
@@ -337,6 +789,9 @@ pub struct SerializedBatch {
 
     /// The highest LSN of any value in the batch
     pub(crate) max_lsn: Lsn,
+
+    /// Whether each value in [`Self::raw`] is followed by its checksum.
+    value_checksums: bool,
 }
 
 impl SerializedBatch {
@@ -360,20 +815,52 @@ pub(crate) fn write_blob_length(len: usize, cursor: &mut std::io::Cursor<Vec<u8>
         }
     }
 
-    pub fn from_values(batch: Vec<(CompactKey, Lsn, usize, Value)>) -> Self {
+    /// Serialize the values of `batch`, each followed by its checksum if `value_checksums` is
+    /// set. It must match [`PageServerConf::ephemeral_value_checksums`] of the layer the batch
+    /// is written to.
+    pub fn from_values(batch: Vec<(CompactKey, Lsn, usize, Value)>, value_checksums: bool) -> Self {
+        Self::from_values_with_max_preallocation(
+            batch,
+            value_checksums,
+            DEFAULT_MAX_BATCH_PREALLOCATION,
+        )
+    }
+
+    /// Like [`Self::from_values`], but pre-allocates at most `max_preallocation` bytes. A larger
+    /// batch grows the buffer while it is being serialized, instead of allocating it all at once.
+    pub fn from_values_with_max_preallocation(
+        batch: Vec<(CompactKey, Lsn, usize, Value)>,
+        value_checksums: bool,
+        max_preallocation: usize,
+    ) -> Self {
+        let checksum_len = if value_checksums {
+            VALUE_CHECKSUM_LEN
+        } else {
+            0
+        };
         // Pre-allocate a big flat buffer to write into. This should be large but not huge: it is soft-limited in practice by
         // [`crate::pgdatadir_mapping::DatadirModification::MAX_PENDING_BYTES`]
-        let buffer_size = batch.iter().map(|i| i.2).sum::<usize>() + 4 * batch.len();
-        let mut cursor = std::io::Cursor::new(Vec::<u8>::with_capacity(buffer_size));
+        let buffer_size =
+            batch.iter().map(|i| i.2).sum::<usize>() + (4 + checksum_len) * batch.len();
+        let mut cursor = std::io::Cursor::new(Vec::<u8>::with_capacity(std::cmp::min(
+            buffer_size,
+            max_preallocation,
+        )));
 
         let mut offsets: Vec<SerializedBatchOffset> = Vec::with_capacity(batch.len());
         let mut max_lsn: Lsn = Lsn(0);
         for (key, lsn, val_ser_size, val) in batch {
             let relative_off = cursor.position();
 
-            Self::write_blob_length(val_ser_size, &mut cursor);
+            Self::write_blob_length(val_ser_size + checksum_len, &mut cursor);
+            let value_off = cursor.position() as usize;
             val.ser_into(&mut cursor)
                 .expect("Writing into in-memory buffer is infallible");
+            if value_checksums {
+                let checksum = crc32c::crc32c(&cursor.get_ref()[value_off..]);
+                std::io::Write::write_all(&mut cursor, &checksum.to_be_bytes())
+                    .expect("Writing to Vec is infallible");
+            }
 
             offsets.push(SerializedBatchOffset {
                 key,
@@ -385,17 +872,248 @@ pub fn from_values(batch: Vec<(CompactKey, Lsn, usize, Value)>) -> Self {
 
         let buffer = cursor.into_inner();
 
-        // Assert that we didn't do any extra allocations while building buffer.
+        // Assert that the buffer size was an upper bound. Unless the pre-allocation was capped, this
+        // means that we didn't do any extra allocations while building the buffer.
         debug_assert!(buffer.len() <= buffer_size);
 
         Self {
             raw: buffer,
             offsets,
             max_lsn,
+            value_checksums,
+        }
+    }
+
+    /// Check that every offset points to a blob length header within [`Self::raw`], and that the
+    /// blobs follow each other back to back in the order of the offsets, the way
+    /// [`Self::from_values`] lays them out.
+    fn validate(&self) -> Result<()> {
+        let mut prev_end = 0;
+        for SerializedBatchOffset { key, lsn, offset } in &self.offsets {
+            let key = Key::from_compact(*key);
+            let off = usize::try_from(*offset)?;
+            ensure!(
+                off == prev_end,
+                "value of key {key} at {lsn} is at offset {off}, but the previous value ends at {prev_end}"
+            );
+            ensure!(
+                off < self.raw.len(),
+                "offset {off} of key {key} at {lsn} is past the end of the {} byte buffer",
+                self.raw.len()
+            );
+            let Some((header_len, len)) = parse_blob_length(&self.raw[off..]) else {
+                anyhow::bail!("length header of key {key} at {lsn} at offset {off} is truncated");
+            };
+            prev_end = off + header_len + len;
+            ensure!(
+                prev_end <= self.raw.len(),
+                "value of key {key} at {lsn} at offset {off} ends at {prev_end}, past the end of the {} byte buffer",
+                self.raw.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Parse the length header that [`SerializedBatch::write_blob_length`] wrote at the start of
+/// `buf`. Returns the lengths of the header and of the blob after it, or `None` if `buf` is too
+/// short to hold the header.
+fn parse_blob_length(buf: &[u8]) -> Option<(usize, usize)> {
+    let first_len_byte = *buf.first()?;
+    if first_len_byte < 0x80 {
+        return Some((1, first_len_byte as usize));
+    }
+    let mut len_buf: [u8; 4] = buf.get(..4)?.try_into().unwrap();
+    len_buf[0] &= 0x7f;
+    Some((4, u32::from_be_bytes(len_buf) as usize))
+}
+
+/// Parse the blob at the start of `buf`, returning its serialized value once the checksum, if
+/// any, has been verified. `buf` may go on past the end of the blob.
+fn parse_blob(buf: &[u8], value_checksums: bool) -> Result<&[u8]> {
+    let Some((header_len, len)) = parse_blob_length(buf) else {
+        anyhow::bail!("length header of {} byte blob is truncated", buf.len());
+    };
+    let Some(blob) = buf.get(header_len..header_len + len) else {
+        anyhow::bail!(
+            "blob of {len} bytes is truncated to {}",
+            buf.len() - header_len
+        );
+    };
+    verify_value_checksum(blob, value_checksums)
+}
+
+/// Copy the `extents` of the ephemeral file into one buffer, back to back in the given order,
+/// streaming the file in windows of `window_size` bytes. The extents must not overlap.
+///
+/// Returns the buffer and the offset of each extent in it.
+async fn read_extents(
+    file: &EphemeralFile,
+    extents: &[Range<u64>],
+    window_size: usize,
+    ctx: &RequestContext,
+) -> Result<(Vec<u8>, Vec<usize>)> {
+    let mut offsets = Vec::with_capacity(extents.len());
+    let mut len = 0;
+    for extent in extents {
+        offsets.push(len);
+        len += usize::try_from(extent.end - extent.start).unwrap();
+    }
+    let mut buf = vec![0; len];
+
+    // Visit the extents in file order, so that the file is only streamed once.
+    let mut order = (0..extents.len()).collect::<Vec<_>>();
+    order.sort_unstable_by_key(|i| extents[*i].start);
+
+    let mut windows = std::pin::pin!(file.stream_windows(window_size, ctx));
+    // The first extent in file order which is not fully copied yet.
+    let mut next = 0;
+    while next < order.len() {
+        let Some((window_start, window)) = windows.try_next().await? else {
+            anyhow::bail!(
+                "extent {:?} ends past the ephemeral file",
+                extents[order[next]]
+            );
+        };
+        let window_end = window_start + window.len() as u64;
+        for &i in &order[next..] {
+            let extent = &extents[i];
+            if extent.start >= window_end {
+                break;
+            }
+            let start = std::cmp::max(extent.start, window_start);
+            let end = std::cmp::min(extent.end, window_end);
+            let n = usize::try_from(end - start).unwrap();
+            let src = usize::try_from(start - window_start).unwrap();
+            let dst = offsets[i] + usize::try_from(start - extent.start).unwrap();
+            buf[dst..dst + n].copy_from_slice(&window[src..src + n]);
+        }
+        while next < order.len() && extents[order[next]].end <= window_end {
+            next += 1;
+        }
+    }
+
+    Ok((buf, offsets))
+}
+
+/// Strip the checksum from a blob read from the ephemeral file, returning the serialized value.
+/// Without `value_checksums`, the blob is the serialized value.
+fn verify_value_checksum(blob: &[u8], value_checksums: bool) -> Result<&[u8]> {
+    if !value_checksums {
+        return Ok(blob);
+    }
+    let Some(value_len) = blob.len().checked_sub(VALUE_CHECKSUM_LEN) else {
+        anyhow::bail!("blob of {} bytes is too short for a checksum", blob.len());
+    };
+    let (value, checksum) = blob.split_at(value_len);
+    let expected = u32::from_be_bytes(checksum.try_into().unwrap());
+    let actual = crc32c::crc32c(value);
+    anyhow::ensure!(
+        expected == actual,
+        "checksum mismatch: expected {expected:#010x}, got {actual:#010x}"
+    );
+    Ok(value)
+}
+
+/// Read the blob at `pos` into `buf`, leaving only the serialized value once its checksum, if
+/// any, has been verified.
+#[cfg(test)]
+async fn read_value_into_buf(
+    reader: &BlockCursor<'_>,
+    pos: u64,
+    value_checksums: bool,
+    buf: &mut Vec<u8>,
+    ctx: &RequestContext,
+) -> Result<()> {
+    reader.read_blob_into_buf(pos, buf, ctx).await?;
+    let value_len = verify_value_checksum(buf, value_checksums)?.len();
+    buf.truncate(value_len);
+    Ok(())
+}
+
+/// Iterator over the values of a frozen [`InMemoryLayer`], see [`InMemoryLayer::iter`].
+#[cfg(test)]
+pub(crate) struct InMemoryLayerIterator<'a> {
+    inner: tokio::sync::RwLockReadGuard<'a, InMemoryLayerInner>,
+    ctx: &'a RequestContext,
+    /// The values still to be returned, in reverse order, as offsets into the ephemeral file.
+    positions: Vec<(Key, Lsn, u64)>,
+    value_checksums: bool,
+    buf: Vec<u8>,
+}
+
+#[cfg(test)]
+impl<'a> InMemoryLayerIterator<'a> {
+    pub(crate) async fn next(&mut self) -> Result<Option<(Key, Lsn, Value)>> {
+        let Some((key, lsn, pos)) = self.positions.pop() else {
+            return Ok(None);
+        };
+        let reader = self.inner.file()?.block_cursor();
+        read_value_into_buf(&reader, pos, self.value_checksums, &mut self.buf, self.ctx)
+            .await
+            .with_context(|| format!("key {key} at {lsn} offset {pos}"))?;
+        Ok(Some((key, lsn, Value::des(&self.buf)?)))
+    }
+}
+
+/// Insert a tombstone into a key's versions. A tombstone replaces a value at the same LSN,
+/// because deletions are applied after the puts of the same LSN.
+fn put_tombstone(vec_map: &mut VecMap<Lsn, u64>, lsn: Lsn) {
+    if vec_map.append_or_update_last(lsn, TOMBSTONE_OFFSET).is_ok() {
+        return;
+    }
+
+    // The tombstone falls between existing versions: rebuild the map around it.
+    let (mut merged, newer) = vec_map.split_at(&lsn);
+    merged
+        .append(lsn, TOMBSTONE_OFFSET)
+        .expect("split_at only keeps smaller LSNs on the left");
+    for (entry_lsn, pos) in newer.as_slice() {
+        if *entry_lsn != lsn {
+            merged
+                .append(*entry_lsn, *pos)
+                .expect("versions are already ordered");
+        }
+    }
+    *vec_map = merged;
+}
+
+/// The versions of a key in `lsn_range` which [`InMemoryLayer::write_to_disk`] writes. The
+/// latest tombstone of the key and the versions it deletes are left out.
+fn versions_to_write<'a>(
+    vec_map: &'a VecMap<Lsn, u64>,
+    lsn_range: &Range<Lsn>,
+) -> &'a [(Lsn, u64)] {
+    let versions = vec_map.slice_range(lsn_range.clone());
+    let tombstone_lsn = vec_map
+        .as_slice()
+        .iter()
+        .rev()
+        .find(|(_, pos)| *pos == TOMBSTONE_OFFSET)
+        .map(|(lsn, _)| *lsn);
+    match tombstone_lsn {
+        Some(tombstone_lsn) => {
+            let deleted = versions.partition_point(|(lsn, _)| *lsn <= tombstone_lsn);
+            &versions[deleted..]
         }
+        None => versions,
     }
 }
 
+/// The keys of `key_range`, if there are at most [`MAX_ENUMERATED_TOMBSTONE_KEYS`] of them.
+fn enumerate_tombstone_keys(key_range: &Range<Key>) -> Option<Vec<Key>> {
+    let mut keys = Vec::new();
+    let mut key = key_range.start;
+    while key < key_range.end {
+        if keys.len() == MAX_ENUMERATED_TOMBSTONE_KEYS {
+            return None;
+        }
+        keys.push(key);
+        key = key.next();
+    }
+    Some(keys)
+}
+
 fn inmem_layer_display(mut f: impl Write, start_lsn: Lsn, end_lsn: Lsn) -> std::fmt::Result {
     write!(f, "inmem-{:016X}-{:016X}", start_lsn.0, end_lsn.0)
 }
@@ -421,7 +1139,7 @@ impl InMemoryLayer {
     /// Get layer size.
     pub async fn size(&self) -> Result<u64> {
         let inner = self.inner.read().await;
-        Ok(inner.file.len())
+        Ok(inner.file_len())
     }
 
     /// Create a new, empty, in-memory layer
@@ -448,73 +1166,149 @@ pub async fn create(
             start_lsn,
             end_lsn: OnceLock::new(),
             opened_at: Instant::now(),
+            key_filter: KeyFilter::new(),
+            value_checksums: conf.ephemeral_value_checksums,
             inner: RwLock::new(InMemoryLayerInner {
                 index: BTreeMap::new(),
-                file,
+                file: Some(file),
                 resource_units: GlobalResourceUnits::new(),
+                duplicate_lsns: DuplicateLsnWarning::new(DuplicateLsnWarning::INTERVAL),
             }),
         })
     }
 
     // Write path.
+    //
+    // Returns a layer size limit if the global amount of dirty data is above the configured
+    // maximum: layers larger than that should be rolled as soon as possible.
     pub async fn put_batch(
         &self,
         serialized_batch: SerializedBatch,
         ctx: &RequestContext,
-    ) -> Result<()> {
+    ) -> Result<Option<u64>> {
+        self.put_batch_many(vec![serialized_batch], ctx).await
+    }
+
+    /// Like [`Self::put_batch`], but for several batches at once: the layer lock is taken once,
+    /// and the layer size is published once after all of them have been written.
+    pub(crate) async fn put_batch_many(
+        &self,
+        serialized_batches: Vec<SerializedBatch>,
+        ctx: &RequestContext,
+    ) -> Result<Option<u64>> {
+        // Check all the batches before writing any, so that a malformed one doesn't leave the
+        // ones before it in the layer.
+        for serialized_batch in &serialized_batches {
+            ensure!(
+                serialized_batch.value_checksums == self.value_checksums,
+                "value checksums of the batch ({}) don't match those of the layer ({})",
+                serialized_batch.value_checksums,
+                self.value_checksums
+            );
+        }
+        if cfg!(debug_assertions) {
+            for serialized_batch in &serialized_batches {
+                serialized_batch
+                    .validate()
+                    .context("malformed serialized batch")?;
+            }
+        }
+
         let mut inner = self.inner.write().await;
         self.assert_writable();
 
-        let base_off = {
-            inner
-                .file
-                .write_raw(
-                    &serialized_batch.raw,
-                    &RequestContextBuilder::extend(ctx)
-                        .page_content_kind(PageContentKind::InMemoryLayer)
-                        .build(),
-                )
-                .await?
-        };
+        let ctx = RequestContextBuilder::extend(ctx)
+            .page_content_kind(PageContentKind::InMemoryLayer)
+            .build();
 
-        for SerializedBatchOffset {
-            key,
-            lsn,
-            offset: relative_off,
-        } in serialized_batch.offsets
-        {
-            let off = base_off + relative_off;
-            let vec_map = inner.index.entry(key).or_default();
-            let old = vec_map.append_or_update_last(lsn, off).unwrap().0;
-            if old.is_some() {
-                // We already had an entry for this LSN. That's odd..
-                warn!("Key {} at {} already exists", key, lsn);
+        for serialized_batch in serialized_batches {
+            // The offsets in each batch are relative to the start of its own buffer, which
+            // lands wherever the previous batch ended.
+            let base_off = inner
+                .file_mut()
+                .write_raw(&serialized_batch.raw, &ctx)
+                .await?;
+
+            for SerializedBatchOffset {
+                key,
+                lsn,
+                offset: relative_off,
+            } in serialized_batch.offsets
+            {
+                let off = base_off + relative_off;
+                self.key_filter.insert(Key::from_compact(key).to_i128());
+                let vec_map = inner.index.entry(key).or_default();
+                let old = vec_map.append_or_update_last(lsn, off).unwrap().0;
+                if old.is_some() {
+                    inner.duplicate_lsns.record(key, lsn);
+                }
             }
         }
 
-        let size = inner.file.len();
-        inner.resource_units.maybe_publish_size(size);
-
-        Ok(())
+        let size = inner.file_len();
+        Ok(inner.resource_units.maybe_publish_size(size))
     }
 
     pub(crate) fn get_opened_at(&self) -> Instant {
         self.opened_at
     }
 
+    /// Decides whether the layer should be rolled, based on the length of its ephemeral file and
+    /// how long it has been open. A layer that is being written to right now is kept.
+    pub(crate) fn should_roll(&self, now: Instant, thresholds: &RollThresholds) -> RollDecision {
+        match self.try_len() {
+            Some(size) => thresholds.decide(size, self.opened_at, now),
+            None => RollDecision::Keep,
+        }
+    }
+
     pub(crate) async fn tick(&self) -> Option<u64> {
         let mut inner = self.inner.write().await;
-        let size = inner.file.len();
+        let size = inner.file_len();
         inner.resource_units.publish_size(size)
     }
 
-    pub(crate) async fn put_tombstones(&self, _key_ranges: &[(Range<Key>, Lsn)]) -> Result<()> {
-        // TODO: Currently, we just leak the storage for any deleted keys
+    /// Record deletions of the given key ranges.
+    ///
+    /// While the layer is in memory, reads at or above the tombstone LSN no longer see the
+    /// deleted keys. [`Self::write_to_disk`] leaves out the tombstones and the versions they
+    /// delete, but the deletion itself is not persisted: once the layer is on disk, reads of the
+    /// deleted keys fall through to the older layers again.
+    ///
+    /// Only ranges of up to [`MAX_ENUMERATED_TOMBSTONE_KEYS`] keys are fully covered. Wider
+    /// ranges only get tombstones for the keys which have versions in this layer, so the
+    /// versions of their other keys in older layers stay visible.
+    pub(crate) async fn put_tombstones(&self, key_ranges: &[(Range<Key>, Lsn)]) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        self.assert_writable();
+
+        for (key_range, lsn) in key_ranges {
+            match enumerate_tombstone_keys(key_range) {
+                Some(keys) => {
+                    for key in keys {
+                        // Reads skip layers whose filter rules out the key, and would miss
+                        // the tombstone of a key without versions in this layer.
+                        self.key_filter.insert(key.to_i128());
+                        put_tombstone(inner.index.entry(key.to_compact()).or_default(), *lsn);
+                    }
+                }
+                None => {
+                    let key_range = key_range.start.to_compact()..key_range.end.to_compact();
+                    for (_, vec_map) in inner.index.range_mut(key_range) {
+                        put_tombstone(vec_map, *lsn);
+                    }
+                }
+            }
+        }
+
         Ok(())
     }
 
     /// Records the end_lsn for non-dropped layers.
     /// `end_lsn` is exclusive
+    ///
+    /// Freezing an already frozen layer again at the same `end_lsn` is a no-op. Freezing it at a
+    /// different one panics.
     pub async fn freeze(&self, end_lsn: Lsn) {
         assert!(
             self.start_lsn < end_lsn,
@@ -522,16 +1316,18 @@ pub async fn freeze(&self, end_lsn: Lsn) {
             self.start_lsn,
             end_lsn
         );
-        self.end_lsn.set(end_lsn).expect("end_lsn set only once");
-
-        self.frozen_local_path_str
-            .set({
-                let mut buf = String::new();
-                inmem_layer_log_display(&mut buf, self.get_timeline_id(), self.start_lsn, end_lsn)
-                    .unwrap();
-                buf.into()
-            })
-            .expect("frozen_local_path_str set only once");
+        let frozen_at = *self.end_lsn.get_or_init(|| end_lsn);
+        assert_eq!(
+            frozen_at, end_lsn,
+            "in-memory layer already frozen at a different end_lsn"
+        );
+
+        self.frozen_local_path_str.get_or_init(|| {
+            let mut buf = String::new();
+            inmem_layer_log_display(&mut buf, self.get_timeline_id(), self.start_lsn, end_lsn)
+                .unwrap();
+            buf.into()
+        });
 
         #[cfg(debug_assertions)]
         {
@@ -548,12 +1344,45 @@ pub async fn freeze(&self, end_lsn: Lsn) {
     /// layer will only contain the key range the user specifies, and may return `None`
     /// if there are no matching keys.
     ///
+    /// If `lsn_range` is set, only the versions within it are written, and the delta layer
+    /// covers its intersection with the LSN range of this layer. Keys without versions in the
+    /// range are skipped.
+    ///
+    /// Tombstones are not written: the versions of a key at or below its latest tombstone are
+    /// left out instead, see [`Self::put_tombstones`].
+    ///
+    /// The ephemeral file is not loaded into memory as a whole, see
+    /// [`WRITE_TO_DISK_MAX_CHUNK_BYTES`].
+    ///
     /// Returns a new delta layer with all the same data as this in-memory layer
     pub async fn write_to_disk(
         &self,
         ctx: &RequestContext,
         key_range: Option<Range<Key>>,
+        lsn_range: Option<Range<Lsn>>,
+        l0_flush_global_state: &l0_flush::Inner,
+    ) -> Result<Option<(PersistentLayerDesc, Utf8PathBuf)>> {
+        self.write_to_disk_in_chunks(
+            ctx,
+            key_range,
+            lsn_range,
+            l0_flush_global_state,
+            WRITE_TO_DISK_MAX_CHUNK_BYTES,
+            WRITE_TO_DISK_WINDOW_SIZE,
+        )
+        .await
+    }
+
+    /// Like [`Self::write_to_disk`], holding at most `max_chunk_bytes` of blobs in memory at once
+    /// (or a single blob, if it's larger) and streaming the file in windows of `window_size`.
+    async fn write_to_disk_in_chunks(
+        &self,
+        ctx: &RequestContext,
+        key_range: Option<Range<Key>>,
+        lsn_range: Option<Range<Lsn>>,
         l0_flush_global_state: &l0_flush::Inner,
+        max_chunk_bytes: u64,
+        window_size: usize,
     ) -> Result<Option<(PersistentLayerDesc, Utf8PathBuf)>> {
         // Grab the lock in read-mode. We hold it over the I/O, but because this
         // layer is not writeable anymore, no one should be trying to acquire the
@@ -565,25 +1394,37 @@ pub async fn write_to_disk(
         // would have to wait until we release it. That race condition is very
         // rare though, so we just accept the potential latency hit for now.
         let inner = self.inner.read().await;
+        let file = inner.file()?;
 
-        use l0_flush::Inner;
-        let _concurrency_permit = match l0_flush_global_state {
-            Inner::Direct { semaphore, .. } => Some(semaphore.acquire().await),
-        };
+        let _concurrency_permit = l0_flush_global_state.acquire_permit().await;
 
         let end_lsn = *self.end_lsn.get().unwrap();
+        let lsn_range = match lsn_range {
+            Some(lsn_range) => {
+                std::cmp::max(lsn_range.start, self.start_lsn)
+                    ..std::cmp::min(lsn_range.end, end_lsn)
+            }
+            None => self.start_lsn..end_lsn,
+        };
+        if lsn_range.is_empty() {
+            return Ok(None);
+        }
 
-        let key_count = if let Some(key_range) = key_range {
-            let key_range = key_range.start.to_compact()..key_range.end.to_compact();
-
-            inner
-                .index
-                .iter()
-                .filter(|(k, _)| key_range.contains(k))
-                .count()
-        } else {
-            inner.index.len()
-        };
+        // Count the versions that will be written in the same pass, to pre-size the layer
+        // writer.
+        let key_range = key_range.map(|r| r.start.to_compact()..r.end.to_compact());
+        let mut key_count = 0;
+        let mut total_versions = 0;
+        for (key, vec_map) in inner.index.iter() {
+            let versions = versions_to_write(vec_map, &lsn_range).len();
+            if versions == 0 {
+                continue;
+            }
+            total_versions += versions;
+            if key_range.as_ref().map_or(true, |r| r.contains(key)) {
+                key_count += 1;
+            }
+        }
         if key_count == 0 {
             return Ok(None);
         }
@@ -593,51 +1434,70 @@ pub async fn write_to_disk(
             self.timeline_id,
             self.tenant_shard_id,
             Key::MIN,
-            self.start_lsn..end_lsn,
+            lsn_range.clone(),
+            Some(total_versions),
             ctx,
         )
         .await?;
 
         match l0_flush_global_state {
             l0_flush::Inner::Direct { .. } => {
-                let file_contents: Vec<u8> = inner.file.load_to_vec(ctx).await?;
-                assert_eq!(
-                    file_contents.len() % PAGE_SZ,
-                    0,
-                    "needed by BlockReaderRef::Slice"
-                );
-                assert_eq!(file_contents.len(), {
-                    let written = usize::try_from(inner.file.len()).unwrap();
-                    if written % PAGE_SZ == 0 {
-                        written
-                    } else {
-                        written.checked_add(PAGE_SZ - (written % PAGE_SZ)).unwrap()
-                    }
-                });
-
-                let cursor = BlockCursor::new(BlockReaderRef::Slice(&file_contents));
+                // The versions to write, in the order the delta layer needs them.
+                let versions = inner
+                    .index
+                    .iter()
+                    .flat_map(|(key, vec_map)| {
+                        let key = Key::from_compact(*key);
+                        versions_to_write(vec_map, &lsn_range)
+                            .iter()
+                            .map(move |(lsn, pos)| (key, *lsn, *pos))
+                    })
+                    .collect::<Vec<_>>();
+
+                // Blobs are written back to back, so a blob ends at most where the next one
+                // starts. Blobs of overwritten versions are not in the index anymore, and are
+                // read along with the blob before them.
+                // TODO: once we have blob lengths in the in-memory index, we don't need to
+                // derive the extents from the positions
+                // => https://github.com/neondatabase/neon/issues/8183
+                let mut blob_starts = inner
+                    .index
+                    .values()
+                    .flat_map(|vec_map| vec_map.as_slice().iter().map(|(_, pos)| *pos))
+                    .filter(|pos| *pos != TOMBSTONE_OFFSET)
+                    .collect::<Vec<_>>();
+                blob_starts.sort_unstable();
+                let blob_extent = |pos: u64| {
+                    let next = blob_starts.partition_point(|start| *start <= pos);
+                    pos..blob_starts.get(next).copied().unwrap_or(file.len())
+                };
 
                 let mut buf = Vec::new();
-
-                for (key, vec_map) in inner.index.iter() {
-                    // Write all page versions
-                    for (lsn, pos) in vec_map.as_slice() {
-                        // TODO: once we have blob lengths in the in-memory index, we can
-                        // 1. get rid of the blob_io / BlockReaderRef::Slice business and
-                        // 2. load the file contents into a Bytes and
-                        // 3. the use `Bytes::slice` to get the `buf` that is our blob
-                        // 4. pass that `buf` into `put_value_bytes`
-                        // => https://github.com/neondatabase/neon/issues/8183
-                        cursor.read_blob_into_buf(*pos, &mut buf, ctx).await?;
-                        let will_init = Value::des(&buf)?.will_init();
+                let mut remaining = versions.as_slice();
+                while !remaining.is_empty() {
+                    // Read the blobs of the next versions, as many as fit into a chunk.
+                    let mut extents = Vec::new();
+                    let mut chunk_bytes = 0;
+                    for (_, _, pos) in remaining {
+                        let extent = blob_extent(*pos);
+                        chunk_bytes += extent.end - extent.start;
+                        if !extents.is_empty() && chunk_bytes > max_chunk_bytes {
+                            break;
+                        }
+                        extents.push(extent);
+                    }
+                    let (chunk, rest) = remaining.split_at(extents.len());
+                    remaining = rest;
+                    let (blobs, offsets) = read_extents(file, &extents, window_size, ctx).await?;
+
+                    for ((key, lsn, pos), offset) in chunk.iter().zip(offsets) {
+                        let value = parse_blob(&blobs[offset..], self.value_checksums)
+                            .with_context(|| format!("key {key} at {lsn} offset {pos}"))?;
+                        let will_init = Value::des(value)?.will_init();
+                        buf.clear();
+                        buf.extend_from_slice(value);
                         let (tmp, res) = delta_layer_writer
-                            .put_value_bytes(
-                                Key::from_compact(*key),
-                                *lsn,
-                                buf.slice_len(),
-                                will_init,
-                                ctx,
-                            )
+                            .put_value_bytes(*key, *lsn, buf.slice_len(), will_init, ctx)
                             .await;
                         res?;
                         buf = tmp.into_raw_slice().into_inner();
@@ -652,8 +1512,8 @@ pub async fn write_to_disk(
         // Hold the permit until all the IO is done, including the fsync in `delta_layer_writer.finish()``.
         //
         // If we didn't and our caller drops this future, tokio-epoll-uring would extend the lifetime of
-        // the `file_contents: Vec<u8>` until the IO is done, but not the permit's lifetime.
-        // Thus, we'd have more concurrenct `Vec<u8>` in existence than the semaphore allows.
+        // the chunk buffers until the IO is done, but not the permit's lifetime.
+        // Thus, we'd have more concurrenct chunk buffers in existence than the semaphore allows.
         //
         // We hold across the fsync so that on ext4 mounted with data=ordered, all the kernel page cache pages
         // we dirtied when writing to the filesystem have been flushed and marked !dirty.
@@ -661,4 +1521,1281 @@ pub async fn write_to_disk(
 
         Ok(Some((desc, path)))
     }
+
+    /// Release the ephemeral file backing this frozen layer, once [`Self::write_to_disk`] wrote
+    /// its contents to delta layers. The layer cannot be read anymore afterwards: reads return
+    /// [`LayerReadOutcome::Reclaimed`] and have to go to the delta layers instead.
+    ///
+    /// Waits for concurrent readers to release the layer. Returns the size of the released file.
+    pub(crate) async fn reclaim_ephemeral_file(&self) -> u64 {
+        assert!(
+            self.end_lsn.get().is_some(),
+            "cannot reclaim the ephemeral file of a writable layer"
+        );
+        let mut inner = self.inner.write().await;
+        // Dropping the file removes it from disk.
+        let Some(file) = inner.file.take() else {
+            return 0;
+        };
+        let size = file.len();
+        drop(file);
+        inner.index = BTreeMap::new();
+        inner.resource_units.publish_size(0);
+        size
+    }
+
+    /// Freeze this writable layer at `end_lsn` and write it to disk right away. See
+    /// [`Self::freeze`] and [`Self::write_to_disk`].
+    ///
+    /// Returns `None` if there are no keys to write in `key_range`.
+    ///
+    /// Only available in tests: timelines freeze their open layer and flush it separately.
+    #[cfg(test)]
+    pub(crate) async fn freeze_and_flush(
+        &self,
+        end_lsn: Lsn,
+        ctx: &RequestContext,
+        key_range: Option<Range<Key>>,
+        l0_flush_global_state: &l0_flush::Inner,
+    ) -> Result<Option<(PersistentLayerDesc, Utf8PathBuf)>> {
+        self.assert_writable();
+        ensure!(
+            self.start_lsn < end_lsn,
+            "cannot freeze in-memory layer starting at {} at end_lsn {}",
+            self.start_lsn,
+            end_lsn
+        );
+
+        self.freeze(end_lsn).await;
+
+        self.write_to_disk(ctx, key_range, None, l0_flush_global_state)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::config::defaults::DEFAULT_EPHEMERAL_VALUE_CHECKSUMS;
+    use crate::l0_flush::{L0FlushConfig, L0FlushGlobalState};
+    use crate::metrics::L0_FLUSH_WAIT_TIME;
+    use crate::page_cache::PAGE_SZ;
+    use crate::tenant::ephemeral_file::is_ephemeral_file;
+    use crate::tenant::harness::{test_img, TenantHarness, TIMELINE_ID};
+    use crate::tenant::storage_layer::{Layer, LayerAccessStatsReset};
+    use crate::tenant::{Tenant, Timeline};
+    use crate::walrecord::NeonWalRecord;
+    use crate::DEFAULT_PG_VERSION;
+
+    fn test_key(blknum: u32) -> Key {
+        let mut key = Key::from_hex("000000067F00008000000000000000000000").unwrap();
+        key.field6 = blknum;
+        key
+    }
+
+    /// Serialize a batch for the layers of the test tenants, which have the default config.
+    fn serialize_batch(values: impl IntoIterator<Item = (Key, Lsn, Value)>) -> SerializedBatch {
+        serialize_batch_with_checksums(values, DEFAULT_EPHEMERAL_VALUE_CHECKSUMS)
+    }
+
+    fn serialize_batch_with_checksums(
+        values: impl IntoIterator<Item = (Key, Lsn, Value)>,
+        value_checksums: bool,
+    ) -> SerializedBatch {
+        let batch = values
+            .into_iter()
+            .map(|(key, lsn, value)| {
+                let size = value.serialized_size().unwrap() as usize;
+                (key.to_compact(), lsn, size, value)
+            })
+            .collect();
+        SerializedBatch::from_values(batch, value_checksums)
+    }
+
+    async fn test_timeline(
+        test_name: &'static str,
+    ) -> anyhow::Result<(Arc<Tenant>, Arc<Timeline>, RequestContext)> {
+        let (tenant, ctx) = TenantHarness::create(test_name).await?.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x08), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+        Ok((tenant, tline, ctx))
+    }
+
+    async fn create_test_layer(
+        tenant: &Tenant,
+        tline: &Timeline,
+        start_lsn: Lsn,
+        ctx: &RequestContext,
+    ) -> anyhow::Result<InMemoryLayer> {
+        InMemoryLayer::create(
+            tenant.conf,
+            tline.timeline_id,
+            tenant.tenant_shard_id,
+            start_lsn,
+            tline.gate.enter()?,
+            ctx,
+        )
+        .await
+    }
+
+    async fn open_layer(tline: &Timeline) -> anyhow::Result<Arc<InMemoryLayer>> {
+        Ok(tline
+            .layers
+            .read()
+            .await
+            .layer_map()?
+            .open_layer
+            .clone()
+            .expect("writes went to the open layer"))
+    }
+
+    #[test]
+    fn layer_size_limit_without_dirty_layers() {
+        let resources = GlobalResources {
+            max_dirty_bytes: AtomicU64::new(1000),
+            dirty_bytes: AtomicU64::new(0),
+            dirty_layers: AtomicUsize::new(0),
+        };
+
+        assert_eq!(resources.layer_size_limit(500), None);
+        assert_eq!(resources.layer_size_limit(2000), Some(2000));
+
+        resources.dirty_layers.store(4, AtomicOrdering::Relaxed);
+        assert_eq!(resources.layer_size_limit(2000), Some(500));
+    }
+
+    #[test]
+    fn maybe_publish_size_returns_limit() {
+        let resources: &'static GlobalResources = Box::leak(Box::new(GlobalResources {
+            max_dirty_bytes: AtomicU64::new(1024),
+            dirty_bytes: AtomicU64::new(0),
+            dirty_layers: AtomicUsize::new(0),
+        }));
+        let mut units = GlobalResourceUnits::new_in(resources);
+        let mut other_units = GlobalResourceUnits::new_in(resources);
+
+        // Small changes are not published
+        assert_eq!(units.maybe_publish_size(2048), None);
+
+        let size = GlobalResourceUnits::MAX_SIZE_DRIFT + 1;
+        assert_eq!(units.maybe_publish_size(size), Some(size / 2));
+        assert_eq!(other_units.maybe_publish_size(size), Some(size));
+
+        drop(units);
+        drop(other_units);
+        assert_eq!(resources.dirty_bytes.load(AtomicOrdering::Relaxed), 0);
+        assert_eq!(resources.dirty_layers.load(AtomicOrdering::Relaxed), 0);
+    }
+
+    #[test]
+    fn key_filter_no_false_negatives() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let filter = KeyFilter::new();
+
+        let keys = (0..10_000)
+            .map(|_| {
+                let mut key = test_key(rng.gen());
+                key.field4 = rng.gen_range(0..16);
+                key
+            })
+            .collect::<Vec<_>>();
+
+        for key in &keys {
+            filter.insert(key.to_i128());
+        }
+
+        for key in &keys {
+            assert!(filter.may_contain_key(key.to_i128()));
+            assert!(filter.may_contain_range(&(*key..key.next())));
+        }
+    }
+
+    #[test]
+    fn key_filter_range_across_masked_fields() {
+        // Ranges ending right after a key whose field1 or field2 are masked by `Key::to_i128`.
+        for (start, key) in [
+            (
+                "7F0000FFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+                "800000000000000000000000000000000000",
+            ),
+            (
+                "000000FFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+                "00FFFFFFFF00000000000000000000000000",
+            ),
+        ] {
+            let (start, key) = (Key::from_hex(start).unwrap(), Key::from_hex(key).unwrap());
+            let filter = KeyFilter::new();
+            filter.insert(key.to_i128());
+            assert!(
+                filter.may_contain_range(&(start..key.next())),
+                "{start}..{key}"
+            );
+        }
+    }
+
+    #[test]
+    fn duplicate_lsn_warning_is_rate_limited() {
+        let key = test_key(0).to_compact();
+        let mut warning = DuplicateLsnWarning::new(Duration::from_millis(100));
+
+        assert!(warning.record(key, Lsn(0x10)));
+        for _ in 0..100 {
+            assert!(!warning.record(key, Lsn(0x10)));
+        }
+        assert_eq!(warning.count, 101);
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(warning.record(key, Lsn(0x10)));
+        assert!(!warning.record(key, Lsn(0x10)));
+        assert_eq!(warning.count, 103);
+    }
+
+    #[tokio::test]
+    async fn put_batch_counts_duplicate_lsns() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) =
+            test_timeline("inmemory_layer_put_batch_counts_duplicate_lsns").await?;
+
+        let key = test_key(0);
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        let batch = serialize_batch((0..100).map(|i| {
+            (
+                key,
+                Lsn(0x10),
+                Value::Image(Bytes::from(format!("version {i}"))),
+            )
+        }));
+        inmem.put_batch(batch, &ctx).await?;
+
+        let mut inner = inmem.inner.write().await;
+        // The last write wins, as before.
+        assert_eq!(inner.index[&key.to_compact()].as_slice().len(), 1);
+        assert_eq!(inner.duplicate_lsns.count, 99);
+        // The warning for the first duplicate holds off the others for the whole interval.
+        assert!(!inner.duplicate_lsns.record(key.to_compact(), Lsn(0x10)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn put_batch_many_matches_sequential_put_batch() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) =
+            test_timeline("inmemory_layer_put_batch_many_matches_sequential_put_batch").await?;
+
+        // Batches of different sizes, with some keys written again in later batches.
+        let make_batches = || {
+            (0..4u32)
+                .map(|i| {
+                    let lsn = Lsn(0x10 + 0x10 * i as u64);
+                    serialize_batch((0..=i + 1).map(|blknum| {
+                        let value = Value::Image(Bytes::from(format!("{blknum} at {lsn}")));
+                        (test_key(blknum), lsn, value)
+                    }))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let sequential = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        for batch in make_batches() {
+            sequential.put_batch(batch, &ctx).await?;
+        }
+        let many = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        many.put_batch_many(make_batches(), &ctx).await?;
+
+        let sequential = sequential.inner.read().await;
+        let many = many.inner.read().await;
+        assert!(!many.index.is_empty());
+        assert_eq!(many.file_len(), sequential.file_len());
+        assert_eq!(
+            many.index.keys().collect::<Vec<_>>(),
+            sequential.index.keys().collect::<Vec<_>>()
+        );
+        for (key, vec_map) in &many.index {
+            assert_eq!(vec_map.as_slice(), sequential.index[key].as_slice());
+        }
+
+        Ok(())
+    }
+
+    async fn frozen_test_layer(test_name: &'static str) -> anyhow::Result<InMemoryLayer> {
+        let (tenant, tline, ctx) = test_timeline(test_name).await?;
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        inmem.freeze(Lsn(0x20)).await;
+        Ok(inmem)
+    }
+
+    #[tokio::test]
+    async fn freeze_again_at_same_lsn_is_noop() -> anyhow::Result<()> {
+        let inmem = frozen_test_layer("inmemory_layer_freeze_again_at_same_lsn_is_noop").await?;
+        let path_str = inmem.frozen_local_path_str.get().cloned().unwrap();
+
+        inmem.freeze(Lsn(0x20)).await;
+
+        assert_eq!(inmem.end_lsn.get(), Some(&Lsn(0x20)));
+        assert_eq!(inmem.frozen_local_path_str.get(), Some(&path_str));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "in-memory layer already frozen at a different end_lsn")]
+    async fn freeze_again_at_other_lsn_panics() {
+        let inmem = frozen_test_layer("inmemory_layer_freeze_again_at_other_lsn_panics")
+            .await
+            .unwrap();
+
+        inmem.freeze(Lsn(0x30)).await;
+    }
+
+    fn test_batch() -> SerializedBatch {
+        serialize_batch([
+            (test_key(0), Lsn(0x10), Value::Image(Bytes::from("short"))),
+            (
+                test_key(0),
+                Lsn(0x20),
+                Value::Image(Bytes::from(vec![0; 200])),
+            ),
+            (
+                test_key(0),
+                Lsn(0x30),
+                Value::WalRecord(NeonWalRecord::wal_append(",0x30")),
+            ),
+        ])
+    }
+
+    #[test]
+    fn validate_serialized_batch() {
+        test_batch().validate().unwrap();
+
+        // Offsets out of order.
+        let mut batch = test_batch();
+        batch.offsets.swap(0, 1);
+        assert!(batch.validate().is_err());
+
+        // An offset into the middle of a value.
+        let mut batch = test_batch();
+        batch.offsets[1].offset += 1;
+        assert!(batch.validate().is_err());
+
+        // An offset past the end of the buffer.
+        let mut batch = test_batch();
+        batch.offsets[2].offset = batch.raw.len() as u64;
+        assert!(batch.validate().is_err());
+
+        // A value running past the end of the buffer.
+        let mut batch = test_batch();
+        batch.raw.pop();
+        assert!(batch.validate().is_err());
+    }
+
+    #[test]
+    fn serialized_batch_above_max_preallocation() {
+        let key = test_key(0);
+        let batch: Vec<_> = (0..100)
+            .map(|i| {
+                let value = Value::Image(Bytes::from(vec![i as u8; 1000]));
+                let size = value.serialized_size().unwrap() as usize;
+                (key.to_compact(), Lsn(0x10 + i), size, value)
+            })
+            .collect();
+
+        let uncapped = SerializedBatch::from_values(batch.clone(), true);
+        let max_preallocation = 4096;
+        assert!(uncapped.raw.len() > max_preallocation);
+
+        let capped =
+            SerializedBatch::from_values_with_max_preallocation(batch, true, max_preallocation);
+        capped.validate().unwrap();
+        assert_eq!(capped.raw, uncapped.raw);
+        assert_eq!(capped.max_lsn, uncapped.max_lsn);
+        let offsets = |batch: &SerializedBatch| {
+            batch
+                .offsets
+                .iter()
+                .map(|o| (Key::from_compact(o.key), o.lsn, o.offset))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(offsets(&capped), offsets(&uncapped));
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn put_batch_rejects_malformed_batch() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) =
+            test_timeline("inmemory_layer_put_batch_rejects_malformed_batch").await?;
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+
+        // The malformed batch is rejected, and neither it nor the valid one before it is written.
+        let mut batch = test_batch();
+        batch.offsets.swap(0, 1);
+        let err = inmem
+            .put_batch_many(vec![test_batch(), batch], &ctx)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("malformed serialized batch"),
+            "{err:#}"
+        );
+        assert_eq!(inmem.size().await?, 0);
+        assert!(inmem.inner.read().await.index.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) = test_timeline("inmemory_layer_checksum_mismatch").await?;
+        let conf: &'static PageServerConf = Box::leak(Box::new(PageServerConf {
+            ephemeral_value_checksums: true,
+            ..tenant.conf.clone()
+        }));
+        let inmem = InMemoryLayer::create(
+            conf,
+            tline.timeline_id,
+            tenant.tenant_shard_id,
+            Lsn(0x10),
+            tline.gate.enter()?,
+            &ctx,
+        )
+        .await?;
+        let values = [(
+            test_key(0),
+            Lsn(0x10),
+            Value::Image(test_img("foo at 0x10")),
+        )];
+
+        // A batch without checksums doesn't fit the layer.
+        let batch = serialize_batch_with_checksums(values.clone(), false);
+        assert!(inmem.put_batch(batch, &ctx).await.is_err());
+        assert_eq!(inmem.size().await?, 0);
+
+        // Flip the last byte of the value, just before its checksum
+        let mut batch = serialize_batch_with_checksums(values, true);
+        let corrupt_pos = batch.raw.len() - 5;
+        batch.raw[corrupt_pos] ^= 0xFF;
+        inmem.put_batch(batch, &ctx).await?;
+
+        let mut reconstruct_state = ValuesReconstructState::new();
+        inmem
+            .get_values_reconstruct_data(
+                KeySpace::single(test_key(0)..test_key(1)),
+                Lsn(0x11),
+                &mut reconstruct_state,
+                &ctx,
+            )
+            .await?;
+        assert!(matches!(
+            reconstruct_state.keys.get(&test_key(0)),
+            Some(Err(PageReconstructError::Corruption(_)))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_roll_past_age_threshold() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) =
+            test_timeline("inmemory_layer_should_roll_past_age_threshold").await?;
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        let thresholds = RollThresholds {
+            max_size: 1024 * 1024,
+            max_age: Duration::from_secs(600),
+        };
+
+        let opened_at = inmem.get_opened_at();
+        assert_eq!(
+            inmem.should_roll(opened_at, &thresholds),
+            RollDecision::Keep
+        );
+        assert_eq!(
+            inmem.should_roll(opened_at + Duration::from_secs(601), &thresholds),
+            RollDecision::RollDueToAge
+        );
+        // Size takes precedence over age.
+        let small = RollThresholds {
+            max_size: 0,
+            ..thresholds
+        };
+        assert_eq!(
+            inmem.should_roll(opened_at + Duration::from_secs(601), &small),
+            RollDecision::RollDueToSize
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stats() -> anyhow::Result<()> {
+        let (_tenant, tline, ctx) = test_timeline("inmemory_layer_stats").await?;
+
+        let mut writer = tline.writer().await;
+        for (blknum, lsn) in [(0, 0x10), (1, 0x10), (0, 0x20), (2, 0x30)] {
+            writer
+                .put(
+                    test_key(blknum),
+                    Lsn(lsn),
+                    &Value::Image(test_img(&format!("{blknum} at {lsn}"))),
+                    &ctx,
+                )
+                .await?;
+            writer.finish_write(Lsn(lsn));
+        }
+        drop(writer);
+
+        let open_layer = open_layer(&tline).await?;
+        let stats = open_layer.stats().await;
+        assert_eq!(stats.num_keys, 3);
+        assert_eq!(stats.num_versions, 4);
+        assert_eq!(stats.file_bytes, open_layer.size().await?);
+        assert_eq!(stats.oldest_lsn, Some(Lsn(0x10)));
+        assert_eq!(stats.newest_lsn, Some(Lsn(0x30)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn layer_info_open_duration() -> anyhow::Result<()> {
+        let (_tenant, tline, ctx) = test_timeline("inmemory_layer_info_open_duration").await?;
+
+        let mut writer = tline.writer().await;
+        writer
+            .put(
+                test_key(0),
+                Lsn(0x10),
+                &Value::Image(test_img("foo at 0x10")),
+                &ctx,
+            )
+            .await?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+
+        let info = tline.layer_map_info(LayerAccessStatsReset::NoReset).await?;
+        let [InMemoryLayerInfo::Open {
+            open_duration_ms: Some(open_duration_ms),
+            stats: Some(stats),
+            ..
+        }] = info.in_memory_layers.as_slice()
+        else {
+            panic!("expected a single open layer: {:?}", info.in_memory_layers);
+        };
+        assert!(*open_duration_ms < 10_000, "{open_duration_ms}ms");
+        assert_eq!(stats.num_keys, 1);
+        assert_eq!(stats.oldest_lsn, Some(Lsn(0x10)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skip_scan_once_keys_are_complete() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) =
+            test_timeline("inmemory_layer_skip_scan_once_keys_are_complete").await?;
+        let keyspace = KeySpace::single(test_key(0)..test_key(4));
+
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        let batch = serialize_batch((0..4).map(|blknum| {
+            let value = Value::Image(Bytes::from(format!("{blknum} at 0x10")));
+            (test_key(blknum), Lsn(0x10), value)
+        }));
+        inmem.put_batch(batch, &ctx).await?;
+        inmem.freeze(Lsn(0x18)).await;
+
+        // A newer layer already had images for all the keys but the last one.
+        let mut reconstruct_state = ValuesReconstructState::new();
+        for blknum in 0..3 {
+            reconstruct_state.update_key(
+                &test_key(blknum),
+                Lsn(0x20),
+                Value::Image(Bytes::from(format!("{blknum} at 0x20"))),
+            );
+        }
+        assert_eq!(reconstruct_state.num_outstanding_keys(&keyspace), 1);
+
+        inmem
+            .get_values_reconstruct_data(keyspace.clone(), Lsn(0x18), &mut reconstruct_state, &ctx)
+            .await?;
+        assert_eq!(reconstruct_state.num_outstanding_keys(&keyspace), 0);
+        for blknum in 0..4 {
+            let lsn = if blknum < 3 { 0x20 } else { 0x10 };
+            let state = reconstruct_state.keys[&test_key(blknum)].as_ref().unwrap();
+            assert_eq!(
+                state.img,
+                Some((Lsn(lsn), Bytes::from(format!("{blknum} at {lsn:#x}"))))
+            );
+        }
+
+        // With nothing left to reconstruct the layer is not scanned: it doesn't even need the
+        // layer lock.
+        let _guard = inmem.inner.write().await;
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            inmem.get_values_reconstruct_data(keyspace, Lsn(0x18), &mut reconstruct_state, &ctx),
+        )
+        .await
+        .expect("in-memory layer was scanned")?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_lsn_window() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) = test_timeline("inmemory_layer_read_lsn_window").await?;
+
+        let key = test_key(0);
+        let keyspace = KeySpace::single(key..key.next());
+
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        let batch = serialize_batch([
+            (key, Lsn(0x10), Value::Image(Bytes::from("0x10"))),
+            (
+                key,
+                Lsn(0x20),
+                Value::WalRecord(NeonWalRecord::wal_append(",0x20")),
+            ),
+            (
+                key,
+                Lsn(0x30),
+                Value::WalRecord(NeonWalRecord::wal_append(",0x30")),
+            ),
+        ]);
+        inmem.put_batch(batch, &ctx).await?;
+        inmem.freeze(Lsn(0x38)).await;
+
+        // The window excludes the image at 0x10, so only the records are returned.
+        let mut reconstruct_state = ValuesReconstructState::new();
+        inmem
+            .get_values_reconstruct_data_directed(
+                keyspace.clone(),
+                Lsn(0x20)..Lsn(0x31),
+                KeyScanDirection::Ascending,
+                &mut reconstruct_state,
+                &ctx,
+            )
+            .await?;
+        let state = reconstruct_state.keys[&key].as_ref().unwrap();
+        assert_eq!(state.img, None);
+        assert_eq!(
+            state
+                .records
+                .iter()
+                .map(|(lsn, _)| *lsn)
+                .collect::<Vec<_>>(),
+            vec![Lsn(0x30), Lsn(0x20)]
+        );
+        assert_eq!(reconstruct_state.num_outstanding_keys(&keyspace), 1);
+
+        // Without a lower bound the image completes the key.
+        let mut reconstruct_state = ValuesReconstructState::new();
+        inmem
+            .get_values_reconstruct_data(keyspace.clone(), Lsn(0x31), &mut reconstruct_state, &ctx)
+            .await?;
+        let state = reconstruct_state.keys[&key].as_ref().unwrap();
+        assert_eq!(state.img, Some((Lsn(0x10), Bytes::from("0x10"))));
+        assert_eq!(state.records.len(), 2);
+        assert_eq!(reconstruct_state.num_outstanding_keys(&keyspace), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_direction() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) = test_timeline("inmemory_layer_scan_direction").await?;
+
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        let mut values = Vec::new();
+        for blknum in 0..8 {
+            values.push((
+                test_key(blknum),
+                Lsn(0x10),
+                Value::Image(test_img(&format!("{blknum} at 0x10"))),
+            ));
+            if blknum % 2 == 0 {
+                values.push((
+                    test_key(blknum),
+                    Lsn(0x20),
+                    Value::WalRecord(NeonWalRecord::wal_append(format!(",{blknum} at 0x20"))),
+                ));
+            }
+            if blknum % 3 == 0 {
+                values.push((
+                    test_key(blknum),
+                    Lsn(0x30),
+                    Value::Image(test_img(&format!("{blknum} at 0x30"))),
+                ));
+            }
+        }
+        inmem.put_batch(serialize_batch(values), &ctx).await?;
+
+        let keyspace = KeySpace {
+            ranges: vec![test_key(0)..test_key(3), test_key(5)..test_key(8)],
+        };
+        let mut results = Vec::new();
+        for direction in [KeyScanDirection::Ascending, KeyScanDirection::Descending] {
+            let mut reconstruct_state = ValuesReconstructState::new();
+            inmem
+                .get_values_reconstruct_data_directed(
+                    keyspace.clone(),
+                    Lsn(0x10)..Lsn(0x31),
+                    direction,
+                    &mut reconstruct_state,
+                    &ctx,
+                )
+                .await?;
+            let (done, _) = reconstruct_state.consume_done_keys();
+            let values = reconstruct_state
+                .keys
+                .into_iter()
+                .map(|(key, state)| {
+                    let state = state.unwrap();
+                    (key, (state.img, state.records))
+                })
+                .collect::<BTreeMap<_, _>>();
+            results.push((done, values));
+        }
+
+        let (ascending, descending) = (&results[0], &results[1]);
+        assert_eq!(ascending.1.len(), 6);
+        assert_eq!(ascending.0, descending.0);
+        assert_eq!(ascending.1, descending.1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tombstone_of_key_without_versions() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) =
+            test_timeline("inmemory_layer_tombstone_of_key_without_versions").await?;
+        let keyspace = KeySpace::single(test_key(0)..test_key(1));
+
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        inmem
+            .put_tombstones(&[(test_key(0)..test_key(1), Lsn(0x10))])
+            .await?;
+        inmem.freeze(Lsn(0x18)).await;
+
+        // The key is done without a value, so the older layers are not read for it.
+        let mut reconstruct_state = ValuesReconstructState::new();
+        inmem
+            .get_values_reconstruct_data(keyspace.clone(), Lsn(0x18), &mut reconstruct_state, &ctx)
+            .await?;
+        assert_eq!(reconstruct_state.num_outstanding_keys(&keyspace), 0);
+        assert!(reconstruct_state.keys.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tombstones() -> anyhow::Result<()> {
+        let (_tenant, tline, ctx) = test_timeline("inmemory_layer_tombstones").await?;
+
+        // Key 1 is only stored in an older layer when it's deleted.
+        let mut writer = tline.writer().await;
+        writer
+            .put(
+                test_key(1),
+                Lsn(0x10),
+                &Value::Image(test_img("foobar at 0x10")),
+                &ctx,
+            )
+            .await?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+        tline.freeze_and_flush().await?;
+
+        // Key 2 is deleted between two of its versions.
+        let mut writer = tline.writer().await;
+        for (key, lsn) in [(test_key(0), Lsn(0x20)), (test_key(2), Lsn(0x20))] {
+            writer
+                .put(
+                    key,
+                    lsn,
+                    &Value::Image(test_img(&format!("{key} at {lsn}"))),
+                    &ctx,
+                )
+                .await?;
+        }
+        writer.finish_write(Lsn(0x20));
+        writer
+            .delete_batch(
+                &[
+                    (test_key(0)..test_key(2), Lsn(0x30)),
+                    (test_key(2)..test_key(3), Lsn(0x30)),
+                ],
+                &ctx,
+            )
+            .await?;
+        writer.finish_write(Lsn(0x30));
+        writer
+            .put(
+                test_key(2),
+                Lsn(0x40),
+                &Value::Image(test_img("2 at 0x40")),
+                &ctx,
+            )
+            .await?;
+        writer.finish_write(Lsn(0x40));
+        drop(writer);
+
+        for key in [test_key(0), test_key(2)] {
+            assert_eq!(
+                tline.get(key, Lsn(0x20), &ctx).await?,
+                test_img(&format!("{key} at {}", Lsn(0x20)))
+            );
+        }
+        assert_eq!(
+            tline.get(test_key(1), Lsn(0x20), &ctx).await?,
+            test_img("foobar at 0x10")
+        );
+        // Key 1 reads back as absent although the tombstone is its only entry in this layer.
+        for key in [test_key(0), test_key(1), test_key(2)] {
+            assert!(
+                matches!(
+                    tline.get(key, Lsn(0x30), &ctx).await,
+                    Err(PageReconstructError::MissingKey(_))
+                ),
+                "{key}"
+            );
+        }
+        assert_eq!(
+            tline.get(test_key(2), Lsn(0x40), &ctx).await?,
+            test_img("2 at 0x40")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_to_disk_omits_deleted_versions() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) =
+            test_timeline("inmemory_layer_write_to_disk_omits_deleted_versions").await?;
+
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        let batch = serialize_batch([
+            (test_key(0), Lsn(0x10), Value::Image(test_img("0 at 0x10"))),
+            (test_key(1), Lsn(0x10), Value::Image(test_img("1 at 0x10"))),
+            (test_key(1), Lsn(0x20), Value::Image(test_img("1 at 0x20"))),
+        ]);
+        inmem.put_batch(batch, &ctx).await?;
+        // Key 1 is deleted between two of its versions.
+        inmem
+            .put_tombstones(&[(test_key(0)..test_key(2), Lsn(0x18))])
+            .await?;
+        inmem.freeze(Lsn(0x28)).await;
+
+        // Only the version newer than the tombstones is written to disk.
+        let (desc, path) = inmem
+            .write_to_disk(&ctx, None, None, tline.l0_flush_global_state.inner())
+            .await?
+            .unwrap();
+        let layer = Layer::finish_creating(tenant.conf, &tline, desc, &path)?;
+        let entries = layer
+            .load_keys(&ctx)
+            .await?
+            .into_iter()
+            .map(|e| (e.key, e.lsn))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec![(test_key(1), Lsn(0x20))]);
+
+        // Nothing is left to write below the tombstones.
+        let layer = inmem
+            .write_to_disk(
+                &ctx,
+                None,
+                Some(Lsn(0x10)..Lsn(0x20)),
+                tline.l0_flush_global_state.inner(),
+            )
+            .await?;
+        assert!(layer.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn iter_frozen_layer() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) = test_timeline("inmemory_layer_iter_frozen_layer").await?;
+
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        let values = [
+            (
+                test_key(1),
+                Lsn(0x10),
+                Value::Image(Bytes::from("1 at 0x10")),
+            ),
+            (test_key(0), Lsn(0x10), Value::Image(Bytes::from("0x10"))),
+            (
+                test_key(0),
+                Lsn(0x20),
+                Value::WalRecord(NeonWalRecord::wal_append(",0x20")),
+            ),
+            (
+                test_key(2),
+                Lsn(0x20),
+                Value::Image(Bytes::from("2 at 0x20")),
+            ),
+        ];
+        inmem
+            .put_batch(serialize_batch(values.iter().cloned()), &ctx)
+            .await?;
+        inmem
+            .put_tombstones(&[(test_key(1)..test_key(2), Lsn(0x30))])
+            .await?;
+
+        // Only frozen layers can be iterated.
+        assert!(inmem.iter(&ctx).await.is_err());
+        inmem.freeze(Lsn(0x38)).await;
+
+        let mut iter = inmem.iter(&ctx).await?;
+        let mut entries = Vec::new();
+        while let Some(entry) = iter.next().await? {
+            entries.push(entry);
+        }
+        // The entries come in (key, lsn) order, without the tombstone.
+        let mut expected = values.to_vec();
+        expected.sort_by_key(|(key, lsn, _)| (*key, *lsn));
+        assert_eq!(entries, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dump_prints_versions() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) = test_timeline("inmemory_layer_dump_prints_versions").await?;
+
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        let batch = serialize_batch([
+            (test_key(0), Lsn(0x10), Value::Image(Bytes::from("0x10"))),
+            (
+                test_key(0),
+                Lsn(0x20),
+                Value::WalRecord(NeonWalRecord::wal_append(",0x20")),
+            ),
+            (
+                test_key(1),
+                Lsn(0x10),
+                Value::Image(Bytes::from("1 at 0x10")),
+            ),
+        ]);
+        inmem.put_batch(batch, &ctx).await?;
+        inmem
+            .put_tombstones(&[(test_key(1)..test_key(2), Lsn(0x20))])
+            .await?;
+        inmem.freeze(Lsn(0x28)).await;
+
+        let mut out = String::new();
+        inmem.dump_to(&mut out, false, 100, &ctx).await?;
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains("LSNs 0/10-0/28"), "{out}");
+
+        let mut out = String::new();
+        inmem.dump_to(&mut out, true, 100, &ctx).await?;
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 7, "{out}");
+        assert_eq!(lines[1], format!("  key {}:", test_key(0)));
+        assert!(lines[2].starts_with("    at 0/10 offset "), "{out}");
+        assert!(lines[2].ends_with(": img 4 bytes"), "{out}");
+        assert!(lines[3].starts_with("    at 0/20 offset "), "{out}");
+        assert!(lines[3].contains(": rec "), "{out}");
+        assert!(lines[3].contains("will_init: false"), "{out}");
+        assert_eq!(lines[4], format!("  key {}:", test_key(1)));
+        assert!(lines[5].ends_with(": img 9 bytes"), "{out}");
+        assert_eq!(
+            lines[6],
+            format!("    at 0/20 offset {TOMBSTONE_OFFSET}: tombstone")
+        );
+
+        // The output stops at the cap.
+        let mut out = String::new();
+        inmem.dump_to(&mut out, true, 2, &ctx).await?;
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5, "{out}");
+        assert_eq!(lines[4], "  ... stopped after 2 versions");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_to_disk_lsn_range() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) = test_timeline("inmemory_layer_write_to_disk_lsn_range").await?;
+
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        // Key 0 is written at every LSN, key 1 only at 0x10 and key 2 only at 0x40.
+        let versions = [
+            (0, Lsn(0x10)),
+            (1, Lsn(0x10)),
+            (0, Lsn(0x20)),
+            (0, Lsn(0x30)),
+            (0, Lsn(0x40)),
+            (2, Lsn(0x40)),
+        ];
+        for (blknum, lsn) in versions {
+            let value = Value::Image(test_img(&format!("{blknum} at {lsn}")));
+            inmem
+                .put_batch(serialize_batch([(test_key(blknum), lsn, value)]), &ctx)
+                .await?;
+        }
+        inmem.freeze(Lsn(0x50)).await;
+
+        let (desc, path) = inmem
+            .write_to_disk(
+                &ctx,
+                None,
+                Some(Lsn(0x20)..Lsn(0x40)),
+                tline.l0_flush_global_state.inner(),
+            )
+            .await?
+            .unwrap();
+        assert_eq!(desc.lsn_range, Lsn(0x20)..Lsn(0x40));
+        let layer = Layer::finish_creating(tenant.conf, &tline, desc, &path)?;
+        let entries = layer
+            .load_keys(&ctx)
+            .await?
+            .into_iter()
+            .map(|e| (e.key, e.lsn))
+            .collect::<Vec<_>>();
+        // Keys 1 and 2 have no versions in the range.
+        assert_eq!(
+            entries,
+            vec![(test_key(0), Lsn(0x20)), (test_key(0), Lsn(0x30))]
+        );
+
+        // The LSN range is clamped to the one of the in-memory layer.
+        let (desc, _) = inmem
+            .write_to_disk(
+                &ctx,
+                None,
+                Some(Lsn(0x40)..Lsn(0x100)),
+                tline.l0_flush_global_state.inner(),
+            )
+            .await?
+            .unwrap();
+        assert_eq!(desc.lsn_range, Lsn(0x40)..Lsn(0x50));
+
+        // No versions in the range.
+        let layer = inmem
+            .write_to_disk(
+                &ctx,
+                None,
+                Some(Lsn(0x41)..Lsn(0x50)),
+                tline.l0_flush_global_state.inner(),
+            )
+            .await?;
+        assert!(layer.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_to_disk_in_chunks() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) = test_timeline("inmemory_layer_write_to_disk_in_chunks").await?;
+
+        // The versions of each key are spread over the file, and values of different sizes
+        // cross window boundaries. Some are larger than a chunk.
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        let mut expected = Vec::new();
+        for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30)] {
+            let values = (0..50)
+                .map(|blknum| {
+                    let value = if blknum % 4 == 0 && lsn > Lsn(0x10) {
+                        Value::WalRecord(NeonWalRecord::wal_append(format!(",{blknum} at {lsn}")))
+                    } else {
+                        Value::Image(Bytes::from(vec![blknum as u8; 100 * blknum as usize + 1]))
+                    };
+                    (test_key(blknum), lsn, value)
+                })
+                .collect::<Vec<_>>();
+            expected.extend(values.iter().cloned());
+            inmem.put_batch(serialize_batch(values), &ctx).await?;
+        }
+        inmem.freeze(Lsn(0x38)).await;
+        expected.sort_by_key(|(key, lsn, _)| (*key, *lsn));
+
+        let (desc, path) = inmem
+            .write_to_disk_in_chunks(
+                &ctx,
+                None,
+                None,
+                tline.l0_flush_global_state.inner(),
+                4096,
+                PAGE_SZ,
+            )
+            .await?
+            .unwrap();
+        let layer = Layer::finish_creating(tenant.conf, &tline, desc, &path)?;
+        let mut entries = Vec::new();
+        for entry in layer.load_keys(&ctx).await? {
+            entries.push((entry.key, entry.lsn, entry.val.load(&ctx).await?));
+        }
+        assert_eq!(entries, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_wait_time() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) = test_timeline("inmemory_layer_flush_wait_time").await?;
+
+        let mut layers = Vec::new();
+        for start_lsn in [Lsn(0x10), Lsn(0x20)] {
+            let inmem = create_test_layer(&tenant, &tline, start_lsn, &ctx).await?;
+            let batch = serialize_batch([(test_key(0), start_lsn, Value::Image(test_img("foo")))]);
+            inmem.put_batch(batch, &ctx).await?;
+            inmem.freeze(start_lsn + 1).await;
+            layers.push(inmem);
+        }
+
+        let l0_flush_global_state = L0FlushGlobalState::new(L0FlushConfig::Direct {
+            max_concurrency: std::num::NonZeroUsize::new(1).unwrap(),
+        });
+        let sample_count = L0_FLUSH_WAIT_TIME.get_sample_count();
+        let sample_sum = L0_FLUSH_WAIT_TIME.get_sample_sum();
+        // With a single permit, one of the flushes waits for the other one to finish.
+        let (first, second) = tokio::join!(
+            layers[0].write_to_disk(&ctx, None, None, l0_flush_global_state.inner()),
+            layers[1].write_to_disk(&ctx, None, None, l0_flush_global_state.inner()),
+        );
+        assert!(first?.is_some());
+        assert!(second?.is_some());
+        // Other tests may flush concurrently, so we can only check that the metrics grew.
+        assert!(L0_FLUSH_WAIT_TIME.get_sample_count() >= sample_count + 2);
+        assert!(L0_FLUSH_WAIT_TIME.get_sample_sum() > sample_sum);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn freeze_and_flush() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) = test_timeline("inmemory_layer_freeze_and_flush").await?;
+
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        let batch = serialize_batch((0..4).map(|blknum| {
+            let value = Value::Image(test_img(&format!("{blknum} at 0x10")));
+            (test_key(blknum), Lsn(0x10), value)
+        }));
+        inmem.put_batch(batch, &ctx).await?;
+
+        // An end_lsn that is not after the start is refused, and leaves the layer writable
+        let err = inmem
+            .freeze_and_flush(Lsn(0x10), &ctx, None, tline.l0_flush_global_state.inner())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("cannot freeze"), "{err}");
+        inmem.assert_writable();
+
+        let (desc, path) = inmem
+            .freeze_and_flush(Lsn(0x11), &ctx, None, tline.l0_flush_global_state.inner())
+            .await?
+            .expect("layer has data");
+        assert_eq!(inmem.get_lsn_range(), Lsn(0x10)..Lsn(0x11));
+        assert_eq!(desc.key_range, Key::MIN..Key::MAX);
+        assert_eq!(desc.lsn_range, Lsn(0x10)..Lsn(0x11));
+
+        let layer = Layer::finish_creating(tenant.conf, &tline, desc, &path)?;
+        let keys = layer
+            .load_keys(&ctx)
+            .await?
+            .into_iter()
+            .map(|e| e.key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, (0..4).map(test_key).collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reclaim_ephemeral_file() -> anyhow::Result<()> {
+        let (tenant, tline, ctx) = test_timeline("inmemory_layer_reclaim_ephemeral_file").await?;
+
+        let ephemeral_files_size = || -> anyhow::Result<u64> {
+            let mut size = 0;
+            for entry in tenant
+                .conf
+                .timeline_path(&tline.tenant_shard_id, &tline.timeline_id)
+                .read_dir_utf8()?
+            {
+                let entry = entry?;
+                if is_ephemeral_file(entry.file_name()) {
+                    size += entry.metadata()?.len();
+                }
+            }
+            Ok(size)
+        };
+
+        const NUM_KEYS: u32 = 1000;
+        let inmem = create_test_layer(&tenant, &tline, Lsn(0x10), &ctx).await?;
+        let batch = serialize_batch((0..NUM_KEYS).map(|blknum| {
+            let value = Value::Image(Bytes::from(vec![blknum as u8; 1024]));
+            (test_key(blknum), Lsn(0x10), value)
+        }));
+        inmem.put_batch(batch, &ctx).await?;
+        inmem.freeze(Lsn(0x11)).await;
+
+        let layer = inmem
+            .write_to_disk(&ctx, None, None, tline.l0_flush_global_state.inner())
+            .await?;
+        assert!(layer.is_some());
+
+        let size_before = ephemeral_files_size()?;
+        let reclaimed = inmem.reclaim_ephemeral_file().await;
+        assert!(reclaimed >= NUM_KEYS as u64 * 1024);
+        let size_after = ephemeral_files_size()?;
+        assert!(
+            size_after < size_before,
+            "disk usage did not drop: {size_before} -> {size_after}"
+        );
+        assert_eq!(inmem.size().await?, 0);
+
+        // The layer cannot be read or written to disk anymore. Reads don't find anything, and
+        // leave the LSN for the layers that replace it.
+        let mut reconstruct_state = ValuesReconstructState::new();
+        let outcome = inmem
+            .get_values_reconstruct_data(
+                KeySpace::single(test_key(0)..test_key(1)),
+                Lsn(0x11),
+                &mut reconstruct_state,
+                &ctx,
+            )
+            .await?;
+        assert_eq!(outcome, LayerReadOutcome::Reclaimed);
+        assert!(reconstruct_state.keys.is_empty());
+        inmem
+            .write_to_disk(&ctx, None, None, tline.l0_flush_global_state.inner())
+            .await
+            .unwrap_err();
+
+        // Reclaiming again is a no-op.
+        assert_eq!(inmem.reclaim_ephemeral_file().await, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_reclaims_ephemeral_file() -> anyhow::Result<()> {
+        let (_tenant, tline, ctx) =
+            test_timeline("inmemory_layer_flush_reclaims_ephemeral_file").await?;
+
+        let mut writer = tline.writer().await;
+        writer
+            .put(
+                test_key(0),
+                Lsn(0x10),
+                &Value::Image(test_img("foo at 0x10")),
+                &ctx,
+            )
+            .await?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+
+        // A reader holding on to the layer doesn't keep its ephemeral file around.
+        let open_layer = open_layer(&tline).await?;
+        assert!(open_layer.size().await? > 0);
+        tline.freeze_and_flush().await?;
+        assert_eq!(open_layer.size().await?, 0);
+
+        assert_eq!(
+            tline.get(test_key(0), Lsn(0x10), &ctx).await?,
+            test_img("foo at 0x10")
+        );
+
+        Ok(())
+    }
 }
@@ -139,6 +139,7 @@ pub async fn new(
                 tenant_shard_id,
                 start_key,
                 lsn_range.clone(),
+                None,
                 ctx,
             )
             .await?,
@@ -170,6 +171,7 @@ pub async fn put_value(
                 self.tenant_shard_id,
                 key,
                 self.lsn_range.clone(),
+                None,
                 ctx,
             )
             .await?;
@@ -291,6 +291,7 @@ enum LooksLike {
     let decision = match e {
         ShuttingDown => None,
         _ if task_cancelled => Some(LooksLike::Info),
+        LockTimeout(_) => Some(LooksLike::Info),
         Other(e) => {
             let root_cause = e.root_cause();
 
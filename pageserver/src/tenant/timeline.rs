@@ -4,6 +4,7 @@
 pub(crate) mod detach_ancestor;
 mod eviction_task;
 pub(crate) mod handle;
+pub(crate) mod image_creation_policy;
 mod init;
 pub mod layer_manager;
 pub(crate) mod logical_size;
@@ -27,9 +28,10 @@
     },
     keyspace::{KeySpaceAccum, KeySpaceRandomAccum, SparseKeyPartitioning},
     models::{
-        AtomicAuxFilePolicy, AuxFilePolicy, CompactionAlgorithm, CompactionAlgorithmSettings,
-        DownloadRemoteLayersTaskInfo, DownloadRemoteLayersTaskSpawnRequest, EvictionPolicy,
-        InMemoryLayerInfo, LayerMapInfo, LsnLease, TimelineState,
+        AtomicAuxFilePolicy, AuxFilePolicy, CompactL0Phase1ValueAccess, CompactionAlgorithm,
+        CompactionAlgorithmSettings, DownloadRemoteLayersTaskInfo,
+        DownloadRemoteLayersTaskSpawnRequest, EvictionPolicy, InMemoryLayerInfo, LayerMapInfo,
+        LsnLease, TimelineState,
     },
     reltag::BlockNumber,
     shard::{ShardIdentity, ShardNumber, TenantShardId},
@@ -82,7 +84,7 @@
     disk_usage_eviction_task::finite_f32,
     tenant::storage_layer::{
         AsLayerDesc, DeltaLayerWriter, EvictionError, ImageLayerWriter, InMemoryLayer, Layer,
-        LayerAccessStatsReset, LayerName, ResidentLayer, ValueReconstructState,
+        LayerAccessStatsReset, LayerName, LayerReadOutcome, ResidentLayer, ValueReconstructState,
         ValuesReconstructState,
     },
 };
@@ -128,13 +130,18 @@
 use crate::task_mgr::TaskKind;
 use crate::ZERO_PAGE;
 
+use self::compaction::io_throttle::CompactionIoThrottle;
+use self::compaction::manifest::GcCompactionManifest;
+use self::compaction::{CompactionObserver, CompactionOutcome};
 use self::delete::DeleteTimelineFlow;
 pub(super) use self::eviction_task::EvictionTaskTenantState;
 use self::eviction_task::EvictionTaskTimelineState;
+use self::image_creation_policy::{DeltaThresholdPolicy, ImageCreationPolicy, ImageCreationStats};
 use self::layer_manager::LayerManager;
 use self::logical_size::LogicalSize;
 use self::walreceiver::{WalReceiver, WalReceiverConf};
 
+use super::storage_layer::inmemory_layer::{RollDecision, RollThresholds};
 use super::{
     config::TenantConf, storage_layer::inmemory_layer, storage_layer::LayerVisibilityHint,
     upload_queue::NotInitialized,
@@ -164,7 +171,8 @@ pub(crate) enum FlushLoopState {
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ImageLayerCreationMode {
-    /// Try to create image layers based on `time_for_new_image_layer`. Used in compaction code path.
+    /// Try to create image layers based on `time_for_new_image_layer`, which asks the
+    /// [`ImageCreationPolicy`] of the timeline. Used in compaction code path.
     Try,
     /// Force creating the image layers if possible. For now, no image layers will be created
     /// for metadata keys. Used in compaction code path with force flag enabled.
@@ -199,6 +207,7 @@ pub struct TimelineResources {
     pub timeline_get_throttle: Arc<
         crate::tenant::throttle::Throttle<&'static crate::metrics::tenant_throttling::TimelineGet>,
     >,
+    pub(crate) compaction_io_throttle: Arc<CompactionIoThrottle>,
     pub l0_flush_global_state: l0_flush::L0FlushGlobalState,
 }
 
@@ -405,11 +414,18 @@ pub struct Timeline {
     /// Timeline deletion will acquire both compaction and gc locks in whatever order.
     gc_lock: tokio::sync::Mutex<()>,
 
+    /// Layers that compaction must neither rewrite nor drop, e.g. to preserve them for debugging.
+    /// Set with [`Timeline::pin_layer`].
+    pinned_layers: std::sync::Mutex<HashSet<PersistentLayerKey>>,
+
     /// Cloned from [`super::Tenant::timeline_get_throttle`] on construction.
     timeline_get_throttle: Arc<
         crate::tenant::throttle::Throttle<&'static crate::metrics::tenant_throttling::TimelineGet>,
     >,
 
+    /// Cloned from [`super::Tenant::compaction_io_throttle`] on construction.
+    pub(crate) compaction_io_throttle: Arc<CompactionIoThrottle>,
+
     /// Keep aux directory cache to avoid it's reconstruction on each update
     pub(crate) aux_files: tokio::sync::Mutex<AuxFilesState>,
 
@@ -427,9 +443,26 @@ pub struct Timeline {
     #[cfg(test)]
     pub(crate) extra_test_dense_keyspace: ArcSwap<KeySpace>,
 
+    /// Makes gc-compaction fail right after recording its first checkpoint, as if the pageserver crashed.
+    #[cfg(test)]
+    pub(crate) gc_compaction_fail_after_checkpoint: std::sync::atomic::AtomicBool,
+
+    /// Makes gc-compaction replace the images it retains with garbage, to test its verification.
+    #[cfg(test)]
+    pub(crate) gc_compaction_corrupt_images: std::sync::atomic::AtomicBool,
+
+    /// Makes [`Timeline::compact_shard_ancestors`] also rewrite the layers of the current
+    /// generation. Their local path must differ from the one of the rewritten layer, as it does for
+    /// the layers of [`Timeline::force_create_ancestor_image_layer`].
+    #[cfg(test)]
+    pub(crate) force_shard_ancestor_rewrite: std::sync::atomic::AtomicBool,
+
     pub(crate) l0_flush_global_state: L0FlushGlobalState,
 
     pub(crate) handles: handle::PerTimelineState<crate::page_service::TenantManagerTypes>,
+
+    /// Decides when compaction creates image layers, see [`Self::time_for_new_image_layer`].
+    image_creation_policy: std::sync::RwLock<Arc<dyn ImageCreationPolicy>>,
 }
 
 pub struct WalReceiverInfo {
@@ -457,6 +490,11 @@ pub(crate) struct GcInfo {
 
     /// Whether our branch point is within our ancestor's PITR interval (for cost estimation)
     pub(crate) within_ancestor_pitr: bool,
+
+    /// The keyspace that each child covers with its own image layers below its branch point, as
+    /// published by the child when it updates its layer visibility. Reads on the child never reach
+    /// our layers for these keys.
+    pub(crate) covered_by_children: HashMap<TimelineId, KeySpace>,
 }
 
 impl GcInfo {
@@ -471,6 +509,31 @@ pub(super) fn insert_child(&mut self, child_id: TimelineId, child_lsn: Lsn) {
 
     pub(super) fn remove_child(&mut self, child_id: TimelineId) {
         self.retain_lsns.retain(|i| i.1 != child_id);
+        self.covered_by_children.remove(&child_id);
+    }
+
+    /// Records the keyspace covered by a child. Children that are not branched off from us
+    /// anymore are ignored.
+    pub(super) fn set_child_coverage(&mut self, child_id: TimelineId, covered: KeySpace) {
+        if self.retain_lsns.iter().any(|i| i.1 == child_id) {
+            self.covered_by_children.insert(child_id, covered);
+        }
+    }
+
+    /// The read points for [`LayerMap::get_visibility_with_coverage`]: the branch points of the
+    /// children, with the keyspace they cover, if they published it.
+    pub(super) fn child_read_points(&self) -> Vec<(Lsn, KeySpace)> {
+        self.retain_lsns
+            .iter()
+            .map(|(child_lsn, child_id)| {
+                let covered = self
+                    .covered_by_children
+                    .get(child_id)
+                    .cloned()
+                    .unwrap_or_default();
+                (*child_lsn, covered)
+            })
+            .collect()
     }
 }
 
@@ -526,6 +589,10 @@ pub(crate) enum PageReconstructError {
 
     #[error("{0}")]
     MissingKey(MissingKeyError),
+
+    /// Stored data failed an integrity check
+    #[error("data corruption: {0:#}")]
+    Corruption(anyhow::Error),
 }
 
 impl From<anyhow::Error> for PageReconstructError {
@@ -604,7 +671,7 @@ pub(crate) fn is_stopping(&self) -> bool {
         use PageReconstructError::*;
         match self {
             Cancelled => true,
-            Other(_) | AncestorLsnTimeout(_) | WalRedo(_) | MissingKey(_) => false,
+            Other(_) | AncestorLsnTimeout(_) | WalRedo(_) | MissingKey(_) | Corruption(_) => false,
         }
     }
 }
@@ -742,6 +809,17 @@ pub(crate) enum CompactFlags {
     DryRun,
 }
 
+/// The options of a compaction run.
+#[derive(Clone, Default)]
+pub(crate) struct CompactOptions {
+    pub(crate) flags: EnumSet<CompactFlags>,
+    /// If set, only the layers overlapping this key range are compacted.
+    pub(crate) compact_range: Option<Range<Key>>,
+    /// If set, legacy compaction doesn't start another phase once it has run for this long, and
+    /// reports pending tasks instead.
+    pub(crate) time_budget: Option<Duration>,
+}
+
 impl std::fmt::Debug for Timeline {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "Timeline<{}>", self.timeline_id)
@@ -1481,16 +1559,19 @@ pub(super) async fn maybe_freeze_ephemeral_layer(&self) {
         let checkpoint_distance =
             checkpoint_distance_override.unwrap_or(self.get_checkpoint_distance());
 
+        let layer_decision =
+            open_layer.should_roll(Instant::now(), &self.roll_thresholds(checkpoint_distance));
         if self.should_roll(
             current_size,
-            current_size,
+            layer_decision,
             checkpoint_distance,
             self.get_last_record_lsn(),
             self.last_freeze_at.load(),
-            open_layer.get_opened_at(),
         ) {
             match open_layer.info() {
-                InMemoryLayerInfo::Frozen { lsn_start, lsn_end } => {
+                InMemoryLayerInfo::Frozen {
+                    lsn_start, lsn_end, ..
+                } => {
                     // We may reach this point if the layer was already frozen by not yet flushed: flushing
                     // happens asynchronously in the background.
                     tracing::debug!(
@@ -1522,6 +1603,27 @@ pub(crate) async fn compact(
         flags: EnumSet<CompactFlags>,
         ctx: &RequestContext,
     ) -> Result<bool, CompactionError> {
+        self.compact_with_options(
+            cancel,
+            CompactOptions {
+                flags,
+                compact_range: None,
+                time_budget: self.get_compaction_time_budget(),
+            },
+            ctx,
+        )
+        .await
+        .map(|outcome| outcome.has_pending_tasks)
+    }
+
+    /// Like [`Self::compact`], with the options that can't be expressed as flags. Returns the
+    /// outcome of the compaction, including the work done by the shard ancestor compaction.
+    pub(crate) async fn compact_with_options(
+        self: &Arc<Self>,
+        cancel: &CancellationToken,
+        options: CompactOptions,
+        ctx: &RequestContext,
+    ) -> Result<CompactionOutcome, CompactionError> {
         // most likely the cancellation token is from background task, but in tests it could be the
         // request task as well.
 
@@ -1541,8 +1643,8 @@ pub(crate) async fn compact(
         // compaction task goes over it's period (20s) which is quite often in production.
         let (_guard, _permit) = tokio::select! {
             tuple = prepare => { tuple },
-            _ = self.cancel.cancelled() => return Ok(false),
-            _ = cancel.cancelled() => return Ok(false),
+            _ = self.cancel.cancelled() => return Ok(CompactionOutcome::default()),
+            _ = cancel.cancelled() => return Ok(CompactionOutcome::default()),
         };
 
         let last_record_lsn = self.get_last_record_lsn();
@@ -1550,15 +1652,15 @@ pub(crate) async fn compact(
         // Last record Lsn could be zero in case the timeline was just created
         if !last_record_lsn.is_valid() {
             warn!("Skipping compaction for potentially just initialized timeline, it has invalid last record lsn: {last_record_lsn}");
-            return Ok(false);
+            return Ok(CompactionOutcome::default());
         }
 
         match self.get_compaction_algorithm_settings().kind {
             CompactionAlgorithm::Tiered => {
                 self.compact_tiered(cancel, ctx).await?;
-                Ok(false)
+                Ok(CompactionOutcome::default())
             }
-            CompactionAlgorithm::Legacy => self.compact_legacy(cancel, flags, ctx).await,
+            CompactionAlgorithm::Legacy => self.compact_legacy(cancel, options, None, ctx).await,
         }
     }
 
@@ -1820,18 +1922,25 @@ pub(crate) async fn layer_map_info(
     ) -> Result<LayerMapInfo, layer_manager::Shutdown> {
         let guard = self.layers.read().await;
         let layer_map = guard.layer_map()?;
-        let mut in_memory_layers = Vec::with_capacity(layer_map.frozen_layers.len() + 1);
-        if let Some(open_layer) = &layer_map.open_layer {
-            in_memory_layers.push(open_layer.info());
-        }
-        for frozen_layer in &layer_map.frozen_layers {
-            in_memory_layers.push(frozen_layer.info());
-        }
+        let inmem_layers = layer_map
+            .open_layer
+            .iter()
+            .chain(layer_map.frozen_layers.iter())
+            .cloned()
+            .collect::<Vec<_>>();
 
         let historic_layers = layer_map
             .iter_historic_layers()
             .map(|desc| guard.get_from_desc(&desc).info(reset))
             .collect();
+        drop(guard);
+
+        // The stats need the lock of each in-memory layer: collect them without holding the
+        // layer map lock.
+        let mut in_memory_layers = Vec::with_capacity(inmem_layers.len());
+        for layer in inmem_layers {
+            in_memory_layers.push(layer.info_with_stats().await);
+        }
 
         Ok(LayerMapInfo {
             in_memory_layers,
@@ -1853,6 +1962,31 @@ pub(crate) async fn download_layer(
         Ok(Some(true))
     }
 
+    /// Pin one layer, so that compaction neither rewrites nor drops it.
+    ///
+    /// Returns `Ok(None)` in the case where the layer could not be found by its `layer_file_name`,
+    /// and `Ok(Some(false))` if it was already pinned.
+    pub(crate) async fn pin_layer(
+        &self,
+        layer_file_name: &LayerName,
+    ) -> anyhow::Result<Option<bool>> {
+        let Some(layer) = self.find_layer(layer_file_name).await? else {
+            return Ok(None);
+        };
+        let key = layer.layer_desc().key();
+        Ok(Some(self.pinned_layers.lock().unwrap().insert(key)))
+    }
+
+    /// Unpin a layer pinned with [`Self::pin_layer`]. Returns whether it was pinned.
+    pub(crate) fn unpin_layer(&self, layer_file_name: &LayerName) -> bool {
+        let key = compaction::manifest::key_of_layer_name(layer_file_name);
+        self.pinned_layers.lock().unwrap().remove(&key)
+    }
+
+    pub(crate) fn is_layer_pinned(&self, key: &PersistentLayerKey) -> bool {
+        self.pinned_layers.lock().unwrap().contains(key)
+    }
+
     /// Evict just one layer.
     ///
     /// Returns `Ok(None)` in the case where the layer could not be found by its `layer_file_name`.
@@ -1880,14 +2014,23 @@ pub(crate) async fn evict_layer(
         }
     }
 
+    /// The limits for rolling the open layer, see [`InMemoryLayer::should_roll`].
+    fn roll_thresholds(&self, checkpoint_distance: u64) -> RollThresholds {
+        RollThresholds {
+            max_size: checkpoint_distance,
+            max_age: self.get_checkpoint_timeout(),
+        }
+    }
+
+    /// Decides whether to roll the open layer, given the decision based on the layer alone, see
+    /// [`InMemoryLayer::should_roll`].
     fn should_roll(
         &self,
         layer_size: u64,
-        projected_layer_size: u64,
+        layer_decision: RollDecision,
         checkpoint_distance: u64,
         projected_lsn: Lsn,
         last_freeze_at: Lsn,
-        opened_at: Instant,
     ) -> bool {
         let distance = projected_lsn.widening_sub(last_freeze_at);
 
@@ -1906,24 +2049,26 @@ fn should_roll(
             );
 
             true
-        } else if projected_layer_size >= checkpoint_distance {
-            info!(
-                "Will roll layer at {} with layer size {} due to layer size ({})",
-                projected_lsn, layer_size, projected_layer_size
-            );
+        } else {
+            match layer_decision {
+                RollDecision::RollDueToSize => {
+                    info!(
+                        "Will roll layer at {} with layer size {} due to layer size",
+                        projected_lsn, layer_size
+                    );
 
-            true
-        } else if distance > 0 && opened_at.elapsed() >= self.get_checkpoint_timeout() {
-            info!(
-                "Will roll layer at {} with layer size {} due to time since first write to the layer ({:?})",
-                projected_lsn,
-                layer_size,
-                opened_at.elapsed()
-            );
+                    true
+                }
+                RollDecision::RollDueToAge if distance > 0 => {
+                    info!(
+                        "Will roll layer at {} with layer size {} due to time since first write to the layer",
+                        projected_lsn, layer_size
+                    );
 
-            true
-        } else {
-            false
+                    true
+                }
+                RollDecision::RollDueToAge | RollDecision::Keep => false,
+            }
         }
     }
 }
@@ -1999,6 +2144,109 @@ fn get_compaction_threshold(&self) -> usize {
             .unwrap_or(self.conf.default_tenant_conf.compaction_threshold)
     }
 
+    fn get_compaction_max_versions_per_key(&self) -> usize {
+        let tenant_conf = self.tenant_conf.load();
+        tenant_conf
+            .tenant_conf
+            .compaction_max_versions_per_key
+            .unwrap_or(
+                self.conf
+                    .default_tenant_conf
+                    .compaction_max_versions_per_key,
+            )
+    }
+
+    fn get_compaction_min_hole_coverage_size(&self) -> usize {
+        let tenant_conf = self.tenant_conf.load();
+        tenant_conf
+            .tenant_conf
+            .compaction_min_hole_coverage_size
+            .unwrap_or(
+                self.conf
+                    .default_tenant_conf
+                    .compaction_min_hole_coverage_size,
+            )
+    }
+
+    fn get_compaction_oversize_warn_multiplier(&self) -> u64 {
+        let tenant_conf = self.tenant_conf.load();
+        tenant_conf
+            .tenant_conf
+            .compaction_oversize_warn_multiplier
+            .unwrap_or(
+                self.conf
+                    .default_tenant_conf
+                    .compaction_oversize_warn_multiplier,
+            )
+    }
+
+    fn get_compaction_align_to_partitions(&self) -> bool {
+        let tenant_conf = self.tenant_conf.load();
+        tenant_conf
+            .tenant_conf
+            .compaction_align_to_partitions
+            .unwrap_or(self.conf.default_tenant_conf.compaction_align_to_partitions)
+    }
+
+    fn get_compaction_upload_concurrency(&self) -> usize {
+        let tenant_conf = self.tenant_conf.load();
+        tenant_conf
+            .tenant_conf
+            .compaction_upload_concurrency
+            .unwrap_or(self.conf.default_tenant_conf.compaction_upload_concurrency)
+    }
+
+    fn get_gc_compaction_verify_every_nth_key(&self) -> u64 {
+        let tenant_conf = self.tenant_conf.load();
+        tenant_conf
+            .tenant_conf
+            .gc_compaction_verify_every_nth_key
+            .unwrap_or(
+                self.conf
+                    .default_tenant_conf
+                    .gc_compaction_verify_every_nth_key,
+            )
+    }
+
+    fn get_compaction_layer_lock_timeout(&self) -> Option<Duration> {
+        let tenant_conf = self.tenant_conf.load();
+        tenant_conf
+            .tenant_conf
+            .compaction_layer_lock_timeout
+            .or(self.conf.default_tenant_conf.compaction_layer_lock_timeout)
+    }
+
+    fn get_compaction_time_budget(&self) -> Option<Duration> {
+        let tenant_conf = self.tenant_conf.load();
+        tenant_conf
+            .tenant_conf
+            .compaction_time_budget
+            .or(self.conf.default_tenant_conf.compaction_time_budget)
+    }
+
+    fn get_l0_compaction_delta_size_limit(&self) -> Option<u64> {
+        let tenant_conf = self.tenant_conf.load();
+        tenant_conf
+            .tenant_conf
+            .l0_compaction_delta_size_limit
+            .or(self.conf.default_tenant_conf.l0_compaction_delta_size_limit)
+    }
+
+    pub(crate) fn get_compact_level0_phase1_value_access(&self) -> CompactL0Phase1ValueAccess {
+        let tenant_conf = self.tenant_conf.load();
+        tenant_conf
+            .tenant_conf
+            .compact_level0_phase1_value_access
+            .clone()
+            .or_else(|| {
+                self.conf
+                    .default_tenant_conf
+                    .compact_level0_phase1_value_access
+                    .clone()
+            })
+            .unwrap_or_else(|| self.conf.compact_level0_phase1_value_access.clone())
+    }
+
     fn get_image_creation_threshold(&self) -> usize {
         let tenant_conf = self.tenant_conf.load();
         tenant_conf
@@ -2214,9 +2462,12 @@ pub(super) fn new(
                 compaction_lock: tokio::sync::Mutex::default(),
                 gc_lock: tokio::sync::Mutex::default(),
 
+                pinned_layers: std::sync::Mutex::default(),
+
                 standby_horizon: AtomicLsn::new(0),
 
                 timeline_get_throttle: resources.timeline_get_throttle,
+                compaction_io_throttle: resources.compaction_io_throttle,
 
                 aux_files: tokio::sync::Mutex::new(AuxFilesState {
                     dir: None,
@@ -2230,9 +2481,20 @@ pub(super) fn new(
                 #[cfg(test)]
                 extra_test_dense_keyspace: ArcSwap::new(Arc::new(KeySpace::default())),
 
+                #[cfg(test)]
+                gc_compaction_fail_after_checkpoint: std::sync::atomic::AtomicBool::new(false),
+
+                #[cfg(test)]
+                gc_compaction_corrupt_images: std::sync::atomic::AtomicBool::new(false),
+
+                #[cfg(test)]
+                force_shard_ancestor_rewrite: std::sync::atomic::AtomicBool::new(false),
+
                 l0_flush_global_state: resources.l0_flush_global_state,
 
                 handles: Default::default(),
+
+                image_creation_policy: std::sync::RwLock::new(Arc::new(DeltaThresholdPolicy)),
             };
 
             if aux_file_policy == Some(AuxFilePolicy::V1) {
@@ -2393,6 +2655,8 @@ pub(super) async fn load_layer_map(
                 let discovered = init::scan_timeline_dir(&timeline_path)?;
                 let mut discovered_layers = Vec::with_capacity(discovered.len());
                 let mut unrecognized_files = Vec::new();
+                // Local-only layers written by an interrupted gc-compaction, which it will resume from.
+                let mut gc_compaction_layers = HashSet::new();
 
                 let mut path = timeline_path;
 
@@ -2408,6 +2672,24 @@ pub(super) async fn load_layer_map(
                                 .fatal_err("Removing .old file");
                             continue;
                         }
+                        Discovered::GcCompactionManifest(path) => {
+                            match GcCompactionManifest::read(&path) {
+                                Ok(manifest) => gc_compaction_layers.extend(
+                                    manifest
+                                        .into_iter()
+                                        .flat_map(|m| m.produced_layers)
+                                        .map(|layer| layer.name),
+                                ),
+                                Err(e) => {
+                                    // the layers it recorded are removed below as local-only layers
+                                    warn!("removing unreadable gc-compaction manifest: {e:#}");
+                                    std::fs::remove_file(path)
+                                        .or_else(fs_ext::ignore_not_found)
+                                        .fatal_err("Removing gc-compaction manifest");
+                                }
+                            }
+                            continue;
+                        }
                         Discovered::Unknown(file_name) => {
                             // we will later error if there are any
                             unrecognized_files.push(file_name);
@@ -2453,6 +2735,10 @@ pub(super) async fn load_layer_map(
                             continue;
                         }
                         Err(DismissedLayer::LocalOnly(local)) => {
+                            if gc_compaction_layers.contains(&name) {
+                                tracing::info!(layer=%name, "keeping local-only layer of an interrupted gc-compaction");
+                                continue;
+                            }
                             init::cleanup_local_only_file(&name, &local)?;
                             // this file never existed remotely, we will have to do rework
                             continue;
@@ -3230,7 +3516,7 @@ async fn get_vectored_reconstruct_data_timeline(
 
             if let Some((layer_to_read, keyspace_to_read, lsn_range)) = fringe.next_layer() {
                 let next_cont_lsn = lsn_range.start;
-                layer_to_read
+                let outcome = layer_to_read
                     .get_values_reconstruct_data(
                         keyspace_to_read.clone(),
                         lsn_range,
@@ -3240,6 +3526,10 @@ async fn get_vectored_reconstruct_data_timeline(
                     .await?;
 
                 unmapped_keyspace = keyspace_to_read;
+                if outcome == LayerReadOutcome::Reclaimed {
+                    // Plan again from the same LSN, with the layers that replaced it.
+                    continue;
+                }
                 cont_lsn = next_cont_lsn;
 
                 reconstruct_state.on_layer_visited(&layer_to_read);
@@ -3604,6 +3894,8 @@ async fn flush_frozen_layer(
                     &rel_partition,
                     self.initdb_lsn,
                     ImageLayerCreationMode::Initial,
+                    None,
+                    None,
                     ctx,
                 )
                 .await?,
@@ -3622,6 +3914,8 @@ async fn flush_frozen_layer(
                         &metadata_partition.into_dense(),
                         self.initdb_lsn,
                         ImageLayerCreationMode::Initial,
+                        None,
+                        None,
                         ctx,
                     )
                     .await?,
@@ -3676,6 +3970,10 @@ async fn flush_frozen_layer(
             // release lock on 'layers'
         };
 
+        // Reads that planned to visit the frozen layer before it was replaced above plan again
+        // with the new layer map, so its ephemeral file can go right away.
+        frozen_layer.reclaim_ephemeral_file().await;
+
         // Backpressure mechanism: wait with continuation of the flush loop until we have uploaded all layer files.
         // This makes us refuse ingest until the new layers have been persisted to the remote.
         self.remote_client
@@ -3778,7 +4076,12 @@ async fn create_delta_layer(
         let ctx = ctx.attached_child();
         let work = async move {
             let Some((desc, path)) = frozen_layer
-                .write_to_disk(&ctx, key_range, self_clone.l0_flush_global_state.inner())
+                .write_to_disk(
+                    &ctx,
+                    key_range,
+                    None,
+                    self_clone.l0_flush_global_state.inner(),
+                )
                 .await?
             else {
                 return Ok(None);
@@ -3872,10 +4175,46 @@ async fn repartition(
     // Is it time to create a new image layer for the given partition?
     async fn time_for_new_image_layer(&self, partition: &KeySpace, lsn: Lsn) -> bool {
         let threshold = self.get_image_creation_threshold();
+        let Some(max_deltas) = self
+            .max_deltas_above_images(partition, lsn, threshold)
+            .await
+        else {
+            return false;
+        };
 
+        let stats = ImageCreationStats {
+            lsn,
+            max_deltas,
+            image_creation_threshold: threshold,
+        };
+        let policy = Arc::clone(&*self.image_creation_policy.read().unwrap());
+        let create = policy.should_create(partition, &stats);
+        if !create {
+            debug!(
+                max_deltas,
+                "image creation policy declined, none of the partitioned ranges had >= {threshold} deltas"
+            );
+        }
+        create
+    }
+
+    /// Replace the policy deciding when compaction creates image layers.
+    #[cfg(test)]
+    pub(crate) fn set_image_creation_policy(&self, policy: Arc<dyn ImageCreationPolicy>) {
+        *self.image_creation_policy.write().unwrap() = policy;
+    }
+
+    /// The height of the tallest stack of delta layers above the latest image layer in any part
+    /// of `partition`, not counting beyond `limit`. `None` if the layer map was shut down.
+    async fn max_deltas_above_images(
+        &self,
+        partition: &KeySpace,
+        lsn: Lsn,
+        limit: usize,
+    ) -> Option<usize> {
         let guard = self.layers.read().await;
         let Ok(layers) = guard.layer_map() else {
-            return false;
+            return None;
         };
 
         let mut max_deltas = 0;
@@ -3900,26 +4239,21 @@ async fn time_for_new_image_layer(&self, partition: &KeySpace, lsn: Lsn) -> bool
                 // are some delta layers *later* than current 'lsn', if more WAL was processed and flushed
                 // after we read last_record_lsn, which is passed here in the 'lsn' argument.
                 if img_lsn < lsn {
-                    let num_deltas =
-                        layers.count_deltas(&img_range, &(img_lsn..lsn), Some(threshold));
+                    let num_deltas = layers.count_deltas(&img_range, &(img_lsn..lsn), Some(limit));
 
                     max_deltas = max_deltas.max(num_deltas);
-                    if num_deltas >= threshold {
+                    if num_deltas >= limit {
                         debug!(
                             "key range {}-{}, has {} deltas on this timeline in LSN range {}..{}",
                             img_range.start, img_range.end, num_deltas, img_lsn, lsn
                         );
-                        return true;
+                        return Some(num_deltas);
                     }
                 }
             }
         }
 
-        debug!(
-            max_deltas,
-            "none of the partitioned ranges had >= {threshold} deltas"
-        );
-        false
+        Some(max_deltas)
     }
 
     /// Create image layers for Postgres data. Assumes the caller passes a partition that is not too large,
@@ -4170,6 +4504,8 @@ async fn create_image_layers(
         partitioning: &KeyPartitioning,
         lsn: Lsn,
         mode: ImageLayerCreationMode,
+        compact_range: Option<&Range<Key>>,
+        observer: Option<&dyn CompactionObserver>,
         ctx: &RequestContext,
     ) -> Result<Vec<ResidentLayer>, CreateImageLayersError> {
         let timer = self.metrics.create_images_time_histo.start_timer();
@@ -4194,6 +4530,10 @@ async fn create_image_layers(
             }
 
             let img_range = start..partition.ranges.last().unwrap().end;
+            if compact_range.is_some_and(|range| !partition.overlaps(range)) {
+                start = img_range.end;
+                continue;
+            }
             let compact_metadata = partition.overlaps(&Key::metadata_key_range());
             if compact_metadata {
                 for range in &partition.ranges {
@@ -4290,6 +4630,12 @@ async fn create_image_layers(
             }
         }
 
+        if let Some(observer) = observer {
+            for layer in &image_layers {
+                observer.on_layer_produced(layer.layer_desc());
+            }
+        }
+
         let mut guard = self.layers.write().await;
 
         // FIXME: we could add the images to be uploaded *before* returning from here, but right
@@ -4428,11 +4774,28 @@ fn drop(&mut self) {
 pub(crate) enum CompactionError {
     #[error("The timeline or pageserver is shutting down")]
     ShuttingDown,
+    /// The layer map lock could not be taken within the `compaction_layer_lock_timeout`.
+    #[error("timed out after {0:?} waiting for the layer map lock")]
+    LockTimeout(Duration),
     /// Compaction cannot be done right now; page reconstruction and so on.
     #[error(transparent)]
     Other(anyhow::Error),
 }
 
+impl CompactionError {
+    /// Adds context to [`CompactionError::Other`]. Shutdowns are passed through unchanged.
+    pub(crate) fn context<C>(self, context: C) -> Self
+    where
+        C: std::fmt::Display + Send + Sync + 'static,
+    {
+        match self {
+            CompactionError::ShuttingDown => CompactionError::ShuttingDown,
+            CompactionError::LockTimeout(timeout) => CompactionError::LockTimeout(timeout),
+            CompactionError::Other(e) => CompactionError::Other(e.context(context)),
+        }
+    }
+}
+
 impl From<CollectKeySpaceError> for CompactionError {
     fn from(err: CollectKeySpaceError) -> Self {
         match err {
@@ -4486,7 +4849,7 @@ fn from(_: layer_manager::Shutdown) -> Self {
 }
 
 #[serde_as]
-#[derive(serde::Serialize)]
+#[derive(Debug, serde::Serialize)]
 struct RecordedDuration(#[serde_as(as = "serde_with::DurationMicroSeconds")] Duration);
 
 #[derive(Default)]
@@ -4645,12 +5008,30 @@ async fn rewrite_layers(
     }
 
     /// Schedules the uploads of the given image layers
-    fn upload_new_image_layers(
+    /// Schedules the upload of the image layers created by a compaction. If the
+    /// `compaction_upload_concurrency` tenant config is set, waits for the scheduled uploads to
+    /// complete whenever that many layers are in flight.
+    async fn upload_new_image_layers(
         self: &Arc<Self>,
         new_images: impl IntoIterator<Item = ResidentLayer>,
-    ) -> Result<(), super::upload_queue::NotInitialized> {
+    ) -> Result<(), CompactionError> {
+        let concurrency = self.get_compaction_upload_concurrency();
+        let mut in_flight = 0;
         for layer in new_images {
+            if concurrency > 0 && in_flight >= concurrency {
+                self.remote_client
+                    .wait_completion()
+                    .await
+                    .map_err(|e| match e {
+                        WaitCompletionError::NotInitialized(ni) => CompactionError::from(ni),
+                        WaitCompletionError::UploadQueueShutDownOrStopped => {
+                            CompactionError::ShuttingDown
+                        }
+                    })?;
+                in_flight = 0;
+            }
             self.remote_client.schedule_layer_file_upload(layer)?;
+            in_flight += 1;
         }
         // should any new image layer been created, not uploading index_part will
         // result in a mismatch between remote_physical_size and layermap calculated
@@ -5407,6 +5788,58 @@ pub(super) async fn force_create_image_layer(
         Ok(())
     }
 
+    /// Force create an image layer as if it was written by the shard `shard` before a shard split,
+    /// and place it into the layer map. The layer gets a local path that no layer written by this
+    /// timeline can have, so that it can be rewritten in the same generation.
+    #[cfg(test)]
+    pub(super) async fn force_create_ancestor_image_layer(
+        self: &Arc<Timeline>,
+        lsn: Lsn,
+        mut images: Vec<(Key, Bytes)>,
+        shard: ShardIndex,
+        ctx: &RequestContext,
+    ) -> anyhow::Result<()> {
+        assert!(lsn <= self.get_last_record_lsn());
+        images.sort_unstable_by(|(ka, _), (kb, _)| ka.cmp(kb));
+        let min_key = *images.first().map(|(k, _)| k).unwrap();
+        let end_key = images.last().map(|(k, _)| k).unwrap().next();
+        let mut image_layer_writer = ImageLayerWriter::new(
+            self.conf,
+            self.timeline_id,
+            self.tenant_shard_id,
+            &(min_key..end_key),
+            lsn,
+            ctx,
+        )
+        .await?;
+        for (key, img) in images {
+            image_layer_writer.put_image(key, img, ctx).await?;
+        }
+        let written = image_layer_writer.finish(self, ctx).await?;
+
+        let local_path = camino::Utf8PathBuf::from(format!("{}.ancestor", written.local_path()));
+        std::fs::rename(written.local_path(), &local_path)?;
+        let metadata = crate::tenant::remote_timeline_client::index::LayerFileMetadata::new(
+            written.metadata().file_size,
+            self.generation,
+            shard,
+        );
+        let image_layer = Layer::for_resident(
+            self.conf,
+            self,
+            local_path,
+            written.layer_desc().layer_name(),
+            metadata,
+        );
+
+        {
+            let mut guard = self.layers.write().await;
+            guard.open_mut().unwrap().force_insert_layer(image_layer);
+        }
+
+        Ok(())
+    }
+
     /// Force create a delta layer and place it into the layer map.
     ///
     /// DO NOT use this function directly. Use [`Tenant::branch_timeline_test_with_layers`]
@@ -5464,6 +5897,7 @@ pub fn overlaps_with<T: Ord>(a: &Range<T>, b: &Range<T>) -> bool {
             self.tenant_shard_id,
             deltas.key_range.start,
             deltas.lsn_range,
+            None,
             ctx,
         )
         .await?;
@@ -5544,6 +5978,9 @@ pub(crate) struct TimelineWriterState {
     max_lsn: Option<Lsn>,
     // Cached details of the last freeze. Avoids going trough the atomic/lock on every put.
     cached_last_freeze_at: Lsn,
+    // Layer size limit due to dirty data pressure, as returned by the last write to the layer
+    // which published its size. Overrides the checkpoint distance, like in the background tick.
+    checkpoint_distance_override: Option<u64>,
 }
 
 impl TimelineWriterState {
@@ -5554,6 +5991,7 @@ fn new(open_layer: Arc<InMemoryLayer>, current_size: u64, last_freeze_at: Lsn) -
             prev_lsn: None,
             max_lsn: None,
             cached_last_freeze_at: last_freeze_at,
+            checkpoint_distance_override: None,
         }
     }
 }
@@ -5673,13 +6111,22 @@ fn get_open_layer_action(&self, lsn: Lsn, new_value_size: u64) -> OpenLayerActio
             return OpenLayerAction::None;
         }
 
+        let checkpoint_distance = state
+            .checkpoint_distance_override
+            .unwrap_or(self.get_checkpoint_distance());
+
+        // The layer is not written yet, so decide based on its size after the write.
+        let layer_decision = self.tl.roll_thresholds(checkpoint_distance).decide(
+            state.current_size + new_value_size,
+            state.open_layer.get_opened_at(),
+            Instant::now(),
+        );
         if self.tl.should_roll(
             state.current_size,
-            state.current_size + new_value_size,
-            self.get_checkpoint_distance(),
+            layer_decision,
+            checkpoint_distance,
             lsn,
             state.cached_last_freeze_at,
-            state.open_layer.get_opened_at(),
         ) {
             OpenLayerAction::Roll
         } else {
@@ -5697,7 +6144,10 @@ pub(crate) async fn put_batch(
             return Ok(());
         }
 
-        let serialized_batch = inmemory_layer::SerializedBatch::from_values(batch);
+        let serialized_batch = inmemory_layer::SerializedBatch::from_values(
+            batch,
+            self.conf.ephemeral_value_checksums,
+        );
         let batch_max_lsn = serialized_batch.max_lsn;
         let buf_size: u64 = serialized_batch.raw.len() as u64;
 
@@ -5708,7 +6158,7 @@ pub(crate) async fn put_batch(
 
         let res = layer.put_batch(serialized_batch, ctx).await;
 
-        if res.is_ok() {
+        if let Ok(size_override) = res {
             // Update the current size only when the entire write was ok.
             // In case of failures, we may have had partial writes which
             // render the size tracking out of sync. That's ok because
@@ -5719,9 +6169,13 @@ pub(crate) async fn put_batch(
             state.current_size += buf_size;
             state.prev_lsn = Some(batch_max_lsn);
             state.max_lsn = std::cmp::max(state.max_lsn, Some(batch_max_lsn));
+            if size_override.is_some() {
+                // Roll at the next batch if we are above the limit
+                state.checkpoint_distance_override = size_override;
+            }
         }
 
-        res
+        res.map(|_| ())
     }
 
     #[cfg(test)]
@@ -5791,7 +6245,7 @@ mod tests {
         tenant::{
             harness::{test_img, TenantHarness},
             layer_map::LayerMap,
-            storage_layer::{Layer, LayerName},
+            storage_layer::{Layer, LayerName, LayerVisibilityHint},
             timeline::{DeltaLayerTestDesc, EvictionError},
             Timeline,
         },
@@ -5887,6 +6341,208 @@ async fn test_heatmap_generation() {
         }
     }
 
+    #[tokio::test]
+    async fn test_recompute_layer_visibility() {
+        let harness = TenantHarness::create("recompute_layer_visibility")
+            .await
+            .unwrap();
+
+        let covered_key = Key::from_hex("620000000033333333444444445500000000").unwrap();
+        let visible_key = Key::from_hex("720000000033333333444444445500000000").unwrap();
+        let delta_layers = vec![
+            DeltaLayerTestDesc::new_with_inferred_key_range(
+                Lsn(0x10)..Lsn(0x20),
+                vec![(covered_key, Lsn(0x11), Value::Image(test_img("foo")))],
+            ),
+            DeltaLayerTestDesc::new_with_inferred_key_range(
+                Lsn(0x20)..Lsn(0x30),
+                vec![(covered_key, Lsn(0x21), Value::Image(test_img("foo")))],
+            ),
+            DeltaLayerTestDesc::new_with_inferred_key_range(
+                Lsn(0x10)..Lsn(0x20),
+                vec![(visible_key, Lsn(0x11), Value::Image(test_img("foo")))],
+            ),
+        ];
+        // Covers both deltas of `covered_key`
+        let image_layers = vec![(Lsn(0x40), vec![(covered_key, test_img("bar"))])];
+
+        let (tenant, ctx) = harness.load().await;
+        let timeline = tenant
+            .create_test_timeline_with_layers(
+                TimelineId::generate(),
+                Lsn(0x10),
+                14,
+                &ctx,
+                delta_layers,
+                image_layers,
+                Lsn(0x100),
+            )
+            .await
+            .unwrap();
+
+        let summary = timeline.recompute_layer_visibility().await.unwrap();
+        let num_layers = timeline
+            .layers
+            .read()
+            .await
+            .layer_map()
+            .unwrap()
+            .iter_historic_layers()
+            .count();
+        assert_eq!(summary.covered, 2);
+        assert_eq!(summary.visible + summary.covered, num_layers);
+    }
+
+    #[tokio::test]
+    async fn test_custom_image_creation_policy() {
+        use super::image_creation_policy::{ImageCreationPolicy, ImageCreationStats};
+        use pageserver_api::keyspace::KeySpace;
+        use std::sync::Arc;
+
+        struct AlwaysCreate;
+
+        impl ImageCreationPolicy for AlwaysCreate {
+            fn should_create(&self, _partition: &KeySpace, _stats: &ImageCreationStats) -> bool {
+                true
+            }
+        }
+
+        let harness = TenantHarness::create("custom_image_creation_policy")
+            .await
+            .unwrap();
+
+        let key = Key::from_hex("620000000033333333444444445500000000").unwrap();
+        // Fewer deltas than the default image creation threshold
+        let delta_layers = vec![
+            DeltaLayerTestDesc::new_with_inferred_key_range(
+                Lsn(0x10)..Lsn(0x20),
+                vec![(key, Lsn(0x11), Value::Image(test_img("foo")))],
+            ),
+            DeltaLayerTestDesc::new_with_inferred_key_range(
+                Lsn(0x20)..Lsn(0x30),
+                vec![(key, Lsn(0x21), Value::Image(test_img("bar")))],
+            ),
+        ];
+
+        let (tenant, ctx) = harness.load().await;
+        let timeline = tenant
+            .create_test_timeline_with_layers(
+                TimelineId::generate(),
+                Lsn(0x10),
+                14,
+                &ctx,
+                delta_layers,
+                vec![],
+                Lsn(0x40),
+            )
+            .await
+            .unwrap();
+        assert!(timeline.get_image_creation_threshold() > 2);
+
+        let partition = KeySpace::single(key..key.next());
+        assert!(
+            !timeline
+                .time_for_new_image_layer(&partition, Lsn(0x40))
+                .await
+        );
+
+        timeline.set_image_creation_policy(Arc::new(AlwaysCreate));
+        assert!(
+            timeline
+                .time_for_new_image_layer(&partition, Lsn(0x40))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_layer_visibility_covered_by_child() {
+        let harness = TenantHarness::create("layer_visibility_covered_by_child")
+            .await
+            .unwrap();
+
+        let covered_key = Key::from_hex("620000000033333333444444445500000000").unwrap();
+        let visible_key = Key::from_hex("720000000033333333444444445500000000").unwrap();
+        // Only read through the branch point at 0x30: the parent's own reads stop at its image layer.
+        let delta_layers = vec![
+            DeltaLayerTestDesc::new_with_inferred_key_range(
+                Lsn(0x10)..Lsn(0x20),
+                vec![(covered_key, Lsn(0x11), Value::Image(test_img("foo")))],
+            ),
+            DeltaLayerTestDesc::new_with_inferred_key_range(
+                Lsn(0x10)..Lsn(0x20),
+                vec![(visible_key, Lsn(0x11), Value::Image(test_img("foo")))],
+            ),
+        ];
+        let image_layers = vec![(
+            Lsn(0x50),
+            vec![
+                (covered_key, test_img("bar")),
+                (visible_key, test_img("bar")),
+            ],
+        )];
+
+        let (tenant, ctx) = harness.load().await;
+        let parent = tenant
+            .create_test_timeline_with_layers(
+                TimelineId::generate(),
+                Lsn(0x10),
+                14,
+                &ctx,
+                delta_layers,
+                image_layers,
+                Lsn(0x100),
+            )
+            .await
+            .unwrap();
+
+        async fn delta_visibility(timeline: &Timeline, key: Key) -> LayerVisibilityHint {
+            let guard = timeline.layers.read().await;
+            let desc = guard
+                .layer_map()
+                .unwrap()
+                .iter_historic_layers()
+                .find(|desc| desc.is_delta() && desc.get_key_range().contains(&key))
+                .unwrap();
+            guard.get_from_desc(&desc).visibility()
+        }
+
+        // The child only covers `covered_key` with its own image layer.
+        let child = tenant
+            .branch_timeline_test_with_layers(
+                &parent,
+                TimelineId::generate(),
+                Some(Lsn(0x30)),
+                &ctx,
+                Vec::new(),
+                vec![(Lsn(0x40), vec![(covered_key, test_img("baz"))])],
+                Lsn(0x100),
+            )
+            .await
+            .unwrap();
+
+        // Until the child publishes its coverage, the whole branch point is assumed to be read.
+        parent.recompute_layer_visibility().await.unwrap();
+        assert_eq!(
+            delta_visibility(&parent, covered_key).await,
+            LayerVisibilityHint::Visible
+        );
+        assert_eq!(
+            delta_visibility(&parent, visible_key).await,
+            LayerVisibilityHint::Visible
+        );
+
+        child.recompute_layer_visibility().await.unwrap();
+        parent.recompute_layer_visibility().await.unwrap();
+        assert_eq!(
+            delta_visibility(&parent, covered_key).await,
+            LayerVisibilityHint::Covered
+        );
+        assert_eq!(
+            delta_visibility(&parent, visible_key).await,
+            LayerVisibilityHint::Visible
+        );
+    }
+
     #[tokio::test]
     async fn two_layer_eviction_attempts_at_the_same_time() {
         let harness = TenantHarness::create("two_layer_eviction_attempts_at_the_same_time")
@@ -4,36 +4,42 @@
 //!
 //! The old legacy algorithm is implemented directly in `timeline.rs`.
 
-use std::collections::{BinaryHeap, HashSet};
+pub(crate) mod io_throttle;
+pub(crate) mod manifest;
+
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
 use std::ops::{Deref, Range};
 use std::sync::Arc;
 
 use super::layer_manager::LayerManager;
 use super::{
-    CompactFlags, CreateImageLayersError, DurationRecorder, ImageLayerCreationMode,
+    CompactFlags, CompactOptions, CreateImageLayersError, DurationRecorder, ImageLayerCreationMode,
     RecordedDuration, Timeline,
 };
 
 use anyhow::{anyhow, Context};
 use bytes::Bytes;
-use enumset::EnumSet;
 use fail::fail_point;
+use futures::{Future, Stream, StreamExt};
 use itertools::Itertools;
 use pageserver_api::key::KEY_SIZE;
 use pageserver_api::keyspace::ShardedRange;
-use pageserver_api::shard::{ShardCount, ShardIdentity, TenantShardId};
+use pageserver_api::models::{CompactL0BypassPageCacheValidation, CompactL0Phase1ValueAccess};
+use pageserver_api::shard::{ShardCount, ShardIdentity, ShardNumber, TenantShardId};
 use serde::Serialize;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, info, info_span, trace, warn, Instrument};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 use utils::id::TimelineId;
 
 use crate::context::{AccessStatsBehavior, RequestContext, RequestContextBuilder};
 use crate::page_cache;
 use crate::tenant::config::defaults::{DEFAULT_CHECKPOINT_DISTANCE, DEFAULT_COMPACTION_THRESHOLD};
+use crate::tenant::layer_map::LayerMap;
 use crate::tenant::remote_timeline_client::WaitCompletionError;
 use crate::tenant::storage_layer::merge_iterator::MergeIterator;
 use crate::tenant::storage_layer::{
-    AsLayerDesc, PersistentLayerDesc, PersistentLayerKey, ValueReconstructState,
+    AsLayerDesc, LayerName, LayerVisibilityHint, PersistentLayerDesc, PersistentLayerKey,
+    ValueReconstructState,
 };
 use crate::tenant::timeline::ImageLayerCreationOutcome;
 use crate::tenant::timeline::{drop_rlock, DeltaLayerWriter, ImageLayerWriter};
@@ -41,7 +47,7 @@
 use crate::tenant::DeltaLayer;
 use crate::virtual_file::{MaybeFatalIo, VirtualFile};
 
-use crate::keyspace::KeySpace;
+use crate::keyspace::{KeyPartitioning, KeySpace, KeySpaceAccum};
 use crate::repository::{Key, Value};
 use crate::walrecord::NeonWalRecord;
 
@@ -52,9 +58,15 @@
 
 use super::CompactionError;
 
+use self::manifest::{GcCompactionManifest, ProducedLayer};
+
 /// Maximum number of deltas before generating an image layer in bottom-most compaction.
 const COMPACTION_DELTA_THRESHOLD: usize = 5;
 
+/// Delta layers produced by bottom-most compaction are not split before reaching
+/// `compaction_target_size / GC_COMPACTION_MIN_DELTA_LAYER_SIZE_DIVISOR`, unless needed to avoid overlaps.
+const GC_COMPACTION_MIN_DELTA_LAYER_SIZE_DIVISOR: u64 = 8;
+
 /// The result of bottom-most compaction for a single key at each LSN.
 #[derive(Debug)]
 #[cfg_attr(test, derive(PartialEq))]
@@ -134,6 +146,34 @@ pub struct CompactionStatistics {
     image_keys_visited: CompactionStatisticsNumSize,
     wal_produced: CompactionStatisticsNumSize,
     image_produced: CompactionStatisticsNumSize,
+    num_ancestor_image_reads: usize,
+    num_ancestor_images_fetched: usize,
+    /// Set once the compaction has written all of its layers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    write_amplification: Option<WriteAmplification>,
+    /// In dry-run mode, the changes that the compaction would make to the layer map.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    layer_map_diff: Option<LayerMapDiff>,
+    /// Set once the compaction is done, see [`CompactGcStats`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<CompactGcStats>,
+}
+
+/// The bytes of the layers produced by a compaction per byte of the layers it visited. A ratio
+/// well above 1 means that the compaction rewrites far more data than it reads. Each ratio is
+/// `None` if no layer of that kind was visited.
+#[derive(Debug, Serialize, Default, Clone, Copy, PartialEq)]
+pub(crate) struct WriteAmplification {
+    pub(crate) delta: Option<f64>,
+    pub(crate) image: Option<f64>,
+    pub(crate) total: Option<f64>,
+}
+
+/// The layers that a compaction removes from and adds to the layer map.
+#[derive(Debug, Serialize)]
+pub(crate) struct LayerMapDiff {
+    pub(crate) removed: Vec<LayerName>,
+    pub(crate) added: Vec<LayerName>,
 }
 
 impl CompactionStatistics {
@@ -197,23 +237,212 @@ fn produce_image_layer(&mut self, size: u64) {
         self.image_layer_produced.num += 1;
         self.image_layer_produced.size += size;
     }
+    fn read_ancestor_images(&mut self, num_keys: usize) {
+        self.num_ancestor_image_reads += 1;
+        self.num_ancestor_images_fetched += num_keys;
+    }
+    pub(crate) fn write_amplification(&self) -> WriteAmplification {
+        fn ratio(produced: u64, visited: u64) -> Option<f64> {
+            if visited == 0 {
+                None
+            } else {
+                Some(produced as f64 / visited as f64)
+            }
+        }
+        WriteAmplification {
+            delta: ratio(
+                self.delta_layer_produced.size,
+                self.delta_layer_visited.size,
+            ),
+            image: ratio(
+                self.image_layer_produced.size,
+                self.image_layer_visited.size,
+            ),
+            total: ratio(
+                self.delta_layer_produced.size + self.image_layer_produced.size,
+                self.delta_layer_visited.size + self.image_layer_visited.size,
+            ),
+        }
+    }
+    #[cfg(test)]
+    pub(crate) fn num_ancestor_image_reads(&self) -> usize {
+        self.num_ancestor_image_reads
+    }
+    #[cfg(test)]
+    pub(crate) fn num_ancestor_images_fetched(&self) -> usize {
+        self.num_ancestor_images_fetched
+    }
+    #[cfg(test)]
+    pub(crate) fn layer_map_diff(&self) -> Option<&LayerMapDiff> {
+        self.layer_map_diff.as_ref()
+    }
+}
+
+/// Summary of the work done by [`Timeline::compact_shard_ancestors`], so that callers can
+/// account for how much I/O a pass actually performed.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub(crate) struct CompactShardAncestorsSummary {
+    /// Ancestral layers dropped because they contain no keys for this shard.
+    pub(crate) layers_dropped: usize,
+    /// Ancestral layers rewritten to only contain keys for this shard.
+    pub(crate) layers_rewritten: usize,
+    /// Bytes of the layers that were read for rewriting.
+    pub(crate) bytes_read: u64,
+    /// Bytes of the rewritten layers.
+    pub(crate) bytes_written: u64,
+    /// Ancestral layers left for a future compaction pass, because of the rewrite limit.
+    pub(crate) layers_postponed: usize,
+}
+
+/// Outcome of a compaction pass, see [`Timeline::compact_legacy`].
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct CompactionOutcome {
+    /// Whether the compaction has pending tasks.
+    pub(crate) has_pending_tasks: bool,
+    /// The work done by the shard ancestor compaction, if the pass got to it.
+    pub(crate) shard_ancestors: Option<CompactShardAncestorsSummary>,
+}
+
+impl CompactionOutcome {
+    /// A pass that stopped early, leaving tasks for the next one.
+    fn pending() -> Self {
+        Self {
+            has_pending_tasks: true,
+            shard_ancestors: None,
+        }
+    }
+}
+
+/// Observes the layers produced by a compaction, e.g. to verify them from outside of the pageserver.
+///
+/// It is called for each layer once it is written, before the layer is added to the layer map,
+/// and without holding the layer map lock.
+pub(crate) trait CompactionObserver: Send + Sync {
+    fn on_layer_produced(&self, layer: &PersistentLayerDesc);
+}
+
+/// Overrides how [`Timeline::generate_key_retention`] retains the history of individual keys, e.g.
+/// to keep more history of metadata keys than of relation pages.
+pub(crate) trait KeyRetentionOverride: Send + Sync {
+    fn key_retention(&self, key: Key) -> KeyRetention;
+}
+
+impl<F: Fn(Key) -> KeyRetention + Send + Sync> KeyRetentionOverride for F {
+    fn key_retention(&self, key: Key) -> KeyRetention {
+        self(key)
+    }
+}
+
+/// The retention of a key, see [`KeyRetentionOverride`]. The default is the retention that
+/// applies to all keys.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KeyRetention {
+    /// Generate an image at each retained LSN and at the horizon, regardless of the delta threshold.
+    pub(crate) force_image: bool,
+    /// Keep all records of the key above this LSN instead of replacing them with images. Takes
+    /// precedence over `force_image`.
+    pub(crate) retain_history_above: Option<Lsn>,
+}
+
+/// The number of layers of each [`LayerVisibilityHint`] computed by [`Timeline::update_layer_visibility`].
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct LayerVisibilitySummary {
+    pub(crate) visible: usize,
+    pub(crate) covered: usize,
+}
+
+/// The outstanding compaction work of a timeline, see [`Timeline::estimate_compaction_work`].
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct CompactionWorkEstimate {
+    /// All L0 delta layers of the timeline.
+    pub(crate) l0_deltas: usize,
+    pub(crate) l0_deltas_bytes: u64,
+    /// The L0 delta layers that the next L0 compaction would pick.
+    pub(crate) l0_deltas_selected: usize,
+    pub(crate) l0_deltas_selected_bytes: u64,
+    /// The holes that the next L0 compaction would leave between the layers it writes.
+    pub(crate) holes: usize,
+    /// The partitions of the key space that are due for a new image layer.
+    pub(crate) image_partitions_due: usize,
+    /// Upper bound of the bytes of the image layers for these partitions.
+    pub(crate) image_bytes_due: u64,
 }
 
 impl Timeline {
+    /// Takes the layer map read lock for compaction. Gives up with [`CompactionError::LockTimeout`]
+    /// if the tenant has a `compaction_layer_lock_timeout` and the lock isn't granted in time.
+    pub(crate) async fn read_layers_for_compaction(
+        &self,
+    ) -> Result<tokio::sync::RwLockReadGuard<'_, LayerManager>, CompactionError> {
+        let Some(timeout) = self.get_compaction_layer_lock_timeout() else {
+            return Ok(self.layers.read().await);
+        };
+        tokio::time::timeout(timeout, self.layers.read())
+            .await
+            .map_err(|_| CompactionError::LockTimeout(timeout))
+    }
+
+    /// Makes a layer resident for compaction. If it has to be downloaded, waits for the download
+    /// to fit into the compaction I/O budget first.
+    async fn download_for_compaction(
+        &self,
+        layer: &Layer,
+    ) -> Result<ResidentLayer, CompactionError> {
+        if !layer.is_likely_resident() {
+            self.compaction_io_throttle
+                .throttle(layer.metadata().file_size, &self.cancel)
+                .await?;
+        }
+        Ok(layer.download_and_keep_resident().await?)
+    }
+
+    /// Writes a value produced by compaction, charging the bytes it adds to the layer to the
+    /// compaction I/O budget.
+    async fn put_value_for_compaction(
+        &self,
+        writer: &mut DeltaLayerWriter,
+        key: Key,
+        lsn: Lsn,
+        value: Value,
+        ctx: &RequestContext,
+    ) -> Result<(), CompactionError> {
+        let size_before = writer.size();
+        writer
+            .put_value(key, lsn, value, ctx)
+            .await
+            .map_err(CompactionError::Other)?;
+        self.compaction_io_throttle
+            .throttle(writer.size() - size_before, &self.cancel)
+            .await
+    }
+
     /// TODO: cancellation
     ///
-    /// Returns whether the compaction has pending tasks.
+    /// If a key range is given, image layers are only created for the partitions overlapping it.
+    /// The L0 layers cover the whole keyspace, so they are compacted regardless.
+    ///
+    /// If a time budget is given, the compaction checks it between the phases, and returns with
+    /// pending tasks instead of starting the next phase once the budget is used up.
+    ///
+    /// Returns whether the compaction has pending tasks, and the work done by the shard ancestor
+    /// compaction.
     pub(crate) async fn compact_legacy(
         self: &Arc<Self>,
         cancel: &CancellationToken,
-        flags: EnumSet<CompactFlags>,
+        options: CompactOptions,
+        observer: Option<&dyn CompactionObserver>,
         ctx: &RequestContext,
-    ) -> Result<bool, CompactionError> {
+    ) -> Result<CompactionOutcome, CompactionError> {
+        let flags = options.flags;
         if flags.contains(CompactFlags::EnhancedGcBottomMostCompaction) {
-            self.compact_with_gc(cancel, flags, ctx)
+            self.compact_with_gc(cancel, options, observer, ctx)
                 .await
-                .map_err(CompactionError::Other)?;
-            return Ok(false);
+                .map_err(|e| match e.downcast::<CompactionError>() {
+                    // Keep lock timeouts and shutdowns distinguishable for the callers.
+                    Ok(e) => e,
+                    Err(e) => CompactionError::Other(e),
+                })?;
+            return Ok(CompactionOutcome::default());
         }
 
         if flags.contains(CompactFlags::DryRun) {
@@ -222,6 +451,11 @@ pub(crate) async fn compact_legacy(
             )));
         }
 
+        if let Some(compact_range) = &options.compact_range {
+            self.check_compact_range(compact_range)
+                .map_err(CompactionError::Other)?;
+        }
+
         // High level strategy for compaction / image creation:
         //
         // 1. First, calculate the desired "partitioning" of the
@@ -264,6 +498,23 @@ pub(crate) async fn compact_legacy(
 
         let target_file_size = self.get_checkpoint_distance();
 
+        let started_at = tokio::time::Instant::now();
+        let over_budget = |next_phase: &str| {
+            let Some(time_budget) = options.time_budget else {
+                return false;
+            };
+            let elapsed = started_at.elapsed();
+            if elapsed < time_budget {
+                return false;
+            }
+            info!(
+                ?elapsed,
+                ?time_budget,
+                "compaction time budget used up, postponing {next_phase}"
+            );
+            true
+        };
+
         // Define partitioning schema if needed
 
         // FIXME: the match should only cover repartitioning, not the next steps
@@ -276,6 +527,7 @@ pub(crate) async fn compact_legacy(
             )
             .await
         {
+            Ok(_) if over_budget("L0 compaction") => return Ok(CompactionOutcome::pending()),
             Ok(((dense_partitioning, sparse_partitioning), lsn)) => {
                 // Disables access_stats updates, so that the files we read remain candidates for eviction after we're done with them
                 let image_ctx = RequestContextBuilder::extend(ctx)
@@ -284,7 +536,12 @@ pub(crate) async fn compact_legacy(
 
                 // 2. Compact
                 let timer = self.metrics.compact_time_histo.start_timer();
-                let fully_compacted = self.compact_level0(target_file_size, ctx).await?;
+                let align_to = self
+                    .get_compaction_align_to_partitions()
+                    .then_some(&dense_partitioning);
+                let fully_compacted = self
+                    .compact_level0(target_file_size, observer, align_to, ctx)
+                    .await?;
                 timer.stop_and_record();
 
                 let mut partitioning = dense_partitioning;
@@ -294,7 +551,9 @@ pub(crate) async fn compact_legacy(
 
                 // 3. Create new image layers for partitions that have been modified
                 // "enough". Skip image layer creation if L0 compaction cannot keep up.
-                if fully_compacted {
+                if fully_compacted && over_budget("image layer creation") {
+                    return Ok(CompactionOutcome::pending());
+                } else if fully_compacted {
                     let image_layers = self
                         .create_image_layers(
                             &partitioning,
@@ -304,11 +563,13 @@ pub(crate) async fn compact_legacy(
                             } else {
                                 ImageLayerCreationMode::Try
                             },
+                            options.compact_range.as_ref(),
+                            observer,
                             &image_ctx,
                         )
                         .await?;
 
-                    self.upload_new_image_layers(image_layers)?;
+                    self.upload_new_image_layers(image_layers).await?;
                 } else {
                     info!("skipping image layer generation due to L0 compaction did not include all layers.");
                 }
@@ -328,16 +589,64 @@ pub(crate) async fn compact_legacy(
             }
         };
 
-        if self.shard_identity.count >= ShardCount::new(2) {
+        let mut shard_ancestors = None;
+        if self.shard_identity.count >= ShardCount::new(2)
+            && over_budget("shard ancestor compaction")
+        {
+            return Ok(CompactionOutcome::pending());
+        } else if self.shard_identity.count >= ShardCount::new(2) {
             // Limit the number of layer rewrites to the number of partitions: this means its
             // runtime should be comparable to a full round of image layer creations, rather than
             // being potentially much longer.
             let rewrite_max = partition_count;
 
-            self.compact_shard_ancestors(rewrite_max, ctx).await?;
+            let summary = self
+                .compact_shard_ancestors(rewrite_max, observer, ctx)
+                .await?;
+            info!(
+                layers_dropped = summary.layers_dropped,
+                layers_rewritten = summary.layers_rewritten,
+                bytes_read = summary.bytes_read,
+                bytes_written = summary.bytes_written,
+                layers_postponed = summary.layers_postponed,
+                "compacted shard ancestors"
+            );
+            shard_ancestors = Some(summary);
+        }
+
+        Ok(CompactionOutcome {
+            has_pending_tasks,
+            shard_ancestors,
+        })
+    }
+
+    /// Checks the key range given to a compaction: it must contain keys of this shard, so that we
+    /// don't compact the keys of other shards.
+    pub(crate) fn check_compact_range(&self, compact_range: &Range<Key>) -> anyhow::Result<()> {
+        if compact_range.is_empty() {
+            anyhow::bail!(
+                "empty compaction key range {}..{}",
+                compact_range.start,
+                compact_range.end
+            );
+        }
+        if ShardedRange::new(compact_range.clone(), &self.shard_identity).page_count() == 0 {
+            anyhow::bail!(
+                "compaction key range {}..{} has no keys on shard {}",
+                compact_range.start,
+                compact_range.end,
+                self.tenant_shard_id.shard_slug()
+            );
         }
+        Ok(())
+    }
 
-        Ok(has_pending_tasks)
+    /// Counts the keys in `range` that each shard of the tenant stores, see [`shard_key_counts`].
+    pub(crate) fn shard_key_counts(
+        &self,
+        range: Range<Key>,
+    ) -> anyhow::Result<BTreeMap<ShardNumber, u32>> {
+        shard_key_counts(&self.shard_identity, range)
     }
 
     /// Check for layers that are elegible to be rewritten:
@@ -347,12 +656,15 @@ pub(crate) async fn compact_legacy(
     ///   rather not maintain compatibility with indefinitely.
     ///
     /// Note: this phase may read and write many gigabytes of data: use rewrite_max to bound
-    /// how much work it will try to do in each compaction pass.
+    /// how much work it will try to do in each compaction pass.  The returned summary reports
+    /// how much work was actually done.
     async fn compact_shard_ancestors(
         self: &Arc<Self>,
         rewrite_max: usize,
+        observer: Option<&dyn CompactionObserver>,
         ctx: &RequestContext,
-    ) -> Result<(), CompactionError> {
+    ) -> Result<CompactShardAncestorsSummary, CompactionError> {
+        let mut summary = CompactShardAncestorsSummary::default();
         let mut drop_layers = Vec::new();
         let mut layers_to_rewrite: Vec<Layer> = Vec::new();
 
@@ -370,7 +682,7 @@ async fn compact_shard_ancestors(
             self.gc_info.read().unwrap().cutoffs.time
         );
 
-        let layers = self.layers.read().await;
+        let layers = self.read_layers_for_compaction().await?;
         for layer_desc in layers.layer_map()?.iter_historic_layers() {
             let layer = layers.get_from_desc(&layer_desc);
             if layer.metadata().shard.shard_count == self.shard_identity.count {
@@ -378,6 +690,11 @@ async fn compact_shard_ancestors(
                 continue;
             }
 
+            if self.is_layer_pinned(&layer_desc.key()) {
+                info!(%layer, "skipping pinned layer");
+                continue;
+            }
+
             // This layer was created on an ancestor shard: check if it contains any data for this shard.
             let sharded_range = ShardedRange::new(layer_desc.get_key_range(), &self.shard_identity);
             let layer_local_page_count = sharded_range.page_count();
@@ -442,7 +759,13 @@ async fn compact_shard_ancestors(
             // Only rewrite layers if their generations differ.  This guarantees:
             //  - that local rewrite is safe, as local layer paths will differ between existing layer and rewritten one
             //  - that the layer is persistent in remote storage, as we only see old-generation'd layer via loading from remote storage
-            if layer.metadata().generation == self.generation {
+            #[cfg(test)]
+            let force_rewrite = self
+                .force_shard_ancestor_rewrite
+                .load(std::sync::atomic::Ordering::Relaxed);
+            #[cfg(not(test))]
+            let force_rewrite = false;
+            if layer.metadata().generation == self.generation && !force_rewrite {
                 debug!(%layer, "Skipping rewrite, is not from old generation");
                 continue;
             }
@@ -451,6 +774,7 @@ async fn compact_shard_ancestors(
                 tracing::info!(%layer, "Will rewrite layer on a future compaction, already rewrote {}",
                     layers_to_rewrite.len()
                 );
+                summary.layers_postponed += 1;
                 continue;
             }
 
@@ -489,12 +813,14 @@ async fn compact_shard_ancestors(
             // - We do not run concurrently with other kinds of compaction, so the only layer map writes we race with are:
             //    - GC, which at worst witnesses us "undelete" a layer that they just deleted.
             //    - ingestion, which only inserts layers, therefore cannot collide with us.
-            let resident = layer.download_and_keep_resident().await?;
+            let resident = self.download_for_compaction(&layer).await?;
 
             let keys_written = resident
                 .filter(&self.shard_identity, &mut image_layer_writer, ctx)
                 .await?;
 
+            summary.bytes_read += layer.metadata().file_size;
+
             if keys_written > 0 {
                 let new_layer = image_layer_writer
                     .finish(self, ctx)
@@ -504,6 +830,12 @@ async fn compact_shard_ancestors(
                     layer.metadata().file_size,
                     new_layer.metadata().file_size);
 
+                summary.layers_rewritten += 1;
+                summary.bytes_written += new_layer.metadata().file_size;
+                if let Some(observer) = observer {
+                    observer.on_layer_produced(new_layer.layer_desc());
+                }
+
                 replace_image_layers.push((layer, new_layer));
             } else {
                 // Drop the old layer.  Usually for this case we would already have noticed that
@@ -517,6 +849,14 @@ async fn compact_shard_ancestors(
         // to remote index) and be removed. This is inefficient but safe.
         fail::fail_point!("compact-shard-ancestors-localonly");
 
+        summary.layers_dropped = drop_layers.len();
+
+        // Name the rewritten layers if we fail to wait for their upload below: it may be stuck.
+        let rewritten_layers = replace_image_layers
+            .iter()
+            .map(|(_, new_layer)| new_layer.layer_desc().layer_name())
+            .join(", ");
+
         // Update the LayerMap so that readers will use the new layers, and enqueue it for writing to remote storage
         self.rewrite_layers(replace_image_layers, drop_layers)
             .await?;
@@ -527,17 +867,21 @@ async fn compact_shard_ancestors(
         // necessary for correctness, but it simplifies testing, and avoids proceeding with another
         // Timeline's compaction while this timeline's uploads may be generating lots of disk I/O
         // load.
-        match self.remote_client.wait_completion().await {
-            Ok(()) => (),
-            Err(WaitCompletionError::NotInitialized(ni)) => return Err(CompactionError::from(ni)),
-            Err(WaitCompletionError::UploadQueueShutDownOrStopped) => {
-                return Err(CompactionError::ShuttingDown)
-            }
+        if let Err(e) = self.remote_client.wait_completion().await {
+            // A shutdown carries no context, so we also log the layers.
+            info!("uploads of rewritten layers did not complete ({e}): {rewritten_layers}");
+            let e = match e {
+                WaitCompletionError::NotInitialized(ni) => CompactionError::from(ni),
+                WaitCompletionError::UploadQueueShutDownOrStopped => CompactionError::ShuttingDown,
+            };
+            return Err(e.context(format!(
+                "waiting for the upload of rewritten layers: {rewritten_layers}"
+            )));
         }
 
         fail::fail_point!("compact-shard-ancestors-persistent");
 
-        Ok(())
+        Ok(summary)
     }
 
     /// Update the LayerVisibilityHint of layers covered by image layers, based on whether there is
@@ -548,7 +892,7 @@ async fn compact_shard_ancestors(
     /// that we know won't be needed for reads.
     pub(super) async fn update_layer_visibility(
         &self,
-    ) -> Result<(), super::layer_manager::Shutdown> {
+    ) -> Result<LayerVisibilitySummary, super::layer_manager::Shutdown> {
         let head_lsn = self.get_last_record_lsn();
 
         // We will sweep through layers in reverse-LSN order.  We only do historic layers.  L0 deltas
@@ -559,34 +903,56 @@ pub(super) async fn update_layer_visibility(
         let layer_map = layer_manager.layer_map()?;
 
         let readable_points = {
-            let children = self.gc_info.read().unwrap().retain_lsns.clone();
-
-            let mut readable_points = Vec::with_capacity(children.len() + 1);
-            for (child_lsn, _child_timeline_id) in &children {
-                readable_points.push(*child_lsn);
-            }
-            readable_points.push(head_lsn);
+            let mut readable_points = self.gc_info.read().unwrap().child_read_points();
+            // Reads at our own tip may reach any key.
+            readable_points.push((head_lsn, KeySpace::default()));
             readable_points
         };
 
-        let (layer_visibility, covered) = layer_map.get_visibility(readable_points);
+        let mut summary = LayerVisibilitySummary::default();
+        let (layer_visibility, covered) = layer_map.get_visibility_with_coverage(readable_points);
         for (layer_desc, visibility) in layer_visibility {
+            match visibility {
+                LayerVisibilityHint::Visible => summary.visible += 1,
+                LayerVisibilityHint::Covered => summary.covered += 1,
+            }
             // FIXME: a more efficiency bulk zip() through the layers rather than NlogN getting each one
             let layer = layer_manager.get_from_desc(&layer_desc);
             layer.set_visibility(visibility);
         }
 
-        // TODO: publish our covered KeySpace to our parent, so that when they update their visibility, they can
-        // avoid assuming that everything at a branch point is visible.
-        drop(covered);
-        Ok(())
+        drop(layer_manager);
+
+        // Publish our covered keyspace to our parent, so that when they update their visibility, they
+        // don't have to assume that everything at our branch point is visible.
+        if let Some(ancestor) = &self.ancestor_timeline {
+            ancestor
+                .gc_info
+                .write()
+                .unwrap()
+                .set_child_coverage(self.timeline_id, covered);
+        }
+        Ok(summary)
+    }
+
+    /// Runs [`Self::update_layer_visibility`] on demand, outside of compaction.
+    pub(crate) async fn recompute_layer_visibility(
+        &self,
+    ) -> Result<LayerVisibilitySummary, super::layer_manager::Shutdown> {
+        // Compaction updates the visibility itself: wait for it rather than racing with it.
+        let _guard = self.compaction_lock.lock().await;
+        self.update_layer_visibility().await
     }
 
     /// Collect a bunch of Level 0 layer files, and compact and reshuffle them as
     /// as Level 1 files. Returns whether the L0 layers are fully compacted.
+    ///
+    /// If `partitioning` is given, no new layer spans the boundary between two of its partitions.
     async fn compact_level0(
         self: &Arc<Self>,
         target_file_size: u64,
+        observer: Option<&dyn CompactionObserver>,
+        partitioning: Option<&KeyPartitioning>,
         ctx: &RequestContext,
     ) -> Result<bool, CompactionError> {
         let CompactLevel0Phase1Result {
@@ -604,13 +970,19 @@ async fn compact_level0(
             };
 
             let begin = tokio::time::Instant::now();
-            let phase1_layers_locked = self.layers.read().await;
+            let phase1_layers_locked = self.read_layers_for_compaction().await?;
             let now = tokio::time::Instant::now();
             stats.read_lock_acquisition_micros =
                 DurationRecorder::Recorded(RecordedDuration(now - begin), now);
-            self.compact_level0_phase1(phase1_layers_locked, stats, target_file_size, &ctx)
-                .instrument(phase1_span)
-                .await?
+            self.compact_level0_phase1(
+                phase1_layers_locked,
+                stats,
+                target_file_size,
+                partitioning,
+                &ctx,
+            )
+            .instrument(phase1_span)
+            .await?
         };
 
         if new_layers.is_empty() && deltas_to_compact.is_empty() {
@@ -618,17 +990,155 @@ async fn compact_level0(
             return Ok(true);
         }
 
+        if let Some(observer) = observer {
+            for layer in &new_layers {
+                observer.on_layer_produced(layer.layer_desc());
+            }
+        }
+
         self.finish_compact_batch(&new_layers, &Vec::new(), &deltas_to_compact)
             .await?;
         Ok(fully_compacted)
     }
 
+    /// Picks the L0 deltas that the next L0 compaction compacts, from `level0_deltas` sorted by
+    /// LSN. Returns whether all of them were picked or the picker hit the size limit.
+    fn select_level0_deltas<'l>(&self, level0_deltas: &'l [Layer]) -> (Vec<&'l Layer>, bool) {
+        let mut level0_deltas_iter = level0_deltas.iter();
+
+        let first_level0_delta = level0_deltas_iter.next().unwrap();
+        let mut prev_lsn_end = first_level0_delta.layer_desc().lsn_range.end;
+        let mut selected = vec![first_level0_delta];
+
+        // Accumulate the size of the selected layers
+        let mut selected_bytes = 0;
+
+        // Under normal circumstances, we will accumulate up to compaction_interval L0s of size
+        // checkpoint_distance each.  To avoid edge cases using extra system resources, bound our
+        // work in this function to only operate on this much delta data at once.
+        //
+        // Take the max of the configured value & the default, so that tests that configure tiny values
+        // can still use a sensible amount of memory, but if a deployed system configures bigger values we
+        // still let them compact a full stack of L0s in one go. An explicitly configured limit wins.
+        let delta_size_limit = self
+            .get_l0_compaction_delta_size_limit()
+            .unwrap_or_else(|| {
+                std::cmp::max(
+                    self.get_compaction_threshold(),
+                    DEFAULT_COMPACTION_THRESHOLD,
+                ) as u64
+                    * std::cmp::max(self.get_checkpoint_distance(), DEFAULT_CHECKPOINT_DISTANCE)
+            });
+
+        for l in level0_deltas_iter {
+            let lsn_range = &l.layer_desc().lsn_range;
+
+            if lsn_range.start != prev_lsn_end {
+                break;
+            }
+            selected.push(l);
+            selected_bytes += l.metadata().file_size;
+            prev_lsn_end = lsn_range.end;
+
+            if selected_bytes >= delta_size_limit {
+                // Proceed with compaction, but only a subset of L0s
+                return (selected, false);
+            }
+        }
+        (selected, true)
+    }
+
+    /// Estimates the outstanding compaction work of the timeline without compacting or
+    /// downloading anything. The L0 deltas are picked like [`Self::compact_level0_phase1`] picks
+    /// them. The holes are found from the keys of the picked layers that are resident, and from
+    /// the key range bounds of the others.
+    pub(crate) async fn estimate_compaction_work(
+        &self,
+        ctx: &RequestContext,
+    ) -> Result<CompactionWorkEstimate, CompactionError> {
+        let mut estimate = CompactionWorkEstimate::default();
+        {
+            let guard = self.read_layers_for_compaction().await?;
+            let layers = guard.layer_map()?;
+            let mut level0_deltas = layers
+                .level0_deltas()
+                .iter()
+                .map(|x| guard.get_from_desc(x))
+                .collect::<Vec<_>>();
+            estimate.l0_deltas = level0_deltas.len();
+            estimate.l0_deltas_bytes = level0_deltas.iter().map(|l| l.metadata().file_size).sum();
+
+            if !level0_deltas.is_empty() && level0_deltas.len() >= self.get_compaction_threshold() {
+                level0_deltas.sort_by_key(|l| l.layer_desc().lsn_range.start);
+                let (selected, _) = self.select_level0_deltas(&level0_deltas);
+                let mut keys = Vec::new();
+                for l in &selected {
+                    estimate.l0_deltas_selected += 1;
+                    estimate.l0_deltas_selected_bytes += l.metadata().file_size;
+                    match l.keep_resident().await {
+                        Some(resident) => {
+                            let entries = resident
+                                .load_keys(ctx)
+                                .await
+                                .map_err(CompactionError::Other)?;
+                            keys.extend(entries.iter().map(|entry| entry.key));
+                        }
+                        None => {
+                            let key_range = &l.layer_desc().key_range;
+                            keys.extend([key_range.start, key_range.end]);
+                        }
+                    }
+                }
+                keys.sort();
+
+                let min_hole_range =
+                    (self.get_checkpoint_distance() / page_cache::PAGE_SZ as u64) as i128;
+                estimate.holes = find_holes(
+                    keys.into_iter(),
+                    layers,
+                    self.get_last_record_lsn(),
+                    selected.len(),
+                    min_hole_range,
+                    self.get_compaction_min_hole_coverage_size(),
+                )
+                .len();
+            }
+        }
+
+        let (partitioning, lsn) = {
+            let guard = self.partitioning.lock().await;
+            (guard.0 .0.clone(), guard.1)
+        };
+        for partition in &partitioning.parts {
+            if self.time_for_new_image_layer(partition, lsn).await {
+                estimate.image_partitions_due += 1;
+                estimate.image_bytes_due +=
+                    partition.total_raw_size() as u64 * page_cache::PAGE_SZ as u64;
+            }
+        }
+
+        Ok(estimate)
+    }
+
+    /// Whether a delta layer produced by L0 compaction exceeds `target_file_size` by more than the
+    /// `compaction_oversize_warn_multiplier` allows.
+    fn is_oversized_delta_layer(&self, file_size: u64, target_file_size: u64) -> bool {
+        // Add two pages for potential overhead. This should in theory be already
+        // accounted for in the target calculation, but for very small targets,
+        // we still might easily hit the limit otherwise.
+        let warn_limit = target_file_size
+            .saturating_mul(self.get_compaction_oversize_warn_multiplier())
+            .saturating_add(page_cache::PAGE_SZ as u64 * 2);
+        file_size > warn_limit
+    }
+
     /// Level0 files first phase of compaction, explained in the [`Self::compact_legacy`] comment.
     async fn compact_level0_phase1<'a>(
         self: &'a Arc<Self>,
         guard: tokio::sync::RwLockReadGuard<'a, LayerManager>,
         mut stats: CompactLevel0Phase1StatsBuilder,
         target_file_size: u64,
+        partitioning: Option<&KeyPartitioning>,
         ctx: &RequestContext,
     ) -> Result<CompactLevel0Phase1Result, CompactionError> {
         stats.read_lock_held_spawn_blocking_startup_micros =
@@ -666,53 +1176,22 @@ async fn compact_level0_phase1<'a>(
         // of a crash, partial download from cloud storage, or something like
         // that, so it's not a big deal in practice.
         level0_deltas.sort_by_key(|l| l.layer_desc().lsn_range.start);
-        let mut level0_deltas_iter = level0_deltas.iter();
-
-        let first_level0_delta = level0_deltas_iter.next().unwrap();
-        let mut prev_lsn_end = first_level0_delta.layer_desc().lsn_range.end;
-        let mut deltas_to_compact = Vec::with_capacity(level0_deltas.len());
-
-        // Accumulate the size of layers in `deltas_to_compact`
-        let mut deltas_to_compact_bytes = 0;
-
-        // Under normal circumstances, we will accumulate up to compaction_interval L0s of size
-        // checkpoint_distance each.  To avoid edge cases using extra system resources, bound our
-        // work in this function to only operate on this much delta data at once.
-        //
-        // Take the max of the configured value & the default, so that tests that configure tiny values
-        // can still use a sensible amount of memory, but if a deployed system configures bigger values we
-        // still let them compact a full stack of L0s in one go.
-        let delta_size_limit = std::cmp::max(
-            self.get_compaction_threshold(),
-            DEFAULT_COMPACTION_THRESHOLD,
-        ) as u64
-            * std::cmp::max(self.get_checkpoint_distance(), DEFAULT_CHECKPOINT_DISTANCE);
-
-        let mut fully_compacted = true;
-
-        deltas_to_compact.push(first_level0_delta.download_and_keep_resident().await?);
-        for l in level0_deltas_iter {
-            let lsn_range = &l.layer_desc().lsn_range;
-
-            if lsn_range.start != prev_lsn_end {
-                break;
-            }
-            deltas_to_compact.push(l.download_and_keep_resident().await?);
-            deltas_to_compact_bytes += l.metadata().file_size;
-            prev_lsn_end = lsn_range.end;
-
-            if deltas_to_compact_bytes >= delta_size_limit {
-                info!(
-                    l0_deltas_selected = deltas_to_compact.len(),
-                    l0_deltas_total = level0_deltas.len(),
-                    "L0 compaction picker hit max delta layer size limit: {}",
-                    delta_size_limit
-                );
-                fully_compacted = false;
-
-                // Proceed with compaction, but only a subset of L0s
-                break;
-            }
+        let (selected, fully_compacted) = self.select_level0_deltas(&level0_deltas);
+        let l0_deltas_remaining = level0_deltas.len() - selected.len();
+        self.metrics
+            .compaction_l0_remaining
+            .set(l0_deltas_remaining as u64);
+        if !fully_compacted {
+            info!(
+                l0_deltas_selected = selected.len(),
+                l0_deltas_total = level0_deltas.len(),
+                "L0 compaction picker hit max delta layer size limit",
+            );
+            self.metrics.compaction_l0_truncated.inc();
+        }
+        let mut deltas_to_compact = Vec::with_capacity(selected.len());
+        for l in selected {
+            deltas_to_compact.push(self.download_for_compaction(l).await?);
         }
         let lsn_range = Range {
             start: deltas_to_compact
@@ -761,76 +1240,16 @@ async fn compact_level0_phase1<'a>(
 
         stats.read_lock_held_key_sort_micros = stats.read_lock_held_prerequisites_micros.till_now();
 
-        // Determine N largest holes where N is number of compacted layers. The vec is sorted by key range start.
-        //
-        // A hole is a key range for which this compaction doesn't have any WAL records.
-        // Our goal in this compaction iteration is to avoid creating L1s that, in terms of their key range,
-        // cover the hole, but actually don't contain any WAL records for that key range.
-        // The reason is that the mere stack of L1s (`count_deltas`) triggers image layer creation (`create_image_layers`).
-        // That image layer creation would be useless for a hole range covered by L1s that don't contain any WAL records.
-        //
-        // The algorithm chooses holes as follows.
-        // - Slide a 2-window over the keys in key orde to get the hole range (=distance between two keys).
-        // - Filter: min threshold on range length
-        // - Rank: by coverage size (=number of image layers required to reconstruct each key in the range for which we have any data)
-        //
-        // For more details, intuition, and some ASCII art see https://github.com/neondatabase/neon/pull/3597#discussion_r1112704451
-        #[derive(PartialEq, Eq)]
-        struct Hole {
-            key_range: Range<Key>,
-            coverage_size: usize,
-        }
-        let holes: Vec<Hole> = {
-            use std::cmp::Ordering;
-            impl Ord for Hole {
-                fn cmp(&self, other: &Self) -> Ordering {
-                    self.coverage_size.cmp(&other.coverage_size).reverse()
-                }
-            }
-            impl PartialOrd for Hole {
-                fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-                    Some(self.cmp(other))
-                }
-            }
-            let max_holes = deltas_to_compact.len();
-            let last_record_lsn = self.get_last_record_lsn();
-            let min_hole_range = (target_file_size / page_cache::PAGE_SZ as u64) as i128;
-            let min_hole_coverage_size = 3; // TODO: something more flexible?
-                                            // min-heap (reserve space for one more element added before eviction)
-            let mut heap: BinaryHeap<Hole> = BinaryHeap::with_capacity(max_holes + 1);
-            let mut prev: Option<Key> = None;
-
-            for &DeltaEntry { key: next_key, .. } in all_keys.iter() {
-                if let Some(prev_key) = prev {
-                    // just first fast filter, do not create hole entries for metadata keys. The last hole in the
-                    // compaction is the gap between data key and metadata keys.
-                    if next_key.to_i128() - prev_key.to_i128() >= min_hole_range
-                        && !Key::is_metadata_key(&prev_key)
-                    {
-                        let key_range = prev_key..next_key;
-                        // Measuring hole by just subtraction of i128 representation of key range boundaries
-                        // has not so much sense, because largest holes will corresponds field1/field2 changes.
-                        // But we are mostly interested to eliminate holes which cause generation of excessive image layers.
-                        // That is why it is better to measure size of hole as number of covering image layers.
-                        let coverage_size =
-                            layers.image_coverage(&key_range, last_record_lsn).len();
-                        if coverage_size >= min_hole_coverage_size {
-                            heap.push(Hole {
-                                key_range,
-                                coverage_size,
-                            });
-                            if heap.len() > max_holes {
-                                heap.pop(); // remove smallest hole
-                            }
-                        }
-                    }
-                }
-                prev = Some(next_key.next());
-            }
-            let mut holes = heap.into_vec();
-            holes.sort_unstable_by_key(|hole| hole.key_range.start);
-            holes
-        };
+        // Determine N largest holes where N is number of compacted layers. See [`find_holes`].
+        let min_hole_range = (target_file_size / page_cache::PAGE_SZ as u64) as i128;
+        let holes = find_holes(
+            all_keys.iter().map(|entry| entry.key),
+            layers,
+            self.get_last_record_lsn(),
+            deltas_to_compact.len(),
+            min_hole_range,
+            self.get_compaction_min_hole_coverage_size(),
+        );
         stats.read_lock_held_compute_holes_micros = stats.read_lock_held_key_sort_micros.till_now();
         drop_rlock(guard);
 
@@ -953,7 +1372,7 @@ macro_rules! rate_limited_warn {
                 }
             }
         }
-        let mut all_values_iter = match &self.conf.compact_level0_phase1_value_access {
+        let mut all_values_iter = match &self.get_compact_level0_phase1_value_access() {
             CompactL0Phase1ValueAccess::PageCachedBlobIo => AllValuesIter::PageCachedBlobIo {
                 all_keys_iter: all_keys.iter(),
             },
@@ -1035,8 +1454,9 @@ macro_rules! rate_limited_warn {
         //  | +-----------+            +--+--+--+--+
         //  |
         //  +--------------> key
-        // TODO: this actually divides the layers into fixed-size chunks, not
-        // based on the partitioning.
+        // This divides the layers into fixed-size chunks. If a partitioning is given,
+        // the layers are additionally split at the start of each partition, so that
+        // the L1 layers line up with the image layers created for the partitions.
         //
         // TODO: we should also opportunistically materialize and
         // garbage collect what we can.
@@ -1047,6 +1467,17 @@ macro_rules! rate_limited_warn {
         let mut dup_start_lsn: Lsn = Lsn::INVALID; // start LSN of layer containing values of the single key
         let mut dup_end_lsn: Lsn = Lsn::INVALID; // end LSN of layer containing values of the single key
         let mut next_hole = 0; // index of next hole in holes vector
+        let boundaries: Vec<Key> = partitioning
+            .map(|partitioning| {
+                partitioning
+                    .parts
+                    .iter()
+                    .skip(1)
+                    .filter_map(|part| part.start())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut next_boundary = 0; // index of next partition boundary in boundaries vector
 
         let mut keys = 0;
 
@@ -1104,15 +1535,22 @@ macro_rules! rate_limited_warn {
                     dup_start_lsn = dup_end_lsn;
                     dup_end_lsn = lsn_range.end;
                 }
+                // Skip all the partition boundaries up to this key, even if no layer is being written.
+                let crosses_boundary =
+                    next_boundary < boundaries.len() && key >= boundaries[next_boundary];
+                while next_boundary < boundaries.len() && key >= boundaries[next_boundary] {
+                    next_boundary += 1;
+                }
                 if writer.is_some() {
                     let written_size = writer.as_mut().unwrap().size();
                     let contains_hole =
                         next_hole < holes.len() && key >= holes[next_hole].key_range.end;
-                    // check if key cause layer overflow or contains hole...
+                    // check if key cause layer overflow, contains hole or starts a new partition...
                     if is_dup_layer
                         || dup_end_lsn.is_valid()
                         || written_size + key_values_total_size > target_file_size
                         || contains_hole
+                        || crosses_boundary
                     {
                         // ... if so, flush previous layer and prepare to write new one
                         let (desc, path) = writer
@@ -1163,6 +1601,7 @@ macro_rules! rate_limited_warn {
                                 debug!("Create new layer {}..{}", lsn_range.start, lsn_range.end);
                                 lsn_range.clone()
                             },
+                            None,
                             ctx,
                         )
                         .await
@@ -1172,12 +1611,8 @@ macro_rules! rate_limited_warn {
                     keys = 0;
                 }
 
-                writer
-                    .as_mut()
-                    .unwrap()
-                    .put_value(key, lsn, value, ctx)
-                    .await
-                    .map_err(CompactionError::Other)?;
+                self.put_value_for_compaction(writer.as_mut().unwrap(), key, lsn, value, ctx)
+                    .await?;
             } else {
                 debug!(
                     "Dropping key {} during compaction (it belongs on shard {:?})",
@@ -1204,16 +1639,14 @@ macro_rules! rate_limited_warn {
 
         // Sync layers
         if !new_layers.is_empty() {
-            // Print a warning if the created layer is larger than double the target size
-            // Add two pages for potential overhead. This should in theory be already
-            // accounted for in the target calculation, but for very small targets,
-            // we still might easily hit the limit otherwise.
-            let warn_limit = target_file_size * 2 + page_cache::PAGE_SZ as u64 * 2;
+            // Print a warning if the created layer is much larger than the target size
             for layer in new_layers.iter() {
-                if layer.layer_desc().file_size > warn_limit {
+                if self.is_oversized_delta_layer(layer.layer_desc().file_size, target_file_size) {
                     warn!(
                         %layer,
-                        "created delta file of size {} larger than double of target of {target_file_size}", layer.layer_desc().file_size
+                        "created delta file of size {} larger than {}x the target of {target_file_size}",
+                        layer.layer_desc().file_size,
+                        self.get_compaction_oversize_warn_multiplier(),
                     );
                 }
             }
@@ -1368,40 +1801,94 @@ fn try_from(value: CompactLevel0Phase1StatsBuilder) -> Result<Self, Self::Error>
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, serde::Deserialize, serde::Serialize)]
-#[serde(tag = "mode", rename_all = "kebab-case", deny_unknown_fields)]
-pub enum CompactL0Phase1ValueAccess {
-    /// The old way.
-    PageCachedBlobIo,
-    /// The new way.
-    StreamingKmerge {
-        /// If set, we run both the old way and the new way, validate that
-        /// they are identical (=> [`CompactL0BypassPageCacheValidation`]),
-        /// and if the validation fails,
-        /// - in tests: fail them with a panic or
-        /// - in prod, log a rate-limited warning and use the old way's results.
-        ///
-        /// If not set, we only run the new way and trust its results.
-        validate: Option<CompactL0BypassPageCacheValidation>,
-    },
+#[derive(Default)]
+struct CompactGcStatsBuilder {
+    version: Option<u64>,
+    tenant_id: Option<TenantShardId>,
+    timeline_id: Option<TimelineId>,
+    layer_selection_micros: DurationRecorder,
+    download_micros: DurationRecorder,
+    key_processing_micros: DurationRecorder,
+    /// Accumulated over the key processing loop.
+    merge_iteration: std::time::Duration,
+    /// Accumulated over the key processing loop.
+    retention: std::time::Duration,
+    layer_finalization_micros: DurationRecorder,
+}
+
+impl CompactGcStatsBuilder {
+    /// Logs the stats, and returns them if all the phases were recorded.
+    fn finish(self, begin: tokio::time::Instant) -> Option<CompactGcStats> {
+        let total = begin.elapsed();
+        match TryInto::<CompactGcStats>::try_into(self).and_then(|mut stats| {
+            stats.total_micros = RecordedDuration(total);
+            let stats_json = serde_json::to_string(&stats).context("serde_json::to_string")?;
+            Ok((stats, stats_json))
+        }) {
+            Ok((stats, stats_json)) => {
+                info!(
+                    stats_json = stats_json.as_str(),
+                    "compact_with_gc stats available"
+                );
+                Some(stats)
+            }
+            Err(e) => {
+                warn!("compact_with_gc stats failed to serialize: {:#}", e);
+                None
+            }
+        }
+    }
 }
 
-/// See [`CompactL0Phase1ValueAccess::StreamingKmerge`].
-#[derive(Debug, PartialEq, Eq, Clone, serde::Deserialize, serde::Serialize)]
-#[serde(rename_all = "kebab-case")]
-pub enum CompactL0BypassPageCacheValidation {
-    /// Validate that the series of (key, lsn) pairs are the same.
-    KeyLsn,
-    /// Validate that the entire output of old and new way is identical.
-    KeyLsnValue,
+/// The time spent in the phases of a gc-compaction. The phases from layer selection to layer
+/// finalization follow each other, while merge iteration and retention computation are parts of
+/// the key processing.
+#[derive(Debug, serde::Serialize)]
+struct CompactGcStats {
+    version: u64,
+    tenant_id: TenantShardId,
+    timeline_id: TimelineId,
+    layer_selection_micros: RecordedDuration,
+    download_micros: RecordedDuration,
+    key_processing_micros: RecordedDuration,
+    merge_iteration_micros: RecordedDuration,
+    retention_micros: RecordedDuration,
+    layer_finalization_micros: RecordedDuration,
+    total_micros: RecordedDuration,
 }
 
-impl Default for CompactL0Phase1ValueAccess {
-    fn default() -> Self {
-        CompactL0Phase1ValueAccess::StreamingKmerge {
-            // TODO(https://github.com/neondatabase/neon/issues/8184): change to None once confident
-            validate: Some(CompactL0BypassPageCacheValidation::KeyLsnValue),
-        }
+impl TryFrom<CompactGcStatsBuilder> for CompactGcStats {
+    type Error = anyhow::Error;
+
+    fn try_from(value: CompactGcStatsBuilder) -> Result<Self, Self::Error> {
+        Ok(Self {
+            version: value.version.ok_or_else(|| anyhow!("version not set"))?,
+            tenant_id: value
+                .tenant_id
+                .ok_or_else(|| anyhow!("tenant_id not set"))?,
+            timeline_id: value
+                .timeline_id
+                .ok_or_else(|| anyhow!("timeline_id not set"))?,
+            layer_selection_micros: value
+                .layer_selection_micros
+                .into_recorded()
+                .ok_or_else(|| anyhow!("layer_selection_micros not set"))?,
+            download_micros: value
+                .download_micros
+                .into_recorded()
+                .ok_or_else(|| anyhow!("download_micros not set"))?,
+            key_processing_micros: value
+                .key_processing_micros
+                .into_recorded()
+                .ok_or_else(|| anyhow!("key_processing_micros not set"))?,
+            merge_iteration_micros: RecordedDuration(value.merge_iteration),
+            retention_micros: RecordedDuration(value.retention),
+            layer_finalization_micros: value
+                .layer_finalization_micros
+                .into_recorded()
+                .ok_or_else(|| anyhow!("layer_finalization_micros not set"))?,
+            total_micros: RecordedDuration(std::time::Duration::ZERO),
+        })
     }
 }
 
@@ -1423,7 +1910,7 @@ pub(crate) async fn compact_tiered(
 
         // Find the top of the historical layers
         let end_lsn = {
-            let guard = self.layers.read().await;
+            let guard = self.read_layers_for_compaction().await?;
             let layers = guard.layer_map()?;
 
             let l0_deltas = layers.level0_deltas();
@@ -1444,9 +1931,21 @@ pub(crate) async fn compact_tiered(
             return Err(CompactionError::ShuttingDown);
         }
 
-        let (dense_ks, _sparse_ks) = self.collect_keyspace(end_lsn, ctx).await?;
-        // TODO(chi): ignore sparse_keyspace for now, compact it in the future.
-        let mut adaptor = TimelineAdaptor::new(self, (end_lsn, dense_ks));
+        let (dense_ks, sparse_ks) = self.collect_keyspace(end_lsn, ctx).await?;
+
+        // The tiered algorithm sizes its layers by the number of keys, which is not known for the
+        // sparse keyspace. It still rewrites the deltas of the sparse keys along with the dense
+        // ones, but their images are created separately, one layer per sparse range like the
+        // legacy compaction does. Decide which ranges need them before the deltas get rewritten.
+        let mut sparse_partitions = Vec::new();
+        for range in &sparse_ks.0.ranges {
+            let partition = KeySpace::single(range.clone());
+            if self.time_for_new_image_layer(&partition, end_lsn).await {
+                sparse_partitions.push(partition);
+            }
+        }
+
+        let mut adaptor = TimelineAdaptor::new(self, (end_lsn, dense_ks));
 
         pageserver_compaction::compact_tiered::compact_tiered(
             &mut adaptor,
@@ -1459,6 +1958,12 @@ pub(crate) async fn compact_tiered(
         // TODO: compact_tiered needs to return CompactionError
         .map_err(CompactionError::Other)?;
 
+        for partition in &sparse_partitions {
+            adaptor
+                .create_sparse_image_impl(end_lsn, partition, ctx)
+                .await?;
+        }
+
         adaptor.flush_updates().await?;
         Ok(())
     }
@@ -1483,7 +1988,17 @@ pub(crate) async fn compact_tiered(
     /// above_horizon    -> deltas=[+F@0x60]             full history above the horizon
     /// ```
     ///
+    /// Within a batch that keeps its deltas, an image replaces the record that makes the key exceed
+    /// `max_versions_per_key` records since its last image. This bounds the history that has to be
+    /// replayed for a hot key with many versions, independently of `delta_threshold_cnt`.
+    ///
     /// Note that `accumulated_values` must be sorted by LSN and should belong to a single key.
+    ///
+    /// On a child branch, `base_img_from_ancestor` only needs to be provided when the history does not start
+    /// with an image or a will_init record.
+    ///
+    /// `retention_override` can change the retention of individual keys, see [`KeyRetention`].
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn generate_key_retention(
         self: &Arc<Timeline>,
         key: Key,
@@ -1491,7 +2006,63 @@ pub(crate) async fn generate_key_retention(
         horizon: Lsn,
         retain_lsn_below_horizon: &[Lsn],
         delta_threshold_cnt: usize,
+        max_versions_per_key: usize,
+        base_img_from_ancestor: Option<(Key, Lsn, Bytes)>,
+        retention_override: Option<&dyn KeyRetentionOverride>,
+    ) -> anyhow::Result<KeyHistoryRetention> {
+        let key_retention =
+            retention_override.map_or_else(KeyRetention::default, |o| o.key_retention(key));
+        // Fast path for keys that consist of a single image, like cold keys, when there is nothing to
+        // retain below the horizon. Produces the same retention as replaying the history.
+        if let ([(_, lsn, Value::Image(img))], [], None, true) = (
+            full_history,
+            retain_lsn_below_horizon,
+            &base_img_from_ancestor,
+            key_retention == KeyRetention::default(),
+        ) {
+            let (lsn, img) = (*lsn, Value::Image(img.clone()));
+            return Ok(if lsn > horizon {
+                KeyHistoryRetention {
+                    below_horizon: vec![(horizon, KeyLogAtLsn(vec![]))],
+                    above_horizon: KeyLogAtLsn(vec![(lsn, img)]),
+                }
+            } else {
+                // The image is materialized at the horizon, unless a child branch keeps it as a delta
+                // because it is below the delta threshold.
+                let generate_image = self.ancestor_timeline.is_none() || delta_threshold_cnt <= 1;
+                let img_lsn = if generate_image { horizon } else { lsn };
+                KeyHistoryRetention {
+                    below_horizon: vec![(horizon, KeyLogAtLsn(vec![(img_lsn, img)]))],
+                    above_horizon: KeyLogAtLsn(vec![]),
+                }
+            });
+        }
+        self.generate_key_retention_by_replay(
+            key,
+            full_history,
+            horizon,
+            retain_lsn_below_horizon,
+            delta_threshold_cnt,
+            max_versions_per_key,
+            base_img_from_ancestor,
+            key_retention,
+        )
+        .await
+    }
+
+    /// The general path of [`Self::generate_key_retention`], which splits the history at the retained
+    /// LSNs and replays it to produce the images.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn generate_key_retention_by_replay(
+        self: &Arc<Timeline>,
+        key: Key,
+        full_history: &[(Key, Lsn, Value)],
+        horizon: Lsn,
+        retain_lsn_below_horizon: &[Lsn],
+        delta_threshold_cnt: usize,
+        max_versions_per_key: usize,
         base_img_from_ancestor: Option<(Key, Lsn, Bytes)>,
+        key_retention: KeyRetention,
     ) -> anyhow::Result<KeyHistoryRetention> {
         // Pre-checks for the invariants
         if cfg!(debug_assertions) {
@@ -1521,7 +2092,7 @@ pub(crate) async fn generate_key_retention(
                 );
             }
         }
-        let has_ancestor = base_img_from_ancestor.is_some();
+        let has_ancestor = base_img_from_ancestor.is_some() || self.ancestor_timeline.is_some();
         // Step 1: split history into len(retain_lsn_below_horizon) + 2 buckets, where the last bucket is for all deltas above the horizon,
         // and the second-to-last bucket is for the horizon. Each bucket contains lsn_last_bucket < deltas <= lsn_this_bucket.
         let (mut split_history, lsn_split_points) = {
@@ -1640,27 +2211,98 @@ fn generate_debug_trace(
             output
         }
 
+        fn reconstruct_error_context(key: Key, request_lsn: Lsn, history_trace: &str) -> String {
+            format!(
+                "failed to reconstruct key {key} at {request_lsn}, replay_history: {history_trace}"
+            )
+        }
+
+        /// Turn a replay history starting with an image or a will_init record into a reconstruct state.
+        fn to_reconstruct_state(
+            history: Vec<(Key, Lsn, Value)>,
+        ) -> anyhow::Result<ValueReconstructState> {
+            let mut img = None;
+            let mut records = Vec::with_capacity(history.len());
+            if let (_, lsn, Value::Image(val)) = history.first().as_ref().unwrap() {
+                img = Some((*lsn, val.clone()));
+                for (_, lsn, val) in history.into_iter().skip(1) {
+                    let Value::WalRecord(rec) = val else {
+                        return Err(anyhow::anyhow!(
+                            "invalid record, first record is image, expect walrecords"
+                        ));
+                    };
+                    records.push((lsn, rec));
+                }
+            } else {
+                for (_, lsn, val) in history.into_iter() {
+                    let Value::WalRecord(rec) = val else {
+                        return Err(anyhow::anyhow!(
+                            "invalid record, first record is walrecord, expect rest are walrecord"
+                        ));
+                    };
+                    records.push((lsn, rec));
+                }
+            }
+            records.reverse();
+            Ok(ValueReconstructState { img, records })
+        }
+
         for (i, split_for_lsn) in split_history.into_iter().enumerate() {
             // TODO: there could be image keys inside the splits, and we can compute records_since_last_image accordingly.
-            records_since_last_image += split_for_lsn.len();
-            let generate_image = if i == 0 && !has_ancestor {
+            let keep_history = matches!(
+                key_retention.retain_history_above,
+                Some(lsn) if i == batch_cnt - 1 || lsn_split_points[i] > lsn
+            );
+            let generate_image = if keep_history {
+                // Keep the records of batches that reach above the LSN to retain history from
+                false
+            } else if i == 0 && !has_ancestor {
                 // We always generate images for the first batch (below horizon / lowest retain_lsn)
                 true
             } else if i == batch_cnt - 1 {
                 // Do not generate images for the last batch (above horizon)
                 false
-            } else if records_since_last_image >= delta_threshold_cnt {
+            } else if key_retention.force_image {
+                true
+            } else if records_since_last_image + split_for_lsn.len() >= delta_threshold_cnt {
                 // Generate images when there are too many records
                 true
             } else {
                 false
             };
-            replay_history.extend(split_for_lsn.iter().map(|x| (*x).clone()));
-            // Only retain the items after the last image record
-            for idx in (0..replay_history.len()).rev() {
-                if replay_history[idx].2.will_init() {
-                    replay_history = replay_history[idx..].to_vec();
-                    break;
+            let mut deltas = Vec::with_capacity(split_for_lsn.len());
+            for (_, lsn, value) in split_for_lsn {
+                records_since_last_image += 1;
+                // Only retain the items after the last image record
+                if value.will_init() {
+                    replay_history.clear();
+                }
+                replay_history.push((key, *lsn, value.clone()));
+                // Materialize an image when the key has too many versions since the last one, so that
+                // a single hot key does not pile up an unbounded history to replay.
+                if keep_history
+                    || replay_history.len() <= max_versions_per_key
+                    || !replay_history.first().unwrap().2.will_init()
+                {
+                    deltas.push((*lsn, value.clone()));
+                    continue;
+                }
+                let history_trace = generate_history_trace(&replay_history);
+                let state = to_reconstruct_state(std::mem::take(&mut replay_history))
+                    .with_context(|| {
+                        generate_debug_trace(None, full_history, retain_lsn_below_horizon, horizon)
+                    })?;
+                let img = self
+                    .reconstruct_value(key, *lsn, state)
+                    .await
+                    .with_context(|| reconstruct_error_context(key, *lsn, &history_trace))?;
+                replay_history.push((key, *lsn, Value::Image(img.clone())));
+                if generate_image {
+                    // The batch is replaced by a single image anyway.
+                    deltas.push((*lsn, value.clone()));
+                } else {
+                    records_since_last_image = 0;
+                    deltas.push((*lsn, Value::Image(img)));
                 }
             }
             if let Some((_, _, val)) = replay_history.first() {
@@ -1685,52 +2327,24 @@ fn generate_debug_trace(
                     None
                 };
                 let replay_history_for_debug_ref = replay_history_for_debug.as_deref();
+                let history_trace = generate_history_trace(&replay_history);
                 let history = std::mem::take(&mut replay_history);
-                let mut img = None;
-                let mut records = Vec::with_capacity(history.len());
-                if let (_, lsn, Value::Image(val)) = history.first().as_ref().unwrap() {
-                    img = Some((*lsn, val.clone()));
-                    for (_, lsn, val) in history.into_iter().skip(1) {
-                        let Value::WalRecord(rec) = val else {
-                            return Err(anyhow::anyhow!(
-                                "invalid record, first record is image, expect walrecords"
-                            ))
-                            .with_context(|| {
-                                generate_debug_trace(
-                                    replay_history_for_debug_ref,
-                                    full_history,
-                                    retain_lsn_below_horizon,
-                                    horizon,
-                                )
-                            });
-                        };
-                        records.push((lsn, rec));
-                    }
-                } else {
-                    for (_, lsn, val) in history.into_iter() {
-                        let Value::WalRecord(rec) = val else {
-                            return Err(anyhow::anyhow!("invalid record, first record is walrecord, expect rest are walrecord"))
-                                .with_context(|| generate_debug_trace(
-                                    replay_history_for_debug_ref,
-                                    full_history,
-                                    retain_lsn_below_horizon,
-                                    horizon,
-                                ));
-                        };
-                        records.push((lsn, rec));
-                    }
-                }
-                records.reverse();
-                let state = ValueReconstructState { img, records };
+                let state = to_reconstruct_state(history).with_context(|| {
+                    generate_debug_trace(
+                        replay_history_for_debug_ref,
+                        full_history,
+                        retain_lsn_below_horizon,
+                        horizon,
+                    )
+                })?;
                 let request_lsn = lsn_split_points[i]; // last batch does not generate image so i is always in range
-                let img = self.reconstruct_value(key, request_lsn, state).await?;
+                let img = self
+                    .reconstruct_value(key, request_lsn, state)
+                    .await
+                    .with_context(|| reconstruct_error_context(key, request_lsn, &history_trace))?;
                 replay_history.push((key, request_lsn, Value::Image(img.clone())));
                 retention.push(vec![(request_lsn, Value::Image(img))]);
             } else {
-                let deltas = split_for_lsn
-                    .iter()
-                    .map(|(_, lsn, value)| (*lsn, value.clone()))
-                    .collect_vec();
                 retention.push(deltas);
             }
         }
@@ -1749,20 +2363,133 @@ fn generate_debug_trace(
         unreachable!("key retention is empty")
     }
 
+    /// Checks that the retention produced for a key by gc-compaction reads the same as the full
+    /// history of the key at the retained LSNs, the GC horizon and the latest LSN of the history.
+    /// This is enabled with the `gc_compaction_verify_every_nth_key` tenant config.
+    #[allow(clippy::too_many_arguments)]
+    async fn verify_key_retention(
+        &self,
+        key: Key,
+        full_history: &[(Key, Lsn, Value)],
+        base_img_from_ancestor: Option<&(Key, Lsn, Bytes)>,
+        retention: &KeyHistoryRetention,
+        horizon: Lsn,
+        retain_lsns_below_horizon: &[Lsn],
+    ) -> anyhow::Result<()> {
+        let base_img =
+            base_img_from_ancestor.map(|(_, lsn, img)| (*lsn, Value::Image(img.clone())));
+        let before = base_img
+            .iter()
+            .cloned()
+            .chain(full_history.iter().map(|(_, lsn, val)| (*lsn, val.clone())))
+            .collect_vec();
+        let after = base_img
+            .into_iter()
+            .chain(
+                retention
+                    .below_horizon
+                    .iter()
+                    .flat_map(|(_, KeyLogAtLsn(logs))| logs.iter().cloned()),
+            )
+            .chain(retention.above_horizon.0.iter().cloned())
+            .collect_vec();
+        let latest_lsn = full_history
+            .last()
+            .map(|(_, lsn, _)| *lsn)
+            .filter(|lsn| *lsn > horizon);
+        for lsn in retain_lsns_below_horizon
+            .iter()
+            .copied()
+            .chain([horizon])
+            .chain(latest_lsn)
+        {
+            let Ok(expected) = self.replay_key_history(key, &before, lsn).await else {
+                // Nothing to compare with, e.g. the history is incomplete below the retained LSNs.
+                continue;
+            };
+            let actual = self
+                .replay_key_history(key, &after, lsn)
+                .await
+                .with_context(|| format!("gc-compaction verification of key {key} at {lsn}"))?;
+            if actual != expected {
+                error!(
+                    %key,
+                    %lsn,
+                    expected_len = expected.as_ref().map(|v| v.len()),
+                    actual_len = actual.as_ref().map(|v| v.len()),
+                    "gc-compaction verification failed: the compacted history reads a different value"
+                );
+                anyhow::bail!("gc-compaction verification failed for key {key} at {lsn}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Reconstructs `key` at `lsn` from its history, sorted by LSN. Returns `None` if the key has no
+    /// records at or below `lsn`.
+    async fn replay_key_history(
+        &self,
+        key: Key,
+        history: &[(Lsn, Value)],
+        lsn: Lsn,
+    ) -> anyhow::Result<Option<Bytes>> {
+        let mut img = None;
+        let mut records = Vec::new();
+        let mut prev_lsn = None;
+        for (record_lsn, val) in history
+            .iter()
+            .take_while(|(record_lsn, _)| *record_lsn <= lsn)
+        {
+            if prev_lsn == Some(*record_lsn) {
+                // An image and a delta at the same LSN, the image comes first.
+                continue;
+            }
+            prev_lsn = Some(*record_lsn);
+            if val.will_init() {
+                img = None;
+                records.clear();
+            }
+            match val {
+                Value::Image(bytes) => img = Some((*record_lsn, bytes.clone())),
+                Value::WalRecord(rec) => records.push((*record_lsn, rec.clone())),
+            }
+        }
+        if img.is_none() && records.is_empty() {
+            return Ok(None);
+        }
+        if img.is_none() && !matches!(records.first(), Some((_, rec)) if rec.will_init()) {
+            anyhow::bail!("history of key {key} has no base image at {lsn}");
+        }
+        records.reverse();
+        let value = self
+            .reconstruct_value(key, lsn, ValueReconstructState { img, records })
+            .await?;
+        Ok(Some(value))
+    }
+
     /// An experimental compaction building block that combines compaction with garbage collection.
     ///
     /// The current implementation picks all delta + image layers that are below or intersecting with
     /// the GC horizon without considering retain_lsns. Then, it does a full compaction over all these delta
     /// layers and image layers, which generates image layers on the gc horizon, drop deltas below gc horizon,
     /// and create delta layers with all deltas >= gc horizon.
+    ///
+    /// If a key range is given, only the layers overlapping it are picked, see Step 0.
+    ///
+    /// Returns the statistics of the compaction, which are also logged.
     pub(crate) async fn compact_with_gc(
         self: &Arc<Self>,
         cancel: &CancellationToken,
-        flags: EnumSet<CompactFlags>,
+        options: CompactOptions,
+        observer: Option<&dyn CompactionObserver>,
         ctx: &RequestContext,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<CompactionStatistics> {
         use std::collections::BTreeSet;
 
+        if let Some(compact_range) = &options.compact_range {
+            self.check_compact_range(compact_range)?;
+        }
+
         // Block other compaction/GC tasks from running for now. GC-compaction could run along
         // with legacy compaction tasks in the future. Always ensure the lock order is compaction -> gc.
         // Note that we already acquired the compaction lock when the outer `compact` function gets called.
@@ -1782,7 +2509,7 @@ pub(crate) async fn compact_with_gc(
         )
         .await?;
 
-        let dry_run = flags.contains(CompactFlags::DryRun);
+        let dry_run = options.flags.contains(CompactFlags::DryRun);
 
         info!("running enhanced gc bottom-most compaction, dry_run={dry_run}");
 
@@ -1791,17 +2518,39 @@ pub(crate) async fn compact_with_gc(
         };
 
         let mut stat = CompactionStatistics::default();
+        let begin = tokio::time::Instant::now();
+        let mut gc_stats = CompactGcStatsBuilder {
+            version: Some(1),
+            tenant_id: Some(self.tenant_shard_id),
+            timeline_id: Some(self.timeline_id),
+            ..Default::default()
+        };
+
+        // An interrupted compaction might have recorded its progress. We resume it if we would
+        // compact the same layers, see [`manifest`].
+        let manifest = if dry_run {
+            None
+        } else {
+            GcCompactionManifest::read(
+                &self
+                    .conf
+                    .gc_compaction_manifest_path(&self.tenant_shard_id, &self.timeline_id),
+            )?
+        };
 
         // Step 0: pick all delta layers + image layers below/intersect with the GC horizon.
         // The layer selection has the following properties:
         // 1. If a layer is in the selection, all layers below it are in the selection.
         // 2. Inferred from (1), for each key in the layer selection, the value can be reconstructed only with the layers in the layer selection.
-        let (layer_selection, gc_cutoff, retain_lsns_below_horizon) = {
-            let guard = self.layers.read().await;
+        //
+        // When compacting a key range, only the layers overlapping it are picked. A picked layer is rewritten over
+        // its whole key range, so the range is widened until it covers all the layers it overlaps with, which keeps (1).
+        let (layer_selection, other_delta_layers, gc_cutoff, retain_lsns_below_horizon, can_resume) = {
+            let guard = self.read_layers_for_compaction().await?;
             let layers = guard.layer_map()?;
             let gc_info = self.gc_info.read().unwrap();
             let mut retain_lsns_below_horizon = Vec::new();
-            let gc_cutoff = gc_info.cutoffs.select_min();
+            let mut gc_cutoff = gc_info.cutoffs.select_min();
             for (lsn, _timeline_id) in &gc_info.retain_lsns {
                 if lsn < &gc_cutoff {
                     retain_lsns_below_horizon.push(*lsn);
@@ -1812,16 +2561,81 @@ pub(crate) async fn compact_with_gc(
                     retain_lsns_below_horizon.push(*lsn);
                 }
             }
-            let mut selected_layers = Vec::new();
+            // Compacting with the GC cutoff of the interrupted compaction picks the same layers if
+            // nothing else has changed them in between.
+            let can_resume = manifest.as_ref().is_some_and(|manifest| {
+                manifest.can_resume(self.generation, gc_cutoff, &retain_lsns_below_horizon)
+            });
+            if let Some(manifest) = manifest.as_ref().filter(|_| can_resume) {
+                gc_cutoff = manifest.gc_cutoff;
+                retain_lsns_below_horizon = manifest.retain_lsns_below_horizon.clone();
+            }
+            let mut candidate_layers = Vec::new();
+            let mut other_delta_layers = Vec::new();
             drop(gc_info);
             for desc in layers.iter_historic_layers() {
                 if desc.get_lsn_range().start <= gc_cutoff {
-                    selected_layers.push(guard.get_from_desc(&desc));
+                    candidate_layers.push(desc);
+                } else if desc.is_delta() {
+                    other_delta_layers.push(desc.key());
                 }
             }
+            let mut compact_range = options.compact_range.unwrap_or(Key::MIN..Key::MAX);
+            loop {
+                let mut widened_range = compact_range.clone();
+                for desc in &candidate_layers {
+                    let key_range = desc.get_key_range();
+                    if overlaps_with(&key_range, &compact_range) {
+                        widened_range.start = widened_range.start.min(key_range.start);
+                        widened_range.end = widened_range.end.max(key_range.end);
+                    }
+                }
+                if widened_range == compact_range {
+                    break;
+                }
+                compact_range = widened_range;
+            }
+            let selected_layers = candidate_layers
+                .iter()
+                .filter(|desc| overlaps_with(&desc.get_key_range(), &compact_range))
+                .map(|desc| guard.get_from_desc(desc))
+                .collect_vec();
             retain_lsns_below_horizon.sort();
-            (selected_layers, gc_cutoff, retain_lsns_below_horizon)
+            (
+                selected_layers,
+                other_delta_layers,
+                gc_cutoff,
+                retain_lsns_below_horizon,
+                can_resume,
+            )
+        };
+        let input_layers = layer_selection
+            .iter()
+            .map(|layer| layer.layer_desc().layer_name())
+            .collect_vec();
+        let manifest = match manifest {
+            Some(manifest) if can_resume && manifest.has_inputs(&input_layers) => {
+                match manifest.produced_resident_layers(self) {
+                    Some(resident_layers) => Some((manifest, resident_layers)),
+                    None => {
+                        info!(
+                            "discarding the progress of an interrupted gc-compaction with missing layers"
+                        );
+                        manifest.remove(self).await?;
+                        None
+                    }
+                }
+            }
+            Some(manifest) => {
+                info!(
+                    "discarding the progress of an interrupted gc-compaction with different inputs"
+                );
+                manifest.remove(self).await?;
+                None
+            }
+            None => None,
         };
+        let resume_key = manifest.as_ref().map(|(manifest, _)| manifest.resume_key);
         let lowest_retain_lsn = if self.ancestor_timeline.is_some() {
             Lsn(self.ancestor_lsn.0 + 1)
         } else {
@@ -1847,12 +2661,29 @@ pub(crate) async fn compact_with_gc(
             gc_cutoff,
             lowest_retain_lsn
         );
+        if layer_selection.is_empty() {
+            // E.g. a new timeline, or one with all its layers above the GC cutoff.
+            info!("no layers below the gc cutoff, nothing to compact");
+            return Ok(stat);
+        }
+        // The image layer covers the key range of the picked layers. It must not cover any key outside of
+        // them, where it would hide the layers we did not pick. Note that an image layer covering the whole
+        // key space is not taken for an L0 delta layer, see `LayerMap::is_l0`.
+        let image_layer_range = layer_selection
+            .iter()
+            .map(|layer| layer.layer_desc().get_key_range())
+            .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+            .expect("picked at least one layer");
+        let now = tokio::time::Instant::now();
+        gc_stats.layer_selection_micros =
+            DurationRecorder::Recorded(RecordedDuration(now - begin), now);
         // Step 1: (In the future) construct a k-merge iterator over all layers. For now, simply collect all keys + LSNs.
         // Also, collect the layer information to decide when to split the new delta layers.
         let mut downloaded_layers = Vec::new();
         let mut delta_split_points = BTreeSet::new();
+        let mut max_delta_lsn = gc_cutoff + 1;
         for layer in &layer_selection {
-            let resident_layer = layer.download_and_keep_resident().await?;
+            let resident_layer = self.download_for_compaction(layer).await?;
             downloaded_layers.push(resident_layer);
 
             let desc = layer.layer_desc();
@@ -1862,6 +2693,7 @@ pub(crate) async fn compact_with_gc(
                 let key_range = desc.get_key_range();
                 delta_split_points.insert(key_range.start);
                 delta_split_points.insert(key_range.end);
+                max_delta_lsn = max_delta_lsn.max(desc.get_lsn_range().end);
                 stat.visit_delta_layer(desc.file_size());
             } else {
                 stat.visit_image_layer(desc.file_size());
@@ -1878,6 +2710,7 @@ pub(crate) async fn compact_with_gc(
                 image_layers.push(layer);
             }
         }
+        gc_stats.download_micros = gc_stats.layer_selection_micros.till_now();
         let mut merge_iter = MergeIterator::create(&delta_layers, &image_layers, ctx);
         // Step 2: Produce images+deltas. TODO: ensure newly-produced delta does not overlap with other deltas.
         // Data of the same key.
@@ -1889,14 +2722,68 @@ enum FlushDeltaResult {
             CreateResidentLayer(ResidentLayer),
             /// Keep an original delta layer
             KeepLayer(PersistentLayerKey),
+            /// A layer that would be created, in dry-run mode
+            DryRunLayer(PersistentLayerKey),
+        }
+
+        /// Decides where to split the produced delta layers.
+        struct DeltaLayerSplitter {
+            split_points: Vec<Key>,
+            current_split_point: usize,
+            min_layer_size: u64,
+            /// The delta layers not picked for this compaction, which we must not overlap with.
+            other_delta_layers: Vec<PersistentLayerKey>,
+            /// Upper bound of the LSN range of any produced delta layer.
+            lsn_range: Range<Lsn>,
+        }
+
+        impl DeltaLayerSplitter {
+            /// Returns whether to finish the delta layer holding `deltas` before adding `next_key` to it.
+            fn should_split(&mut self, deltas: &[(Key, Lsn, Value)], next_key: Key) -> bool {
+                let mut need_split = false;
+                while self.current_split_point < self.split_points.len()
+                    && next_key >= self.split_points[self.current_split_point]
+                {
+                    self.current_split_point += 1;
+                    need_split = true;
+                }
+                if !need_split {
+                    return false;
+                }
+                let Some((first_key, _, _)) = deltas.first() else {
+                    return true;
+                };
+                let layer_size: u64 = deltas
+                    .iter()
+                    .map(|(_, _, val)| {
+                        (CompactionStatistics::estimated_size_of_value(val)
+                            + CompactionStatistics::estimated_size_of_key())
+                            as u64
+                    })
+                    .sum();
+                if layer_size >= self.min_layer_size {
+                    return true;
+                }
+                // Skipping this split point lets the layer grow up to the next one, where we decide again.
+                // That's only fine if no layer outside of the compaction overlaps with it.
+                let key_end = self
+                    .split_points
+                    .get(self.current_split_point)
+                    .copied()
+                    .unwrap_or(Key::MAX);
+                let key_range = *first_key..key_end;
+                self.other_delta_layers.iter().any(|layer| {
+                    overlaps_with(&layer.key_range, &key_range)
+                        && overlaps_with(&layer.lsn_range, &self.lsn_range)
+                })
+            }
         }
 
         #[allow(clippy::too_many_arguments)]
         async fn flush_deltas(
             deltas: &mut Vec<(Key, Lsn, crate::repository::Value)>,
-            last_key: Key,
-            delta_split_points: &[Key],
-            current_delta_split_point: &mut usize,
+            next_key: Key,
+            splitter: &mut DeltaLayerSplitter,
             tline: &Arc<Timeline>,
             lowest_retain_lsn: Lsn,
             ctx: &RequestContext,
@@ -1915,14 +2802,9 @@ async fn flush_deltas(
             //
             // And we choose to compact delta 2+3+5. We will get an overlapping delta layer with delta 1+4.
             // A simple solution here is to split the delta layers using the original boundary, while this
-            // might produce a lot of small layers. This should be improved and fixed in the future.
-            let mut need_split = false;
-            while *current_delta_split_point < delta_split_points.len()
-                && last_key >= delta_split_points[*current_delta_split_point]
-            {
-                *current_delta_split_point += 1;
-                need_split = true;
-            }
+            // might produce a lot of small layers. To avoid that, the splitter skips the boundaries that
+            // would produce a layer that is too small, when it can do so without overlapping.
+            let need_split = splitter.should_split(deltas, next_key);
             if !need_split && !last_batch {
                 return Ok(None);
             }
@@ -1959,6 +2841,7 @@ async fn flush_deltas(
                     drop(guard);
                     if layer_generation == tline.generation {
                         stats.discard_delta_layer();
+                        tline.metrics.compaction_discarded_layers_delta.inc();
                         // TODO: depending on whether we design this compaction process to run along with
                         // other compactions, there could be layer map modifications after we drop the
                         // layer guard, and in case it creates duplicated layer key, we will still error
@@ -1979,16 +2862,19 @@ async fn flush_deltas(
                 tline.tenant_shard_id,
                 delta_key.key_range.start,
                 lowest_retain_lsn..end_lsn,
+                None,
                 ctx,
             )
             .await?;
             for (key, lsn, val) in deltas {
-                delta_layer_writer.put_value(key, lsn, val, ctx).await?;
+                tline
+                    .put_value_for_compaction(&mut delta_layer_writer, key, lsn, val, ctx)
+                    .await?;
             }
 
             stats.produce_delta_layer(delta_layer_writer.size());
             if dry_run {
-                return Ok(None);
+                return Ok(Some(FlushDeltaResult::DryRunLayer(delta_key)));
             }
 
             let (desc, path) = delta_layer_writer
@@ -1998,13 +2884,9 @@ async fn flush_deltas(
             Ok(Some(FlushDeltaResult::CreateResidentLayer(delta_layer)))
         }
 
-        // Hack the key range to be min..(max-1). Otherwise, the image layer will be
-        // interpreted as an L0 delta layer.
-        let hack_image_layer_range = {
-            let mut end_key = Key::MAX;
-            end_key.field6 -= 1;
-            Key::MIN..end_key
-        };
+        // The image layer is split when the compaction records its progress, so it starts at the key
+        // we resume from.
+        let mut image_layer_start = resume_key.unwrap_or(image_layer_range.start);
 
         // Only create image layers when there is no ancestor branches. TODO: create covering image layer
         // when some condition meet.
@@ -2014,7 +2896,7 @@ async fn flush_deltas(
                     self.conf,
                     self.timeline_id,
                     self.tenant_shard_id,
-                    &hack_image_layer_range, // covers the full key range
+                    &(image_layer_start..image_layer_range.end), // covers the rest of the key range
                     lowest_retain_lsn,
                     ctx,
                 )
@@ -2024,41 +2906,58 @@ async fn flush_deltas(
             None
         };
 
-        /// Returns None if there is no ancestor branch. Throw an error when the key is not found.
-        ///
-        /// Currently, we always get the ancestor image for each key in the child branch no matter whether the image
-        /// is needed for reconstruction. This should be fixed in the future.
+        /// Fetches the ancestor images for the keys in the batch whose history on this timeline does not start
+        /// with an image or a will_init record, with a single vectored get. Other keys don't need a base image
+        /// for reconstruction, so they are skipped.
         ///
-        /// Furthermore, we should do vectored get instead of a single get, or better, use k-merge for ancestor
-        /// images.
-        async fn get_ancestor_image(
+        /// Returns an empty map if there is no ancestor branch. Throw an error when a key is not found.
+        async fn get_ancestor_images(
             tline: &Arc<Timeline>,
-            key: Key,
+            histories: &[(Key, Vec<(Key, Lsn, Value)>)],
+            stats: &mut CompactionStatistics,
             ctx: &RequestContext,
-        ) -> anyhow::Result<Option<(Key, Lsn, Bytes)>> {
+        ) -> anyhow::Result<HashMap<Key, (Key, Lsn, Bytes)>> {
             if tline.ancestor_timeline.is_none() {
-                return Ok(None);
+                return Ok(HashMap::new());
             };
+            let mut keyspace = KeySpaceAccum::new();
+            let mut num_keys = 0;
+            for (key, history) in histories {
+                let has_base_image = history.first().is_some_and(|(_, _, val)| val.will_init());
+                if !has_base_image {
+                    keyspace.add_key(*key);
+                    num_keys += 1;
+                }
+            }
+            if num_keys == 0 {
+                return Ok(HashMap::new());
+            }
+            stats.read_ancestor_images(num_keys);
             // This function is implemented as a get of the current timeline at ancestor LSN, therefore reusing
             // as much existing code as possible.
-            let img = tline.get(key, tline.ancestor_lsn, ctx).await?;
-            Ok(Some((key, tline.ancestor_lsn, img)))
+            let imgs = tline
+                .get_vectored(keyspace.to_keyspace(), tline.ancestor_lsn, ctx)
+                .await?;
+            let mut res = HashMap::with_capacity(imgs.len());
+            for (key, img) in imgs {
+                let img = img.with_context(|| format!("failed to get ancestor image of {key}"))?;
+                res.insert(key, (key, tline.ancestor_lsn, img));
+            }
+            Ok(res)
         }
-        let image_layer_key = PersistentLayerKey {
-            key_range: hack_image_layer_range,
-            lsn_range: PersistentLayerDesc::image_layer_lsn_range(lowest_retain_lsn),
-            is_delta: false,
-        };
 
-        // Like with delta layers, it can happen that we re-produce an already existing image layer.
-        // This could happen when a user triggers force compaction and image generation. In this case,
-        // it's always safe to rewrite the layer.
-        let discard_image_layer = {
-            let guard = self.layers.read().await;
-            if guard.contains_key(&image_layer_key) {
-                let layer_generation = guard.get_from_key(&image_layer_key).metadata().generation;
+        /// Like with delta layers, it can happen that we re-produce an already existing image layer.
+        /// This could happen when a user triggers force compaction and image generation. In this case,
+        /// it's always safe to rewrite the layer.
+        async fn is_duplicated_image_layer(
+            tline: &Arc<Timeline>,
+            image_layer_key: &PersistentLayerKey,
+        ) -> bool {
+            let guard = tline.layers.read().await;
+            if guard.contains_key(image_layer_key) {
+                let layer_generation = guard.get_from_key(image_layer_key).metadata().generation;
                 drop(guard);
-                if layer_generation == self.generation {
+                if layer_generation == tline.generation {
                     // TODO: depending on whether we design this compaction process to run along with
                     // other compactions, there could be layer map modifications after we drop the
                     // layer guard, and in case it creates duplicated layer key, we will still error
@@ -2075,144 +2974,305 @@ async fn get_ancestor_image(
             } else {
                 false
             }
-        };
+        }
 
         // Actually, we can decide not to write to the image layer at all at this point because
         // the key and LSN range are determined. However, to keep things simple here, we still
         // create this writer, and discard the writer in the end.
 
         let mut delta_values = Vec::new();
-        let delta_split_points = delta_split_points.into_iter().collect_vec();
-        let mut current_delta_split_point = 0;
+        let mut delta_splitter = DeltaLayerSplitter {
+            split_points: delta_split_points.into_iter().collect_vec(),
+            current_split_point: 0,
+            min_layer_size: self.get_compaction_target_size()
+                / GC_COMPACTION_MIN_DELTA_LAYER_SIZE_DIVISOR,
+            other_delta_layers,
+            lsn_range: lowest_retain_lsn..max_delta_lsn,
+        };
         let mut delta_layers = Vec::new();
-        while let Some((key, lsn, val)) = merge_iter.next().await? {
+        // The image layers finished when recording the progress, except for the last one.
+        let mut split_image_layers = Vec::new();
+        if let Some((manifest, resident_layers)) = manifest {
+            info!(resume_key=%manifest.resume_key, "resuming an interrupted gc-compaction");
+            for layer in resident_layers {
+                if layer.layer_desc().is_delta() {
+                    delta_layers.push(FlushDeltaResult::CreateResidentLayer(layer));
+                } else {
+                    split_image_layers.push(FlushDeltaResult::CreateResidentLayer(layer));
+                }
+            }
+            for name in &manifest.kept_layers {
+                let key = manifest::key_of_layer_name(name);
+                if key.is_delta {
+                    delta_layers.push(FlushDeltaResult::KeepLayer(key));
+                } else {
+                    split_image_layers.push(FlushDeltaResult::KeepLayer(key));
+                }
+            }
+        }
+        // We record the progress whenever we have written about a target layer size since the last time.
+        // The layers are split at the same keys in dry-run mode, so that it reports the same layers.
+        let checkpoint_size = self.get_compaction_target_size();
+        let mut delta_bytes_at_checkpoint = 0;
+        let max_versions_per_key = self.get_compaction_max_versions_per_key();
+        let verify_every_nth_key = self.get_gc_compaction_verify_every_nth_key();
+        let mut num_keys_processed = 0u64;
+        // Full histories of the keys waiting to be processed, so that the ancestor images can be fetched in batch.
+        let mut pending_histories = Vec::new();
+        loop {
+            let merge_started_at = tokio::time::Instant::now();
+            let next = merge_iter.next().await?;
+            gc_stats.merge_iteration += merge_started_at.elapsed();
             if cancel.is_cancelled() {
                 return Err(anyhow!("cancelled")); // TODO: refactor to CompactionError and pass cancel error
             }
-            match val {
-                Value::Image(_) => stat.visit_image_key(&val),
-                Value::WalRecord(_) => stat.visit_wal_key(&val),
-            }
-            if last_key.is_none() || last_key.as_ref() == Some(&key) {
-                if last_key.is_none() {
-                    last_key = Some(key);
+            let exhausted = next.is_none();
+            if let Some((key, lsn, val)) = next {
+                if resume_key.is_some_and(|resume_key| key < resume_key) {
+                    // Already processed before the compaction got interrupted.
+                    continue;
+                }
+                match val {
+                    Value::Image(_) => stat.visit_image_key(&val),
+                    Value::WalRecord(_) => stat.visit_wal_key(&val),
                 }
+                if let Some(last_key) = last_key.filter(|last_key| *last_key != key) {
+                    stat.on_unique_key_visited();
+                    pending_histories.push((last_key, std::mem::take(&mut accumulated_values)));
+                }
+                last_key = Some(key);
                 accumulated_values.push((key, lsn, val));
-            } else {
-                let last_key = last_key.as_mut().unwrap();
+            } else if let Some(last_key) = last_key {
                 stat.on_unique_key_visited();
-                let retention = self
+                pending_histories.push((last_key, std::mem::take(&mut accumulated_values)));
+            } else {
+                // The picked layers contain no keys, or all of them were processed before the
+                // compaction got interrupted.
+                debug!("no keys produced during compaction");
+            }
+            if !exhausted && pending_histories.len() < Timeline::MAX_GET_VECTORED_KEYS as usize {
+                continue;
+            }
+
+            let retention_started_at = tokio::time::Instant::now();
+            let mut ancestor_images =
+                get_ancestor_images(self, &pending_histories, &mut stat, ctx).await?;
+            gc_stats.retention += retention_started_at.elapsed();
+            for (key, history) in pending_histories.drain(..) {
+                let ancestor_image = ancestor_images.remove(&key);
+                let retention_started_at = tokio::time::Instant::now();
+                #[allow(unused_mut)]
+                let mut retention = self
                     .generate_key_retention(
-                        *last_key,
-                        &accumulated_values,
+                        key,
+                        &history,
                         gc_cutoff,
                         &retain_lsns_below_horizon,
                         COMPACTION_DELTA_THRESHOLD,
-                        get_ancestor_image(self, *last_key, ctx).await?,
+                        max_versions_per_key,
+                        ancestor_image.clone(),
+                        None,
                     )
                     .await?;
+                gc_stats.retention += retention_started_at.elapsed();
+                #[cfg(test)]
+                if self
+                    .gc_compaction_corrupt_images
+                    .load(std::sync::atomic::Ordering::Relaxed)
+                {
+                    for (_, KeyLogAtLsn(logs)) in &mut retention.below_horizon {
+                        for (_, val) in logs.iter_mut() {
+                            if let Value::Image(img) = val {
+                                *img = Bytes::from(format!("corrupted {key}"));
+                            }
+                        }
+                    }
+                }
+                if verify_every_nth_key > 0 && num_keys_processed % verify_every_nth_key == 0 {
+                    self.verify_key_retention(
+                        key,
+                        &history,
+                        ancestor_image.as_ref(),
+                        &retention,
+                        gc_cutoff,
+                        &retain_lsns_below_horizon,
+                    )
+                    .await?;
+                }
+                num_keys_processed += 1;
+                // Finish the current delta layer before this key if we cross a split point, so that the
+                // produced layer does not extend beyond it.
+                delta_layers.extend(
+                    flush_deltas(
+                        &mut delta_values,
+                        key,
+                        &mut delta_splitter,
+                        self,
+                        lowest_retain_lsn,
+                        ctx,
+                        &mut stat,
+                        dry_run,
+                        false,
+                    )
+                    .await?,
+                );
+                let bytes_since_checkpoint = stat.delta_layer_produced.size
+                    - delta_bytes_at_checkpoint
+                    + image_layer_writer
+                        .as_ref()
+                        .map_or(0, |writer| writer.size());
+                if key > image_layer_start && bytes_since_checkpoint >= checkpoint_size {
+                    // Finish all the layers below this key and record them, so that we can resume from here.
+                    delta_layers.extend(
+                        flush_deltas(
+                            &mut delta_values,
+                            key,
+                            &mut delta_splitter,
+                            self,
+                            lowest_retain_lsn,
+                            ctx,
+                            &mut stat,
+                            dry_run,
+                            true,
+                        )
+                        .await?,
+                    );
+                    if let Some(writer) = image_layer_writer.take() {
+                        let image_layer_key = PersistentLayerKey {
+                            key_range: image_layer_start..key,
+                            lsn_range: PersistentLayerDesc::image_layer_lsn_range(
+                                lowest_retain_lsn,
+                            ),
+                            is_delta: false,
+                        };
+                        if is_duplicated_image_layer(self, &image_layer_key).await {
+                            stat.discard_image_layer();
+                            self.metrics.compaction_discarded_layers_image.inc();
+                            split_image_layers.push(FlushDeltaResult::KeepLayer(image_layer_key));
+                        } else if dry_run {
+                            stat.produce_image_layer(writer.size());
+                            split_image_layers.push(FlushDeltaResult::DryRunLayer(image_layer_key));
+                        } else {
+                            stat.produce_image_layer(writer.size());
+                            split_image_layers.push(FlushDeltaResult::CreateResidentLayer(
+                                writer.finish_with_end_key(self, key, ctx).await?,
+                            ));
+                        }
+                        image_layer_writer = Some(
+                            ImageLayerWriter::new(
+                                self.conf,
+                                self.timeline_id,
+                                self.tenant_shard_id,
+                                &(key..image_layer_range.end),
+                                lowest_retain_lsn,
+                                ctx,
+                            )
+                            .await?,
+                        );
+                    }
+                    image_layer_start = key;
+                    delta_bytes_at_checkpoint = stat.delta_layer_produced.size;
+                    if !dry_run {
+                        let mut produced_layers = Vec::new();
+                        let mut kept_layers = Vec::new();
+                        for result in delta_layers.iter().chain(split_image_layers.iter()) {
+                            match result {
+                                FlushDeltaResult::CreateResidentLayer(layer) => {
+                                    produced_layers.push(ProducedLayer {
+                                        name: layer.layer_desc().layer_name(),
+                                        metadata: layer.metadata(),
+                                    });
+                                }
+                                FlushDeltaResult::KeepLayer(key) => {
+                                    kept_layers.push(manifest::layer_name_of_key(key));
+                                }
+                                FlushDeltaResult::DryRunLayer(_) => {
+                                    unreachable!("no dry-run layers outside of dry-run mode")
+                                }
+                            }
+                        }
+                        GcCompactionManifest {
+                            generation: self.generation,
+                            gc_cutoff,
+                            retain_lsns_below_horizon: retain_lsns_below_horizon.clone(),
+                            input_layers: input_layers.clone(),
+                            resume_key: key,
+                            produced_layers,
+                            kept_layers,
+                        }
+                        .persist(self)
+                        .await?;
+                        info!(resume_key=%key, "recorded gc-compaction progress");
+
+                        #[cfg(test)]
+                        if self
+                            .gc_compaction_fail_after_checkpoint
+                            .load(std::sync::atomic::Ordering::Relaxed)
+                        {
+                            anyhow::bail!("failing gc-compaction after recording its progress");
+                        }
+                    }
+                }
                 // Put the image into the image layer. Currently we have a single big layer for the compaction.
                 retention
                     .pipe_to(
-                        *last_key,
+                        key,
                         &mut delta_values,
                         image_layer_writer.as_mut(),
                         &mut stat,
                         ctx,
                     )
                     .await?;
+            }
+            if exhausted {
                 delta_layers.extend(
                     flush_deltas(
                         &mut delta_values,
-                        *last_key,
-                        &delta_split_points,
-                        &mut current_delta_split_point,
+                        Key::MAX,
+                        &mut delta_splitter,
                         self,
                         lowest_retain_lsn,
                         ctx,
                         &mut stat,
                         dry_run,
-                        false,
+                        true,
                     )
                     .await?,
                 );
-                accumulated_values.clear();
-                *last_key = key;
-                accumulated_values.push((key, lsn, val));
+                break;
             }
         }
-
-        let last_key = last_key.expect("no keys produced during compaction");
-        // TODO: move this part to the loop body
-        stat.on_unique_key_visited();
-        let retention = self
-            .generate_key_retention(
-                last_key,
-                &accumulated_values,
-                gc_cutoff,
-                &retain_lsns_below_horizon,
-                COMPACTION_DELTA_THRESHOLD,
-                get_ancestor_image(self, last_key, ctx).await?,
-            )
-            .await?;
-        // Put the image into the image layer. Currently we have a single big layer for the compaction.
-        retention
-            .pipe_to(
-                last_key,
-                &mut delta_values,
-                image_layer_writer.as_mut(),
-                &mut stat,
-                ctx,
-            )
-            .await?;
-        delta_layers.extend(
-            flush_deltas(
-                &mut delta_values,
-                last_key,
-                &delta_split_points,
-                &mut current_delta_split_point,
-                self,
-                lowest_retain_lsn,
-                ctx,
-                &mut stat,
-                dry_run,
-                true,
-            )
-            .await?,
-        );
         assert!(delta_values.is_empty(), "unprocessed keys");
+        gc_stats.key_processing_micros = gc_stats.download_micros.till_now();
 
+        let image_layer_key = PersistentLayerKey {
+            key_range: image_layer_start..image_layer_range.end,
+            lsn_range: PersistentLayerDesc::image_layer_lsn_range(lowest_retain_lsn),
+            is_delta: false,
+        };
+        let discard_image_layer = is_duplicated_image_layer(self, &image_layer_key).await;
+        let mut dry_run_layers = Vec::new();
         let image_layer = if discard_image_layer {
             stat.discard_image_layer();
+            self.metrics.compaction_discarded_layers_image.inc();
             None
         } else if let Some(writer) = image_layer_writer {
             stat.produce_image_layer(writer.size());
             if !dry_run {
                 Some(writer.finish(self, ctx).await?)
             } else {
+                dry_run_layers.push(image_layer_key.clone());
                 None
             }
         } else {
             None
         };
 
-        info!(
-            "gc-compaction statistics: {}",
-            serde_json::to_string(&stat)?
-        );
-
-        if dry_run {
-            return Ok(());
-        }
-
-        info!(
-            "produced {} delta layers and {} image layers",
-            delta_layers.len(),
-            if image_layer.is_some() { 1 } else { 0 }
-        );
+        let num_delta_layers = delta_layers.len();
+        let num_image_layers = split_image_layers.len() + if image_layer.is_some() { 1 } else { 0 };
         let mut compact_to = Vec::new();
         let mut keep_layers = HashSet::new();
-        for action in delta_layers {
+        for action in delta_layers.into_iter().chain(split_image_layers) {
             match action {
                 FlushDeltaResult::CreateResidentLayer(layer) => {
                     compact_to.push(layer);
@@ -2220,29 +3280,174 @@ async fn get_ancestor_image(
                 FlushDeltaResult::KeepLayer(l) => {
                     keep_layers.insert(l);
                 }
+                FlushDeltaResult::DryRunLayer(l) => {
+                    dry_run_layers.push(l);
+                }
             }
         }
         if discard_image_layer {
             keep_layers.insert(image_layer_key);
         }
         let mut layer_selection = layer_selection;
-        layer_selection.retain(|x| !keep_layers.contains(&x.layer_desc().key()));
+        layer_selection.retain(|x| {
+            let key = x.layer_desc().key();
+            if keep_layers.contains(&key) {
+                return false;
+            }
+            if self.is_layer_pinned(&key) {
+                info!(layer=%x, "keeping pinned layer");
+                return false;
+            }
+            true
+        });
+
+        if dry_run {
+            stat.layer_map_diff = Some(LayerMapDiff {
+                removed: layer_selection
+                    .iter()
+                    .map(|layer| layer.layer_desc().layer_name())
+                    .collect(),
+                added: dry_run_layers
+                    .iter()
+                    .map(manifest::layer_name_of_key)
+                    .collect(),
+            });
+        }
+
+        stat.write_amplification = Some(stat.write_amplification());
+        info!(
+            "gc-compaction statistics: {}",
+            serde_json::to_string(&stat)?
+        );
+
+        if dry_run {
+            gc_stats.layer_finalization_micros = gc_stats.key_processing_micros.till_now();
+            stat.timings = gc_stats.finish(begin);
+            return Ok(stat);
+        }
+
+        info!(
+            "produced {} delta layers and {} image layers",
+            num_delta_layers, num_image_layers
+        );
         compact_to.extend(image_layer);
+        if let Some(observer) = observer {
+            for layer in &compact_to {
+                observer.on_layer_produced(layer.layer_desc());
+            }
+        }
 
         // Step 3: Place back to the layer map.
         {
             let mut guard = self.layers.write().await;
             guard
                 .open_mut()?
-                .finish_gc_compaction(&layer_selection, &compact_to, &self.metrics)
+                .finish_gc_compaction(&layer_selection, &compact_to, &self.metrics);
+            // Without an ancestor, the compaction covers the whole key range of the picked layers with
+            // images at the lowest retained LSN. A gap means that the layers selected for removal
+            // included one that should have been kept.
+            if cfg!(debug_assertions) && self.ancestor_timeline.is_none() {
+                if let Ok(layer_map) = guard.layer_map() {
+                    let missing =
+                        missing_image_coverage(layer_map, &image_layer_range, lowest_retain_lsn);
+                    assert!(
+                        missing.is_empty(),
+                        "gc-compaction left {missing:?} without an image layer at {lowest_retain_lsn}"
+                    );
+                }
+            }
         };
         self.remote_client
             .schedule_compaction_update(&layer_selection, &compact_to)?;
+        // The produced layers are now tracked by the layer map and the remote index.
+        manifest::remove_manifest(self)?;
 
         drop(gc_lock);
 
-        Ok(())
+        gc_stats.layer_finalization_micros = gc_stats.key_processing_micros.till_now();
+        stat.timings = gc_stats.finish(begin);
+
+        Ok(stat)
+    }
+}
+
+/// Counts the keys in `range` that each shard stores, for the shard count and stripe size of
+/// `shard_identity`. Keys that are stored on all shards, like relation sizes, are counted for each
+/// of them. The range must be within a single relation, as the keys of other ranges are sparse.
+fn shard_key_counts(
+    shard_identity: &ShardIdentity,
+    range: Range<Key>,
+) -> anyhow::Result<BTreeMap<ShardNumber, u32>> {
+    let shards = if shard_identity.count < ShardCount::new(2) {
+        vec![*shard_identity]
+    } else {
+        (0..shard_identity.count.count())
+            .map(|number| {
+                ShardIdentity::new(
+                    ShardNumber(number),
+                    shard_identity.count,
+                    shard_identity.stripe_size,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let mut counts = BTreeMap::new();
+    for shard in &shards {
+        let count = ShardedRange::new(range.clone(), shard).page_count();
+        if count == u32::MAX {
+            anyhow::bail!(
+                "cannot count the keys of range {}..{}, it spans relations",
+                range.start,
+                range.end
+            );
+        }
+        counts.insert(shard.number, count);
     }
+    Ok(counts)
+}
+
+/// Returns the parts of `key_range` not covered by an image layer at exactly `lsn`.
+fn missing_image_coverage(
+    layer_map: &LayerMap,
+    key_range: &Range<Key>,
+    lsn: Lsn,
+) -> Vec<Range<Key>> {
+    layer_map
+        .image_coverage(key_range, lsn)
+        .into_iter()
+        .filter(|(_, image)| {
+            image
+                .as_ref()
+                .map_or(true, |image| image.get_lsn_range().start != lsn)
+        })
+        .map(|(range, _)| range)
+        .collect()
+}
+
+/// How many values [`TimelineAdaptor::create_delta`] loads ahead of the one it writes.
+const CREATE_DELTA_LOAD_CONCURRENCY: usize = 16;
+
+/// Runs `load` for the items with up to `concurrency` loads in flight, and yields the results in
+/// the order of the items.
+fn load_in_order<I, F, Fut>(
+    items: I,
+    concurrency: usize,
+    load: F,
+) -> impl Stream<Item = Fut::Output>
+where
+    I: IntoIterator,
+    F: FnMut(I::Item) -> Fut,
+    Fut: Future,
+{
+    futures::stream::iter(items).map(load).buffered(concurrency)
+}
+
+/// The key space was requested at another LSN than the one [`TimelineAdaptor`] collected it at.
+#[derive(thiserror::Error, Debug)]
+#[error("keyspace not available for requested lsn {requested}, only for lsn {available}")]
+pub(crate) struct KeySpaceNotAvailable {
+    pub(crate) requested: Lsn,
+    pub(crate) available: Lsn,
 }
 
 struct TimelineAdaptor {
@@ -2279,7 +3484,8 @@ pub async fn flush_updates(&mut self) -> Result<(), CompactionError> {
             .await?;
 
         self.timeline
-            .upload_new_image_layers(std::mem::take(&mut self.new_images))?;
+            .upload_new_image_layers(std::mem::take(&mut self.new_images))
+            .await?;
 
         self.new_deltas.clear();
         self.layers_to_delete.clear();
@@ -2340,7 +3546,11 @@ async fn get_keyspace(
         } else {
             // The current compaction implementation only ever requests the key space
             // at the compaction end LSN.
-            anyhow::bail!("keyspace not available for requested lsn");
+            Err(KeySpaceNotAvailable {
+                requested: lsn,
+                available: self.keyspace.0,
+            }
+            .into())
         }
     }
 
@@ -2354,7 +3564,7 @@ async fn downcast_delta_layer(
                 let guard = self.timeline.layers.read().await;
                 guard.get_from_desc(layer)
             };
-            let result = l.download_and_keep_resident().await?;
+            let result = self.timeline.download_for_compaction(&l).await?;
 
             Ok(Some(ResidentDeltaLayer(result)))
         } else {
@@ -2395,6 +3605,7 @@ async fn create_delta(
             self.timeline.tenant_shard_id,
             key_range.start,
             lsn_range.clone(),
+            None,
             ctx,
         )
         .await?;
@@ -2404,10 +3615,8 @@ async fn create_delta(
         // This iterator walks through all key-value pairs from all the layers
         // we're compacting, in key, LSN order.
         let mut prev: Option<(Key, Lsn)> = None;
-        for &DeltaEntry {
-            key, lsn, ref val, ..
-        } in all_entries.iter()
-        {
+        let mut entries = Vec::with_capacity(all_entries.len());
+        for entry @ &DeltaEntry { key, lsn, .. } in all_entries.iter() {
             if prev == Some((key, lsn)) {
                 // This is a duplicate. Skip it.
                 //
@@ -2419,14 +3628,23 @@ async fn create_delta(
                 dup_values += 1;
                 continue;
             }
-
-            let value = val.load(ctx).await?;
-
-            writer.put_value(key, lsn, value, ctx).await?;
-
+            entries.push(entry);
             prev = Some((key, lsn));
         }
 
+        // Load the next values while writing the current one, so that the reads don't wait for each other.
+        let mut values =
+            load_in_order(entries, CREATE_DELTA_LOAD_CONCURRENCY, |entry| async move {
+                let value = entry.val.load(ctx).await?;
+                anyhow::Ok((entry.key, entry.lsn, value))
+            });
+        while let Some(loaded) = values.next().await {
+            let (key, lsn, value) = loaded?;
+            self.timeline
+                .put_value_for_compaction(&mut writer, key, lsn, value, ctx)
+                .await?;
+        }
+
         if dup_values > 0 {
             warn!("delta layer created with {} duplicate values", dup_values);
         }
@@ -2462,6 +3680,15 @@ async fn create_image_impl(
         key_range: &Range<Key>,
         ctx: &RequestContext,
     ) -> Result<(), CreateImageLayersError> {
+        // The tiered algorithm only knows about the dense keyspace, but may ask for images up to
+        // the end of the key space. Those must not cover the sparse keys, whose deltas they would
+        // hide: missing sparse keys in an image layer are treated as deleted.
+        let key_range =
+            &(key_range.start..std::cmp::min(key_range.end, Key::metadata_key_range().start));
+        if key_range.is_empty() {
+            return Ok(());
+        }
+
         let timer = self.timeline.metrics.create_images_time_histo.start_timer();
 
         let image_layer_writer = ImageLayerWriter::new(
@@ -2508,6 +3735,49 @@ async fn create_image_impl(
 
         Ok(())
     }
+
+    /// Creates an image layer for a range of the sparse keyspace, which the tiered algorithm
+    /// doesn't know about.
+    async fn create_sparse_image_impl(
+        &mut self,
+        lsn: Lsn,
+        partition: &KeySpace,
+        ctx: &RequestContext,
+    ) -> Result<(), CreateImageLayersError> {
+        let (Some(start), Some(end)) = (partition.start(), partition.end()) else {
+            return Ok(());
+        };
+        let img_range = start..end;
+
+        let image_layer_writer = ImageLayerWriter::new(
+            self.timeline.conf,
+            self.timeline.timeline_id,
+            self.timeline.tenant_shard_id,
+            &img_range,
+            lsn,
+            ctx,
+        )
+        .await?;
+
+        let ImageLayerCreationOutcome {
+            image,
+            next_start_key: _,
+        } = self
+            .timeline
+            .create_image_layer_for_metadata_keys(
+                partition,
+                image_layer_writer,
+                lsn,
+                ctx,
+                img_range,
+                ImageLayerCreationMode::Force,
+                start,
+            )
+            .await?;
+
+        self.new_images.extend(image);
+        Ok(())
+    }
 }
 
 impl CompactionRequestContext for crate::context::RequestContext {}
@@ -2610,3 +3880,606 @@ fn is_delta(&self) -> bool {
     }
 }
 impl CompactionImageLayer<TimelineAdaptor> for ResidentImageLayer {}
+
+/// A key range without any keys in the compacted L0 layers. See [`find_holes`].
+#[derive(Debug, PartialEq, Eq)]
+struct Hole {
+    key_range: Range<Key>,
+    coverage_size: usize,
+}
+
+impl Ord for Hole {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.coverage_size.cmp(&other.coverage_size).reverse()
+    }
+}
+
+impl PartialOrd for Hole {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Determine the `max_holes` largest holes among `keys`, which are sorted. The result is sorted
+/// by key range start.
+///
+/// A hole is a key range for which this compaction doesn't have any WAL records.
+/// Our goal in this compaction iteration is to avoid creating L1s that, in terms of their key range,
+/// cover the hole, but actually don't contain any WAL records for that key range.
+/// The reason is that the mere stack of L1s (`count_deltas`) triggers image layer creation (`create_image_layers`).
+/// That image layer creation would be useless for a hole range covered by L1s that don't contain any WAL records.
+///
+/// The algorithm chooses holes as follows.
+/// - Slide a 2-window over the keys in key orde to get the hole range (=distance between two keys).
+/// - Filter: min threshold on range length (`min_hole_range`)
+/// - Filter: min threshold on coverage size (`min_hole_coverage_size`, from the tenant config)
+/// - Rank: by coverage size (=number of image layers required to reconstruct each key in the range for which we have any data)
+///
+/// For more details, intuition, and some ASCII art see https://github.com/neondatabase/neon/pull/3597#discussion_r1112704451
+fn find_holes(
+    keys: impl Iterator<Item = Key>,
+    layers: &LayerMap,
+    last_record_lsn: Lsn,
+    max_holes: usize,
+    min_hole_range: i128,
+    min_hole_coverage_size: usize,
+) -> Vec<Hole> {
+    // min-heap (reserve space for one more element added before eviction)
+    let mut heap: BinaryHeap<Hole> = BinaryHeap::with_capacity(max_holes + 1);
+    let mut prev: Option<Key> = None;
+
+    for next_key in keys {
+        if let Some(prev_key) = prev {
+            // just first fast filter, do not create hole entries for metadata keys. The last hole in the
+            // compaction is the gap between data key and metadata keys.
+            if next_key.to_i128() - prev_key.to_i128() >= min_hole_range
+                && !Key::is_metadata_key(&prev_key)
+            {
+                let key_range = prev_key..next_key;
+                // Measuring hole by just subtraction of i128 representation of key range boundaries
+                // has not so much sense, because largest holes will corresponds field1/field2 changes.
+                // But we are mostly interested to eliminate holes which cause generation of excessive image layers.
+                // That is why it is better to measure size of hole as number of covering image layers.
+                let coverage_size = layers.image_coverage(&key_range, last_record_lsn).len();
+                if coverage_size >= min_hole_coverage_size {
+                    heap.push(Hole {
+                        key_range,
+                        coverage_size,
+                    });
+                    if heap.len() > max_holes {
+                        heap.pop(); // remove smallest hole
+                    }
+                }
+            }
+        }
+        prev = Some(next_key.next());
+    }
+    let mut holes = heap.into_vec();
+    holes.sort_unstable_by_key(|hole| hole.key_range.start);
+    holes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenant::config::TenantConfOpt;
+    use crate::tenant::harness::{TenantHarness, TIMELINE_ID};
+    use crate::DEFAULT_PG_VERSION;
+
+    #[test]
+    fn compaction_statistics_write_amplification() {
+        let mut stat = CompactionStatistics::default();
+        assert_eq!(stat.write_amplification(), WriteAmplification::default());
+
+        stat.visit_delta_layer(100);
+        stat.visit_delta_layer(100);
+        stat.produce_delta_layer(500);
+        assert_eq!(
+            stat.write_amplification(),
+            WriteAmplification {
+                delta: Some(2.5),
+                image: None,
+                total: Some(2.5),
+            }
+        );
+
+        stat.visit_image_layer(200);
+        stat.produce_image_layer(100);
+        assert_eq!(
+            stat.write_amplification(),
+            WriteAmplification {
+                delta: Some(2.5),
+                image: Some(0.5),
+                total: Some(1.5),
+            }
+        );
+
+        stat.write_amplification = Some(stat.write_amplification());
+        let json: serde_json::Value = serde_json::to_value(&stat).unwrap();
+        assert_eq!(json["write_amplification"]["image"], 0.5);
+    }
+
+    #[test]
+    fn find_holes_respects_min_coverage_size() {
+        // Four image layers cover the gap between keys 0 and 1000, two the one between 1000 and
+        // 2000.
+        let mut layer_map = LayerMap::default();
+        let mut updates = layer_map.batch_update();
+        for (start, end) in [
+            (0, 250),
+            (250, 500),
+            (500, 750),
+            (750, 1000),
+            (1000, 1500),
+            (1500, 2001),
+        ] {
+            updates.insert_historic(PersistentLayerDesc::new_test(
+                Key::from_i128(start)..Key::from_i128(end),
+                Lsn(0x10)..Lsn(0x11),
+                false,
+            ));
+        }
+        updates.flush();
+
+        let keys = [0, 1000, 2000].map(Key::from_i128);
+        let holes = |min_hole_coverage_size| {
+            find_holes(
+                keys.into_iter(),
+                &layer_map,
+                Lsn(0x20),
+                10,
+                100,
+                min_hole_coverage_size,
+            )
+            .into_iter()
+            .map(|hole| (hole.key_range, hole.coverage_size))
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            holes(1),
+            vec![
+                (Key::from_i128(1)..Key::from_i128(1000), 4),
+                (Key::from_i128(1001)..Key::from_i128(2000), 2),
+            ]
+        );
+        assert_eq!(holes(3), vec![(Key::from_i128(1)..Key::from_i128(1000), 4)]);
+        assert_eq!(holes(5), vec![]);
+    }
+
+    #[tokio::test]
+    async fn timeline_adaptor_keyspace_at_other_lsn() {
+        let harness = TenantHarness::create("timeline_adaptor_keyspace_at_other_lsn")
+            .await
+            .unwrap();
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await
+            .unwrap();
+
+        let key_range = Key::from_i128(0)..Key::from_i128(100);
+        let keyspace = KeySpace {
+            ranges: vec![key_range.clone()],
+        };
+        let mut adaptor = TimelineAdaptor::new(&tline, (Lsn(0x20), keyspace));
+
+        let ranges = adaptor
+            .get_keyspace(&key_range, Lsn(0x20), &ctx)
+            .await
+            .unwrap();
+        assert_eq!(ranges, vec![key_range.clone()]);
+
+        let err = adaptor
+            .get_keyspace(&key_range, Lsn(0x30), &ctx)
+            .await
+            .unwrap_err();
+        let err = err
+            .downcast_ref::<KeySpaceNotAvailable>()
+            .expect("typed error");
+        assert_eq!(err.requested, Lsn(0x30));
+        assert_eq!(err.available, Lsn(0x20));
+    }
+
+    #[test]
+    fn missing_image_coverage_detects_dropped_layer() {
+        let layer_map_with = |image_ranges: &[(i128, i128)]| {
+            let mut layer_map = LayerMap::default();
+            let mut updates = layer_map.batch_update();
+            // An older image below, which must not count as coverage.
+            updates.insert_historic(PersistentLayerDesc::new_test(
+                Key::from_i128(0)..Key::from_i128(300),
+                Lsn(0x10)..Lsn(0x11),
+                false,
+            ));
+            updates.insert_historic(PersistentLayerDesc::new_test(
+                Key::from_i128(0)..Key::from_i128(300),
+                Lsn(0x20)..Lsn(0x40),
+                true,
+            ));
+            for (start, end) in image_ranges {
+                updates.insert_historic(PersistentLayerDesc::new_test(
+                    Key::from_i128(*start)..Key::from_i128(*end),
+                    Lsn(0x30)..Lsn(0x31),
+                    false,
+                ));
+            }
+            updates.flush();
+            layer_map
+        };
+        let key_range = Key::from_i128(0)..Key::from_i128(300);
+
+        let layer_map = layer_map_with(&[(0, 100), (100, 200), (200, 300)]);
+        assert!(missing_image_coverage(&layer_map, &key_range, Lsn(0x30)).is_empty());
+
+        let layer_map = layer_map_with(&[(0, 100), (200, 300)]);
+        assert_eq!(
+            missing_image_coverage(&layer_map, &key_range, Lsn(0x30)),
+            vec![Key::from_i128(100)..Key::from_i128(200)]
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_delta_layer_warn_multiplier() {
+        let harness = TenantHarness::create("oversized_delta_layer_warn_multiplier")
+            .await
+            .unwrap();
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await
+            .unwrap();
+
+        let mut key = Key::from_hex("010000000033333333444444445500000000").unwrap();
+        let mut lsn = Lsn(0x10);
+        for blknum in 0..100 {
+            lsn = Lsn(lsn.0 + 0x10);
+            key.field6 = blknum;
+            let mut writer = tline.writer().await;
+            writer
+                .put(key, lsn, &Value::Image(Bytes::from(vec![0xab; 8192])), &ctx)
+                .await
+                .unwrap();
+            writer.finish_write(lsn);
+        }
+        tline.freeze_and_flush().await.unwrap();
+        let file_size = {
+            let guard = tline.layers.read().await;
+            let layer_map = guard.layer_map().unwrap();
+            layer_map.level0_deltas()[0].file_size()
+        };
+
+        // The layer is between one and two times the target size.
+        let target_file_size = file_size * 2 / 3;
+        assert!(!tline.is_oversized_delta_layer(file_size, target_file_size));
+
+        tenant.set_new_tenant_config(TenantConfOpt {
+            compaction_oversize_warn_multiplier: Some(1),
+            ..TenantConfOpt::default()
+        });
+        assert!(tline.is_oversized_delta_layer(file_size, target_file_size));
+    }
+
+    #[tokio::test]
+    async fn shard_key_counts_per_shard() {
+        use crate::tenant::config::TenantConf;
+        use pageserver_api::shard::ShardStripeSize;
+        use utils::{generation::Generation, id::TenantId};
+
+        let shard_identity =
+            ShardIdentity::new(ShardNumber(1), ShardCount::new(4), ShardStripeSize(8)).unwrap();
+        let harness = TenantHarness::create_custom(
+            "shard_key_counts_per_shard",
+            TenantConf::default(),
+            TenantId::generate(),
+            shard_identity,
+            Generation::new(0xdeadbeef),
+        )
+        .await
+        .unwrap();
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await
+            .unwrap();
+
+        let start = Key::from_hex("000000067F00000005000040100000000000").unwrap();
+        let range = start..start.add(100);
+
+        let counts = tline.shard_key_counts(range.clone()).unwrap();
+        let mut expected = BTreeMap::new();
+        for number in 0..4 {
+            expected.insert(ShardNumber(number), 0);
+        }
+        for i in 0..100 {
+            *expected
+                .get_mut(&shard_identity.get_shard_number(&start.add(i)))
+                .unwrap() += 1;
+        }
+        assert_eq!(counts, expected);
+        assert_eq!(counts.values().sum::<u32>(), 100);
+
+        // Unsharded tenants store all the keys on shard zero.
+        let counts = shard_key_counts(&ShardIdentity::unsharded(), range).unwrap();
+        assert_eq!(counts, BTreeMap::from([(ShardNumber(0), 100)]));
+
+        // Ranges spanning relations can't be counted.
+        let end = Key::from_hex("000000067F00000005000040130000004000").unwrap();
+        assert!(tline.shard_key_counts(start..end).is_err());
+    }
+
+    #[tokio::test]
+    async fn image_layer_uploads_respect_concurrency() {
+        use crate::tenant::remote_timeline_client::index::LayerFileMetadata;
+        use crate::tenant::storage_layer::layer::local_layer_path;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        const CONCURRENCY: usize = 2;
+
+        let harness = TenantHarness::create("image_layer_uploads_respect_concurrency")
+            .await
+            .unwrap();
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await
+            .unwrap();
+        tline.remote_client.wait_completion().await.unwrap();
+        tenant.set_new_tenant_config(TenantConfOpt {
+            compaction_upload_concurrency: Some(CONCURRENCY),
+            ..TenantConfOpt::default()
+        });
+
+        let names: Vec<LayerName> = (0..5)
+            .map(|i| {
+                format!(
+                    "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__{:016X}",
+                    0x100 + i
+                )
+                .parse()
+                .unwrap()
+            })
+            .collect();
+        let layers = names
+            .iter()
+            .map(|name| {
+                let local_path = local_layer_path(
+                    harness.conf,
+                    &tline.tenant_shard_id,
+                    &tline.timeline_id,
+                    name,
+                    &harness.generation,
+                );
+                let contents = format!("contents for {name}");
+                std::fs::write(&local_path, &contents).unwrap();
+                Layer::for_resident(
+                    harness.conf,
+                    &tline,
+                    local_path,
+                    name.clone(),
+                    LayerFileMetadata::new(
+                        contents.len() as u64,
+                        harness.generation,
+                        harness.shard,
+                    ),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // The uploads run on this runtime, so the in-flight uploads can be sampled in between.
+        let done = AtomicBool::new(false);
+        let upload = async {
+            tline.upload_new_image_layers(layers).await.unwrap();
+            done.store(true, Ordering::Relaxed);
+        };
+        let probe = async {
+            let mut max_in_flight = 0;
+            while !done.load(Ordering::Relaxed) {
+                let in_flight = tline
+                    .remote_client
+                    .initialized_upload_queue()
+                    .unwrap()
+                    .num_inprogress_layer_uploads();
+                max_in_flight = std::cmp::max(max_in_flight, in_flight);
+                tokio::task::yield_now().await;
+            }
+            max_in_flight
+        };
+        let ((), max_in_flight) = tokio::join!(upload, probe);
+        assert_eq!(max_in_flight, CONCURRENCY);
+
+        tline.remote_client.wait_completion().await.unwrap();
+        let queue = tline.remote_client.initialized_upload_queue().unwrap();
+        let index_part = queue.latest_uploaded_index_part();
+        for name in &names {
+            assert!(index_part.layer_metadata.contains_key(name), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn l0_compaction_aligns_to_partitioning() {
+        let harness = TenantHarness::create("l0_compaction_aligns_to_partitioning")
+            .await
+            .unwrap();
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await
+            .unwrap();
+
+        let key_at = |blknum: u32| {
+            let mut key = Key::from_hex("010000000033333333444444445500000000").unwrap();
+            key.field6 = blknum;
+            key
+        };
+        // Enough L0 layers to trigger compaction, each covering the whole key range.
+        let mut lsn = Lsn(0x10);
+        for _ in 0..DEFAULT_COMPACTION_THRESHOLD {
+            for blknum in 0..100 {
+                lsn = Lsn(lsn.0 + 0x10);
+                let mut writer = tline.writer().await;
+                writer
+                    .put(
+                        key_at(blknum),
+                        lsn,
+                        &Value::Image(Bytes::from(format!("{blknum}@{lsn}"))),
+                        &ctx,
+                    )
+                    .await
+                    .unwrap();
+                writer.finish_write(lsn);
+            }
+            tline.freeze_and_flush().await.unwrap();
+        }
+
+        let partitioning = KeyPartitioning {
+            parts: vec![
+                KeySpace::single(key_at(0)..key_at(25)),
+                KeySpace::single(key_at(25)..key_at(50)),
+                KeySpace::single(key_at(50)..key_at(75)),
+                KeySpace::single(key_at(75)..key_at(100)),
+            ],
+        };
+        let boundaries = [key_at(25), key_at(50), key_at(75)];
+
+        // The target size is large enough to fit all the data into a single layer.
+        let fully_compacted = tline
+            .compact_level0(128 * 1024 * 1024, None, Some(&partitioning), &ctx)
+            .await
+            .unwrap();
+        assert!(fully_compacted);
+
+        let guard = tline.layers.read().await;
+        let layer_map = guard.layer_map().unwrap();
+        assert!(layer_map.level0_deltas().is_empty());
+        let l1_ranges: Vec<Range<Key>> = layer_map
+            .iter_historic_layers()
+            .filter(|layer| layer.is_delta())
+            .map(|layer| layer.get_key_range())
+            .collect();
+        assert_eq!(l1_ranges.len(), boundaries.len() + 1, "{l1_ranges:?}");
+        for range in &l1_ranges {
+            for boundary in &boundaries {
+                assert!(
+                    !(range.start < *boundary && *boundary < range.end),
+                    "layer {range:?} spans partition boundary {boundary}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn gc_compaction_records_timings() {
+        let harness = TenantHarness::create("gc_compaction_records_timings")
+            .await
+            .unwrap();
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await
+            .unwrap();
+
+        let key_at = |blknum: u32| {
+            let mut key = Key::from_hex("010000000033333333444444445500000000").unwrap();
+            key.field6 = blknum;
+            key
+        };
+        let mut lsn = Lsn(0x10);
+        for _ in 0..3 {
+            for blknum in 0..100 {
+                lsn = Lsn(lsn.0 + 0x10);
+                let mut writer = tline.writer().await;
+                writer
+                    .put(
+                        key_at(blknum),
+                        lsn,
+                        &Value::Image(Bytes::from(format!("{blknum}@{lsn}"))),
+                        &ctx,
+                    )
+                    .await
+                    .unwrap();
+                writer.finish_write(lsn);
+            }
+            tline.freeze_and_flush().await.unwrap();
+        }
+        tline.gc_info.write().unwrap().cutoffs = crate::tenant::timeline::GcCutoffs {
+            space: lsn,
+            time: lsn,
+        };
+
+        let cancel = CancellationToken::new();
+        let stat = tline
+            .compact_with_gc(&cancel, CompactOptions::default(), None, &ctx)
+            .await
+            .unwrap();
+        let timings = stat.timings.expect("all the phases were recorded");
+
+        // The parts of the key processing fit into it, and the phases into the whole compaction.
+        assert!(
+            timings.merge_iteration_micros.0 + timings.retention_micros.0
+                <= timings.key_processing_micros.0,
+            "{timings:?}"
+        );
+        let phases = timings.layer_selection_micros.0
+            + timings.download_micros.0
+            + timings.key_processing_micros.0
+            + timings.layer_finalization_micros.0;
+        assert!(phases <= timings.total_micros.0, "{timings:?}");
+        assert!(timings.total_micros.0 > std::time::Duration::ZERO);
+
+        let json: serde_json::Value = serde_json::to_value(&timings).unwrap();
+        for field in [
+            "layer_selection_micros",
+            "download_micros",
+            "key_processing_micros",
+            "merge_iteration_micros",
+            "retention_micros",
+            "layer_finalization_micros",
+            "total_micros",
+        ] {
+            assert!(json[field].is_u64(), "{field} missing from {json}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn load_in_order_overlaps_loads() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        const NUM_VALUES: usize = 64;
+        const CONCURRENCY: usize = 8;
+
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+        // A slow value source, where later values may be ready before earlier ones.
+        let slow_load = |i: usize| {
+            let (in_flight, max_in_flight) = (&in_flight, &max_in_flight);
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+                max_in_flight.fetch_max(now, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(10 + (NUM_VALUES - i) as u64 % 5)).await;
+                in_flight.fetch_sub(1, Ordering::Relaxed);
+                i
+            }
+        };
+
+        let start = tokio::time::Instant::now();
+        let serial: Vec<usize> = load_in_order(0..NUM_VALUES, 1, slow_load).collect().await;
+        let serial_elapsed = start.elapsed();
+
+        let start = tokio::time::Instant::now();
+        let pipelined: Vec<usize> = load_in_order(0..NUM_VALUES, CONCURRENCY, slow_load)
+            .collect()
+            .await;
+        let pipelined_elapsed = start.elapsed();
+
+        assert_eq!(serial, (0..NUM_VALUES).collect_vec());
+        assert_eq!(pipelined, serial);
+        assert_eq!(max_in_flight.load(Ordering::Relaxed), CONCURRENCY);
+        assert!(serial_elapsed >= Duration::from_millis(10 * NUM_VALUES as u64));
+        assert!(
+            pipelined_elapsed * 4 <= serial_elapsed,
+            "pipelined: {pipelined_elapsed:?}, serial: {serial_elapsed:?}"
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! A cap on the bytes that compaction downloads and writes per second.
+//!
+//! All timelines of a tenant share one [`CompactionIoThrottle`], so the cap applies to the
+//! compaction I/O of the tenant as a whole. It is configured with the
+//! `compaction_io_throttle_bytes_per_sec` tenant config, where zero disables it.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use tokio_util::sync::CancellationToken;
+
+use crate::tenant::timeline::CompactionError;
+
+/// How often per second the token bucket is refilled. The bucket holds at most one second worth
+/// of bytes.
+const REFILLS_PER_SEC: u64 = 10;
+const REFILL_INTERVAL: Duration = Duration::from_millis(1000 / REFILLS_PER_SEC);
+
+pub(crate) struct CompactionIoThrottle {
+    rate_limiter: ArcSwapOption<leaky_bucket::RateLimiter>,
+}
+
+impl CompactionIoThrottle {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        CompactionIoThrottle {
+            rate_limiter: ArcSwapOption::new(Self::new_rate_limiter(bytes_per_sec)),
+        }
+    }
+
+    fn new_rate_limiter(bytes_per_sec: u64) -> Option<Arc<leaky_bucket::RateLimiter>> {
+        if bytes_per_sec == 0 {
+            return None;
+        }
+        let refill = std::cmp::max(1, bytes_per_sec / REFILLS_PER_SEC);
+        let refill = usize::try_from(refill).unwrap_or(usize::MAX);
+        let max = usize::try_from(bytes_per_sec).unwrap_or(usize::MAX);
+        Some(Arc::new(
+            leaky_bucket::RateLimiter::builder()
+                .initial(refill)
+                .interval(REFILL_INTERVAL)
+                .refill(refill)
+                .max(max)
+                .fair(true)
+                .build(),
+        ))
+    }
+
+    pub(crate) fn reconfigure(&self, bytes_per_sec: u64) {
+        self.rate_limiter
+            .store(Self::new_rate_limiter(bytes_per_sec));
+    }
+
+    /// Waits until `bytes` more bytes of compaction I/O fit into the budget.
+    ///
+    /// Returns [`CompactionError::ShuttingDown`] if `cancel` fires while waiting.
+    pub(crate) async fn throttle(
+        &self,
+        bytes: u64,
+        cancel: &CancellationToken,
+    ) -> Result<(), CompactionError> {
+        let Some(rate_limiter) = self.rate_limiter.load_full() else {
+            return Ok(());
+        };
+        // Larger requests than the bucket can hold are served in bucket-sized chunks.
+        let mut remaining = usize::try_from(bytes).unwrap_or(usize::MAX);
+        while remaining > 0 {
+            let chunk = std::cmp::min(remaining, rate_limiter.max());
+            tokio::select! {
+                _ = rate_limiter.acquire(chunk) => {}
+                _ = cancel.cancelled() => return Err(CompactionError::ShuttingDown),
+            }
+            remaining -= chunk;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn throughput_stays_under_cap() {
+        const BYTES_PER_SEC: u64 = 1024 * 1024;
+        const WRITE_SIZE: u64 = 8192;
+        const TOTAL: u64 = 5 * BYTES_PER_SEC;
+
+        let throttle = CompactionIoThrottle::new(BYTES_PER_SEC);
+        let cancel = CancellationToken::new();
+        let start = tokio::time::Instant::now();
+        let mut written = 0;
+        while written < TOTAL {
+            throttle.throttle(WRITE_SIZE, &cancel).await.unwrap();
+            written += WRITE_SIZE;
+        }
+        let elapsed = start.elapsed();
+
+        // The bucket starts with one refill worth of bytes, everything else has to be waited for.
+        let initial = BYTES_PER_SEC / REFILLS_PER_SEC;
+        let min_elapsed = Duration::from_secs_f64((TOTAL - initial) as f64 / BYTES_PER_SEC as f64);
+        assert!(
+            elapsed >= min_elapsed,
+            "{TOTAL} bytes took {elapsed:?}, expected at least {min_elapsed:?}"
+        );
+        // Large requests are split into chunks the bucket can hold.
+        let start = tokio::time::Instant::now();
+        throttle.throttle(3 * BYTES_PER_SEC, &cancel).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disabled_and_reconfigured() {
+        let throttle = CompactionIoThrottle::new(0);
+        let cancel = CancellationToken::new();
+        let start = tokio::time::Instant::now();
+        throttle.throttle(u64::MAX, &cancel).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        throttle.reconfigure(1000);
+        throttle.throttle(1100, &cancel).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_is_cancellable() {
+        let throttle = CompactionIoThrottle::new(1000);
+        let cancel = CancellationToken::new();
+        throttle.throttle(100, &cancel).await.unwrap();
+
+        let waiter = throttle.throttle(1_000_000, &cancel);
+        let canceller = async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            cancel.cancel();
+        };
+        let (res, ()) = tokio::join!(waiter, canceller);
+        assert!(matches!(res, Err(CompactionError::ShuttingDown)));
+    }
+}
@@ -0,0 +1,196 @@
+//! The manifest of an in-progress gc-compaction.
+//!
+//! gc-compaction only commits its output to the layer map at the very end, so the work of an
+//! interrupted compaction would be lost. To avoid redoing it, the compaction periodically stops
+//! at a key boundary, finishes the layers it is writing and records them in this manifest. The
+//! layers only exist locally until the compaction completes, so the timeline loading code keeps
+//! the local-only layers listed in the manifest instead of removing them.
+//!
+//! The next gc-compaction resumes from the recorded key if it would run on the same input layers,
+//! and otherwise removes the recorded layers and starts over.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use camino::Utf8Path;
+use pageserver_api::key::Key;
+use serde::{Deserialize, Serialize};
+use utils::{crashsafe::path_with_suffix_extension, fs_ext, generation::Generation, lsn::Lsn};
+
+use crate::tenant::remote_timeline_client::index::LayerFileMetadata;
+use crate::tenant::storage_layer::layer::local_layer_path;
+use crate::tenant::storage_layer::{
+    DeltaLayerName, ImageLayerName, Layer, LayerName, PersistentLayerDesc, PersistentLayerKey,
+    ResidentLayer,
+};
+use crate::tenant::Timeline;
+use crate::virtual_file::VirtualFile;
+use crate::TEMP_FILE_SUFFIX;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct GcCompactionManifest {
+    /// The generation that produced the layers. Layers are never resumed across generations.
+    #[serde(default = "Generation::none")]
+    #[serde(skip_serializing_if = "Generation::is_none")]
+    pub(crate) generation: Generation,
+    pub(crate) gc_cutoff: Lsn,
+    pub(crate) retain_lsns_below_horizon: Vec<Lsn>,
+    /// The layers picked for the compaction.
+    pub(crate) input_layers: Vec<LayerName>,
+    /// All keys below this one have been processed, and their output is in the layers below.
+    pub(crate) resume_key: Key,
+    /// The layers written so far, which are not in the layer map yet.
+    pub(crate) produced_layers: Vec<ProducedLayer>,
+    /// The input layers that would have been written again unchanged, and must be kept.
+    pub(crate) kept_layers: Vec<LayerName>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ProducedLayer {
+    pub(crate) name: LayerName,
+    pub(crate) metadata: LayerFileMetadata,
+}
+
+impl GcCompactionManifest {
+    /// Reads the manifest of the timeline, if any.
+    pub(crate) fn read(path: &Utf8Path) -> anyhow::Result<Option<Self>> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(serde_json::from_slice(&bytes)?))
+    }
+
+    pub(crate) async fn persist(&self, tline: &Timeline) -> anyhow::Result<()> {
+        let path = tline
+            .conf
+            .gc_compaction_manifest_path(&tline.tenant_shard_id, &tline.timeline_id);
+        let temp_path = path_with_suffix_extension(&path, TEMP_FILE_SUFFIX);
+        let bytes = serde_json::to_vec(self)?;
+        VirtualFile::crashsafe_overwrite(path, temp_path, bytes).await?;
+        Ok(())
+    }
+
+    /// Whether a compaction with the current GC cutoff and retained LSNs can continue the one
+    /// recorded in this manifest, using the recorded ones instead. That is the case if it would not
+    /// remove any data that the current ones retain.
+    pub(crate) fn can_resume(
+        &self,
+        generation: Generation,
+        gc_cutoff: Lsn,
+        retain_lsns_below_horizon: &[Lsn],
+    ) -> bool {
+        let recorded_retain_lsns: HashSet<Lsn> =
+            self.retain_lsns_below_horizon.iter().copied().collect();
+        self.generation == generation
+            && self.gc_cutoff <= gc_cutoff
+            && retain_lsns_below_horizon
+                .iter()
+                .filter(|lsn| **lsn < self.gc_cutoff)
+                .all(|lsn| recorded_retain_lsns.contains(lsn))
+    }
+
+    /// Whether the compaction picked the same layers as the recorded one.
+    pub(crate) fn has_inputs(&self, input_layers: &[LayerName]) -> bool {
+        let input_layers: HashSet<&LayerName> = input_layers.iter().collect();
+        self.input_layers.len() == input_layers.len()
+            && self
+                .input_layers
+                .iter()
+                .all(|layer| input_layers.contains(layer))
+    }
+
+    /// Opens the recorded layers. Returns `None` if any of them is missing or has a different size.
+    pub(crate) fn produced_resident_layers(
+        &self,
+        tline: &Arc<Timeline>,
+    ) -> Option<Vec<ResidentLayer>> {
+        let mut resident_layers = Vec::with_capacity(self.produced_layers.len());
+        for layer in &self.produced_layers {
+            let path = local_layer_path(
+                tline.conf,
+                &tline.tenant_shard_id,
+                &tline.timeline_id,
+                &layer.name,
+                &layer.metadata.generation,
+            );
+            match std::fs::metadata(&path) {
+                Ok(m) if m.len() == layer.metadata.file_size => {}
+                _ => return None,
+            }
+            resident_layers.push(Layer::for_resident(
+                tline.conf,
+                tline,
+                path,
+                layer.name.clone(),
+                layer.metadata.clone(),
+            ));
+        }
+        Some(resident_layers)
+    }
+
+    /// Removes the manifest and all the layers it recorded from the timeline directory, except
+    /// for those in the layer map: the pageserver might have stopped right after the compaction
+    /// completed, before removing the manifest.
+    pub(crate) async fn remove(&self, tline: &Timeline) -> anyhow::Result<()> {
+        let guard = tline.layers.read().await;
+        for layer in &self.produced_layers {
+            let key = key_of_layer_name(&layer.name);
+            if guard.contains_key(&key)
+                && guard.get_from_key(&key).metadata().generation == layer.metadata.generation
+            {
+                continue;
+            }
+            let path = local_layer_path(
+                tline.conf,
+                &tline.tenant_shard_id,
+                &tline.timeline_id,
+                &layer.name,
+                &layer.metadata.generation,
+            );
+            std::fs::remove_file(path).or_else(fs_ext::ignore_not_found)?;
+        }
+        remove_manifest(tline)
+    }
+}
+
+/// Removes the manifest once the compaction is done with it, leaving the layers in place.
+pub(crate) fn remove_manifest(tline: &Timeline) -> anyhow::Result<()> {
+    let path = tline
+        .conf
+        .gc_compaction_manifest_path(&tline.tenant_shard_id, &tline.timeline_id);
+    std::fs::remove_file(path).or_else(fs_ext::ignore_not_found)?;
+    Ok(())
+}
+
+pub(crate) fn layer_name_of_key(key: &PersistentLayerKey) -> LayerName {
+    if key.is_delta {
+        DeltaLayerName {
+            key_range: key.key_range.clone(),
+            lsn_range: key.lsn_range.clone(),
+        }
+        .into()
+    } else {
+        ImageLayerName {
+            key_range: key.key_range.clone(),
+            lsn: key.lsn_range.start,
+        }
+        .into()
+    }
+}
+
+pub(crate) fn key_of_layer_name(name: &LayerName) -> PersistentLayerKey {
+    match name {
+        LayerName::Image(image) => PersistentLayerKey {
+            key_range: image.key_range.clone(),
+            lsn_range: PersistentLayerDesc::image_layer_lsn_range(image.lsn),
+            is_delta: false,
+        },
+        LayerName::Delta(delta) => PersistentLayerKey {
+            key_range: delta.key_range.clone(),
+            lsn_range: delta.lsn_range.clone(),
+            is_delta: true,
+        },
+    }
+}
@@ -0,0 +1,34 @@
+//! Deciding whether compaction creates image layers for a partition.
+//!
+//! In [`super::ImageLayerCreationMode::Try`] mode, compaction asks the timeline's
+//! [`ImageCreationPolicy`] for every partition. The default, [`DeltaThresholdPolicy`], creates
+//! image layers once reads might have to visit `image_creation_threshold` delta layers.
+
+use pageserver_api::keyspace::KeySpace;
+use utils::lsn::Lsn;
+
+/// What an [`ImageCreationPolicy`] gets to know about the layers of a partition.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ImageCreationStats {
+    /// The LSN the image layers would be created at.
+    pub(crate) lsn: Lsn,
+    /// The height of the tallest stack of delta layers above the latest image layer, for any
+    /// part of the partition. Not counted beyond `image_creation_threshold`.
+    pub(crate) max_deltas: usize,
+    /// The `image_creation_threshold` of the timeline.
+    pub(crate) image_creation_threshold: usize,
+}
+
+pub(crate) trait ImageCreationPolicy: Send + Sync {
+    /// Whether to create image layers for `partition`.
+    fn should_create(&self, partition: &KeySpace, stats: &ImageCreationStats) -> bool;
+}
+
+/// Creates image layers once there are `image_creation_threshold` deltas above the latest image.
+pub(crate) struct DeltaThresholdPolicy;
+
+impl ImageCreationPolicy for DeltaThresholdPolicy {
+    fn should_create(&self, _partition: &KeySpace, stats: &ImageCreationStats) -> bool {
+        stats.max_deltas >= stats.image_creation_threshold
+    }
+}
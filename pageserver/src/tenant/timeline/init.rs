@@ -8,6 +8,7 @@
         },
         storage_layer::LayerName,
     },
+    GC_COMPACTION_MANIFEST_NAME,
 };
 use anyhow::Context;
 use camino::{Utf8Path, Utf8PathBuf};
@@ -29,6 +30,8 @@ pub(super) enum Discovered {
     TemporaryDownload(String),
     /// Backup file from previously future layers
     IgnoredBackup(Utf8PathBuf),
+    /// Progress of an interrupted gc-compaction, see [`super::compaction::manifest`]
+    GcCompactionManifest(Utf8PathBuf),
     /// Unrecognized, warn about these
     Unknown(String),
 }
@@ -50,7 +53,9 @@ pub(super) fn scan_timeline_dir(path: &Utf8Path) -> anyhow::Result<Vec<Discovere
                 )
             }
             Err(_) => {
-                if file_name.ends_with(".old") {
+                if file_name == GC_COMPACTION_MANIFEST_NAME {
+                    Discovered::GcCompactionManifest(direntry.path().to_owned())
+                } else if file_name.ends_with(".old") {
                     // ignore these
                     Discovered::IgnoredBackup(direntry.path().to_owned())
                 } else if remote_timeline_client::is_temp_download_file(direntry.path()) {
@@ -26,7 +26,9 @@
 use utils::vec_map::VecMap;
 
 use crate::context::RequestContext;
-use crate::tenant::blob_io::{BYTE_UNCOMPRESSED, BYTE_ZSTD, LEN_COMPRESSION_BIT_MASK};
+use crate::tenant::blob_io::{
+    BLOB_REF_LEN, BYTE_REF, BYTE_UNCOMPRESSED, BYTE_ZSTD, LEN_COMPRESSION_BIT_MASK,
+};
 use crate::virtual_file::VirtualFile;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -330,28 +332,8 @@ pub async fn read_blobs(
 
         for ((offset, meta), next) in pairs {
             let offset_in_buf = offset - start_offset;
-            let first_len_byte = buf[offset_in_buf as usize];
-
-            // Each blob is prefixed by a header containing its size and compression information.
-            // Extract the size and skip that header to find the start of the data.
-            // The size can be 1 or 4 bytes. The most significant bit is 0 in the
-            // 1 byte case and 1 in the 4 byte case.
-            let (size_length, blob_size, compression_bits) = if first_len_byte < 0x80 {
-                (1, first_len_byte as u64, BYTE_UNCOMPRESSED)
-            } else {
-                let mut blob_size_buf = [0u8; 4];
-                let offset_in_buf = offset_in_buf as usize;
-
-                blob_size_buf.copy_from_slice(&buf[offset_in_buf..offset_in_buf + 4]);
-                blob_size_buf[0] &= !LEN_COMPRESSION_BIT_MASK;
-
-                let compression_bits = first_len_byte & LEN_COMPRESSION_BIT_MASK;
-                (
-                    4,
-                    u32::from_be_bytes(blob_size_buf) as u64,
-                    compression_bits,
-                )
-            };
+            let (size_length, blob_size, compression_bits) =
+                parse_blob_header(&buf[offset_in_buf as usize..]);
 
             let start_raw = offset_in_buf + size_length;
             let end_raw = match next {
@@ -374,6 +356,33 @@ pub async fn read_blobs(
                 buf.extend_from_slice(&decompressed_vec);
                 end = buf.len();
                 decompressed_vec.clear();
+            } else if compression_bits == BYTE_REF && blob_size == BLOB_REF_LEN as u64 {
+                let target = u64::from_be_bytes(
+                    buf[start_raw as usize..end_raw as usize]
+                        .try_into()
+                        .expect("checked the length above"),
+                );
+                if target >= *offset {
+                    let error = std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("invalid blob reference from {offset} to {target}"),
+                    );
+                    return Err(error);
+                }
+                // The referenced blob is usually part of the same read, so we can share its
+                // decoded bytes. Otherwise, it has to be read separately.
+                match blobs_at.binary_search_by_key(&target, |(blob_offset, _)| *blob_offset) {
+                    Ok(idx) => {
+                        start = metas[idx].start;
+                        end = metas[idx].end;
+                    }
+                    Err(_) => {
+                        let referenced = self.read_referenced_blob(*offset, target, ctx).await?;
+                        start = buf.len();
+                        buf.extend_from_slice(&referenced);
+                        end = buf.len();
+                    }
+                }
             } else {
                 let error = std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
@@ -391,6 +400,73 @@ pub async fn read_blobs(
 
         Ok(VectoredBlobsBuf { buf, blobs: metas })
     }
+
+    /// Reads the blob that the reference at `from` points to, for references whose target is
+    /// not part of the same vectored read.
+    async fn read_referenced_blob(
+        &self,
+        from: u64,
+        target: u64,
+        ctx: &RequestContext,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        let header = self
+            .file
+            .read_exact_at(BytesMut::with_capacity(4).slice(0..4), target, ctx)
+            .await?
+            .into_inner();
+        let (size_length, blob_size, compression_bits) = parse_blob_header(&header);
+        let blob = if blob_size == 0 {
+            BytesMut::new()
+        } else {
+            self.file
+                .read_exact_at(
+                    BytesMut::with_capacity(blob_size as usize).slice(0..blob_size as usize),
+                    target + size_length,
+                    ctx,
+                )
+                .await?
+                .into_inner()
+        };
+        if compression_bits == BYTE_UNCOMPRESSED {
+            Ok(blob.to_vec())
+        } else if compression_bits == BYTE_ZSTD {
+            let mut decompressed = Vec::new();
+            let mut decoder = async_compression::tokio::write::ZstdDecoder::new(&mut decompressed);
+            decoder.write_all(&blob).await?;
+            decoder.flush().await?;
+            Ok(decompressed)
+        } else {
+            // References never point to other references.
+            let error = std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid blob reference from {from} to {target}"),
+            );
+            Err(error)
+        }
+    }
+}
+
+/// Parses the header that each blob is prefixed with, containing its size and compression
+/// information. Returns the length of the header, the size of the blob and the compression bits.
+///
+/// The size can be 1 or 4 bytes. The most significant bit is 0 in the
+/// 1 byte case and 1 in the 4 byte case.
+fn parse_blob_header(buf: &[u8]) -> (u64, u64, u8) {
+    let first_len_byte = buf[0];
+    if first_len_byte < 0x80 {
+        (1, first_len_byte as u64, BYTE_UNCOMPRESSED)
+    } else {
+        let mut blob_size_buf = [0u8; 4];
+        blob_size_buf.copy_from_slice(&buf[..4]);
+        blob_size_buf[0] &= !LEN_COMPRESSION_BIT_MASK;
+
+        let compression_bits = first_len_byte & LEN_COMPRESSION_BIT_MASK;
+        (
+            4,
+            u32::from_be_bytes(blob_size_buf) as u64,
+            compression_bits,
+        )
+    }
 }
 
 /// Read planner used in [`crate::tenant::storage_layer::image_layer::ImageLayerIterator`]. It provides a streaming API for
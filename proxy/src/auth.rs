@@ -73,6 +73,9 @@ pub enum AuthErrorImpl {
 
     #[error("Authentication timed out")]
     UserTimeout(Elapsed),
+
+    #[error(transparent)]
+    Jwt(#[from] backend::jwt::JwtError),
 }
 
 #[derive(Debug, Error)]
@@ -125,6 +128,7 @@ fn to_string_client(&self) -> String {
             AuthErrorImpl::IpAddressNotAllowed(_) => self.to_string(),
             AuthErrorImpl::TooManyConnections => self.to_string(),
             AuthErrorImpl::UserTimeout(_) => self.to_string(),
+            AuthErrorImpl::Jwt(e) => e.to_string_client(),
         }
     }
 }
@@ -143,6 +147,7 @@ fn get_error_kind(&self) -> crate::error::ErrorKind {
             AuthErrorImpl::IpAddressNotAllowed(_) => crate::error::ErrorKind::User,
             AuthErrorImpl::TooManyConnections => crate::error::ErrorKind::RateLimit,
             AuthErrorImpl::UserTimeout(_) => crate::error::ErrorKind::User,
+            AuthErrorImpl::Jwt(e) => e.get_error_kind(),
         }
     }
 }
@@ -1,5 +1,6 @@
 use std::{
     future::Future,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
     time::{Duration, SystemTime},
 };
@@ -7,19 +8,32 @@
 use anyhow::{bail, ensure, Context};
 use arc_swap::ArcSwapOption;
 use dashmap::DashMap;
+use futures::{future::BoxFuture, StreamExt};
 use jose_jwk::crypto::KeyInfo;
 use serde::{Deserialize, Deserializer};
 use signature::Verifier;
 use tokio::time::Instant;
 
-use crate::{context::RequestMonitoring, http::parse_json_body_with_limit, EndpointId, RoleName};
+use crate::{
+    context::RequestMonitoring,
+    error::{ErrorKind, ReportableError, UserFacingError},
+    http::parse_encoded_json_body_with_limit,
+    EndpointId, RoleName,
+};
 
 // TODO(conrad): make these configurable.
-const CLOCK_SKEW_LEEWAY: Duration = Duration::from_secs(30);
 const MIN_RENEW: Duration = Duration::from_secs(30);
 const AUTO_RENEW: Duration = Duration::from_secs(300);
 const MAX_RENEW: Duration = Duration::from_secs(3600);
-const MAX_JWK_BODY_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_JWKS_BODY_SIZE: usize = 64 * 1024;
+const DEFAULT_JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_CLOCK_SKEW_LEEWAY: Duration = Duration::from_secs(30);
+const MAX_CONCURRENT_JWKS_FETCHES: usize = 4;
+/// The same limit as reqwest's default redirect policy.
+const MAX_JWKS_REDIRECTS: usize = 10;
+/// How long to remember that a key id could not be found, before we try to renew the JWKs for it again.
+const MISSING_KID_TTL: Duration = Duration::from_secs(120);
+const MAX_MISSING_KIDS: usize = 1024;
 
 /// How to get the JWT auth rules
 pub trait FetchAuthRules: Clone + Send + Sync + 'static {
@@ -29,19 +43,199 @@ fn fetch_auth_rules(
     ) -> impl Future<Output = anyhow::Result<Vec<AuthRule>>> + Send;
 }
 
+/// Why a JWT was rejected.
+#[derive(Debug, thiserror::Error)]
+pub enum JwtError {
+    #[error("Provided authentication token is not a valid JWT encoding")]
+    Malformed,
+    #[error("signature algorithm not supported")]
+    UnsupportedAlgorithm,
+    #[error("jwk not found")]
+    KeyNotFound,
+    #[error("JWT has no key id (kid), and no single key could be used instead")]
+    MissingKeyId,
+    #[error("invalid JWT token audience")]
+    AudienceMismatch,
+    #[error("JWT token has expired")]
+    Expired,
+    #[error("JWT token is not yet valid")]
+    NotYetValid,
+    #[error("JWT is missing the required {0} claim")]
+    MissingClaim(&'static str),
+    #[error("invalid JWT signature")]
+    SignatureInvalid,
+    #[error("could not get the JWT auth rules: {0:#}")]
+    AuthRules(anyhow::Error),
+}
+
+impl ReportableError for JwtError {
+    fn get_error_kind(&self) -> ErrorKind {
+        match self {
+            JwtError::Malformed
+            | JwtError::UnsupportedAlgorithm
+            | JwtError::KeyNotFound
+            | JwtError::MissingKeyId
+            | JwtError::AudienceMismatch
+            | JwtError::Expired
+            | JwtError::NotYetValid
+            | JwtError::MissingClaim(_)
+            | JwtError::SignatureInvalid => ErrorKind::User,
+            JwtError::AuthRules(_) => ErrorKind::ControlPlane,
+        }
+    }
+}
+
+impl UserFacingError for JwtError {
+    fn to_string_client(&self) -> String {
+        match self {
+            JwtError::AuthRules(_) => "could not get the JWT auth rules".to_string(),
+            _ => self.to_string(),
+        }
+    }
+}
+
 pub struct AuthRule {
     pub id: String,
     pub jwks_url: url::Url,
-    pub audience: Option<String>,
+    pub audience: AudienceMatcher,
+    /// Reject tokens that do not carry an expiration (`exp`) claim.
+    pub require_exp: bool,
+    /// Reject tokens that do not carry a not-before (`nbf`) claim.
+    pub require_nbf: bool,
+    /// Verify tokens without a key id (`kid`) with the only key of this rule. Such tokens are
+    /// rejected if the rule has several keys, or if several rules allow them.
+    pub allow_missing_kid: bool,
+    /// Keys to use instead of fetching them from `jwks_url`, e.g. for air-gapped deployments
+    /// or providers that rarely rotate their keys.
+    pub static_jwks: Option<jose_jwk::JwkSet>,
+}
+
+impl AuthRule {
+    fn into_key_set(self, jwks: jose_jwk::JwkSet) -> (String, KeySet) {
+        let key_set = KeySet {
+            rule_id: self.id.clone(),
+            jwks,
+            audience: self.audience,
+            require_exp: self.require_exp,
+            require_nbf: self.require_nbf,
+            allow_missing_kid: self.allow_missing_kid,
+        };
+        (self.id, key_set)
+    }
+}
+
+/// The audiences accepted by an [`AuthRule`]. A token is accepted if its `aud` claim matches
+/// any of them, or regardless of its `aud` claim if there are none.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AudienceMatcher(Vec<String>);
+
+impl AudienceMatcher {
+    /// Accept any audience, including tokens without an `aud` claim.
+    pub fn any() -> Self {
+        Self::default()
+    }
+
+    pub fn new(audiences: impl IntoIterator<Item = String>) -> Self {
+        Self(audiences.into_iter().collect())
+    }
+
+    fn matches(&self, audience: Option<&str>) -> bool {
+        if self.0.is_empty() {
+            return true;
+        }
+        audience.is_some_and(|audience| self.0.iter().any(|expected| expected == audience))
+    }
+}
+
+impl From<Option<String>> for AudienceMatcher {
+    fn from(audience: Option<String>) -> Self {
+        Self::new(audience)
+    }
+}
+
+/// How to resolve the host of a JWKs url, so we can check where it points before fetching it.
+pub trait ResolveJwksHost: Send + Sync + 'static {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, std::io::Result<Vec<IpAddr>>>;
+}
+
+/// Resolves JWKs hosts using the system resolver.
+pub struct SystemResolver;
+
+impl ResolveJwksHost for SystemResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, std::io::Result<Vec<IpAddr>>> {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host, port)).await?;
+            Ok(addrs.map(|addr| addr.ip()).collect())
+        })
+    }
+}
+
+/// Resolves the hosts that the JWKs client connects to, refusing any non-public address. Checking
+/// the addresses that the client then connects to, rather than resolving the host separately
+/// beforehand, means that a DNS answer cannot change in between.
+struct PublicOnlyResolver(Arc<dyn ResolveJwksHost>);
+
+impl reqwest::dns::Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let resolver = Arc::clone(&self.0);
+        Box::pin(async move {
+            // reqwest sets the port of the url on the returned addresses.
+            let addrs = resolve_public_addrs(&*resolver, name.as_str(), 0).await?;
+            let addrs: reqwest::dns::Addrs =
+                Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct JwkCacheConfig {
+    /// How long to wait for a single JWKs URL to respond before giving up on it.
+    pub fetch_timeout: Duration,
+    /// The largest JWKs response body we are willing to decode.
+    pub max_body_size: usize,
+    /// Used to check that JWKs urls only point at the public internet.
+    pub resolver: Arc<dyn ResolveJwksHost>,
+    /// Skip the public internet check on JWKs urls. Only meant for tests.
+    pub allow_private_urls: bool,
+    /// How far our clock may disagree with the token issuer's when checking the `exp` and `nbf`
+    /// claims.
+    pub clock_skew_leeway: Duration,
+}
+
+impl Default for JwkCacheConfig {
+    fn default() -> Self {
+        JwkCacheConfig {
+            fetch_timeout: DEFAULT_JWKS_FETCH_TIMEOUT,
+            max_body_size: DEFAULT_MAX_JWKS_BODY_SIZE,
+            resolver: Arc::new(SystemResolver),
+            allow_private_urls: false,
+            clock_skew_leeway: DEFAULT_CLOCK_SKEW_LEEWAY,
+        }
+    }
 }
 
-#[derive(Default)]
 pub struct JwkCache {
     client: reqwest::Client,
+    config: JwkCacheConfig,
 
     map: DashMap<(EndpointId, RoleName), Arc<JwkCacheEntryLock>>,
 }
 
+impl Default for JwkCache {
+    fn default() -> Self {
+        JwkCache::new(JwkCacheConfig::default())
+    }
+}
+
 pub struct JwkCacheEntry {
     /// Should refetch at least every hour to verify when old keys have been removed.
     /// Should refetch when new key IDs are seen only every 5 minutes or so
@@ -52,18 +246,37 @@ pub struct JwkCacheEntry {
 }
 
 impl JwkCacheEntry {
-    fn find_jwk_and_audience(&self, key_id: &str) -> Option<(&jose_jwk::Jwk, Option<&str>)> {
-        self.key_sets.values().find_map(|key_set| {
-            key_set
-                .find_key(key_id)
-                .map(|jwk| (jwk, key_set.audience.as_deref()))
-        })
+    fn find_jwk_and_key_set(&self, key_id: &str) -> Option<(&jose_jwk::Jwk, &KeySet)> {
+        self.key_sets
+            .values()
+            .find_map(|key_set| key_set.find_key(key_id).map(|jwk| (jwk, key_set)))
+    }
+
+    /// The key to verify a token without a key id with: the only key of the rules that allow it.
+    fn find_single_jwk_and_key_set(&self) -> Option<(&jose_jwk::Jwk, &KeySet)> {
+        let mut keys = self
+            .key_sets
+            .values()
+            .filter(|key_set| key_set.allow_missing_kid)
+            .flat_map(|key_set| key_set.jwks.keys.iter().map(move |jwk| (jwk, key_set)));
+        let key = keys.next()?;
+        if keys.next().is_some() {
+            // ambiguous
+            return None;
+        }
+        Some(key)
     }
 }
 
+#[derive(Clone)]
 struct KeySet {
+    /// The id of the [`AuthRule`] these keys belong to.
+    rule_id: String,
     jwks: jose_jwk::JwkSet,
-    audience: Option<String>,
+    audience: AudienceMatcher,
+    require_exp: bool,
+    require_nbf: bool,
+    allow_missing_kid: bool,
 }
 
 impl KeySet {
@@ -78,6 +291,10 @@ fn find_key(&self, key_id: &str) -> Option<&jose_jwk::Jwk> {
 pub struct JwkCacheEntryLock {
     cached: ArcSwapOption<JwkCacheEntry>,
     lookup: tokio::sync::Semaphore,
+
+    /// Key ids that recently could not be found, so that a burst of tokens with an unknown
+    /// key id doesn't make us renew the JWKs over and over.
+    missing_kids: parking_lot::Mutex<ahash::HashMap<String, Instant>>,
 }
 
 impl Default for JwkCacheEntryLock {
@@ -85,6 +302,7 @@ fn default() -> Self {
         JwkCacheEntryLock {
             cached: ArcSwapOption::empty(),
             lookup: tokio::sync::Semaphore::new(1),
+            missing_kids: parking_lot::Mutex::default(),
         }
     }
 }
@@ -98,56 +316,80 @@ fn try_acquire_permit<'a>(self: &'a Arc<Self>) -> Option<JwkRenewalPermit<'a>> {
         JwkRenewalPermit::try_acquire_permit(self)
     }
 
+    fn is_known_missing(&self, kid: &str) -> bool {
+        self.missing_kids
+            .lock()
+            .get(kid)
+            .is_some_and(|missed_at| missed_at.elapsed() < MISSING_KID_TTL)
+    }
+
+    fn mark_missing(&self, kid: &str) {
+        let mut missing_kids = self.missing_kids.lock();
+        missing_kids.retain(|_, missed_at| missed_at.elapsed() < MISSING_KID_TTL);
+        if missing_kids.len() < MAX_MISSING_KIDS {
+            missing_kids.insert(kid.to_owned(), Instant::now());
+        }
+    }
+
     async fn renew_jwks<F: FetchAuthRules>(
         &self,
         _permit: JwkRenewalPermit<'_>,
         client: &reqwest::Client,
+        config: &JwkCacheConfig,
         role_name: RoleName,
         auth_rules: &F,
     ) -> anyhow::Result<Arc<JwkCacheEntry>> {
         // double check that no one beat us to updating the cache.
         let now = Instant::now();
-        let guard = self.cached.load_full();
-        if let Some(cached) = guard {
+        let previous = self.cached.load_full();
+        if let Some(cached) = &previous {
             let last_update = now.duration_since(cached.last_retrieved);
-            if last_update < Duration::from_secs(300) {
-                return Ok(cached);
+            if last_update < MIN_RENEW {
+                return Ok(Arc::clone(cached));
             }
         }
 
         let rules = auth_rules.fetch_auth_rules(role_name).await?;
         let mut key_sets =
             ahash::HashMap::with_capacity_and_hasher(rules.len(), ahash::RandomState::new());
-        // TODO(conrad): run concurrently
+
+        // rules with static keys don't need to fetch anything.
+        let mut fetch_rules = Vec::with_capacity(rules.len());
+        for mut rule in rules {
+            match rule.static_jwks.take() {
+                Some(jwks) => {
+                    let (id, key_set) = rule.into_key_set(jwks);
+                    key_sets.insert(id, key_set);
+                }
+                None => fetch_rules.push(rule),
+            }
+        }
+
         // TODO(conrad): strip the JWKs urls (should be checked by cplane as well - cloud#16284)
-        for rule in rules {
-            let req = client.get(rule.jwks_url.clone());
-            // TODO(conrad): eventually switch to using reqwest_middleware/`new_client_with_timeout`.
-            // TODO(conrad): We need to filter out URLs that point to local resources. Public internet only.
-            match req.send().await.and_then(|r| r.error_for_status()) {
-                // todo: should we re-insert JWKs if we want to keep this JWKs URL?
-                // I expect these failures would be quite sparse.
-                Err(e) => tracing::warn!(url=?rule.jwks_url, error=?e, "could not fetch JWKs"),
-                Ok(r) => {
-                    let resp: http::Response<reqwest::Body> = r.into();
-                    match parse_json_body_with_limit::<jose_jwk::JwkSet>(
-                        resp.into_body(),
-                        MAX_JWK_BODY_SIZE,
-                    )
-                    .await
+        let mut fetches = futures::stream::iter(fetch_rules)
+            .map(|rule| async move {
+                let fetch = fetch_jwks(client, config, &rule.jwks_url);
+                let res = tokio::time::timeout(config.fetch_timeout, fetch).await;
+                (rule, res)
+            })
+            .buffer_unordered(MAX_CONCURRENT_JWKS_FETCHES);
+
+        while let Some((rule, res)) = fetches.next().await {
+            match res {
+                Ok(Some(jwks)) => {
+                    let (id, key_set) = rule.into_key_set(jwks);
+                    key_sets.insert(id, key_set);
+                }
+                failed => {
+                    if failed.is_err() {
+                        tracing::warn!(url=?rule.jwks_url, timeout=?config.fetch_timeout, "timed out fetching JWKs");
+                    }
+                    // keep serving the keys we already had for this url rather than dropping them,
+                    // with the current settings of the rule.
+                    if let Some(key_set) = previous.as_ref().and_then(|p| p.key_sets.get(&rule.id))
                     {
-                        Err(e) => {
-                            tracing::warn!(url=?rule.jwks_url, error=?e, "could not decode JWKs");
-                        }
-                        Ok(jwks) => {
-                            key_sets.insert(
-                                rule.id,
-                                KeySet {
-                                    jwks,
-                                    audience: rule.audience,
-                                },
-                            );
-                        }
+                        let (id, key_set) = rule.into_key_set(key_set.jwks.clone());
+                        key_sets.insert(id, key_set);
                     }
                 }
             }
@@ -158,6 +400,8 @@ async fn renew_jwks<F: FetchAuthRules>(
             key_sets,
         });
         self.cached.swap(Some(Arc::clone(&entry)));
+        // the new keys might contain ids we couldn't find before.
+        self.missing_kids.lock().clear();
 
         Ok(entry)
     }
@@ -166,6 +410,7 @@ async fn get_or_update_jwk_cache<F: FetchAuthRules>(
         self: &Arc<Self>,
         ctx: &RequestMonitoring,
         client: &reqwest::Client,
+        config: &JwkCacheConfig,
         role_name: RoleName,
         fetch: &F,
     ) -> Result<Arc<JwkCacheEntry>, anyhow::Error> {
@@ -176,7 +421,9 @@ async fn get_or_update_jwk_cache<F: FetchAuthRules>(
         let Some(cached) = guard else {
             let _paused = ctx.latency_timer_pause(crate::metrics::Waiting::Compute);
             let permit = self.acquire_permit().await;
-            return self.renew_jwks(permit, client, role_name, fetch).await;
+            return self
+                .renew_jwks(permit, client, config, role_name, fetch)
+                .await;
         };
 
         let last_update = now.duration_since(cached.last_retrieved);
@@ -187,7 +434,9 @@ async fn get_or_update_jwk_cache<F: FetchAuthRules>(
             let permit = self.acquire_permit().await;
 
             // it's been too long since we checked the keys. wait for them to update.
-            return self.renew_jwks(permit, client, role_name, fetch).await;
+            return self
+                .renew_jwks(permit, client, config, role_name, fetch)
+                .await;
         }
 
         // every 5 minutes we should spawn a job to eagerly update the token.
@@ -197,9 +446,13 @@ async fn get_or_update_jwk_cache<F: FetchAuthRules>(
                 let permit = permit.into_owned();
                 let entry = self.clone();
                 let client = client.clone();
+                let config = config.clone();
                 let fetch = fetch.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = entry.renew_jwks(permit, &client, role_name, &fetch).await {
+                    if let Err(e) = entry
+                        .renew_jwks(permit, &client, &config, role_name, &fetch)
+                        .await
+                    {
                         tracing::warn!(error=?e, "could not fetch JWKs in background job");
                     }
                 });
@@ -216,99 +469,146 @@ async fn check_jwt<F: FetchAuthRules>(
         ctx: &RequestMonitoring,
         jwt: &str,
         client: &reqwest::Client,
+        config: &JwkCacheConfig,
         role_name: RoleName,
         fetch: &F,
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<String, JwtError> {
         // JWT compact form is defined to be
         // <B64(Header)> || . || <B64(Payload)> || . || <B64(Signature)>
         // where Signature = alg(<B64(Header)> || . || <B64(Payload)>);
 
-        let (header_payload, signature) = jwt
-            .rsplit_once(".")
-            .context("Provided authentication token is not a valid JWT encoding")?;
-        let (header, payload) = header_payload
-            .split_once(".")
-            .context("Provided authentication token is not a valid JWT encoding")?;
+        let (header_payload, signature) = jwt.rsplit_once(".").ok_or(JwtError::Malformed)?;
+        let (header, payload) = header_payload.split_once(".").ok_or(JwtError::Malformed)?;
 
         let header = base64::decode_config(header, base64::URL_SAFE_NO_PAD)
-            .context("Provided authentication token is not a valid JWT encoding")?;
-        let header = serde_json::from_slice::<JwtHeader<'_>>(&header)
-            .context("Provided authentication token is not a valid JWT encoding")?;
+            .map_err(|_| JwtError::Malformed)?;
+        let header =
+            serde_json::from_slice::<JwtHeader<'_>>(&header).map_err(|_| JwtError::Malformed)?;
 
         let sig = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
-            .context("Provided authentication token is not a valid JWT encoding")?;
+            .map_err(|_| JwtError::Malformed)?;
 
-        ensure!(header.typ == "JWT");
-        let kid = header.key_id.context("missing key id")?;
+        if header.typ != "JWT" {
+            return Err(JwtError::Malformed);
+        }
 
         let mut guard = self
-            .get_or_update_jwk_cache(ctx, client, role_name.clone(), fetch)
-            .await?;
-
-        // get the key from the JWKs if possible. If not, wait for the keys to update.
-        let (jwk, expected_audience) = loop {
-            match guard.find_jwk_and_audience(kid) {
-                Some(jwk) => break jwk,
-                None if guard.last_retrieved.elapsed() > MIN_RENEW => {
-                    let _paused = ctx.latency_timer_pause(crate::metrics::Waiting::Compute);
-
-                    let permit = self.acquire_permit().await;
-                    guard = self
-                        .renew_jwks(permit, client, role_name.clone(), fetch)
-                        .await?;
-                }
-                _ => {
-                    bail!("jwk not found");
+            .get_or_update_jwk_cache(ctx, client, config, role_name.clone(), fetch)
+            .await
+            .map_err(JwtError::AuthRules)?;
+
+        let (jwk, key_set) = match header.key_id {
+            // some providers only have a single key, and don't name it.
+            None => guard
+                .find_single_jwk_and_key_set()
+                .ok_or(JwtError::MissingKeyId)?,
+            // get the key from the JWKs if possible. If not, wait for the keys to update.
+            Some(kid) => loop {
+                match guard.find_jwk_and_key_set(kid) {
+                    Some(jwk) => break jwk,
+                    None if guard.last_retrieved.elapsed() > MIN_RENEW
+                        && !self.is_known_missing(kid) =>
+                    {
+                        let _paused = ctx.latency_timer_pause(crate::metrics::Waiting::Compute);
+
+                        let permit = self.acquire_permit().await;
+                        guard = self
+                            .renew_jwks(permit, client, config, role_name.clone(), fetch)
+                            .await
+                            .map_err(JwtError::AuthRules)?;
+                    }
+                    _ => {
+                        self.mark_missing(kid);
+                        return Err(JwtError::KeyNotFound);
+                    }
                 }
-            }
+            },
         };
 
-        ensure!(
-            jwk.is_supported(&header.algorithm),
-            "signature algorithm not supported"
-        );
-
-        match &jwk.key {
+        let algorithm_supported = match &jwk.key {
+            // jose_jwk doesn't know about OKP keys, EdDSA is the only algorithm they support.
+            jose_jwk::Key::Okp(_) => {
+                header.algorithm == jose_jwa::Algorithm::Signing(jose_jwa::Signing::EdDsa)
+            }
+            // jose_jwk doesn't know about P521 keys, so check the algorithm against the curve ourselves.
             jose_jwk::Key::Ec(key) => {
-                verify_ec_signature(header_payload.as_bytes(), &sig, key)?;
+                ec_signing_algorithm(&key.crv)
+                    .is_some_and(|alg| header.algorithm == jose_jwa::Algorithm::Signing(alg))
+                    && jwk
+                        .prm
+                        .alg
+                        .as_ref()
+                        .map_or(true, |alg| *alg == header.algorithm)
             }
+            _ => jwk.is_supported(&header.algorithm),
+        };
+        if !algorithm_supported {
+            return Err(JwtError::UnsupportedAlgorithm);
+        }
+
+        let verified = match &jwk.key {
+            jose_jwk::Key::Ec(key) => verify_ec_signature(header_payload.as_bytes(), &sig, key),
             jose_jwk::Key::Rsa(key) => {
-                verify_rsa_signature(header_payload.as_bytes(), &sig, key, &jwk.prm.alg)?;
+                verify_rsa_signature(header_payload.as_bytes(), &sig, key, &jwk.prm.alg)
             }
-            key => bail!("unsupported key type {key:?}"),
+            jose_jwk::Key::Okp(key) => verify_eddsa_signature(header_payload.as_bytes(), &sig, key),
+            _ => return Err(JwtError::UnsupportedAlgorithm),
         };
+        if let Err(e) = verified {
+            tracing::debug!(error=?e, "JWT signature verification failed");
+            return Err(JwtError::SignatureInvalid);
+        }
 
         let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
-            .context("Provided authentication token is not a valid JWT encoding")?;
-        let payload = serde_json::from_slice::<JwtPayload<'_>>(&payload)
-            .context("Provided authentication token is not a valid JWT encoding")?;
+            .map_err(|_| JwtError::Malformed)?;
+        let payload =
+            serde_json::from_slice::<JwtPayload<'_>>(&payload).map_err(|_| JwtError::Malformed)?;
 
         tracing::debug!(?payload, "JWT signature valid with claims");
 
-        match (expected_audience, payload.audience) {
-            // check the audience matches
-            (Some(aud1), Some(aud2)) => ensure!(aud1 == aud2, "invalid JWT token audience"),
-            // the audience is expected but is missing
-            (Some(_), None) => bail!("invalid JWT token audience"),
-            // we don't care for the audience field
-            (None, _) => {}
+        if !key_set.audience.matches(payload.audience) {
+            return Err(JwtError::AudienceMismatch);
         }
 
         let now = SystemTime::now();
 
         if let Some(exp) = payload.expiration {
-            ensure!(now < exp + CLOCK_SKEW_LEEWAY);
+            if now >= exp + config.clock_skew_leeway {
+                return Err(JwtError::Expired);
+            }
+        } else if key_set.require_exp {
+            return Err(JwtError::MissingClaim("exp"));
         }
 
         if let Some(nbf) = payload.not_before {
-            ensure!(nbf < now + CLOCK_SKEW_LEEWAY);
+            if nbf >= now + config.clock_skew_leeway {
+                return Err(JwtError::NotYetValid);
+            }
+        } else if key_set.require_nbf {
+            return Err(JwtError::MissingClaim("nbf"));
         }
 
-        Ok(())
+        if let Some(issuer) = payload.issuer {
+            ctx.set_jwt_issuer(issuer);
+        }
+        if let Some(subject) = payload.subject {
+            ctx.set_jwt_subject(subject);
+        }
+
+        Ok(key_set.rule_id.clone())
     }
 }
 
 impl JwkCache {
+    pub fn new(config: JwkCacheConfig) -> Self {
+        JwkCache {
+            client: new_jwks_client(&config),
+            config,
+            map: DashMap::default(),
+        }
+    }
+
+    /// Verifies the JWT, returning the id of the [`AuthRule`] whose keys it was signed with.
     pub async fn check_jwt<F: FetchAuthRules>(
         &self,
         ctx: &RequestMonitoring,
@@ -316,22 +616,227 @@ pub async fn check_jwt<F: FetchAuthRules>(
         role_name: RoleName,
         fetch: &F,
         jwt: &str,
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<String, JwtError> {
+        let entry = self.get_entry(endpoint, role_name.clone());
+
+        entry
+            .check_jwt(ctx, jwt, &self.client, &self.config, role_name, fetch)
+            .await
+    }
+
+    /// Start fetching the JWKs for this endpoint and role in the background, if we don't have them yet,
+    /// so that the first JWT doesn't need to wait for them.
+    pub fn prefetch<F: FetchAuthRules>(
+        &self,
+        endpoint: EndpointId,
+        role_name: RoleName,
+        fetch: &F,
+    ) {
+        let entry = self.get_entry(endpoint, role_name.clone());
+        if entry.cached.load().is_some() {
+            return;
+        }
+
+        // if the permit is taken, someone is already fetching the keys.
+        let Some(permit) = entry.try_acquire_permit() else {
+            return;
+        };
+        let permit = permit.into_owned();
+        let client = self.client.clone();
+        let config = self.config.clone();
+        let fetch = fetch.clone();
+        tokio::spawn(async move {
+            if let Err(e) = entry
+                .renew_jwks(permit, &client, &config, role_name, &fetch)
+                .await
+            {
+                tracing::warn!(error=?e, "could not prefetch JWKs");
+            }
+        });
+    }
+
+    fn get_entry(&self, endpoint: EndpointId, role_name: RoleName) -> Arc<JwkCacheEntryLock> {
         // try with just a read lock first
-        let key = (endpoint, role_name.clone());
+        let key = (endpoint, role_name);
         let entry = self.map.get(&key).as_deref().map(Arc::clone);
-        let entry = match entry {
+        match entry {
             Some(entry) => entry,
             None => {
                 // acquire a write lock after to insert.
                 let entry = self.map.entry(key).or_default();
                 Arc::clone(&*entry)
             }
-        };
+        }
+    }
+}
 
-        entry
-            .check_jwt(ctx, jwt, &self.client, role_name, fetch)
+/// Creates the client to fetch JWKs with. Unless private urls are allowed, it only connects to
+/// public addresses, also when following redirects.
+fn new_jwks_client(config: &JwkCacheConfig) -> reqwest::Client {
+    let builder = reqwest::ClientBuilder::new();
+    let builder = if config.allow_private_urls {
+        builder
+    } else {
+        builder
+            .dns_resolver(Arc::new(PublicOnlyResolver(Arc::clone(&config.resolver))))
+            .redirect(jwks_redirect_policy())
+            // a proxy would resolve the host itself, bypassing the resolver above.
+            .no_proxy()
+    };
+    builder.build().expect("Failed to create JWKs http client")
+}
+
+/// Follows redirects like reqwest's default policy, but not to non-public addresses. Domains are
+/// checked by [`PublicOnlyResolver`] when connecting to them, which leaves the ip address hosts.
+fn jwks_redirect_policy() -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_JWKS_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        match check_jwks_redirect_url(attempt.url()) {
+            Ok(()) => attempt.follow(),
+            Err(e) => attempt.error(e),
+        }
+    })
+}
+
+fn check_jwks_redirect_url(url: &url::Url) -> anyhow::Result<()> {
+    match url.host() {
+        Some(url::Host::Ipv4(ip)) => check_public_addrs(&[IpAddr::V4(ip)]),
+        Some(url::Host::Ipv6(ip)) => check_public_addrs(&[IpAddr::V6(ip)]),
+        Some(url::Host::Domain(_)) => Ok(()),
+        None => bail!("JWKs url has no host"),
+    }
+}
+
+/// Fetches and decodes the JWKs from the given url, logging why if that's not possible.
+async fn fetch_jwks(
+    client: &reqwest::Client,
+    config: &JwkCacheConfig,
+    url: &url::Url,
+) -> Option<jose_jwk::JwkSet> {
+    if !config.allow_private_urls {
+        if let Err(e) = check_jwks_url(&*config.resolver, url).await {
+            tracing::warn!(?url, error=?e, "refusing to fetch JWKs");
+            return None;
+        }
+    }
+
+    // Large key sets are often served compressed.
+    let req = client
+        .get(url.clone())
+        .header(http::header::ACCEPT_ENCODING, "gzip, deflate");
+    // No timeout on the request itself: the caller bounds the whole fetch, including reading the
+    // body, by `config.fetch_timeout`.
+    match req.send().await.and_then(|r| r.error_for_status()) {
+        // the caller keeps serving the previously fetched JWKs for this url.
+        Err(e) => {
+            tracing::warn!(?url, error=?e, "could not fetch JWKs");
+            None
+        }
+        Ok(r) => {
+            let resp: http::Response<reqwest::Body> = r.into();
+            let content_encoding = resp
+                .headers()
+                .get(http::header::CONTENT_ENCODING)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned);
+            match parse_encoded_json_body_with_limit::<jose_jwk::JwkSet>(
+                resp.into_body(),
+                content_encoding.as_deref(),
+                config.max_body_size,
+            )
             .await
+            {
+                Err(e) => {
+                    tracing::warn!(?url, error=?e, "could not decode JWKs");
+                    None
+                }
+                Ok(jwks) => Some(jwks),
+            }
+        }
+    }
+}
+
+/// Checks that the JWKs url only resolves to addresses on the public internet,
+/// so that a tenant cannot make us fetch internal resources.
+///
+/// This is checked again by the client when connecting, see [`new_jwks_client`].
+async fn check_jwks_url(resolver: &dyn ResolveJwksHost, url: &url::Url) -> anyhow::Result<()> {
+    match url.host() {
+        Some(url::Host::Domain(domain)) => {
+            let port = url
+                .port_or_known_default()
+                .context("JWKs url has no port")?;
+            resolve_public_addrs(resolver, domain, port).await?;
+            Ok(())
+        }
+        _ => check_jwks_redirect_url(url),
+    }
+}
+
+async fn resolve_public_addrs(
+    resolver: &dyn ResolveJwksHost,
+    host: &str,
+    port: u16,
+) -> anyhow::Result<Vec<IpAddr>> {
+    let addrs = resolver
+        .resolve(host, port)
+        .await
+        .context("could not resolve JWKs url")?;
+    check_public_addrs(&addrs)?;
+    Ok(addrs)
+}
+
+fn check_public_addrs(addrs: &[IpAddr]) -> anyhow::Result<()> {
+    ensure!(!addrs.is_empty(), "JWKs url did not resolve to any address");
+    for addr in addrs {
+        ensure!(
+            !is_non_public_addr(*addr),
+            "JWKs url resolves to non-public address {addr}"
+        );
+    }
+    Ok(())
+}
+
+fn is_non_public_addr(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // "this network", 0.0.0.0/8
+                || first == 0
+                // shared address space, 100.64.0.0/10
+                || (first == 100 && (second & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_non_public_addr(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local, fc00::/7
+                    || (first & 0xfe00) == 0xfc00
+                    // link local, fe80::/10
+                    || (first & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+/// The only signing algorithm allowed for each elliptic curve.
+fn ec_signing_algorithm(crv: &jose_jwk::EcCurves) -> Option<jose_jwa::Signing> {
+    match crv {
+        jose_jwk::EcCurves::P256 => Some(jose_jwa::Signing::Es256),
+        jose_jwk::EcCurves::P384 => Some(jose_jwa::Signing::Es384),
+        jose_jwk::EcCurves::P521 => Some(jose_jwa::Signing::Es512),
+        _ => None,
     }
 }
 
@@ -347,12 +852,47 @@ fn verify_ec_signature(data: &[u8], sig: &[u8], key: &jose_jwk::Ec) -> anyhow::R
             let sig = Signature::from_slice(sig)?;
             key.verify(data, &sig)?;
         }
+        jose_jwk::EcCurves::P384 => {
+            let pk =
+                p384::PublicKey::try_from(key).map_err(|_| anyhow::anyhow!("invalid P384 key"))?;
+            let key = p384::ecdsa::VerifyingKey::from(&pk);
+            let sig = Signature::from_slice(sig)?;
+            key.verify(data, &sig)?;
+        }
+        jose_jwk::EcCurves::P521 => {
+            // jose_jwk has no p521 conversion, so build the uncompressed SEC1 point ourselves.
+            let mut point = Vec::with_capacity(1 + key.x.len() + key.y.len());
+            point.push(0x04);
+            point.extend_from_slice(&key.x);
+            point.extend_from_slice(&key.y);
+            let key = p521::ecdsa::VerifyingKey::from_sec1_bytes(&point)
+                .map_err(|_| anyhow::anyhow!("invalid P521 key"))?;
+            let sig = p521::ecdsa::Signature::from_slice(sig)?;
+            key.verify(data, &sig)?;
+        }
         key => bail!("unsupported ec key type {key:?}"),
     }
 
     Ok(())
 }
 
+fn verify_eddsa_signature(data: &[u8], sig: &[u8], key: &jose_jwk::Okp) -> anyhow::Result<()> {
+    use ed25519_dalek::{Signature, VerifyingKey};
+    use signature::Verifier;
+
+    match key.crv {
+        jose_jwk::OkpCurves::Ed25519 => {
+            let key = VerifyingKey::try_from(&key.x[..])
+                .map_err(|_| anyhow::anyhow!("invalid Ed25519 key"))?;
+            let sig = Signature::from_slice(sig)?;
+            key.verify(data, &sig)?;
+        }
+        key => bail!("unsupported okp key type {key:?}"),
+    }
+
+    Ok(())
+}
+
 fn verify_rsa_signature(
     data: &[u8],
     sig: &[u8],
@@ -406,7 +946,7 @@ struct JwtPayload<'a> {
     #[serde(deserialize_with = "numeric_date_opt", rename = "nbf", default)]
     not_before: Option<SystemTime>,
 
-    // the following entries are only extracted for the sake of debug logging.
+    // the following entries are only extracted for the sake of logging.
     /// Issuer of the JWT
     #[serde(rename = "iss")]
     issuer: Option<&'a str>,
@@ -494,7 +1034,12 @@ mod tests {
 
     use super::*;
 
-    use std::{future::IntoFuture, net::SocketAddr, time::SystemTime};
+    use std::{
+        future::IntoFuture,
+        net::SocketAddr,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+        time::SystemTime,
+    };
 
     use base64::URL_SAFE_NO_PAD;
     use bytes::Bytes;
@@ -506,14 +1051,107 @@ mod tests {
     use signature::Signer;
     use tokio::net::TcpListener;
 
-    fn new_ec_jwk(kid: String) -> (p256::SecretKey, jose_jwk::Jwk) {
-        let sk = p256::SecretKey::random(&mut OsRng);
-        let pk = sk.public_key().into();
+    enum EcSigningKey {
+        P256(p256::ecdsa::SigningKey),
+        P384(p384::ecdsa::SigningKey),
+        P521(p521::ecdsa::SigningKey),
+    }
+
+    impl EcSigningKey {
+        fn random(crv: &jose_jwk::EcCurves) -> Self {
+            match crv {
+                jose_jwk::EcCurves::P256 => {
+                    EcSigningKey::P256(p256::ecdsa::SigningKey::random(&mut OsRng))
+                }
+                jose_jwk::EcCurves::P384 => {
+                    EcSigningKey::P384(p384::ecdsa::SigningKey::random(&mut OsRng))
+                }
+                jose_jwk::EcCurves::P521 => {
+                    EcSigningKey::P521(p521::ecdsa::SigningKey::random(&mut OsRng))
+                }
+                crv => panic!("unsupported curve {crv:?}"),
+            }
+        }
+
+        fn signing(&self) -> jose_jwa::Signing {
+            match self {
+                EcSigningKey::P256(_) => jose_jwa::Signing::Es256,
+                EcSigningKey::P384(_) => jose_jwa::Signing::Es384,
+                EcSigningKey::P521(_) => jose_jwa::Signing::Es512,
+            }
+        }
+
+        /// The affine x and y coordinates of the public key
+        fn public_coordinates(&self) -> (Vec<u8>, Vec<u8>) {
+            match self {
+                EcSigningKey::P256(sk) => {
+                    let point = sk.verifying_key().to_encoded_point(false);
+                    (point.x().unwrap().to_vec(), point.y().unwrap().to_vec())
+                }
+                EcSigningKey::P384(sk) => {
+                    let point = sk.verifying_key().to_encoded_point(false);
+                    (point.x().unwrap().to_vec(), point.y().unwrap().to_vec())
+                }
+                EcSigningKey::P521(sk) => {
+                    let point = sk.verifying_key().to_encoded_point(false);
+                    (point.x().unwrap().to_vec(), point.y().unwrap().to_vec())
+                }
+            }
+        }
+
+        fn sign(&self, data: &[u8]) -> Vec<u8> {
+            match self {
+                EcSigningKey::P256(sk) => {
+                    let sig: p256::ecdsa::Signature = sk.sign(data);
+                    sig.to_bytes().to_vec()
+                }
+                EcSigningKey::P384(sk) => {
+                    let sig: p384::ecdsa::Signature = sk.sign(data);
+                    sig.to_bytes().to_vec()
+                }
+                EcSigningKey::P521(sk) => {
+                    let sig: p521::ecdsa::Signature = sk.sign(data);
+                    sig.to_bytes().to_vec()
+                }
+            }
+        }
+    }
+
+    fn new_ec_jwk(kid: String, crv: jose_jwk::EcCurves) -> (EcSigningKey, jose_jwk::Jwk) {
+        let sk = EcSigningKey::random(&crv);
+        let (x, y) = sk.public_coordinates();
+        let key = serde_json::from_value(serde_json::json!({
+            "kty": "EC",
+            "crv": crv,
+            "x": base64::encode_config(x, URL_SAFE_NO_PAD),
+            "y": base64::encode_config(y, URL_SAFE_NO_PAD),
+        }))
+        .unwrap();
+        let jwk = jose_jwk::Jwk {
+            key,
+            prm: jose_jwk::Parameters {
+                kid: Some(kid),
+                alg: Some(jose_jwa::Algorithm::Signing(sk.signing())),
+                ..Default::default()
+            },
+        };
+        (sk, jwk)
+    }
+
+    fn new_ed25519_jwk(kid: String) -> (ed25519_dalek::SigningKey, jose_jwk::Jwk) {
+        let sk = ed25519_dalek::SigningKey::from_bytes(&rand::random());
+        let x = base64::encode_config(sk.verifying_key().as_bytes(), URL_SAFE_NO_PAD);
+        let key = serde_json::from_value(serde_json::json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "x": x,
+        }))
+        .unwrap();
         let jwk = jose_jwk::Jwk {
-            key: jose_jwk::Key::Ec(pk),
+            key,
             prm: jose_jwk::Parameters {
                 kid: Some(kid),
-                alg: Some(jose_jwa::Algorithm::Signing(jose_jwa::Signing::Es256)),
+                alg: Some(jose_jwa::Algorithm::Signing(jose_jwa::Signing::EdDsa)),
                 ..Default::default()
             },
         };
@@ -535,27 +1173,59 @@ fn new_rsa_jwk(kid: String) -> (rsa::RsaPrivateKey, jose_jwk::Jwk) {
     }
 
     fn build_jwt_payload(kid: String, sig: jose_jwa::Signing) -> String {
+        let body = typed_json::json! {{
+            "exp": SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() + 3600,
+        }};
+
+        build_jwt_payload_with_claims(kid, sig, &body.to_string())
+    }
+
+    fn build_jwt_payload_with_claims(kid: String, sig: jose_jwa::Signing, claims: &str) -> String {
         let header = JwtHeader {
             typ: "JWT",
             algorithm: jose_jwa::Algorithm::Signing(sig),
             key_id: Some(&kid),
         };
-        let body = typed_json::json! {{
-            "exp": SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() + 3600,
-        }};
 
         let header =
             base64::encode_config(serde_json::to_string(&header).unwrap(), URL_SAFE_NO_PAD);
-        let body = base64::encode_config(body.to_string(), URL_SAFE_NO_PAD);
+        let body = base64::encode_config(claims, URL_SAFE_NO_PAD);
 
         format!("{header}.{body}")
     }
 
-    fn new_ec_jwt(kid: String, key: p256::SecretKey) -> String {
-        use p256::ecdsa::{Signature, SigningKey};
+    fn new_ec_jwt(kid: String, key: EcSigningKey) -> String {
+        let payload = build_jwt_payload(kid, key.signing());
+        let sig = base64::encode_config(key.sign(payload.as_bytes()), URL_SAFE_NO_PAD);
+
+        format!("{payload}.{sig}")
+    }
+
+    fn new_ec_jwt_with_claims(kid: String, key: &EcSigningKey, claims: &str) -> String {
+        let payload = build_jwt_payload_with_claims(kid, key.signing(), claims);
+        let sig = base64::encode_config(key.sign(payload.as_bytes()), URL_SAFE_NO_PAD);
+
+        format!("{payload}.{sig}")
+    }
+
+    fn new_ec_jwt_without_kid(key: &EcSigningKey) -> String {
+        let header = JwtHeader {
+            typ: "JWT",
+            algorithm: jose_jwa::Algorithm::Signing(key.signing()),
+            key_id: None,
+        };
+        let header =
+            base64::encode_config(serde_json::to_string(&header).unwrap(), URL_SAFE_NO_PAD);
+        let body = base64::encode_config("{}", URL_SAFE_NO_PAD);
+        let payload = format!("{header}.{body}");
+        let sig = base64::encode_config(key.sign(payload.as_bytes()), URL_SAFE_NO_PAD);
+
+        format!("{payload}.{sig}")
+    }
 
-        let payload = build_jwt_payload(kid, jose_jwa::Signing::Es256);
-        let sig: Signature = SigningKey::from(key).sign(payload.as_bytes());
+    fn new_ed25519_jwt(kid: String, key: ed25519_dalek::SigningKey) -> String {
+        let payload = build_jwt_payload(kid, jose_jwa::Signing::EdDsa);
+        let sig = key.sign(payload.as_bytes());
         let sig = base64::encode_config(sig.to_bytes(), URL_SAFE_NO_PAD);
 
         format!("{payload}.{sig}")
@@ -576,19 +1246,23 @@ fn new_rsa_jwt(kid: String, key: rsa::RsaPrivateKey) -> String {
     async fn renew() {
         let (rs1, jwk1) = new_rsa_jwk("1".into());
         let (rs2, jwk2) = new_rsa_jwk("2".into());
-        let (ec1, jwk3) = new_ec_jwk("3".into());
-        let (ec2, jwk4) = new_ec_jwk("4".into());
+        let (ec1, jwk3) = new_ec_jwk("3".into(), jose_jwk::EcCurves::P256);
+        let (ec2, jwk4) = new_ec_jwk("4".into(), jose_jwk::EcCurves::P256);
+        let (ed1, jwk5) = new_ed25519_jwk("5".into());
+        let (ed2, jwk6) = new_ed25519_jwk("6".into());
 
         let jwt1 = new_rsa_jwt("1".into(), rs1);
         let jwt2 = new_rsa_jwt("2".into(), rs2);
         let jwt3 = new_ec_jwt("3".into(), ec1);
         let jwt4 = new_ec_jwt("4".into(), ec2);
+        let jwt5 = new_ed25519_jwt("5".into(), ed1);
+        let jwt6 = new_ed25519_jwt("6".into(), ed2);
 
         let foo_jwks = jose_jwk::JwkSet {
-            keys: vec![jwk1, jwk3],
+            keys: vec![jwk1, jwk3, jwk5],
         };
         let bar_jwks = jose_jwk::JwkSet {
-            keys: vec![jwk2, jwk4],
+            keys: vec![jwk2, jwk4, jwk6],
         };
 
         let service = service_fn(move |req| {
@@ -636,12 +1310,20 @@ async fn fetch_auth_rules(
                     AuthRule {
                         id: "foo".to_owned(),
                         jwks_url: format!("http://{}/foo", self.0).parse().unwrap(),
-                        audience: None,
+                        audience: AudienceMatcher::any(),
+                        require_exp: false,
+                        require_nbf: false,
+                        allow_missing_kid: false,
+                        static_jwks: None,
                     },
                     AuthRule {
                         id: "bar".to_owned(),
                         jwks_url: format!("http://{}/bar", self.0).parse().unwrap(),
-                        audience: None,
+                        audience: AudienceMatcher::any(),
+                        require_exp: false,
+                        require_nbf: false,
+                        allow_missing_kid: false,
+                        static_jwks: None,
                     },
                 ])
             }
@@ -651,17 +1333,1250 @@ async fn fetch_auth_rules(
 
         let jwk_cache = Arc::new(JwkCacheEntryLock::default());
 
-        for token in [jwt1, jwt2, jwt3, jwt4] {
-            jwk_cache
+        for (token, expected_rule_id) in [
+            (jwt1, "foo"),
+            (jwt2, "bar"),
+            (jwt3, "foo"),
+            (jwt4, "bar"),
+            (jwt5, "foo"),
+            (jwt6, "bar"),
+        ] {
+            let rule_id = jwk_cache
                 .check_jwt(
                     &RequestMonitoring::test(),
                     &token,
                     &client,
+                    &JwkCacheConfig {
+                        allow_private_urls: true,
+                        ..Default::default()
+                    },
                     role_name.clone(),
                     &Fetch(addr),
                 )
                 .await
                 .unwrap();
+            assert_eq!(rule_id, expected_rule_id);
         }
     }
+
+    /// Serves the given JWKs on every path, delaying the response for paths starting with `/slow`.
+    async fn slow_jwks_server(jwks: jose_jwk::JwkSet, delay: Duration) -> SocketAddr {
+        let service = service_fn(move |req| {
+            let jwks = jwks.clone();
+            async move {
+                if req.uri().path().starts_with("/slow") {
+                    tokio::time::sleep(delay).await;
+                }
+                let body = serde_json::to_vec(&jwks).unwrap();
+                Response::builder()
+                    .status(200)
+                    .body(Full::new(Bytes::from(body)))
+            }
+        });
+
+        let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let server = hyper1::server::conn::http1::Builder::new();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (s, _) = listener.accept().await.unwrap();
+                let serve = server.serve_connection(TokioIo::new(s), service.clone());
+                tokio::spawn(serve.into_future());
+            }
+        });
+
+        addr
+    }
+
+    /// Serves the given JWKs on every path, counting how many times they were fetched.
+    async fn counting_jwks_server(jwks: jose_jwk::JwkSet) -> (SocketAddr, Arc<AtomicUsize>) {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let service = service_fn({
+            let fetches = Arc::clone(&fetches);
+            move |_req| {
+                fetches.fetch_add(1, Ordering::Relaxed);
+                let body = serde_json::to_vec(&jwks).unwrap();
+                async move {
+                    Response::builder()
+                        .status(200)
+                        .body(Full::new(Bytes::from(body)))
+                }
+            }
+        });
+
+        let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let server = hyper1::server::conn::http1::Builder::new();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (s, _) = listener.accept().await.unwrap();
+                let serve = server.serve_connection(TokioIo::new(s), service.clone());
+                tokio::spawn(serve.into_future());
+            }
+        });
+
+        (addr, fetches)
+    }
+
+    #[tokio::test]
+    async fn fetch_compressed_jwks() {
+        use async_compression::tokio::write::{GzipEncoder, ZlibEncoder};
+        use tokio::io::AsyncWriteExt;
+
+        // large and repetitive, so that it compresses well below the size limit below
+        let (_, jwk) = new_ec_jwk("1".into(), jose_jwk::EcCurves::P256);
+        let jwks = jose_jwk::JwkSet {
+            keys: vec![jwk; 20],
+        };
+        let json = Bytes::from(serde_json::to_vec(&jwks).unwrap());
+        let json_len = json.len();
+
+        let mut gzip = GzipEncoder::new(Vec::new());
+        gzip.write_all(&json).await.unwrap();
+        gzip.shutdown().await.unwrap();
+        let gzip = Bytes::from(gzip.into_inner());
+        let mut deflate = ZlibEncoder::new(Vec::new());
+        deflate.write_all(&json).await.unwrap();
+        deflate.shutdown().await.unwrap();
+        let deflate = Bytes::from(deflate.into_inner());
+
+        // serves the JWKs with the encoding named by the path
+        let service = service_fn(move |req| {
+            let (encoding, body) = match req.uri().path() {
+                "/gzip" => (Some("gzip"), gzip.clone()),
+                "/deflate" => (Some("deflate"), deflate.clone()),
+                "/br" => (Some("br"), gzip.clone()),
+                _ => (None, json.clone()),
+            };
+            async move {
+                let mut resp = Response::builder().status(200);
+                if let Some(encoding) = encoding {
+                    resp = resp.header(http::header::CONTENT_ENCODING, encoding);
+                }
+                resp.body(Full::new(body))
+            }
+        });
+
+        let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let server = hyper1::server::conn::http1::Builder::new();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (s, _) = listener.accept().await.unwrap();
+                let serve = server.serve_connection(TokioIo::new(s), service.clone());
+                tokio::spawn(serve.into_future());
+            }
+        });
+
+        let client = reqwest::Client::new();
+        let config = JwkCacheConfig {
+            allow_private_urls: true,
+            ..Default::default()
+        };
+        for path in ["identity", "gzip", "deflate"] {
+            let url = format!("http://{addr}/{path}").parse().unwrap();
+            let fetched = fetch_jwks(&client, &config, &url).await.unwrap();
+            assert_eq!(
+                serde_json::to_vec(&fetched).unwrap(),
+                serde_json::to_vec(&jwks).unwrap(),
+                "{path}"
+            );
+        }
+
+        // unknown encodings are not mistaken for plain JSON
+        let url = format!("http://{addr}/br").parse().unwrap();
+        assert!(fetch_jwks(&client, &config, &url).await.is_none());
+
+        // the limit applies to the decompressed size
+        let config = JwkCacheConfig {
+            allow_private_urls: true,
+            max_body_size: json_len - 1,
+            ..Default::default()
+        };
+        let url = format!("http://{addr}/gzip").parse().unwrap();
+        assert!(fetch_jwks(&client, &config, &url).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn renew_timeout() {
+        let (_, jwk) = new_ec_jwk("1".into(), jose_jwk::EcCurves::P256);
+        let jwks = jose_jwk::JwkSet { keys: vec![jwk] };
+        let addr = slow_jwks_server(jwks, Duration::from_secs(30)).await;
+
+        #[derive(Clone)]
+        struct Fetch(SocketAddr);
+
+        impl FetchAuthRules for Fetch {
+            async fn fetch_auth_rules(
+                &self,
+                _role_name: RoleName,
+            ) -> anyhow::Result<Vec<AuthRule>> {
+                Ok(vec![
+                    AuthRule {
+                        id: "fast".to_owned(),
+                        jwks_url: format!("http://{}/fast", self.0).parse().unwrap(),
+                        audience: AudienceMatcher::any(),
+                        require_exp: false,
+                        require_nbf: false,
+                        allow_missing_kid: false,
+                        static_jwks: None,
+                    },
+                    AuthRule {
+                        id: "slow".to_owned(),
+                        jwks_url: format!("http://{}/slow", self.0).parse().unwrap(),
+                        audience: AudienceMatcher::any(),
+                        require_exp: false,
+                        require_nbf: false,
+                        allow_missing_kid: false,
+                        static_jwks: None,
+                    },
+                ])
+            }
+        }
+
+        let client = reqwest::Client::new();
+        let config = JwkCacheConfig {
+            fetch_timeout: Duration::from_millis(100),
+            allow_private_urls: true,
+            ..Default::default()
+        };
+        let jwk_cache = Arc::new(JwkCacheEntryLock::default());
+
+        let start = std::time::Instant::now();
+        let permit = jwk_cache.acquire_permit().await;
+        let entry = jwk_cache
+            .renew_jwks(
+                permit,
+                &client,
+                &config,
+                RoleName::from("user"),
+                &Fetch(addr),
+            )
+            .await
+            .unwrap();
+
+        // the slow url is given up on, rather than holding up the renewal.
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(entry.key_sets.contains_key("fast"));
+        assert!(!entry.key_sets.contains_key("slow"));
+    }
+
+    #[tokio::test]
+    async fn renew_keeps_keys_of_failing_urls() {
+        let (foo_key, foo_jwk) = new_ec_jwk("foo".into(), jose_jwk::EcCurves::P256);
+        let (bar_key, bar_jwk) = new_ec_jwk("bar".into(), jose_jwk::EcCurves::P256);
+        let foo_jwt = new_ec_jwt("foo".into(), foo_key);
+        let bar_jwt = new_ec_jwt("bar".into(), bar_key);
+        let foo_jwks = jose_jwk::JwkSet {
+            keys: vec![foo_jwk],
+        };
+        let bar_jwks = jose_jwk::JwkSet {
+            keys: vec![bar_jwk],
+        };
+
+        let bar_failing = Arc::new(AtomicBool::new(false));
+        let service = service_fn({
+            let bar_failing = Arc::clone(&bar_failing);
+            move |req| {
+                let jwks = match req.uri().path() {
+                    "/bar" if bar_failing.load(Ordering::Relaxed) => None,
+                    "/bar" => Some(&bar_jwks),
+                    _ => Some(&foo_jwks),
+                };
+                let response = match jwks {
+                    Some(jwks) => Response::builder()
+                        .status(200)
+                        .body(Full::new(Bytes::from(serde_json::to_vec(jwks).unwrap()))),
+                    None => Response::builder()
+                        .status(500)
+                        .body(Full::new(Bytes::new())),
+                };
+                async move { response }
+            }
+        });
+
+        let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let server = hyper1::server::conn::http1::Builder::new();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (s, _) = listener.accept().await.unwrap();
+                let serve = server.serve_connection(TokioIo::new(s), service.clone());
+                tokio::spawn(serve.into_future());
+            }
+        });
+
+        #[derive(Clone)]
+        struct Fetch(SocketAddr);
+
+        impl FetchAuthRules for Fetch {
+            async fn fetch_auth_rules(
+                &self,
+                _role_name: RoleName,
+            ) -> anyhow::Result<Vec<AuthRule>> {
+                Ok(vec![
+                    AuthRule {
+                        id: "foo".to_owned(),
+                        jwks_url: format!("http://{}/foo", self.0).parse().unwrap(),
+                        audience: AudienceMatcher::any(),
+                        require_exp: false,
+                        require_nbf: false,
+                        allow_missing_kid: false,
+                        static_jwks: None,
+                    },
+                    AuthRule {
+                        id: "bar".to_owned(),
+                        jwks_url: format!("http://{}/bar", self.0).parse().unwrap(),
+                        audience: AudienceMatcher::any(),
+                        require_exp: false,
+                        require_nbf: false,
+                        allow_missing_kid: false,
+                        static_jwks: None,
+                    },
+                ])
+            }
+        }
+
+        let client = reqwest::Client::new();
+        let config = JwkCacheConfig {
+            allow_private_urls: true,
+            ..Default::default()
+        };
+        let jwk_cache = Arc::new(JwkCacheEntryLock::default());
+        let check_all = || async {
+            for jwt in [&foo_jwt, &bar_jwt] {
+                jwk_cache
+                    .check_jwt(
+                        &RequestMonitoring::test(),
+                        jwt,
+                        &client,
+                        &config,
+                        RoleName::from("user"),
+                        &Fetch(addr),
+                    )
+                    .await
+                    .unwrap();
+            }
+        };
+
+        check_all().await;
+
+        // bar starts failing. Renew as if the cached keys were old.
+        bar_failing.store(true, Ordering::Relaxed);
+        let entry = jwk_cache.cached.load_full().unwrap();
+        jwk_cache.cached.store(Some(Arc::new(JwkCacheEntry {
+            last_retrieved: Instant::now() - MIN_RENEW * 2,
+            key_sets: entry.key_sets.clone(),
+        })));
+        let permit = jwk_cache.acquire_permit().await;
+        let entry = jwk_cache
+            .renew_jwks(
+                permit,
+                &client,
+                &config,
+                RoleName::from("user"),
+                &Fetch(addr),
+            )
+            .await
+            .unwrap();
+        assert!(entry.last_retrieved.elapsed() < MIN_RENEW);
+        assert!(entry.key_sets.contains_key("bar"));
+
+        check_all().await;
+    }
+
+    #[tokio::test]
+    async fn renew_large_jwks() {
+        // enough keys to push the JWKs just over the default body size limit
+        let mut jwks = jose_jwk::JwkSet { keys: vec![] };
+        while serde_json::to_vec(&jwks).unwrap().len() <= DEFAULT_MAX_JWKS_BODY_SIZE {
+            let kid = jwks.keys.len().to_string();
+            jwks.keys.push(new_ec_jwk(kid, jose_jwk::EcCurves::P256).1);
+        }
+        let body_size = serde_json::to_vec(&jwks).unwrap().len();
+        let (addr, _) = counting_jwks_server(jwks).await;
+
+        #[derive(Clone)]
+        struct Fetch(SocketAddr);
+
+        impl FetchAuthRules for Fetch {
+            async fn fetch_auth_rules(
+                &self,
+                _role_name: RoleName,
+            ) -> anyhow::Result<Vec<AuthRule>> {
+                Ok(vec![AuthRule {
+                    id: "large".to_owned(),
+                    jwks_url: format!("http://{}/", self.0).parse().unwrap(),
+                    audience: AudienceMatcher::any(),
+                    require_exp: false,
+                    require_nbf: false,
+                    allow_missing_kid: false,
+                    static_jwks: None,
+                }])
+            }
+        }
+
+        let client = reqwest::Client::new();
+
+        let config = JwkCacheConfig {
+            allow_private_urls: true,
+            ..Default::default()
+        };
+        let jwk_cache = Arc::new(JwkCacheEntryLock::default());
+        let permit = jwk_cache.acquire_permit().await;
+        let entry = jwk_cache
+            .renew_jwks(
+                permit,
+                &client,
+                &config,
+                RoleName::from("user"),
+                &Fetch(addr),
+            )
+            .await
+            .unwrap();
+        assert!(!entry.key_sets.contains_key("large"));
+
+        let config = JwkCacheConfig {
+            max_body_size: body_size,
+            allow_private_urls: true,
+            ..Default::default()
+        };
+        let jwk_cache = Arc::new(JwkCacheEntryLock::default());
+        let permit = jwk_cache.acquire_permit().await;
+        let entry = jwk_cache
+            .renew_jwks(
+                permit,
+                &client,
+                &config,
+                RoleName::from("user"),
+                &Fetch(addr),
+            )
+            .await
+            .unwrap();
+        assert!(entry.key_sets.contains_key("large"));
+    }
+
+    struct StaticResolver(IpAddr);
+
+    impl ResolveJwksHost for StaticResolver {
+        fn resolve<'a>(
+            &'a self,
+            _host: &'a str,
+            _port: u16,
+        ) -> BoxFuture<'a, std::io::Result<Vec<IpAddr>>> {
+            Box::pin(async move { Ok(vec![self.0]) })
+        }
+    }
+
+    #[tokio::test]
+    async fn jwks_url_filter() {
+        let url: url::Url = "https://jwks.example.com/.well-known/jwks.json"
+            .parse()
+            .unwrap();
+
+        for (addr, public) in [
+            ([127, 0, 0, 1], false),
+            ([10, 1, 2, 3], false),
+            ([169, 254, 169, 254], false),
+            ([0, 1, 2, 3], false),
+            ([100, 64, 0, 1], false),
+            ([100, 127, 255, 254], false),
+            ([224, 0, 0, 251], false),
+            ([100, 128, 0, 1], true),
+            ([93, 184, 215, 14], true),
+        ] {
+            let resolver = StaticResolver(IpAddr::from(addr));
+            let res = check_jwks_url(&resolver, &url).await;
+            assert_eq!(res.is_ok(), public, "{addr:?}: {res:?}");
+
+            let url: url::Url = format!("https://{}/jwks.json", IpAddr::from(addr))
+                .parse()
+                .unwrap();
+            let res = check_jwks_url(&resolver, &url).await;
+            assert_eq!(res.is_ok(), public, "{addr:?}: {res:?}");
+        }
+    }
+
+    #[test]
+    fn ipv4_mapped_addrs() {
+        for (addr, public) in [
+            ("::ffff:127.0.0.1", false),
+            ("::ffff:169.254.169.254", false),
+            ("::ffff:100.64.0.1", false),
+            ("::ffff:224.0.0.251", false),
+            ("::ffff:0.1.2.3", false),
+            ("ff02::1", false),
+            ("::ffff:93.184.215.14", true),
+        ] {
+            let addr: IpAddr = addr.parse().unwrap();
+            assert_eq!(!is_non_public_addr(addr), public, "{addr}");
+        }
+    }
+
+    #[tokio::test]
+    async fn jwks_client_only_connects_to_public_addrs() {
+        use reqwest::dns::Resolve;
+
+        let resolver =
+            PublicOnlyResolver(Arc::new(StaticResolver(IpAddr::from([169, 254, 169, 254]))));
+        assert!(resolver
+            .resolve("jwks.example.com".parse().unwrap())
+            .await
+            .is_err());
+
+        let resolver =
+            PublicOnlyResolver(Arc::new(StaticResolver(IpAddr::from([93, 184, 215, 14]))));
+        let addrs: Vec<_> = resolver
+            .resolve("jwks.example.com".parse().unwrap())
+            .await
+            .unwrap()
+            .map(|addr| addr.ip())
+            .collect();
+        assert_eq!(addrs, vec![IpAddr::from([93, 184, 215, 14])]);
+
+        // redirects to ip addresses don't go through the resolver.
+        for (url, public) in [
+            ("http://169.254.169.254/latest/meta-data/", false),
+            ("http://[::ffff:10.0.0.1]/jwks.json", false),
+            ("https://93.184.215.14/jwks.json", true),
+            ("https://jwks.example.com/jwks.json", true),
+        ] {
+            let url: url::Url = url.parse().unwrap();
+            assert_eq!(check_jwks_redirect_url(&url).is_ok(), public, "{url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn jwks_client_does_not_follow_redirects_to_private_addrs() {
+        // a server on a private address, redirecting to another private address.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let service = service_fn(|_req| async {
+                Ok::<_, std::convert::Infallible>(
+                    Response::builder()
+                        .status(http::StatusCode::FOUND)
+                        .header(http::header::LOCATION, "http://169.254.169.254/jwks.json")
+                        .body(Full::new(Bytes::new()))
+                        .unwrap(),
+                )
+            });
+            let (s, _) = listener.accept().await.unwrap();
+            hyper1::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(s), service)
+                .await
+                .unwrap();
+        });
+
+        let client = new_jwks_client(&JwkCacheConfig::default());
+        let err = client
+            .get(format!("http://{addr}/jwks.json"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_redirect(), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn renew_skips_private_urls() {
+        #[derive(Clone)]
+        struct Fetch;
+
+        impl FetchAuthRules for Fetch {
+            async fn fetch_auth_rules(
+                &self,
+                _role_name: RoleName,
+            ) -> anyhow::Result<Vec<AuthRule>> {
+                Ok(vec![AuthRule {
+                    id: "metadata".to_owned(),
+                    jwks_url: "http://169.254.169.254/jwks.json".parse().unwrap(),
+                    audience: AudienceMatcher::any(),
+                    require_exp: false,
+                    require_nbf: false,
+                    allow_missing_kid: false,
+                    static_jwks: None,
+                }])
+            }
+        }
+
+        let jwk_cache = Arc::new(JwkCacheEntryLock::default());
+        let permit = jwk_cache.acquire_permit().await;
+        let entry = jwk_cache
+            .renew_jwks(
+                permit,
+                &reqwest::Client::new(),
+                &JwkCacheConfig::default(),
+                RoleName::from("user"),
+                &Fetch,
+            )
+            .await
+            .unwrap();
+
+        assert!(entry.key_sets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn renew_concurrently() {
+        let (_, jwk) = new_ec_jwk("1".into(), jose_jwk::EcCurves::P256);
+        let jwks = jose_jwk::JwkSet { keys: vec![jwk] };
+        let addr = slow_jwks_server(jwks, Duration::from_millis(500)).await;
+
+        #[derive(Clone)]
+        struct Fetch(SocketAddr);
+
+        impl FetchAuthRules for Fetch {
+            async fn fetch_auth_rules(
+                &self,
+                _role_name: RoleName,
+            ) -> anyhow::Result<Vec<AuthRule>> {
+                Ok(vec![
+                    AuthRule {
+                        id: "slow1".to_owned(),
+                        jwks_url: format!("http://{}/slow1", self.0).parse().unwrap(),
+                        audience: AudienceMatcher::any(),
+                        require_exp: false,
+                        require_nbf: false,
+                        allow_missing_kid: false,
+                        static_jwks: None,
+                    },
+                    AuthRule {
+                        id: "slow2".to_owned(),
+                        jwks_url: format!("http://{}/slow2", self.0).parse().unwrap(),
+                        audience: AudienceMatcher::any(),
+                        require_exp: false,
+                        require_nbf: false,
+                        allow_missing_kid: false,
+                        static_jwks: None,
+                    },
+                ])
+            }
+        }
+
+        let config = JwkCacheConfig {
+            allow_private_urls: true,
+            ..Default::default()
+        };
+        let jwk_cache = Arc::new(JwkCacheEntryLock::default());
+
+        let start = std::time::Instant::now();
+        let permit = jwk_cache.acquire_permit().await;
+        let entry = jwk_cache
+            .renew_jwks(
+                permit,
+                &reqwest::Client::new(),
+                &config,
+                RoleName::from("user"),
+                &Fetch(addr),
+            )
+            .await
+            .unwrap();
+        let elapsed = start.elapsed();
+
+        // both urls take 500ms, fetching them one after the other would take at least 1s.
+        assert!(elapsed >= Duration::from_millis(500), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(900), "{elapsed:?}");
+        assert!(entry.key_sets.contains_key("slow1"));
+        assert!(entry.key_sets.contains_key("slow2"));
+    }
+
+    #[tokio::test]
+    async fn require_claims() {
+        let (ec, jwk) = new_ec_jwk("1".into(), jose_jwk::EcCurves::P256);
+        let jwks = jose_jwk::JwkSet { keys: vec![jwk] };
+        let addr = slow_jwks_server(jwks, Duration::ZERO).await;
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let no_claims = new_ec_jwt_with_claims("1".into(), &ec, "{}");
+        let exp_only =
+            new_ec_jwt_with_claims("1".into(), &ec, &format!(r#"{{"exp":{}}}"#, now + 3600));
+        let exp_and_nbf = new_ec_jwt_with_claims(
+            "1".into(),
+            &ec,
+            &format!(r#"{{"exp":{},"nbf":{}}}"#, now + 3600, now - 60),
+        );
+
+        #[derive(Clone)]
+        struct Fetch {
+            addr: SocketAddr,
+            require_exp: bool,
+            require_nbf: bool,
+        }
+
+        impl FetchAuthRules for Fetch {
+            async fn fetch_auth_rules(
+                &self,
+                _role_name: RoleName,
+            ) -> anyhow::Result<Vec<AuthRule>> {
+                Ok(vec![AuthRule {
+                    id: "foo".to_owned(),
+                    jwks_url: format!("http://{}/foo", self.addr).parse().unwrap(),
+                    audience: AudienceMatcher::any(),
+                    require_exp: self.require_exp,
+                    require_nbf: self.require_nbf,
+                    allow_missing_kid: false,
+                    static_jwks: None,
+                }])
+            }
+        }
+
+        let config = JwkCacheConfig {
+            allow_private_urls: true,
+            ..Default::default()
+        };
+        let client = reqwest::Client::new();
+
+        for (require_exp, require_nbf, expected) in [
+            (false, false, [true, true, true]),
+            (true, false, [false, true, true]),
+            (false, true, [false, false, true]),
+        ] {
+            let fetch = Fetch {
+                addr,
+                require_exp,
+                require_nbf,
+            };
+            let jwk_cache = Arc::new(JwkCacheEntryLock::default());
+
+            for (token, ok) in [&no_claims, &exp_only, &exp_and_nbf]
+                .into_iter()
+                .zip(expected)
+            {
+                let res = jwk_cache
+                    .check_jwt(
+                        &RequestMonitoring::test(),
+                        token,
+                        &client,
+                        &config,
+                        RoleName::from("user"),
+                        &fetch,
+                    )
+                    .await;
+                assert_eq!(
+                    res.is_ok(),
+                    ok,
+                    "require_exp={require_exp} require_nbf={require_nbf}: {res:?}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn ec_curves() {
+        let (p384, jwk1) = new_ec_jwk("1".into(), jose_jwk::EcCurves::P384);
+        let (p521, jwk2) = new_ec_jwk("2".into(), jose_jwk::EcCurves::P521);
+        let (mismatched, jwk3) = new_ec_jwk("3".into(), jose_jwk::EcCurves::P384);
+        let jwt1 = new_ec_jwt("1".into(), p384);
+        let jwt2 = new_ec_jwt("2".into(), p521);
+
+        // a P384 key signing a token that claims to be ES256.
+        let payload = build_jwt_payload("3".into(), jose_jwa::Signing::Es256);
+        let sig = base64::encode_config(mismatched.sign(payload.as_bytes()), URL_SAFE_NO_PAD);
+        let jwt3 = format!("{payload}.{sig}");
+
+        let jwks = jose_jwk::JwkSet {
+            keys: vec![jwk1, jwk2, jwk3],
+        };
+        let addr = slow_jwks_server(jwks, Duration::ZERO).await;
+
+        #[derive(Clone)]
+        struct Fetch(SocketAddr);
+
+        impl FetchAuthRules for Fetch {
+            async fn fetch_auth_rules(
+                &self,
+                _role_name: RoleName,
+            ) -> anyhow::Result<Vec<AuthRule>> {
+                Ok(vec![AuthRule {
+                    id: "foo".to_owned(),
+                    jwks_url: format!("http://{}/foo", self.0).parse().unwrap(),
+                    audience: AudienceMatcher::any(),
+                    require_exp: false,
+                    require_nbf: false,
+                    allow_missing_kid: false,
+                    static_jwks: None,
+                }])
+            }
+        }
+
+        let config = JwkCacheConfig {
+            allow_private_urls: true,
+            ..Default::default()
+        };
+        let client = reqwest::Client::new();
+        let jwk_cache = Arc::new(JwkCacheEntryLock::default());
+
+        for (token, ok) in [(jwt1, true), (jwt2, true), (jwt3, false)] {
+            let res = jwk_cache
+                .check_jwt(
+                    &RequestMonitoring::test(),
+                    &token,
+                    &client,
+                    &config,
+                    RoleName::from("user"),
+                    &Fetch(addr),
+                )
+                .await;
+            assert_eq!(res.is_ok(), ok, "{res:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn static_jwks() {
+        let (ec, jwk) = new_ec_jwk("1".into(), jose_jwk::EcCurves::P256);
+        let jwt = new_ec_jwt("1".into(), ec);
+        let jwks = jose_jwk::JwkSet { keys: vec![jwk] };
+
+        #[derive(Clone)]
+        struct Fetch(jose_jwk::JwkSet);
+
+        impl FetchAuthRules for Fetch {
+            async fn fetch_auth_rules(
+                &self,
+                _role_name: RoleName,
+            ) -> anyhow::Result<Vec<AuthRule>> {
+                Ok(vec![AuthRule {
+                    id: "static".to_owned(),
+                    // nothing listens here: the keys must not be fetched.
+                    jwks_url: "http://127.0.0.1:1/jwks.json".parse().unwrap(),
+                    audience: AudienceMatcher::any(),
+                    require_exp: false,
+                    require_nbf: false,
+                    allow_missing_kid: false,
+                    static_jwks: Some(self.0.clone()),
+                }])
+            }
+        }
+
+        let config = JwkCacheConfig {
+            allow_private_urls: true,
+            ..Default::default()
+        };
+        let client = reqwest::Client::new();
+        let jwk_cache = Arc::new(JwkCacheEntryLock::default());
+
+        jwk_cache
+            .check_jwt(
+                &RequestMonitoring::test(),
+                &jwt,
+                &client,
+                &config,
+                RoleName::from("user"),
+                &Fetch(jwks),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn clock_skew_leeway() {
+        let (ec, jwk) = new_ec_jwk("1".into(), jose_jwk::EcCurves::P256);
+        let jwks = jose_jwk::JwkSet { keys: vec![jwk] };
+
+        #[derive(Clone)]
+        struct Fetch(jose_jwk::JwkSet);
+
+        impl FetchAuthRules for Fetch {
+            async fn fetch_auth_rules(
+                &self,
+                _role_name: RoleName,
+            ) -> anyhow::Result<Vec<AuthRule>> {
+                Ok(vec![AuthRule {
+                    id: "static".to_owned(),
+                    jwks_url: "http://127.0.0.1:1/jwks.json".parse().unwrap(),
+                    audience: AudienceMatcher::any(),
+                    require_exp: false,
+                    require_nbf: false,
+                    allow_missing_kid: false,
+                    static_jwks: Some(self.0.clone()),
+                }])
+            }
+        }
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let expired =
+            new_ec_jwt_with_claims("1".into(), &ec, &format!(r#"{{"exp":{}}}"#, now - 20));
+        let not_yet_valid =
+            new_ec_jwt_with_claims("1".into(), &ec, &format!(r#"{{"nbf":{}}}"#, now + 20));
+
+        let client = reqwest::Client::new();
+        let check = |jwt: &str, leeway: Duration| {
+            let config = JwkCacheConfig {
+                clock_skew_leeway: leeway,
+                ..Default::default()
+            };
+            let jwt = jwt.to_owned();
+            let client = &client;
+            let fetch = Fetch(jwks.clone());
+            async move {
+                Arc::new(JwkCacheEntryLock::default())
+                    .check_jwt(
+                        &RequestMonitoring::test(),
+                        &jwt,
+                        client,
+                        &config,
+                        RoleName::from("user"),
+                        &fetch,
+                    )
+                    .await
+            }
+        };
+
+        check(&expired, Duration::from_secs(30)).await.unwrap();
+        let err = check(&expired, Duration::from_secs(10)).await.unwrap_err();
+        assert!(matches!(err, JwtError::Expired), "{err:?}");
+
+        check(&not_yet_valid, Duration::from_secs(30))
+            .await
+            .unwrap();
+        let err = check(&not_yet_valid, Duration::from_secs(10))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, JwtError::NotYetValid), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn missing_kid_single_key() {
+        let (ec, jwk) = new_ec_jwk("1".into(), jose_jwk::EcCurves::P256);
+        let (_, other_jwk) = new_ec_jwk("2".into(), jose_jwk::EcCurves::P256);
+        let jwt = new_ec_jwt_without_kid(&ec);
+
+        #[derive(Clone)]
+        struct Fetch {
+            jwks: jose_jwk::JwkSet,
+            allow_missing_kid: bool,
+        }
+
+        impl FetchAuthRules for Fetch {
+            async fn fetch_auth_rules(
+                &self,
+                _role_name: RoleName,
+            ) -> anyhow::Result<Vec<AuthRule>> {
+                Ok(vec![AuthRule {
+                    id: "static".to_owned(),
+                    jwks_url: "http://127.0.0.1:1/jwks.json".parse().unwrap(),
+                    audience: AudienceMatcher::any(),
+                    require_exp: false,
+                    require_nbf: false,
+                    allow_missing_kid: self.allow_missing_kid,
+                    static_jwks: Some(self.jwks.clone()),
+                }])
+            }
+        }
+
+        let config = JwkCacheConfig::default();
+        let client = reqwest::Client::new();
+        let check = |jwks: Vec<jose_jwk::Jwk>, allow_missing_kid: bool| {
+            let jwt = &jwt;
+            let client = &client;
+            let config = &config;
+            async move {
+                Arc::new(JwkCacheEntryLock::default())
+                    .check_jwt(
+                        &RequestMonitoring::test(),
+                        jwt,
+                        client,
+                        config,
+                        RoleName::from("user"),
+                        &Fetch {
+                            jwks: jose_jwk::JwkSet { keys: jwks },
+                            allow_missing_kid,
+                        },
+                    )
+                    .await
+            }
+        };
+
+        // the only key is used.
+        check(vec![jwk.clone()], true).await.unwrap();
+
+        // strict by default.
+        let err = check(vec![jwk.clone()], false).await.unwrap_err();
+        assert!(matches!(err, JwtError::MissingKeyId), "{err:?}");
+
+        // ambiguous with several keys.
+        let err = check(vec![jwk, other_jwk], true).await.unwrap_err();
+        assert!(matches!(err, JwtError::MissingKeyId), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn missing_kid_does_not_renew() {
+        let (_, jwk) = new_ec_jwk("1".into(), jose_jwk::EcCurves::P256);
+        let (unknown, _) = new_ec_jwk("unknown".into(), jose_jwk::EcCurves::P256);
+        let jwt = new_ec_jwt("unknown".into(), unknown);
+        let jwks = jose_jwk::JwkSet { keys: vec![jwk] };
+
+        let (addr, fetches) = counting_jwks_server(jwks).await;
+
+        #[derive(Clone)]
+        struct Fetch(SocketAddr);
+
+        impl FetchAuthRules for Fetch {
+            async fn fetch_auth_rules(
+                &self,
+                _role_name: RoleName,
+            ) -> anyhow::Result<Vec<AuthRule>> {
+                Ok(vec![AuthRule {
+                    id: "foo".to_owned(),
+                    jwks_url: format!("http://{}/foo", self.0).parse().unwrap(),
+                    audience: AudienceMatcher::any(),
+                    require_exp: false,
+                    require_nbf: false,
+                    allow_missing_kid: false,
+                    static_jwks: None,
+                }])
+            }
+        }
+
+        let config = JwkCacheConfig {
+            allow_private_urls: true,
+            ..Default::default()
+        };
+        let client = reqwest::Client::new();
+        let jwk_cache = Arc::new(JwkCacheEntryLock::default());
+
+        // pretend the cached keys are old enough that a missing key id would cause a renewal.
+        let backdate = |jwk_cache: &JwkCacheEntryLock| {
+            let entry = jwk_cache.cached.load_full().unwrap();
+            jwk_cache.cached.store(Some(Arc::new(JwkCacheEntry {
+                last_retrieved: Instant::now() - MIN_RENEW * 2,
+                key_sets: entry.key_sets.clone(),
+            })));
+        };
+
+        for _ in 0..10 {
+            let res = jwk_cache
+                .check_jwt(
+                    &RequestMonitoring::test(),
+                    &jwt,
+                    &client,
+                    &config,
+                    RoleName::from("user"),
+                    &Fetch(addr),
+                )
+                .await;
+            assert!(res.is_err());
+            backdate(&jwk_cache);
+        }
+        // only the initial fetch.
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+
+        // a successful renewal forgets about the missing key ids.
+        let permit = jwk_cache.acquire_permit().await;
+        jwk_cache
+            .renew_jwks(
+                permit,
+                &client,
+                &config,
+                RoleName::from("user"),
+                &Fetch(addr),
+            )
+            .await
+            .unwrap();
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+        backdate(&jwk_cache);
+
+        let res = jwk_cache
+            .check_jwt(
+                &RequestMonitoring::test(),
+                &jwt,
+                &client,
+                &config,
+                RoleName::from("user"),
+                &Fetch(addr),
+            )
+            .await;
+        assert!(res.is_err());
+        assert_eq!(fetches.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn audience_matcher() {
+        let any = AudienceMatcher::any();
+        assert!(any.matches(Some("neon")));
+        assert!(any.matches(None));
+
+        let single = AudienceMatcher::from(Some("neon".to_owned()));
+        assert!(single.matches(Some("neon")));
+        assert!(!single.matches(Some("other")));
+        assert!(!single.matches(None));
+
+        let multi = AudienceMatcher::new(["neon".to_owned(), "console".to_owned()]);
+        assert!(multi.matches(Some("console")));
+        assert!(!multi.matches(Some("other")));
+        assert!(!multi.matches(None));
+    }
+
+    #[tokio::test]
+    async fn check_jwt_errors() {
+        let (ec, jwk) = new_ec_jwk("1".into(), jose_jwk::EcCurves::P256);
+        let (other, _) = new_ec_jwk("1".into(), jose_jwk::EcCurves::P256);
+        let jwks = jose_jwk::JwkSet { keys: vec![jwk] };
+        let addr = slow_jwks_server(jwks, Duration::ZERO).await;
+
+        #[derive(Clone)]
+        struct Fetch(SocketAddr);
+
+        impl FetchAuthRules for Fetch {
+            async fn fetch_auth_rules(
+                &self,
+                _role_name: RoleName,
+            ) -> anyhow::Result<Vec<AuthRule>> {
+                Ok(vec![AuthRule {
+                    id: "foo".to_owned(),
+                    jwks_url: format!("http://{}/foo", self.0).parse().unwrap(),
+                    audience: AudienceMatcher::new(["neon".to_owned()]),
+                    require_exp: false,
+                    require_nbf: false,
+                    allow_missing_kid: false,
+                    static_jwks: None,
+                }])
+            }
+        }
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let valid = format!(r#"{{"aud":"neon","exp":{}}}"#, now + 3600);
+
+        let unsupported_algorithm = {
+            let payload =
+                build_jwt_payload_with_claims("1".into(), jose_jwa::Signing::Es384, &valid);
+            let sig = base64::encode_config(ec.sign(payload.as_bytes()), URL_SAFE_NO_PAD);
+            format!("{payload}.{sig}")
+        };
+
+        let cases = [
+            ("not a jwt".to_owned(), "malformed"),
+            (unsupported_algorithm, "unsupported algorithm"),
+            (
+                new_ec_jwt_with_claims("2".into(), &ec, &valid),
+                "key not found",
+            ),
+            (
+                new_ec_jwt_with_claims("1".into(), &ec, r#"{"aud":"other"}"#),
+                "audience mismatch",
+            ),
+            (
+                new_ec_jwt_with_claims(
+                    "1".into(),
+                    &ec,
+                    &format!(r#"{{"aud":"neon","exp":{}}}"#, now - 3600),
+                ),
+                "expired",
+            ),
+            (
+                new_ec_jwt_with_claims(
+                    "1".into(),
+                    &ec,
+                    &format!(r#"{{"aud":"neon","nbf":{}}}"#, now + 3600),
+                ),
+                "not yet valid",
+            ),
+            (
+                new_ec_jwt_with_claims("1".into(), &other, &valid),
+                "signature invalid",
+            ),
+        ];
+
+        let config = JwkCacheConfig {
+            allow_private_urls: true,
+            ..Default::default()
+        };
+        let client = reqwest::Client::new();
+        let jwk_cache = Arc::new(JwkCacheEntryLock::default());
+
+        for (token, expected) in cases {
+            let err = jwk_cache
+                .check_jwt(
+                    &RequestMonitoring::test(),
+                    &token,
+                    &client,
+                    &config,
+                    RoleName::from("user"),
+                    &Fetch(addr),
+                )
+                .await
+                .unwrap_err();
+
+            let matched = match expected {
+                "malformed" => matches!(err, JwtError::Malformed),
+                "unsupported algorithm" => matches!(err, JwtError::UnsupportedAlgorithm),
+                "key not found" => matches!(err, JwtError::KeyNotFound),
+                "audience mismatch" => matches!(err, JwtError::AudienceMismatch),
+                "expired" => matches!(err, JwtError::Expired),
+                "not yet valid" => matches!(err, JwtError::NotYetValid),
+                "signature invalid" => matches!(err, JwtError::SignatureInvalid),
+                _ => unreachable!(),
+            };
+            assert!(matched, "expected {expected}, got {err:?}");
+            assert_eq!(err.get_error_kind(), ErrorKind::User);
+        }
+
+        let err = JwtError::AuthRules(anyhow::anyhow!("control plane is down"));
+        assert_eq!(err.get_error_kind(), ErrorKind::ControlPlane);
+    }
+
+    #[tokio::test]
+    async fn prefetch() {
+        let (ec, jwk) = new_ec_jwk("1".into(), jose_jwk::EcCurves::P256);
+        let jwt = new_ec_jwt("1".into(), ec);
+        let jwks = jose_jwk::JwkSet { keys: vec![jwk] };
+        let (addr, fetches) = counting_jwks_server(jwks).await;
+
+        #[derive(Clone)]
+        struct Fetch(SocketAddr);
+
+        impl FetchAuthRules for Fetch {
+            async fn fetch_auth_rules(
+                &self,
+                _role_name: RoleName,
+            ) -> anyhow::Result<Vec<AuthRule>> {
+                Ok(vec![AuthRule {
+                    id: "foo".to_owned(),
+                    jwks_url: format!("http://{}/foo", self.0).parse().unwrap(),
+                    audience: AudienceMatcher::any(),
+                    require_exp: false,
+                    require_nbf: false,
+                    allow_missing_kid: false,
+                    static_jwks: None,
+                }])
+            }
+        }
+
+        let jwk_cache = JwkCache::new(JwkCacheConfig {
+            allow_private_urls: true,
+            ..Default::default()
+        });
+        let endpoint = EndpointId::from("ep");
+        let role_name = RoleName::from("user");
+
+        jwk_cache.prefetch(endpoint.clone(), role_name.clone(), &Fetch(addr));
+        // a second prefetch while the first is in flight, or done, must not fetch again.
+        jwk_cache.prefetch(endpoint.clone(), role_name.clone(), &Fetch(addr));
+
+        let entry = jwk_cache.get_entry(endpoint.clone(), role_name.clone());
+        while entry.cached.load().is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+
+        jwk_cache
+            .check_jwt(
+                &RequestMonitoring::test(),
+                endpoint,
+                role_name,
+                &Fetch(addr),
+                &jwt,
+            )
+            .await
+            .unwrap();
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+    }
 }
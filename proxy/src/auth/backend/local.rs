@@ -13,7 +13,7 @@
     RoleName,
 };
 
-use super::jwt::{AuthRule, FetchAuthRules, JwkCache};
+use super::jwt::{AuthRule, FetchAuthRules, JwkCache, JwkCacheConfig};
 
 pub struct LocalBackend {
     pub jwks_cache: JwkCache,
@@ -22,9 +22,9 @@ pub struct LocalBackend {
 }
 
 impl LocalBackend {
-    pub fn new(postgres_addr: SocketAddr) -> Self {
+    pub fn new(postgres_addr: SocketAddr, jwks_config: JwkCacheConfig) -> Self {
         LocalBackend {
-            jwks_cache: JwkCache::default(),
+            jwks_cache: JwkCache::new(jwks_config),
             postgres_addr,
             node_info: NodeInfo {
                 config: {
@@ -70,7 +70,11 @@ async fn fetch_auth_rules(&self, role_name: RoleName) -> anyhow::Result<Vec<Auth
             rules.push(AuthRule {
                 id: setting.id.clone(),
                 jwks_url: setting.jwks_url.clone(),
-                audience: setting.jwt_audience.clone(),
+                audience: setting.jwt_audience.clone().into(),
+                require_exp: setting.require_exp,
+                require_nbf: setting.require_nbf,
+                allow_missing_kid: false,
+                static_jwks: None,
             });
         }
 
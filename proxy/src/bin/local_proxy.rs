@@ -10,7 +10,10 @@
 use dashmap::DashMap;
 use futures::{future::Either, FutureExt};
 use proxy::{
-    auth::backend::local::{JwksRoleSettings, LocalBackend, JWKS_ROLE_MAP},
+    auth::backend::{
+        jwt::JwkCacheConfig,
+        local::{JwksRoleSettings, LocalBackend, JWKS_ROLE_MAP},
+    },
     cancellation::CancellationHandlerMain,
     config::{self, AuthenticationConfig, HttpConfig, ProxyConfig, RetryConfig},
     console::{locks::ApiLocks, messages::JwksRoleMapping},
@@ -75,6 +78,15 @@ struct LocalProxyCliArgs {
     /// File address of the local proxy config file
     #[clap(long, default_value = "./localproxy.json")]
     config_path: PathBuf,
+    /// timeout for fetching a single JWKs url
+    #[clap(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    jwks_fetch_timeout: tokio::time::Duration,
+    /// largest JWKs response body to accept, in bytes
+    #[clap(long, default_value_t = 64 * 1024)]
+    jwks_max_body_size: usize,
+    /// how far our clock may disagree with the JWT issuer's when checking expiry
+    #[clap(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    jwt_clock_skew_leeway: tokio::time::Duration,
 }
 
 #[derive(clap::Args, Clone, Copy, Debug)]
@@ -213,7 +225,15 @@ fn build_config(args: &LocalProxyCliArgs) -> anyhow::Result<&'static ProxyConfig
     Ok(Box::leak(Box::new(ProxyConfig {
         tls_config: None,
         auth_backend: proxy::auth::BackendType::Local(proxy::auth::backend::MaybeOwned::Owned(
-            LocalBackend::new(args.compute),
+            LocalBackend::new(
+                args.compute,
+                JwkCacheConfig {
+                    fetch_timeout: args.jwks_fetch_timeout,
+                    max_body_size: args.jwks_max_body_size,
+                    clock_skew_leeway: args.jwt_clock_skew_leeway,
+                    ..Default::default()
+                },
+            ),
         )),
         metric_collection: None,
         allow_self_signed_compute: false,
@@ -6,7 +6,6 @@
 use aws_config::provider_config::ProviderConfig;
 use aws_config::web_identity_token::WebIdentityTokenCredentialsProvider;
 use aws_config::Region;
-use futures::future::Either;
 use proxy::auth;
 use proxy::auth::backend::AuthRateLimiter;
 use proxy::auth::backend::MaybeOwned;
@@ -40,7 +39,6 @@
 use proxy::serverless;
 use remote_storage::RemoteStorageConfig;
 use std::net::SocketAddr;
-use std::pin::pin;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::sync::Mutex;
@@ -111,9 +109,17 @@ struct ProxyCliArgs {
     /// path to directory with TLS certificates for client postgres connections
     #[clap(long)]
     certs_dir: Option<String>,
+    /// common name of the certificate to serve when the client sends no SNI or an unknown one
+    /// (defaults to the tls-cert certificate)
+    #[clap(long)]
+    default_cert_name: Option<String>,
     /// timeout for the TLS handshake
     #[clap(long, default_value = "15s", value_parser = humantime::parse_duration)]
     handshake_timeout: tokio::time::Duration,
+    /// how long to wait for in-flight client sessions to finish after a shutdown signal
+    /// before exiting anyway (waits indefinitely if unset)
+    #[clap(long, value_parser = humantime::parse_duration)]
+    shutdown_grace_period: Option<tokio::time::Duration>,
     /// http endpoint to receive periodic metric updates
     #[clap(long)]
     metric_collection_endpoint: Option<String>,
@@ -499,36 +505,21 @@ async fn main() -> anyhow::Result<()> {
                 let cache = api.caches.endpoints_cache.clone();
                 let con = regional_redis_client;
                 let span = tracing::info_span!("endpoints_cache");
+                let cancellation_token = cancellation_token.clone();
                 maintenance_tasks.spawn(
-                    async move { cache.do_read(con, cancellation_token.clone()).await }
-                        .instrument(span),
+                    async move { cache.do_read(con, cancellation_token).await }.instrument(span),
                 );
             }
         }
     }
 
-    let maintenance = loop {
-        // get one complete task
-        match futures::future::select(
-            pin!(maintenance_tasks.join_next()),
-            pin!(client_tasks.join_next()),
-        )
-        .await
-        {
-            // exit immediately on maintenance task completion
-            Either::Left((Some(res), _)) => break proxy::flatten_err(res)?,
-            // exit with error immediately if all maintenance tasks have ceased (should be caught by branch above)
-            Either::Left((None, _)) => bail!("no maintenance tasks running. invalid state"),
-            // exit immediately on client task error
-            Either::Right((Some(res), _)) => proxy::flatten_err(res)?,
-            // exit if all our client tasks have shutdown gracefully
-            Either::Right((None, _)) => return Ok(()),
-        }
-    };
-
-    // maintenance tasks return Infallible success values, this is an impossible value
-    // so this match statically ensures that there are no possibilities for that value
-    match maintenance {}
+    proxy::run_tasks(
+        maintenance_tasks,
+        client_tasks,
+        cancellation_token,
+        args.shutdown_grace_period,
+    )
+    .await
 }
 
 /// ProxyConfig is created at proxy startup, and lives forever.
@@ -541,6 +532,7 @@ fn build_config(args: &ProxyCliArgs) -> anyhow::Result<&'static ProxyConfig> {
             key_path,
             cert_path,
             args.certs_dir.as_ref(),
+            args.default_cert_name.as_deref(),
         )?),
         (None, None) => None,
         _ => bail!("either both or neither tls-key and tls-cert must be specified"),
@@ -283,6 +283,7 @@ pub async fn connect(
         let pause = ctx.latency_timer_pause(crate::metrics::Waiting::Compute);
         let (socket_addr, stream, host) = self.connect_raw(timeout).await?;
         drop(pause);
+        ctx.set_compute_addr(socket_addr);
 
         let client_config = if allow_self_signed_compute {
             // Allow all certificates for creating the connection
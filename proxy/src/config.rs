@@ -80,6 +80,7 @@ pub fn configure_tls(
     key_path: &str,
     cert_path: &str,
     certs_dir: Option<&String>,
+    default_cert_name: Option<&str>,
 ) -> anyhow::Result<TlsConfig> {
     let mut cert_resolver = CertResolver::new();
 
@@ -106,6 +107,11 @@ pub fn configure_tls(
         }
     }
 
+    // optionally serve one of the extra certificates by default instead
+    if let Some(common_name) = default_cert_name {
+        cert_resolver.set_default(common_name)?;
+    }
+
     let common_names = cert_resolver.get_common_names();
 
     let cert_resolver = Arc::new(cert_resolver);
@@ -271,6 +277,16 @@ pub fn add_cert(
         Ok(())
     }
 
+    /// Use the already loaded certificate for `common_name` for clients that send no SNI,
+    /// or one that doesn't match any certificate.
+    pub fn set_default(&mut self, common_name: &str) -> anyhow::Result<()> {
+        let cert = self.certs.get(common_name).with_context(|| {
+            format!("no TLS certificate loaded for common name '{common_name}'")
+        })?;
+        self.default = Some(cert.clone());
+        Ok(())
+    }
+
     pub fn get_common_names(&self) -> HashSet<String> {
         self.certs.keys().map(|s| s.to_string()).collect()
     }
@@ -304,7 +320,10 @@ pub fn resolve(
                 if let Some((_, rest)) = sni_name.split_once('.') {
                     sni_name = rest;
                 } else {
-                    return None;
+                    // Unknown SNI, fall back to the default certificate. The client
+                    // will still reject it in verify-full mode, but the others get
+                    // a proper error about the endpoint instead of a failed handshake.
+                    return self.default.as_ref().cloned();
                 }
             }
         } else {
@@ -361,6 +361,10 @@ pub struct JwksSettings {
     pub jwks_url: url::Url,
     pub provider_name: String,
     pub jwt_audience: Option<String>,
+    #[serde(default)]
+    pub require_exp: bool,
+    #[serde(default)]
+    pub require_nbf: bool,
 }
 
 #[cfg(test)]
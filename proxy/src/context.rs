@@ -4,8 +4,12 @@
 use once_cell::sync::OnceCell;
 use pq_proto::StartupMessageParams;
 use smol_str::SmolStr;
-use std::net::IpAddr;
-use tokio::sync::mpsc;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::mpsc::{
+    self,
+    error::{SendTimeoutError, TrySendError},
+};
 use tracing::{field::display, info, info_span, Span};
 use try_lock::TryLock;
 use uuid::Uuid;
@@ -14,16 +18,24 @@
     console::messages::{ColdStartInfo, MetricsAuxInfo},
     error::ErrorKind,
     intern::{BranchIdInt, ProjectIdInt},
-    metrics::{ConnectOutcome, InvalidEndpointsGroup, LatencyTimer, Metrics, Protocol, Waiting},
+    metrics::{
+        ConnectOutcome, InvalidEndpointsGroup, LatencyTimer, Metrics, Protocol, RequestLogKind,
+        Waiting,
+    },
     DbName, EndpointId, RoleName,
 };
 
-use self::parquet::RequestData;
+use self::parquet::{LogChannelFullPolicy, RequestData, RequestLogSampling};
 
 pub mod parquet;
 
-pub static LOG_CHAN: OnceCell<mpsc::WeakUnboundedSender<RequestData>> = OnceCell::new();
-pub static LOG_CHAN_DISCONNECT: OnceCell<mpsc::WeakUnboundedSender<RequestData>> = OnceCell::new();
+pub static LOG_CHAN: OnceCell<mpsc::WeakSender<RequestData>> = OnceCell::new();
+pub static LOG_CHAN_DISCONNECT: OnceCell<mpsc::WeakSender<RequestData>> = OnceCell::new();
+pub static LOG_CHAN_FULL_POLICY: OnceCell<LogChannelFullPolicy> = OnceCell::new();
+pub static LOG_SAMPLING: OnceCell<RequestLogSampling> = OnceCell::new();
+
+/// How long a record waits for room in a full channel with [`LogChannelFullPolicy::WaitBriefly`].
+const LOG_CHAN_WAIT_TIMEOUT: Duration = Duration::from_millis(100);
 
 /// Context data for a single request to connect to a database.
 ///
@@ -52,21 +64,39 @@ struct RequestMonitoringInner {
     dbname: Option<DbName>,
     user: Option<RoleName>,
     application: Option<SmolStr>,
+    compute_addr: Option<SocketAddr>,
     error_kind: Option<ErrorKind>,
     pub(crate) auth_method: Option<AuthMethod>,
+    auth_rule_id: Option<String>,
+    tls_negotiation: Option<TlsNegotiation>,
     success: bool,
+    outcome_reason: Option<SmolStr>,
     pub(crate) cold_start_info: ColdStartInfo,
     pg_options: Option<StartupMessageParams>,
 
     // extra
     // This sender is here to keep the request monitoring channel open while requests are taking place.
-    sender: Option<mpsc::UnboundedSender<RequestData>>,
+    sender: Option<mpsc::Sender<RequestData>>,
     // This sender is only used to log the length of session in case of success.
-    disconnect_sender: Option<mpsc::UnboundedSender<RequestData>>,
+    disconnect_sender: Option<mpsc::Sender<RequestData>>,
     pub latency_timer: LatencyTimer,
     // Whether proxy decided that it's not a valid endpoint end rejected it before going to cplane.
     rejected: Option<bool>,
     disconnect_timestamp: Option<chrono::DateTime<Utc>>,
+    // Whether the records of this request are logged, decided when the first one is sent.
+    log_sampled: Option<bool>,
+}
+
+/// How a postgres protocol client set up TLS. Not recorded for HTTP and websocket connections,
+/// which are encrypted (or not) outside of the postgres protocol.
+#[derive(Clone, Copy, Debug)]
+pub enum TlsNegotiation {
+    /// The client didn't use TLS.
+    Plaintext,
+    /// The client requested TLS with an SSLRequest message.
+    SslRequest,
+    /// The client started the TLS handshake right away (`sslnegotiation=direct`).
+    Direct,
 }
 
 #[derive(Clone, Debug)]
@@ -78,6 +108,33 @@ pub enum AuthMethod {
     Cleartext,
 }
 
+/// A copy of the data of a [`RequestMonitoring`], taken at a single point in time.
+#[derive(Clone, Debug)]
+pub struct RequestMonitoringSnapshot {
+    pub peer_addr: IpAddr,
+    pub session_id: Uuid,
+    pub protocol: Protocol,
+    pub first_packet: chrono::DateTime<Utc>,
+    pub region: &'static str,
+    pub project: Option<ProjectIdInt>,
+    pub branch: Option<BranchIdInt>,
+    pub endpoint_id: Option<EndpointId>,
+    pub dbname: Option<DbName>,
+    pub user: Option<RoleName>,
+    pub application: Option<SmolStr>,
+    pub compute_addr: Option<SocketAddr>,
+    pub error_kind: Option<ErrorKind>,
+    pub auth_method: Option<AuthMethod>,
+    pub auth_rule_id: Option<String>,
+    pub tls_negotiation: Option<TlsNegotiation>,
+    pub success: bool,
+    pub outcome_reason: Option<SmolStr>,
+    pub cold_start_info: ColdStartInfo,
+    pub pg_options: Option<StartupMessageParams>,
+    pub rejected: Option<bool>,
+    pub disconnect_timestamp: Option<chrono::DateTime<Utc>>,
+}
+
 impl RequestMonitoring {
     pub fn new(
         session_id: Uuid,
@@ -92,6 +149,8 @@ pub fn new(
             %peer_addr,
             ep = tracing::field::Empty,
             role = tracing::field::Empty,
+            jwt_iss = tracing::field::Empty,
+            jwt_sub = tracing::field::Empty,
         );
 
         let inner = RequestMonitoringInner {
@@ -108,9 +167,13 @@ pub fn new(
             dbname: None,
             user: None,
             application: None,
+            compute_addr: None,
             error_kind: None,
             auth_method: None,
+            auth_rule_id: None,
+            tls_negotiation: None,
             success: false,
+            outcome_reason: None,
             rejected: None,
             cold_start_info: ColdStartInfo::Unknown,
             pg_options: None,
@@ -119,6 +182,7 @@ pub fn new(
             disconnect_sender: LOG_CHAN_DISCONNECT.get().and_then(|tx| tx.upgrade()),
             latency_timer: LatencyTimer::new(protocol),
             disconnect_timestamp: None,
+            log_sampled: None,
         };
 
         Self(TryLock::new(inner))
@@ -178,6 +242,15 @@ pub fn set_project_id(&self, project_id: ProjectIdInt) {
         this.project = Some(project_id);
     }
 
+    /// Record the branch before the full project info is known.
+    ///
+    /// The branch from [`Self::set_project`] takes precedence: it overwrites this value,
+    /// and this does not overwrite a branch that was already set by it.
+    pub fn set_branch_id(&self, branch_id: BranchIdInt) {
+        let mut this = self.0.try_lock().expect("should not deadlock");
+        this.branch.get_or_insert(branch_id);
+    }
+
     pub fn set_endpoint_id(&self, endpoint_id: EndpointId) {
         self.0
             .try_lock()
@@ -199,11 +272,40 @@ pub fn set_user(&self, user: RoleName) {
             .set_user(user);
     }
 
+    /// Record the issuer of a successfully verified JWT, to correlate with the identity provider logs.
+    pub fn set_jwt_issuer(&self, issuer: &str) {
+        let this = self.0.try_lock().expect("should not deadlock");
+        this.span.record("jwt_iss", issuer);
+    }
+
+    /// Record the subject of a successfully verified JWT, to correlate with the identity provider logs.
+    pub fn set_jwt_subject(&self, subject: &str) {
+        let this = self.0.try_lock().expect("should not deadlock");
+        this.span.record("jwt_sub", subject);
+    }
+
+    /// Record the address of the compute node we connected to for this request.
+    pub fn set_compute_addr(&self, compute_addr: SocketAddr) {
+        let mut this = self.0.try_lock().expect("should not deadlock");
+        this.compute_addr = Some(compute_addr);
+    }
+
     pub fn set_auth_method(&self, auth_method: AuthMethod) {
         let mut this = self.0.try_lock().expect("should not deadlock");
         this.auth_method = Some(auth_method);
     }
 
+    pub fn set_tls_negotiation(&self, tls_negotiation: TlsNegotiation) {
+        let mut this = self.0.try_lock().expect("should not deadlock");
+        this.tls_negotiation = Some(tls_negotiation);
+    }
+
+    /// Record the id of the JWT auth rule that authenticated this request.
+    pub fn set_auth_rule_id(&self, auth_rule_id: String) {
+        let mut this = self.0.try_lock().expect("should not deadlock");
+        this.auth_rule_id = Some(auth_rule_id);
+    }
+
     pub fn has_private_peer_addr(&self) -> bool {
         self.0
             .try_lock()
@@ -230,6 +332,13 @@ pub fn set_success(&self) {
         this.success = true;
     }
 
+    /// Record the outcome of the request along with a human-readable reason for the request log.
+    pub fn set_outcome(&self, success: bool, reason: Option<SmolStr>) {
+        let mut this = self.0.try_lock().expect("should not deadlock");
+        this.success = success;
+        this.outcome_reason = reason;
+    }
+
     pub fn log_connect(&self) {
         self.0
             .try_lock()
@@ -253,6 +362,12 @@ pub fn peer_addr(&self) -> IpAddr {
         self.0.try_lock().expect("should not deadlock").peer_addr
     }
 
+    /// How long ago the first packet of this connection was received.
+    pub fn connection_age(&self) -> std::time::Duration {
+        let first_packet = self.0.try_lock().expect("should not deadlock").first_packet;
+        (Utc::now() - first_packet).to_std().unwrap_or_default()
+    }
+
     pub fn cold_start_info(&self) -> ColdStartInfo {
         self.0
             .try_lock()
@@ -260,6 +375,45 @@ pub fn cold_start_info(&self) -> ColdStartInfo {
             .cold_start_info
     }
 
+    /// Copy all the data recorded so far, under a single lock acquisition so that the copy is
+    /// consistent.
+    pub fn snapshot(&self) -> RequestMonitoringSnapshot {
+        let this = self.0.try_lock().expect("should not deadlock");
+        RequestMonitoringSnapshot {
+            peer_addr: this.peer_addr,
+            session_id: this.session_id,
+            protocol: this.protocol,
+            first_packet: this.first_packet,
+            region: this.region,
+            project: this.project,
+            branch: this.branch,
+            endpoint_id: this.endpoint_id.clone(),
+            dbname: this.dbname.clone(),
+            user: this.user.clone(),
+            application: this.application.clone(),
+            compute_addr: this.compute_addr,
+            error_kind: this.error_kind,
+            auth_method: this.auth_method.clone(),
+            auth_rule_id: this.auth_rule_id.clone(),
+            tls_negotiation: this.tls_negotiation,
+            success: this.success,
+            outcome_reason: this.outcome_reason.clone(),
+            cold_start_info: this.cold_start_info,
+            pg_options: this.pg_options.clone(),
+            rejected: this.rejected,
+            disconnect_timestamp: this.disconnect_timestamp,
+        }
+    }
+
+    /// Total time spent waiting for `waiting_for` so far, see [`Self::latency_timer_pause`].
+    pub fn waiting_time(&self, waiting_for: Waiting) -> std::time::Duration {
+        self.0
+            .try_lock()
+            .expect("should not deadlock")
+            .latency_timer
+            .waiting_time(waiting_for)
+    }
+
     pub fn latency_timer_pause(&self, waiting_for: Waiting) -> LatencyTimerPause<'_> {
         LatencyTimerPause {
             ctx: self,
@@ -328,7 +482,14 @@ fn set_user(&mut self, user: RoleName) {
     fn has_private_peer_addr(&self) -> bool {
         match self.peer_addr {
             IpAddr::V4(ip) => ip.is_private(),
-            IpAddr::V6(_) => false,
+            IpAddr::V6(ip) => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    // unique local, fc00::/7
+                    || (first & 0xfe00) == 0xfc00
+                    // link local, fe80::/10
+                    || (first & 0xffc0) == 0xfe80
+            }
         }
     }
 
@@ -361,7 +522,14 @@ fn log_connect(&mut self) {
                 });
         }
         if let Some(tx) = self.sender.take() {
-            let _: Result<(), _> = tx.send(RequestData::from(&*self));
+            if self.should_log(&LOG_SAMPLING.get().copied().unwrap_or_default()) {
+                send_request_data(
+                    tx,
+                    RequestData::from(&*self),
+                    RequestLogKind::Connect,
+                    LOG_CHAN_FULL_POLICY.get().copied().unwrap_or_default(),
+                );
+            }
         }
     }
 
@@ -370,8 +538,63 @@ fn log_disconnect(&mut self) {
         // Here we log the length of the session.
         self.disconnect_timestamp = Some(Utc::now());
         if let Some(tx) = self.disconnect_sender.take() {
-            let _: Result<(), _> = tx.send(RequestData::from(&*self));
+            if self.should_log(&LOG_SAMPLING.get().copied().unwrap_or_default()) {
+                send_request_data(
+                    tx,
+                    RequestData::from(&*self),
+                    RequestLogKind::Disconnect,
+                    LOG_CHAN_FULL_POLICY.get().copied().unwrap_or_default(),
+                );
+            }
+        }
+    }
+
+    /// Whether the records of this request are uploaded. This is decided once, for the connect
+    /// record: failed requests and requests that took long to connect are always logged, the
+    /// others are sampled by session id. The disconnect record reuses the decision, so that the
+    /// records of a session are either both logged or both skipped, however long it lasts.
+    fn should_log(&mut self, sampling: &RequestLogSampling) -> bool {
+        if let Some(sampled) = self.log_sampled {
+            return sampled;
+        }
+        let failed = !self.success || self.error_kind.is_some();
+        let connect_duration = (Utc::now() - self.first_packet)
+            .to_std()
+            .unwrap_or_default();
+        let sampled = failed
+            || connect_duration >= sampling.always_log_longer_than
+            || sampling.samples(self.session_id);
+        self.log_sampled = Some(sampled);
+        sampled
+    }
+}
+
+/// Hands a record over to the parquet upload worker. This runs when the request context is
+/// dropped, so it never blocks: if the worker has fallen behind and the channel is full, the
+/// record is dropped and counted, with [`LogChannelFullPolicy::WaitBriefly`] only after waiting
+/// for room in a background task.
+fn send_request_data(
+    tx: mpsc::Sender<RequestData>,
+    data: RequestData,
+    kind: RequestLogKind,
+    policy: LogChannelFullPolicy,
+) {
+    let data = match tx.try_send(data) {
+        Ok(()) | Err(TrySendError::Closed(_)) => return,
+        Err(TrySendError::Full(data)) => data,
+    };
+    let dropped = &Metrics::get().proxy.parquet_dropped_records_total;
+    match (policy, tokio::runtime::Handle::try_current()) {
+        (LogChannelFullPolicy::WaitBriefly, Ok(handle)) => {
+            handle.spawn(async move {
+                if let Err(SendTimeoutError::Timeout(_)) =
+                    tx.send_timeout(data, LOG_CHAN_WAIT_TIMEOUT).await
+                {
+                    dropped.inc(kind);
+                }
+            });
         }
+        _ => dropped.inc(kind),
     }
 }
 
@@ -384,3 +607,310 @@ fn drop(&mut self) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        span::{Id, Record},
+        Subscriber,
+    };
+    use tracing_subscriber::{layer::Context, prelude::*, Layer};
+
+    use super::{
+        parquet::{LogChannelFullPolicy, RequestData, RequestLogSampling},
+        send_request_data, AuthMethod, RequestMonitoring, TlsNegotiation, LOG_CHAN_WAIT_TIMEOUT,
+    };
+    use crate::{
+        console::messages::{ColdStartInfo, MetricsAuxInfo},
+        error::ErrorKind,
+        intern::{BranchIdTag, EndpointIdTag, InternId, ProjectIdTag},
+        metrics::{Metrics, Protocol, RequestLogKind},
+    };
+
+    fn aux_info(branch: &str) -> MetricsAuxInfo {
+        MetricsAuxInfo {
+            endpoint_id: EndpointIdTag::get_interner().get_or_intern("endpoint"),
+            project_id: ProjectIdTag::get_interner().get_or_intern("project"),
+            branch_id: BranchIdTag::get_interner().get_or_intern(branch),
+            cold_start_info: ColdStartInfo::Unknown,
+        }
+    }
+
+    fn branch(ctx: &RequestMonitoring) -> Option<String> {
+        ctx.0
+            .try_lock()
+            .unwrap()
+            .branch
+            .as_deref()
+            .map(String::from)
+    }
+
+    /// Collects every field recorded on a span after it was created.
+    #[derive(Clone, Default)]
+    struct RecordedFields(Arc<Mutex<Vec<(&'static str, String)>>>);
+
+    impl Visit for RecordedFields {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((field.name(), format!("{value:?}")));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((field.name(), value.to_owned()));
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for RecordedFields {
+        fn on_record(&self, _id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    #[test]
+    fn jwt_fields_are_recorded_on_span() {
+        let recorded = RecordedFields::default();
+        let subscriber = tracing_subscriber::registry().with(recorded.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let ctx = RequestMonitoring::test();
+            ctx.set_jwt_issuer("https://issuer.example.com");
+            ctx.set_jwt_subject("user-123");
+        });
+
+        let recorded = recorded.0.lock().unwrap();
+        assert!(recorded.contains(&("jwt_iss", "https://issuer.example.com".to_owned())));
+        assert!(recorded.contains(&("jwt_sub", "user-123".to_owned())));
+    }
+
+    #[test]
+    fn connection_age() {
+        let ctx = RequestMonitoring::test();
+
+        std::thread::sleep(Duration::from_millis(10));
+        let age1 = ctx.connection_age();
+        assert!(age1 >= Duration::from_millis(10), "{age1:?}");
+
+        std::thread::sleep(Duration::from_millis(10));
+        let age2 = ctx.connection_age();
+        assert!(age2 > age1, "{age1:?} {age2:?}");
+    }
+
+    #[test]
+    fn set_branch_id_then_project() {
+        let ctx = RequestMonitoring::test();
+        ctx.set_branch_id(BranchIdTag::get_interner().get_or_intern("early"));
+        assert_eq!(branch(&ctx).as_deref(), Some("early"));
+
+        ctx.set_project(aux_info("from-project"));
+        assert_eq!(branch(&ctx).as_deref(), Some("from-project"));
+
+        // the branch from the project info is not overwritten.
+        ctx.set_branch_id(BranchIdTag::get_interner().get_or_intern("late"));
+        assert_eq!(branch(&ctx).as_deref(), Some("from-project"));
+    }
+
+    #[test]
+    fn private_ipv6_peer_addr() {
+        for (addr, private) in [
+            ("::1", true),
+            ("fc00::1", true),
+            ("fd12:3456::1", true),
+            ("fe80::1", true),
+            ("2001:4860:4860::8888", false),
+        ] {
+            let ctx = RequestMonitoring::new(
+                uuid::Uuid::now_v7(),
+                addr.parse().unwrap(),
+                Protocol::Tcp,
+                "test",
+            );
+            assert_eq!(ctx.has_private_peer_addr(), private, "{addr}");
+        }
+    }
+
+    fn test_ctx() -> RequestMonitoring {
+        RequestMonitoring::new(
+            uuid::Uuid::now_v7(),
+            [127, 0, 0, 1].into(),
+            Protocol::Tcp,
+            "test",
+        )
+    }
+
+    fn dropped_records(kind: RequestLogKind) -> u64 {
+        let metric = &Metrics::get().proxy.parquet_dropped_records_total;
+        metric
+            .get_metric(metric.with_labels(kind))
+            .count
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_log_channel_drops_records() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        let dropped = dropped_records(RequestLogKind::Connect);
+
+        // Dropping the contexts doesn't wait for the channel to have room.
+        for _ in 0..5 {
+            let ctx = test_ctx();
+            ctx.0.try_lock().unwrap().sender = Some(tx.clone());
+            drop(ctx);
+        }
+        assert_eq!(dropped_records(RequestLogKind::Connect) - dropped, 3);
+        drop(tx);
+        let mut received = 0;
+        while rx.recv().await.is_some() {
+            received += 1;
+        }
+        assert_eq!(received, 2);
+
+        // With the wait-briefly policy, a record that finds room in time is delivered...
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let dropped = dropped_records(RequestLogKind::Disconnect);
+        let send = |tx| {
+            let data = RequestData::from(&*test_ctx().0.try_lock().unwrap());
+            send_request_data(
+                tx,
+                data,
+                RequestLogKind::Disconnect,
+                LogChannelFullPolicy::WaitBriefly,
+            );
+        };
+        send(tx.clone());
+        send(tx.clone());
+        assert!(rx.recv().await.is_some());
+        assert!(rx.recv().await.is_some());
+
+        // ... and one that doesn't is dropped.
+        send(tx.clone());
+        send(tx.clone());
+        tokio::time::sleep(LOG_CHAN_WAIT_TIMEOUT * 2).await;
+        assert_eq!(dropped_records(RequestLogKind::Disconnect) - dropped, 1);
+        drop(tx);
+        assert!(rx.recv().await.is_some());
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn sampled_request_log() {
+        let sampling = RequestLogSampling {
+            rate: 0.0,
+            always_log_longer_than: Duration::from_secs(60),
+        };
+        let should_log = |ctx: &RequestMonitoring| ctx.0.try_lock().unwrap().should_log(&sampling);
+
+        // With a zero rate, only failed and long requests are logged.
+        let ctx = test_ctx();
+        assert!(should_log(&ctx));
+        let ctx = test_ctx();
+        ctx.set_success();
+        assert!(!should_log(&ctx));
+        let ctx = test_ctx();
+        ctx.set_success();
+        ctx.0.try_lock().unwrap().first_packet -= chrono::Duration::minutes(2);
+        assert!(should_log(&ctx));
+
+        // The decision is the same for the connect and disconnect records of a session.
+        let sampling = RequestLogSampling {
+            rate: 0.5,
+            ..sampling
+        };
+        let mut sampled = 0;
+        for _ in 0..1000 {
+            let ctx = test_ctx();
+            ctx.set_success();
+            let mut inner = ctx.0.try_lock().unwrap();
+            let on_connect = inner.should_log(&sampling);
+            inner.disconnect_timestamp = Some(chrono::Utc::now());
+            assert_eq!(inner.should_log(&sampling), on_connect);
+            sampled += usize::from(on_connect);
+        }
+        assert!((400..600).contains(&sampled), "{sampled} sessions sampled");
+    }
+
+    #[test]
+    fn sampled_request_log_long_session() {
+        let sampling = RequestLogSampling {
+            rate: 0.0,
+            always_log_longer_than: Duration::from_secs(60),
+        };
+
+        // A session that outlives `always_log_longer_than` after a quick connect keeps the
+        // decision made for its connect record.
+        let ctx = test_ctx();
+        ctx.set_success();
+        let mut inner = ctx.0.try_lock().unwrap();
+        assert!(!inner.should_log(&sampling));
+        inner.first_packet -= chrono::Duration::minutes(2);
+        inner.disconnect_timestamp = Some(chrono::Utc::now());
+        assert!(!inner.should_log(&sampling));
+    }
+
+    #[test]
+    fn snapshot_has_recorded_fields() {
+        let ctx = test_ctx();
+        let mut options = pq_proto::StartupMessageParamsBuilder::default();
+        options.insert("user", "alice");
+        options.insert("database", "neondb");
+        options.insert("application_name", "psql");
+        ctx.set_db_options(options.freeze());
+        ctx.set_project(aux_info("main"));
+        ctx.set_compute_addr("10.0.0.1:5432".parse().unwrap());
+        ctx.set_auth_method(AuthMethod::ScramSha256);
+        ctx.set_auth_rule_id("rule".to_owned());
+        ctx.set_tls_negotiation(TlsNegotiation::Direct);
+        ctx.set_rejected(false);
+        ctx.set_cold_start_info(ColdStartInfo::VmPoolHit);
+        ctx.set_error_kind(ErrorKind::User);
+        ctx.set_outcome(true, Some("done".into()));
+
+        let snapshot = ctx.snapshot();
+        assert_eq!(snapshot.session_id, ctx.session_id());
+        assert_eq!(snapshot.peer_addr, ctx.peer_addr());
+        assert_eq!(snapshot.protocol.as_str(), "tcp");
+        assert_eq!(snapshot.region, "test");
+        assert_eq!(snapshot.user.as_deref(), Some("alice"));
+        assert_eq!(snapshot.dbname.as_deref(), Some("neondb"));
+        assert_eq!(snapshot.application.as_deref(), Some("psql"));
+        assert_eq!(snapshot.endpoint_id.as_deref(), Some("endpoint"));
+        assert_eq!(snapshot.project.as_deref(), Some("project"));
+        assert_eq!(snapshot.branch.as_deref(), Some("main"));
+        assert_eq!(
+            snapshot.compute_addr,
+            Some("10.0.0.1:5432".parse().unwrap())
+        );
+        assert!(matches!(
+            snapshot.auth_method,
+            Some(AuthMethod::ScramSha256)
+        ));
+        assert_eq!(snapshot.auth_rule_id.as_deref(), Some("rule"));
+        assert!(matches!(
+            snapshot.tls_negotiation,
+            Some(TlsNegotiation::Direct)
+        ));
+        assert_eq!(snapshot.rejected, Some(false));
+        assert!(matches!(snapshot.cold_start_info, ColdStartInfo::VmPoolHit));
+        assert_eq!(snapshot.error_kind, Some(ErrorKind::User));
+        assert!(snapshot.success);
+        assert_eq!(snapshot.outcome_reason.as_deref(), Some("done"));
+        assert_eq!(
+            snapshot
+                .pg_options
+                .as_ref()
+                .and_then(|options| options.get("user")),
+            Some("alice")
+        );
+        assert_eq!(snapshot.disconnect_timestamp, None);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{sync::Arc, time::SystemTime};
+use std::{num::NonZeroUsize, sync::Arc, time::SystemTime};
 
 use anyhow::Context;
 use bytes::{buf::Writer, BufMut, BytesMut};
@@ -21,7 +21,11 @@
 use tracing::{debug, info, Span};
 use utils::backoff;
 
-use crate::{config::remote_storage_from_toml, context::LOG_CHAN_DISCONNECT};
+use crate::{
+    config::remote_storage_from_toml,
+    context::{LOG_CHAN_DISCONNECT, LOG_CHAN_FULL_POLICY, LOG_SAMPLING},
+    metrics::Waiting,
+};
 
 use super::{RequestMonitoringInner, LOG_CHAN};
 
@@ -55,6 +59,68 @@ pub struct ParquetUploadArgs {
     /// What level of compression to use
     #[clap(long, default_value_t = Compression::UNCOMPRESSED)]
     parquet_upload_compression: Compression,
+
+    /// How many request records can wait for the upload worker
+    #[clap(long, default_value = "100000")]
+    parquet_upload_channel_capacity: NonZeroUsize,
+
+    /// What to do with a request record when the upload worker has fallen behind
+    #[clap(long, value_enum, default_value_t = LogChannelFullPolicy::DropNewest)]
+    parquet_upload_channel_full_policy: LogChannelFullPolicy,
+
+    /// The fraction of successful sessions to log, between 0 and 1
+    #[clap(long, default_value_t = 1.0)]
+    parquet_upload_sample_rate: f64,
+
+    /// Requests that fail or take longer than this are logged regardless of the sample rate
+    #[clap(long, default_value = "1m", value_parser = humantime::parse_duration)]
+    parquet_upload_always_log_longer_than: std::time::Duration,
+}
+
+/// Which request records are uploaded to parquet.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestLogSampling {
+    /// The fraction of the sessions that are logged.
+    pub rate: f64,
+    /// Records of requests that took at least this long are always logged.
+    pub always_log_longer_than: std::time::Duration,
+}
+
+impl Default for RequestLogSampling {
+    fn default() -> Self {
+        Self {
+            rate: 1.0,
+            always_log_longer_than: std::time::Duration::MAX,
+        }
+    }
+}
+
+impl RequestLogSampling {
+    /// Whether the session is in the sample. The decision only depends on the session id.
+    pub fn samples(&self, session_id: uuid::Uuid) -> bool {
+        if self.rate >= 1.0 {
+            return true;
+        }
+        if self.rate <= 0.0 {
+            return false;
+        }
+        // Session ids are v7 uuids, which start with a timestamp: hash them to spread them evenly.
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        std::hash::Hash::hash(&session_id, &mut hasher);
+        (std::hash::Hasher::finish(&hasher) as f64 / u64::MAX as f64) < self.rate
+    }
+}
+
+/// What to do with a request record when the channel to the upload worker is full. Records are
+/// sent when request contexts are dropped, so they are never sent with blocking waits: records
+/// that don't fit are counted in `proxy_parquet_dropped_records_total`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogChannelFullPolicy {
+    /// Drop the record right away.
+    #[default]
+    DropNewest,
+    /// Wait a little for room in a background task, then drop the record.
+    WaitBriefly,
 }
 
 // Occasional network issues and such can cause remote operations to fail, and
@@ -86,17 +152,31 @@ pub struct RequestData {
     database: Option<String>,
     project: Option<String>,
     branch: Option<String>,
+    /// The compute node the proxy connected to
+    compute_addr: Option<String>,
     pg_options: Option<String>,
     auth_method: Option<&'static str>,
+    /// The JWT auth rule that authenticated the request
+    auth_rule_id: Option<String>,
+    /// How a postgres protocol client set up TLS
+    tls_negotiation: Option<&'static str>,
     error: Option<&'static str>,
     /// Success is counted if we form a HTTP response with sql rows inside
     /// Or if we make it to proxy_pass
     success: bool,
+    /// Why the request succeeded or failed, if recorded
+    outcome_reason: Option<String>,
     /// Indicates if the cplane started the new compute node for this request.
     cold_start_info: &'static str,
     /// Tracks time from session start (HTTP request/libpq TCP handshake)
     /// Through to success/failure
     duration_us: u64,
+    /// Parts of `duration_us` spent waiting for the control plane, the client, the compute node
+    /// and between retries
+    time_waiting_cplane_us: u64,
+    time_waiting_client_us: u64,
+    time_waiting_compute_us: u64,
+    time_waiting_retry_us: u64,
     /// If the session was successful after the disconnect, will be created one more event with filled `disconnect_timestamp`.
     disconnect_timestamp: Option<chrono::NaiveDateTime>,
 }
@@ -130,6 +210,7 @@ fn from(value: &RequestMonitoringInner) -> Self {
             database: value.dbname.as_deref().map(String::from),
             project: value.project.as_deref().map(String::from),
             branch: value.branch.as_deref().map(String::from),
+            compute_addr: value.compute_addr.map(|addr| addr.to_string()),
             pg_options: value
                 .pg_options
                 .as_ref()
@@ -140,20 +221,35 @@ fn from(value: &RequestMonitoringInner) -> Self {
                 super::AuthMethod::ScramSha256Plus => "scram_sha_256_plus",
                 super::AuthMethod::Cleartext => "cleartext",
             }),
+            auth_rule_id: value.auth_rule_id.clone(),
+            tls_negotiation: value.tls_negotiation.map(|x| match x {
+                super::TlsNegotiation::Plaintext => "plaintext",
+                super::TlsNegotiation::SslRequest => "ssl_request",
+                super::TlsNegotiation::Direct => "direct",
+            }),
             protocol: value.protocol.as_str(),
             region: value.region,
             error: value.error_kind.as_ref().map(|e| e.to_metric_label()),
             success: value.success,
+            outcome_reason: value.outcome_reason.as_deref().map(String::from),
             cold_start_info: value.cold_start_info.as_str(),
             duration_us: SystemTime::from(value.first_packet)
                 .elapsed()
                 .unwrap_or_default()
                 .as_micros() as u64, // 584 millenia... good enough
+            time_waiting_cplane_us: waiting_us(value, Waiting::Cplane),
+            time_waiting_client_us: waiting_us(value, Waiting::Client),
+            time_waiting_compute_us: waiting_us(value, Waiting::Compute),
+            time_waiting_retry_us: waiting_us(value, Waiting::RetryTimeout),
             disconnect_timestamp: value.disconnect_timestamp.map(|x| x.naive_utc()),
         }
     }
 }
 
+fn waiting_us(value: &RequestMonitoringInner, waiting_for: Waiting) -> u64 {
+    value.latency_timer.waiting_time(waiting_for).as_micros() as u64
+}
+
 /// Parquet request context worker
 ///
 /// It listened on a channel for all completed requests, extracts the data and writes it into a parquet file,
@@ -167,8 +263,17 @@ pub async fn worker(
         return Ok(());
     };
 
-    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (tx, mut rx) = mpsc::channel(config.parquet_upload_channel_capacity.get());
     LOG_CHAN.set(tx.downgrade()).unwrap();
+    LOG_CHAN_FULL_POLICY
+        .set(config.parquet_upload_channel_full_policy)
+        .unwrap();
+    LOG_SAMPLING
+        .set(RequestLogSampling {
+            rate: config.parquet_upload_sample_rate,
+            always_log_longer_than: config.parquet_upload_always_log_longer_than,
+        })
+        .unwrap();
 
     // setup row stream that will close on cancellation
     let cancellation_token2 = cancellation_token.clone();
@@ -203,7 +308,8 @@ pub async fn worker(
     if let Some(disconnect_events_storage_config) =
         config.parquet_upload_disconnect_events_remote_storage
     {
-        let (tx_disconnect, mut rx_disconnect) = mpsc::unbounded_channel();
+        let (tx_disconnect, mut rx_disconnect) =
+            mpsc::channel(config.parquet_upload_channel_capacity.get());
         LOG_CHAN_DISCONNECT.set(tx_disconnect.downgrade()).unwrap();
 
         // setup row stream that will close on cancellation
@@ -407,7 +513,7 @@ async fn upload_parquet(
 
 #[cfg(test)]
 mod tests {
-    use std::{net::Ipv4Addr, num::NonZeroUsize, sync::Arc};
+    use std::{net::Ipv4Addr, num::NonZeroUsize, sync::Arc, time::Duration};
 
     use camino::Utf8Path;
     use clap::Parser;
@@ -429,7 +535,11 @@ mod tests {
     use tokio::{sync::mpsc, time};
     use walkdir::WalkDir;
 
-    use super::{worker_inner, ParquetConfig, ParquetUploadArgs, RequestData};
+    use super::{
+        worker_inner, LogChannelFullPolicy, ParquetConfig, ParquetUploadArgs, RequestData,
+    };
+    use crate::context::RequestMonitoring;
+    use crate::metrics::Waiting;
 
     #[derive(Parser)]
     struct ProxyCliArgs {
@@ -452,6 +562,19 @@ fn default_parser() {
             parquet_upload.parquet_upload_compression,
             Compression::UNCOMPRESSED
         );
+        assert_eq!(
+            parquet_upload.parquet_upload_channel_capacity,
+            NonZeroUsize::new(100_000).unwrap()
+        );
+        assert_eq!(
+            parquet_upload.parquet_upload_channel_full_policy,
+            LogChannelFullPolicy::DropNewest
+        );
+        assert_eq!(parquet_upload.parquet_upload_sample_rate, 1.0);
+        assert_eq!(
+            parquet_upload.parquet_upload_always_log_longer_than,
+            time::Duration::from_secs(60)
+        );
     }
 
     #[test]
@@ -470,6 +593,14 @@ fn full_parser() {
             "10m",
             "--parquet-upload-compression",
             "zstd(5)",
+            "--parquet-upload-channel-capacity",
+            "1000",
+            "--parquet-upload-channel-full-policy",
+            "wait-briefly",
+            "--parquet-upload-sample-rate",
+            "0.01",
+            "--parquet-upload-always-log-longer-than",
+            "10s",
         ]);
         assert_eq!(
             parquet_upload.parquet_upload_remote_storage,
@@ -500,6 +631,19 @@ fn full_parser() {
             parquet_upload.parquet_upload_compression,
             Compression::ZSTD(ZstdLevel::try_new(5).unwrap())
         );
+        assert_eq!(
+            parquet_upload.parquet_upload_channel_capacity,
+            NonZeroUsize::new(1000).unwrap()
+        );
+        assert_eq!(
+            parquet_upload.parquet_upload_channel_full_policy,
+            LogChannelFullPolicy::WaitBriefly
+        );
+        assert_eq!(parquet_upload.parquet_upload_sample_rate, 0.01);
+        assert_eq!(
+            parquet_upload.parquet_upload_always_log_longer_than,
+            time::Duration::from_secs(10)
+        );
     }
 
     fn generate_request_data(rng: &mut impl Rng) -> RequestData {
@@ -517,14 +661,22 @@ fn generate_request_data(rng: &mut impl Rng) -> RequestData {
             database: Some(hex::encode(rng.gen::<[u8; 16]>())),
             project: Some(hex::encode(rng.gen::<[u8; 16]>())),
             branch: Some(hex::encode(rng.gen::<[u8; 16]>())),
+            compute_addr: None,
             pg_options: None,
             auth_method: None,
+            auth_rule_id: None,
+            tls_negotiation: None,
             protocol: ["tcp", "ws", "http"][rng.gen_range(0..3)],
             region: "us-east-1",
             error: None,
             success: rng.gen(),
+            outcome_reason: None,
             cold_start_info: "no",
             duration_us: rng.gen_range(0..30_000_000),
+            time_waiting_cplane_us: 0,
+            time_waiting_client_us: 0,
+            time_waiting_compute_us: 0,
+            time_waiting_retry_us: 0,
             disconnect_timestamp: None,
         }
     }
@@ -580,6 +732,85 @@ async fn run_test(
             .collect()
     }
 
+    #[test]
+    fn request_data_compute_addr() {
+        let ctx = RequestMonitoring::test();
+        let data = RequestData::from(&*ctx.0.try_lock().unwrap());
+        assert_eq!(data.compute_addr, None);
+
+        ctx.set_compute_addr("10.0.0.1:5432".parse().unwrap());
+        let data = RequestData::from(&*ctx.0.try_lock().unwrap());
+        assert_eq!(data.compute_addr.as_deref(), Some("10.0.0.1:5432"));
+    }
+
+    #[test]
+    fn request_data_auth_rule_id() {
+        let ctx = RequestMonitoring::test();
+        ctx.set_auth_rule_id("rule-1".to_owned());
+        let data = RequestData::from(&*ctx.0.try_lock().unwrap());
+        assert_eq!(data.auth_rule_id.as_deref(), Some("rule-1"));
+    }
+
+    #[test]
+    fn request_data_tls_negotiation() {
+        let ctx = RequestMonitoring::test();
+        let data = RequestData::from(&*ctx.0.try_lock().unwrap());
+        assert_eq!(data.tls_negotiation, None);
+
+        ctx.set_tls_negotiation(crate::context::TlsNegotiation::Direct);
+        let data = RequestData::from(&*ctx.0.try_lock().unwrap());
+        assert_eq!(data.tls_negotiation, Some("direct"));
+    }
+
+    #[tokio::test]
+    async fn request_data_waiting_times() {
+        tokio::time::pause();
+        let ctx = RequestMonitoring::test();
+        {
+            let _pause = ctx.latency_timer_pause(Waiting::Compute);
+            tokio::time::advance(Duration::from_millis(30)).await;
+        }
+        {
+            let _pause = ctx.latency_timer_pause(Waiting::Cplane);
+            tokio::time::advance(Duration::from_millis(20)).await;
+        }
+        {
+            let _pause = ctx.latency_timer_pause(Waiting::Compute);
+            tokio::time::advance(Duration::from_millis(5)).await;
+        }
+
+        assert_eq!(
+            ctx.waiting_time(Waiting::Compute),
+            Duration::from_millis(35)
+        );
+        assert_eq!(ctx.waiting_time(Waiting::Cplane), Duration::from_millis(20));
+        assert_eq!(ctx.waiting_time(Waiting::Client), Duration::ZERO);
+
+        let data = RequestData::from(&*ctx.0.try_lock().unwrap());
+        assert_eq!(data.time_waiting_compute_us, 35_000);
+        assert_eq!(data.time_waiting_cplane_us, 20_000);
+        assert_eq!(data.time_waiting_client_us, 0);
+        assert_eq!(data.time_waiting_retry_us, 0);
+    }
+
+    #[test]
+    fn request_data_outcome_reason() {
+        let ctx = RequestMonitoring::test();
+        let data = RequestData::from(&*ctx.0.try_lock().unwrap());
+        assert!(!data.success);
+        assert_eq!(data.outcome_reason, None);
+
+        ctx.set_outcome(false, Some("endpoint is disabled".into()));
+        let data = RequestData::from(&*ctx.0.try_lock().unwrap());
+        assert!(!data.success);
+        assert_eq!(data.outcome_reason.as_deref(), Some("endpoint is disabled"));
+
+        ctx.set_outcome(true, None);
+        let data = RequestData::from(&*ctx.0.try_lock().unwrap());
+        assert!(data.success);
+        assert_eq!(data.outcome_reason, None);
+    }
+
     #[tokio::test]
     async fn verify_parquet_no_compression() {
         let tmpdir = camino_tempfile::tempdir().unwrap();
@@ -4,13 +4,15 @@
 
 pub mod health_server;
 
-use std::time::Duration;
+use std::{pin::Pin, time::Duration};
 
 use anyhow::bail;
+use async_compression::tokio::bufread::{GzipDecoder, ZlibDecoder};
 use bytes::Bytes;
 use http_body_util::BodyExt;
 use hyper1::body::Body;
 use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 pub use reqwest::{Request, Response, StatusCode};
 pub use reqwest_middleware::{ClientWithMiddleware, Error};
@@ -103,9 +105,54 @@ pub async fn execute(&self, request: Request) -> Result<Response, Error> {
 }
 
 pub async fn parse_json_body_with_limit<D: DeserializeOwned>(
-    mut b: impl Body<Data = Bytes, Error = reqwest::Error> + Unpin,
+    b: impl Body<Data = Bytes, Error = reqwest::Error> + Unpin,
     limit: usize,
 ) -> anyhow::Result<D> {
+    let bytes = read_body_with_limit(b, limit).await?;
+    Ok(serde_json::from_slice::<D>(&bytes)?)
+}
+
+/// Like [`parse_json_body_with_limit`], for a body encoded with the given `Content-Encoding`.
+/// The limit applies to the decoded body as well.
+pub async fn parse_encoded_json_body_with_limit<D: DeserializeOwned>(
+    b: impl Body<Data = Bytes, Error = reqwest::Error> + Unpin,
+    content_encoding: Option<&str>,
+    limit: usize,
+) -> anyhow::Result<D> {
+    let bytes = read_body_with_limit(b, limit).await?;
+    let bytes = decode_body_with_limit(bytes, content_encoding, limit).await?;
+    Ok(serde_json::from_slice::<D>(&bytes)?)
+}
+
+async fn decode_body_with_limit(
+    bytes: Vec<u8>,
+    content_encoding: Option<&str>,
+    limit: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let content_encoding = content_encoding.map(|e| e.trim().to_ascii_lowercase());
+    let decoder: Pin<Box<dyn AsyncRead + Send + '_>> = match content_encoding.as_deref() {
+        None | Some("identity") => return Ok(bytes),
+        Some("gzip" | "x-gzip") => Box::pin(GzipDecoder::new(&bytes[..])),
+        Some("deflate") => Box::pin(ZlibDecoder::new(&bytes[..])),
+        Some(other) => bail!("Unsupported content encoding {other}"),
+    };
+
+    // read one byte past the limit to tell if the decoded body exceeds it.
+    let mut decoded = Vec::new();
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decoded)
+        .await?;
+    if decoded.len() > limit {
+        bail!("Decoded content length exceeds limit of {limit} bytes")
+    }
+    Ok(decoded)
+}
+
+async fn read_body_with_limit(
+    mut b: impl Body<Data = Bytes, Error = reqwest::Error> + Unpin,
+    limit: usize,
+) -> anyhow::Result<Vec<u8>> {
     // We could use `b.limited().collect().await.to_bytes()` here
     // but this ends up being slightly more efficient as far as I can tell.
 
@@ -126,7 +173,7 @@ pub async fn parse_json_body_with_limit<D: DeserializeOwned>(
         }
     }
 
-    Ok(serde_json::from_slice::<D>(&bytes)?)
+    Ok(bytes)
 }
 
 #[cfg(test)]
@@ -88,11 +88,11 @@
 // List of temporarily allowed lints to unblock beta/nightly.
 #![allow(unknown_lints, clippy::manual_inspect)]
 
-use std::{convert::Infallible, future::Future};
+use std::{convert::Infallible, future::Future, pin::pin, time::Duration};
 
 use anyhow::{bail, Context};
 use intern::{EndpointIdInt, EndpointIdTag, InternId};
-use tokio::task::JoinError;
+use tokio::task::{JoinError, JoinSet};
 use tokio_util::sync::CancellationToken;
 use tracing::warn;
 
@@ -157,6 +157,56 @@ pub async fn handle_signals<F, Fut>(
     }
 }
 
+/// Drive the maintenance and client tasks until the proxy should exit.
+///
+/// Returns immediately if a maintenance task completes or a client task fails.
+/// Once `token` is cancelled the client tasks are expected to stop accepting new
+/// connections and drain the existing ones. If `grace_period` is set and they
+/// have not finished by then, we stop waiting and return anyway.
+pub async fn run_tasks(
+    mut maintenance_tasks: JoinSet<anyhow::Result<Infallible>>,
+    mut client_tasks: JoinSet<anyhow::Result<()>>,
+    token: CancellationToken,
+    grace_period: Option<Duration>,
+) -> anyhow::Result<()> {
+    let mut drain_deadline = pin!(async {
+        token.cancelled().await;
+        match grace_period {
+            Some(grace_period) => tokio::time::sleep(grace_period).await,
+            None => std::future::pending().await,
+        }
+    });
+
+    let maintenance = loop {
+        tokio::select! {
+            res = maintenance_tasks.join_next() => match res {
+                // exit immediately on maintenance task completion
+                Some(res) => break flatten_err(res)?,
+                // exit with error immediately if all maintenance tasks have ceased (should be caught by branch above)
+                None => bail!("no maintenance tasks running. invalid state"),
+            },
+            res = client_tasks.join_next() => match res {
+                // exit immediately on client task error
+                Some(res) => flatten_err(res)?,
+                // exit if all our client tasks have shutdown gracefully
+                None => return Ok(()),
+            },
+            // exit once the grace period after shutdown has elapsed, abandoning any sessions still running
+            () = &mut drain_deadline => {
+                warn!(
+                    remaining_tasks = client_tasks.len(),
+                    "shutdown grace period elapsed, exiting with client tasks still running"
+                );
+                return Ok(());
+            }
+        }
+    };
+
+    // maintenance tasks return Infallible success values, this is an impossible value
+    // so this match statically ensures that there are no possibilities for that value
+    match maintenance {}
+}
+
 /// Flattens `Result<Result<T>>` into `Result<T>`.
 pub fn flatten_err<T>(r: Result<anyhow::Result<T>, JoinError>) -> anyhow::Result<T> {
     r.context("join error").and_then(|x| x)
@@ -279,3 +329,97 @@ pub fn as_project(&self) -> ProjectId {
         ProjectId(self.0.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_util::task::TaskTracker;
+
+    use super::*;
+
+    /// A minimal stand-in for `proxy::task_main`: echoes on every connection,
+    /// stops accepting on cancellation and then waits for open sessions.
+    async fn echo_task_main(listener: TcpListener, token: CancellationToken) -> anyhow::Result<()> {
+        let connections = TaskTracker::new();
+        loop {
+            tokio::select! {
+                accept = listener.accept() => {
+                    let (mut stream, _) = accept?;
+                    connections.spawn(async move {
+                        let (mut read, mut write) = stream.split();
+                        tokio::io::copy(&mut read, &mut write).await
+                    });
+                }
+                () = token.cancelled() => break,
+            }
+        }
+        connections.close();
+        connections.wait().await;
+        Ok(())
+    }
+
+    async fn start(
+        grace_period: Duration,
+    ) -> (TcpStream, CancellationToken, JoinSet<anyhow::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let token = CancellationToken::new();
+
+        let mut maintenance_tasks = JoinSet::new();
+        maintenance_tasks.spawn(std::future::pending());
+        let mut client_tasks = JoinSet::new();
+        client_tasks.spawn(echo_task_main(listener, token.clone()));
+
+        let client = TcpStream::connect(addr).await.unwrap();
+
+        let mut main = JoinSet::new();
+        main.spawn(run_tasks(
+            maintenance_tasks,
+            client_tasks,
+            token.clone(),
+            Some(grace_period),
+        ));
+        (client, token, main)
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_sessions_within_grace_period() {
+        let (mut client, token, mut main) = start(Duration::from_secs(30)).await;
+
+        token.cancel();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // the session keeps working after shutdown has started
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        drop(client);
+        let res = tokio::time::timeout(Duration::from_secs(10), main.join_next())
+            .await
+            .unwrap()
+            .unwrap();
+        flatten_err(res).unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_gives_up_after_grace_period() {
+        let grace_period = Duration::from_millis(100);
+        let (_client, token, mut main) = start(grace_period).await;
+
+        let start = Instant::now();
+        token.cancel();
+        let res = tokio::time::timeout(Duration::from_secs(10), main.join_next())
+            .await
+            .unwrap()
+            .unwrap();
+        flatten_err(res).unwrap();
+        assert!(start.elapsed() >= grace_period);
+    }
+}
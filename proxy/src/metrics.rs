@@ -137,6 +137,9 @@ pub struct ProxyMetrics {
     /// Number of events consumed from redis (per event type).
     pub redis_events_count: CounterVec<StaticLabelSet<RedisEventsCount>>,
 
+    /// Number of request records not uploaded to parquet because the upload worker fell behind.
+    pub parquet_dropped_records_total: CounterVec<StaticLabelSet<RequestLogKind>>,
+
     #[metric(namespace = "connect_compute_lock")]
     pub connect_compute_lock: ApiLockMetrics,
 
@@ -422,6 +425,16 @@ pub fn unpause(&mut self, start: Instant, waiting_for: Waiting) {
         }
     }
 
+    /// Total time spent waiting for `waiting_for` so far.
+    pub fn waiting_time(&self, waiting_for: Waiting) -> time::Duration {
+        match waiting_for {
+            Waiting::Cplane => self.accumulated.cplane,
+            Waiting::Client => self.accumulated.client,
+            Waiting::Compute => self.accumulated.compute,
+            Waiting::RetryTimeout => self.accumulated.retry,
+        }
+    }
+
     pub fn cold_start_info(&mut self, cold_start_info: ColdStartInfo) {
         self.cold_start_info = cold_start_info;
     }
@@ -547,6 +560,13 @@ pub enum RedisEventsCount {
     AllowedIpsUpdate,
 }
 
+#[derive(FixedCardinalityLabel, Clone, Copy, Debug)]
+#[label(singleton = "kind")]
+pub enum RequestLogKind {
+    Connect,
+    Disconnect,
+}
+
 pub struct ThreadPoolWorkers(usize);
 pub struct ThreadPoolWorkerId(pub usize);
 
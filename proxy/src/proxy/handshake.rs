@@ -10,9 +10,9 @@
 use crate::{
     auth::endpoint_sni,
     config::{TlsConfig, PG_ALPN_PROTOCOL},
-    context::RequestMonitoring,
+    context::{RequestMonitoring, TlsNegotiation},
     error::ReportableError,
-    metrics::Metrics,
+    metrics::{Metrics, Protocol},
     proxy::ERR_INSECURE_CONNECTION,
     stream::{PqStream, Stream, StreamUpgradeError},
 };
@@ -167,6 +167,12 @@ pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
                             .resolve(conn_info.server_name())
                             .ok_or(HandshakeError::MissingCertificate)?;
 
+                        ctx.set_tls_negotiation(if direct {
+                            TlsNegotiation::Direct
+                        } else {
+                            TlsNegotiation::SslRequest
+                        });
+
                         stream = PqStream {
                             framed: Framed {
                                 stream: Stream::Tls {
@@ -200,6 +206,7 @@ pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
                         .throw_error_str(ERR_INSECURE_CONNECTION, crate::error::ErrorKind::User)
                         .await?;
                 }
+                record_plaintext(ctx, &stream);
 
                 info!(
                     ?version,
@@ -226,6 +233,8 @@ pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
 
                 // TODO: remove unsupported options so we don't send them to compute.
 
+                record_plaintext(ctx, &stream);
+
                 stream
                     .write_message(&Be::NegotiateProtocolVersion {
                         version: PG_PROTOCOL_LATEST,
@@ -255,3 +264,11 @@ pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
         }
     }
 }
+
+/// Record that a postgres protocol client didn't use TLS, if the connection isn't a websocket
+/// one, which is encrypted outside of the postgres protocol.
+fn record_plaintext<S>(ctx: &RequestMonitoring, stream: &PqStream<Stream<S>>) {
+    if let (Stream::Raw { .. }, Protocol::Tcp) = (stream.get_ref(), ctx.protocol()) {
+        ctx.set_tls_negotiation(TlsNegotiation::Plaintext);
+    }
+}
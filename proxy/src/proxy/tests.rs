@@ -237,6 +237,97 @@ async fn handshake_tls() -> anyhow::Result<()> {
     proxy.await?
 }
 
+/// Complete a TLS handshake against `tls` and return the subject of the certificate served for `sni`.
+async fn served_cert_subject(
+    tls: &TlsConfig,
+    roots: &rustls::RootCertStore,
+    sni: &str,
+) -> anyhow::Result<String> {
+    let (client, server) = tokio::io::duplex(8192);
+
+    let acceptor = tokio_rustls::TlsAcceptor::from(tls.to_server_config());
+    let server = tokio::spawn(async move { acceptor.accept(server).await });
+
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth(),
+    ));
+    let stream = connector
+        .connect(pki_types::ServerName::try_from(sni.to_owned())?, client)
+        .await?;
+    server.await??;
+
+    let cert = &stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .context("no server certificate")?[0];
+    let (_, cert) =
+        x509_parser::parse_x509_certificate(cert).context("failed to parse server certificate")?;
+    Ok(cert.subject().to_string())
+}
+
+#[tokio::test]
+async fn handshake_tls_selects_cert_by_sni() -> anyhow::Result<()> {
+    let ca = rcgen::Certificate::from_params({
+        let mut params = rcgen::CertificateParams::default();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params
+    })?;
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(pki_types::CertificateDer::from(ca.serialize_der()?))?;
+
+    // lay out the certificates the same way cert-manager does
+    let certs_dir = camino_tempfile::tempdir()?;
+    for name in ["first", "second"] {
+        let cert = rcgen::Certificate::from_params({
+            let mut params = rcgen::CertificateParams::new(vec![format!("*.{name}.localhost")]);
+            params.distinguished_name = rcgen::DistinguishedName::new();
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, format!("*.{name}.localhost"));
+            params
+        })?;
+        let dir = certs_dir.path().join(name);
+        std::fs::create_dir(&dir)?;
+        std::fs::write(dir.join("tls.crt"), cert.serialize_pem_with_signer(&ca)?)?;
+        std::fs::write(dir.join("tls.key"), cert.serialize_private_key_pem())?;
+    }
+
+    let first = certs_dir.path().join("first");
+    let tls = crate::config::configure_tls(
+        first.join("tls.key").as_str(),
+        first.join("tls.crt").as_str(),
+        Some(&certs_dir.path().to_string()),
+        Some("second.localhost"),
+    )?;
+
+    assert_eq!(
+        served_cert_subject(&tls, &roots, "ep-foo.first.localhost").await?,
+        "CN=*.first.localhost"
+    );
+    assert_eq!(
+        served_cert_subject(&tls, &roots, "ep-bar.second.localhost").await?,
+        "CN=*.second.localhost"
+    );
+
+    // no SNI, or an unknown one, gets the configured default
+    let (default, _) = tls
+        .cert_resolver
+        .resolve(Some("second.localhost"))
+        .context("no certificate for second.localhost")?;
+    for server_name in [None, Some("ep-baz.third.localhost")] {
+        let (cert, _) = tls
+            .cert_resolver
+            .resolve(server_name)
+            .context("no default certificate")?;
+        assert!(Arc::ptr_eq(&cert, &default));
+    }
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn handshake_raw() -> anyhow::Result<()> {
     let (client, server) = tokio::io::duplex(1024);
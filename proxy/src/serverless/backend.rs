@@ -108,7 +108,7 @@ pub async fn authenticate_with_jwt(
                 "JWT login over link proxy is not supported",
             )),
             crate::auth::BackendType::Local(cache) => {
-                cache
+                let auth_rule_id = cache
                     .jwks_cache
                     .check_jwt(
                         ctx,
@@ -117,8 +117,8 @@ pub async fn authenticate_with_jwt(
                         &StaticAuthRules,
                         jwt,
                     )
-                    .await
-                    .map_err(|e| AuthError::auth_failed(e.to_string()))?;
+                    .await?;
+                ctx.set_auth_rule_id(auth_rule_id);
                 Ok(ComputeCredentials {
                     info: user_info.clone(),
                     keys: crate::auth::backend::ComputeCredentialKeys::None,
@@ -41,6 +41,8 @@ class InMemoryLayerInfo:
     kind: str
     lsn_start: str
     lsn_end: Optional[str]
+    open_duration_ms: Optional[int]
+    stats: Optional[Dict[str, Any]]
 
     @classmethod
     def from_json(cls, d: Dict[str, Any]) -> InMemoryLayerInfo:
@@ -48,6 +50,8 @@ class InMemoryLayerInfo:
             kind=d["kind"],
             lsn_start=d["lsn_start"],
             lsn_end=d.get("lsn_end"),
+            open_duration_ms=d.get("open_duration_ms"),
+            stats=d.get("stats"),
         )
 
 
@@ -588,7 +592,9 @@ class PageserverHttpClient(requests.Session, MetricsGetter):
         force_image_layer_creation=False,
         wait_until_uploaded=False,
         enhanced_gc_bottom_most_compaction=False,
-    ):
+        compact_key_range: Optional[Tuple[str, str]] = None,
+        time_budget_ms: Optional[int] = None,
+    ) -> Dict[str, Any]:
         self.is_testing_enabled_or_skip()
         query = {}
         if force_repartition:
@@ -599,6 +605,11 @@ class PageserverHttpClient(requests.Session, MetricsGetter):
             query["wait_until_uploaded"] = "true"
         if enhanced_gc_bottom_most_compaction:
             query["enhanced_gc_bottom_most_compaction"] = "true"
+        if compact_key_range is not None:
+            query["compact_key_range_start"] = compact_key_range[0]
+            query["compact_key_range_end"] = compact_key_range[1]
+        if time_budget_ms is not None:
+            query["time_budget_ms"] = str(time_budget_ms)
 
         log.info(f"Requesting compact: tenant {tenant_id}, timeline {timeline_id}")
         res = self.put(
@@ -608,7 +619,19 @@ class PageserverHttpClient(requests.Session, MetricsGetter):
         log.info(f"Got compact request response code: {res.status_code}")
         self.verbose_error(res)
         res_json = res.json()
-        assert res_json is None
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_update_layer_visibility(
+        self, tenant_id: Union[TenantId, TenantShardId], timeline_id: TimelineId
+    ) -> Dict[str, Any]:
+        res = self.put(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/update_layer_visibility",
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
 
     def timeline_preserve_initdb_archive(
         self, tenant_id: Union[TenantId, TenantShardId], timeline_id: TimelineId
@@ -886,6 +909,26 @@ class PageserverHttpClient(requests.Session, MetricsGetter):
 
         assert res.status_code in (200, 304)
 
+    def pin_layer(
+        self, tenant_id: Union[TenantId, TenantShardId], timeline_id: TimelineId, layer_name: str
+    ):
+        res = self.put(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/layer/{layer_name}/pin",
+        )
+        self.verbose_error(res)
+
+        assert res.status_code in (200, 304)
+
+    def unpin_layer(
+        self, tenant_id: Union[TenantId, TenantShardId], timeline_id: TimelineId, layer_name: str
+    ):
+        res = self.delete(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/layer/{layer_name}/pin",
+        )
+        self.verbose_error(res)
+
+        assert res.status_code in (200, 304)
+
     def evict_all_layers(self, tenant_id: Union[TenantId, TenantShardId], timeline_id: TimelineId):
         info = self.layer_map_info(tenant_id, timeline_id)
         for layer in info.historic_layers:
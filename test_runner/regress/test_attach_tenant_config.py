@@ -145,6 +145,20 @@ def test_fully_custom_config(positive_env: NeonEnv):
         "compaction_algorithm": {
             "kind": "tiered",
         },
+        "compaction_max_versions_per_key": 100,
+        "compaction_io_throttle_bytes_per_sec": 10485760,
+        "compaction_min_hole_coverage_size": 5,
+        "compaction_oversize_warn_multiplier": 3,
+        "compaction_align_to_partitions": True,
+        "compaction_upload_concurrency": 4,
+        "gc_compaction_verify_every_nth_key": 100,
+        "compaction_layer_lock_timeout": "30s",
+        "compaction_time_budget": "10s",
+        "l0_compaction_delta_size_limit": 268435456,
+        "compact_level0_phase1_value_access": {
+            "mode": "streaming-kmerge",
+            "validate": "key-lsn",
+        },
         "eviction_policy": {
             "kind": "LayerAccessThreshold",
             "period": "20s",
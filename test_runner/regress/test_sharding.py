@@ -1,4 +1,5 @@
 import os
+import threading
 import time
 from collections import defaultdict
 from typing import Dict, List, Optional, Union
@@ -16,6 +17,7 @@ from fixtures.neon_fixtures import (
     tenant_get_shards,
     wait_for_last_flush_lsn,
 )
+from fixtures.pageserver.http import PageserverApiException
 from fixtures.pageserver.utils import assert_prefix_empty, assert_prefix_not_empty
 from fixtures.remote_storage import s3_storage
 from fixtures.utils import wait_until
@@ -285,10 +287,11 @@ def test_sharding_split_compaction(neon_env_builder: NeonEnvBuilder, failpoint:
 
         # We will compare stats before + after compaction
         detail_before = ps.http_client().timeline_detail(shard, timeline_id)
+        layers_before = ps.http_client().layer_map_info(shard, timeline_id)
 
         # Invoke compaction: this should rewrite layers that are behind the pitr horizon
         try:
-            ps.http_client().timeline_compact(shard, timeline_id)
+            outcome = ps.http_client().timeline_compact(shard, timeline_id)
         except requests.ConnectionError as e:
             if failpoint is None:
                 raise e
@@ -315,6 +318,42 @@ def test_sharding_split_compaction(neon_env_builder: NeonEnvBuilder, failpoint:
             # Physical size should shrink because layers are smaller
             assert detail_after["current_physical_size"] < detail_before["current_physical_size"]
 
+            # The compaction summary should match the image layers that were rewritten or dropped.
+            summary = outcome["shard_ancestors"]
+            assert summary is not None
+            log.info(f"shard {shard} compaction summary: {summary}")
+
+            layers_after = ps.http_client().layer_map_info(shard, timeline_id)
+            images_before = {
+                layer.layer_file_name: layer.layer_file_size
+                for layer in layers_before.image_layers()
+            }
+            images_after = {
+                layer.layer_file_name: layer.layer_file_size
+                for layer in layers_after.image_layers()
+            }
+            rewritten = [
+                name
+                for name, size in images_before.items()
+                if name in images_after and images_after[name] != size
+            ]
+            # Ancestral layers without any keys of this shard were already dropped by the first
+            # compaction, so only image layers whose rewrite came out empty are dropped here.
+            dropped = [name for name in images_before if name not in images_after]
+
+            assert len(rewritten) > 0
+            assert summary["layers_rewritten"] == len(rewritten)
+            assert summary["layers_dropped"] == len(dropped)
+            assert summary["bytes_written"] == sum(images_after[name] for name in rewritten)
+            # Layers dropped without a rewrite aren't read, so they may or may not count as read
+            bytes_read_rewritten = sum(images_before[name] for name in rewritten)
+            bytes_read_dropped = sum(images_before[name] for name in dropped)
+            assert (
+                bytes_read_rewritten
+                <= summary["bytes_read"]
+                <= bytes_read_rewritten + bytes_read_dropped
+            )
+
     # Validate size statistics
     for shard in shards:
         ps = env.get_tenant_pageserver(shard)
@@ -346,6 +385,92 @@ def test_sharding_split_compaction(neon_env_builder: NeonEnvBuilder, failpoint:
     workload.validate()
 
 
+def test_sharding_split_compaction_stuck_upload(neon_env_builder: NeonEnvBuilder):
+    """
+    Test that when the uploads of the layers rewritten after a split don't complete, the failure
+    names the layers that were pending.
+    """
+
+    TENANT_CONF = {
+        "checkpoint_distance": 128 * 1024,
+        "compaction_threshold": 1,
+        "compaction_target_size": 128 * 1024,
+        "pitr_interval": "3600s",
+        "gc_period": "0s",
+        "compaction_period": "0s",
+        "image_creation_threshold": 9999,
+        "image_layer_creation_check_threshold": 0,
+    }
+
+    env = neon_env_builder.init_start(initial_tenant_conf=TENANT_CONF)
+    tenant_id = env.initial_tenant
+    timeline_id = env.initial_timeline
+
+    workload = Workload(env, tenant_id, timeline_id, branch_name="main")
+    workload.init()
+    workload.write_rows(256)
+    workload.validate()
+    workload.stop()
+
+    env.pageserver.http_client().timeline_checkpoint(
+        tenant_id, timeline_id, force_image_layer_creation=True, wait_until_uploaded=True
+    )
+
+    shards = env.storage_controller.tenant_shard_split(tenant_id, shard_count=2)
+    env.storage_controller.reconcile_until_idle()
+
+    # Layers are only rewritten after the generation has increased, and outside the PITR window
+    env.pageserver.stop()
+    env.pageserver.start()
+    updated_conf = TENANT_CONF.copy()
+    updated_conf["pitr_interval"] = "0s"
+    env.storage_controller.pageserver_api().set_tenant_config(tenant_id, updated_conf)
+    env.storage_controller.reconcile_until_idle()
+
+    shard = shards[0]
+    ps = env.get_tenant_pageserver(shard)
+    ps.http_client().timeline_gc(shard, timeline_id, gc_horizon=0)
+
+    ps.allowed_errors.extend(
+        [
+            ".*failed to perform remote task UploadLayer.*, will retry.*",
+            ".* ERROR .*Error processing HTTP request: InternalServerError\\(The timeline or pageserver is shutting down",
+        ]
+    )
+    ps.http_client().configure_failpoints(("before-upload-layer", "return"))
+
+    # The compaction gets stuck waiting for the uploads of the rewritten layers
+    compaction_errors: List[PageserverApiException] = []
+
+    def compaction_thread_fn():
+        try:
+            ps.http_client().timeline_compact(shard, timeline_id)
+        except PageserverApiException as e:
+            compaction_errors.append(e)
+
+    compaction_thread = threading.Thread(target=compaction_thread_fn)
+    compaction_thread.start()
+
+    def assert_upload_failed():
+        assert ps.log_contains(".*failpoint before-upload-layer.*")
+
+    wait_until(50, 0.2, assert_upload_failed)
+
+    # Detaching the tenant stops the upload queue, which fails the wait
+    env.storage_controller.tenant_policy_update(tenant_id, {"placement": "Detached"})
+    env.storage_controller.reconcile_until_idle()
+
+    compaction_thread.join(20.0)
+    assert not compaction_thread.is_alive()
+    assert len(compaction_errors) == 1
+
+    assert ps.log_contains(
+        ".*uploads of rewritten layers did not complete.*: [0-9A-F]+-[0-9A-F]+__[0-9A-F]+.*"
+    )
+
+    ps.http_client().configure_failpoints(("before-upload-layer", "off"))
+
+
 def test_sharding_split_smoke(
     neon_env_builder: NeonEnvBuilder,
 ):